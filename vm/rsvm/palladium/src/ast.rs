@@ -0,0 +1,152 @@
+use crate::token::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    And,
+    Or,
+}
+
+impl BinaryOperator {
+    /// Whether it's one of the operators with an instruction of their own
+    pub fn is_arithmetic(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Add
+                | BinaryOperator::Subtract
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Remainder
+        )
+    }
+
+    /// Whether it compares its operands, giving 1 or 0
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Equal
+                | BinaryOperator::NotEqual
+                | BinaryOperator::GreaterThan
+                | BinaryOperator::GreaterThanOrEqual
+                | BinaryOperator::LessThan
+                | BinaryOperator::LessThanOrEqual
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Integer {
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
+    Variable {
+        name: String,
+    },
+    /// Only `print` takes strings
+    String {
+        value: String,
+    },
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Unary {
+        operator: UnaryOperator,
+        value: Box<Expr>,
+    },
+    Call {
+        name: String,
+        arguments: Vec<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Let {
+        name: String,
+        value: Expr,
+    },
+    Assignment {
+        name: String,
+        value: Expr,
+    },
+    If {
+        condition: Expr,
+        body: Vec<Stmt>,
+        /// An `else if` is an else with just the inner if in it
+        else_body: Option<Vec<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Vec<Stmt>,
+    },
+    Return {
+        value: Option<Expr>,
+    },
+    /// Writes out strings and numbers one after the other, then a newline
+    Print {
+        values: Vec<Expr>,
+    },
+    /// An expression on its own, which gives the program its result if it's the last
+    Expression {
+        value: Expr,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+/// What a program is a list of
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Function(Function),
+    Statement(Stmt),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub items: Vec<Item>,
+}
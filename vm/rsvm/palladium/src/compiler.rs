@@ -1,158 +1,2051 @@
-use super::parser::program_parser::program;
-use crate::token::Token;
+use std::{collections::HashMap, fmt};
+
+use vm::assembler::{assembler_error::AssemblerError, Assembler};
+
+use crate::ast::{
+    BinaryOperator, Expr, ExprKind, Function, Item, Program, Stmt, StmtKind, UnaryOperator,
+};
+use crate::optimizer::{self, OptimizationLevel};
+use crate::parser::{parse, MAX_NESTING};
+use crate::peephole;
+use crate::symbol_table::SymbolTable;
+use crate::token::{Span, Token};
 use crate::visitor::Visitor;
 
+/// The largest integer literal, as registers are i32s. Write the smallest i32 as
+/// `-2147483647 - 1`, like in C.
+pub(crate) const MAX_INTEGER: i64 = i32::MAX as i64;
+
+/// Never holds a value, only jump targets and the like for an instruction or two
+const SCRATCH: u8 = 31;
+
+#[derive(Debug, Clone)]
+pub enum CompileError {
+    UnexpectedCharacter {
+        character: char,
+        span: Span,
+    },
+    /// A string with no `"` at its end
+    UnterminatedString {
+        span: Span,
+    },
+    /// A `\` in a string before something it doesn't escape
+    InvalidEscape {
+        character: char,
+        span: Span,
+    },
+    /// Digits that don't make a number, like an integer too big for an i64
+    InvalidNumber {
+        text: String,
+        span: Span,
+    },
+    /// The grammar doesn't allow the token where it is
+    UnexpectedToken {
+        expected: String,
+        found: Token,
+        span: Span,
+    },
+    /// More than a register holds
+    IntegerOutOfRange {
+        value: i64,
+        span: Span,
+    },
+    Unsupported {
+        feature: &'static str,
+        span: Span,
+    },
+    UndeclaredVariable {
+        name: String,
+        span: Span,
+    },
+    RedeclaredVariable {
+        name: String,
+        span: Span,
+    },
+    /// Calling a function no `fn` defines, which includes `main`
+    UndefinedFunction {
+        name: String,
+        span: Span,
+    },
+    RedefinedFunction {
+        name: String,
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// With a `fn main`, everything else has to be in functions too
+    StatementsOutsideMain {
+        span: Span,
+    },
+    /// Values spill onto the VM stack when the registers run out, but variables can't
+    OutOfRegisters {
+        span: Span,
+    },
+    /// Deeper than the compiler recurses safely, which includes long chains like `1+1+...`
+    NestedTooDeeply {
+        span: Span,
+    },
+    Assembler {
+        errors: Vec<AssemblerError>,
+    },
+}
+
+impl CompileError {
+    /// Where in the program the error is, which is everywhere for the assembler's
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::UnexpectedCharacter { span, .. }
+            | CompileError::UnterminatedString { span }
+            | CompileError::InvalidEscape { span, .. }
+            | CompileError::InvalidNumber { span, .. }
+            | CompileError::UnexpectedToken { span, .. }
+            | CompileError::IntegerOutOfRange { span, .. }
+            | CompileError::Unsupported { span, .. }
+            | CompileError::UndeclaredVariable { span, .. }
+            | CompileError::RedeclaredVariable { span, .. }
+            | CompileError::UndefinedFunction { span, .. }
+            | CompileError::RedefinedFunction { span, .. }
+            | CompileError::ArityMismatch { span, .. }
+            | CompileError::StatementsOutsideMain { span }
+            | CompileError::OutOfRegisters { span }
+            | CompileError::NestedTooDeeply { span } => Some(*span),
+            CompileError::Assembler { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}: ", span)?;
+        }
+        match self {
+            CompileError::UnexpectedCharacter { character, .. } => {
+                write!(f, "Unexpected character {:?}", character)
+            }
+            CompileError::UnterminatedString { .. } => {
+                f.write_str("String is missing its closing \"")
+            }
+            CompileError::InvalidEscape { character, .. } => {
+                write!(f, "Unknown escape \\{} in a string", character)
+            }
+            CompileError::InvalidNumber { text, .. } => write!(f, "{} is not a valid number", text),
+            CompileError::UnexpectedToken {
+                expected, found, ..
+            } => write!(f, "Expected {}, found {}", expected, found),
+            CompileError::IntegerOutOfRange { value, .. } => write!(
+                f,
+                "Integer {} is out of range, it must be between 0 and {}",
+                value, MAX_INTEGER
+            ),
+            CompileError::Unsupported { feature, .. } => {
+                write!(f, "{} are not supported yet", feature)
+            }
+            CompileError::UndeclaredVariable { name, .. } => {
+                write!(
+                    f,
+                    "Variable {} is used before being declared with let",
+                    name
+                )
+            }
+            CompileError::RedeclaredVariable { name, .. } => {
+                write!(f, "Variable {} is already declared", name)
+            }
+            CompileError::UndefinedFunction { name, .. } => {
+                write!(f, "Function {} is not defined", name)
+            }
+            CompileError::RedefinedFunction { name, .. } => {
+                write!(f, "Function {} is already defined", name)
+            }
+            CompileError::ArityMismatch {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "Function {} takes {} arguments but was given {}",
+                name, expected, found
+            ),
+            CompileError::StatementsOutsideMain { .. } => {
+                f.write_str("Programs with a main function can only define functions")
+            }
+            CompileError::OutOfRegisters { .. } => write!(
+                f,
+                "More variables are in scope than the {} registers can hold",
+                SCRATCH
+            ),
+            CompileError::NestedTooDeeply { .. } => write!(
+                f,
+                "Nested more than {} levels deep, split it up with variables",
+                MAX_NESTING
+            ),
+            CompileError::Assembler { errors } => {
+                f.write_str("The generated assembly was rejected:")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Where a value the compiler is working on is
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Register(u8),
+    /// Pushed onto the VM stack to free the register it was in, in the order of the operands
+    Spilled(u8),
+}
+
+/// What a call needs to know about the function it calls
+struct Signature {
+    label: String,
+    arity: usize,
+}
+
+/// Compiles Palladium to Iridium assembly.
+///
+/// The program starts at the `main` label, which is either `fn main` or the
+/// statements outside of functions, and halts with its result in $0.
+///
+/// Functions are called with every register the caller is using pushed onto
+/// the VM stack, followed by the arguments in order. The callee pops its
+/// arguments into registers of its own, and returns by pushing its result
+/// and RETing. The caller then pops the result and its registers back.
+///
+/// When an expression needs more registers than there are, the values that
+/// will be needed last are pushed onto the VM stack, and popped back when they are.
 pub struct Compiler {
     free_registers: Vec<u8>,
-    used_registers: Vec<u8>,
+    used_registers: Vec<Operand>,
     assembly: Vec<String>,
+    symbols: SymbolTable,
+    functions: HashMap<String, Signature>,
+    /// Whether `return` goes back to a caller, rather than ending the program from main
+    in_function: bool,
+    /// How many labels were generated, to number the next one
+    labels: usize,
+    /// The statement being compiled, for errors that have nothing smaller to point at
+    statement: Span,
+    errors: Vec<CompileError>,
+    optimization: OptimizationLevel,
+    /// The `.data` section, of the strings `print` uses
+    data: Vec<String>,
+    /// The label of each string in the data, so each is only there once
+    strings: HashMap<String, String>,
+    /// The label of the routine that prints numbers, once something needs it
+    print_number: Option<String>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
         let mut free_registers = vec![];
-        for i in 0..32 {
+        for i in 0..SCRATCH {
             free_registers.push(i);
         }
         free_registers.reverse();
         Compiler {
-            free_registers: free_registers,
+            free_registers,
             used_registers: vec![],
             assembly: vec![],
+            symbols: SymbolTable::new(),
+            functions: HashMap::new(),
+            in_function: false,
+            labels: 0,
+            statement: Span::default(),
+            errors: vec![],
+            optimization: OptimizationLevel::default(),
+            data: vec![],
+            strings: HashMap::new(),
+            print_number: None,
+        }
+    }
+
+    /// How hard `compile` works on the program, O0 unless set
+    pub fn set_optimization(&mut self, optimization: OptimizationLevel) {
+        self.optimization = optimization;
+    }
+
+    /// Compiles a program into a PIE image the VM can run.
+    /// The value of main's `return`, or its last expression, ends up in $0.
+    pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, CompileError> {
+        let assembly = self.compile_to_assembly(source)?;
+        Assembler::new()
+            .assemble(&assembly)
+            .map_err(|errors| CompileError::Assembler { errors })
+    }
+
+    /// Compiles a program into the Iridium assembly `compile` assembles
+    pub fn compile_to_assembly(&mut self, source: &str) -> Result<String, CompileError> {
+        let mut program = parse(source)?;
+
+        *self = Compiler {
+            optimization: self.optimization,
+            ..Compiler::new()
+        };
+        if self.optimization == OptimizationLevel::O1 {
+            program = optimizer::optimize(program);
+        }
+        self.visit_program(&program);
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0));
+        }
+        if self.optimization == OptimizationLevel::O1 {
+            self.assembly = peephole::optimize(std::mem::take(&mut self.assembly));
+        }
+
+        let mut assembly = String::from(".data\n");
+        for line in &self.data {
+            assembly.push_str(line);
+            assembly.push('\n');
+        }
+        assembly.push_str(".code\n.entry @main\n");
+        for line in &self.assembly {
+            assembly.push_str(line);
+            assembly.push('\n');
+        }
+        Ok(assembly)
+    }
+
+    /// Puts the last statement's value in $0, as the program's result. Statements
+    /// like `if` and `while` have no value, so a program ending in one gives 0.
+    fn move_result_to_zero(&mut self) {
+        if self.used_registers.is_empty() {
+            self.assembly.push("LOAD $0 #0".to_string());
+        } else {
+            let result_reg = self.take_operand();
+            self.copy(result_reg, 0);
+        }
+    }
+
+    /// A LOAD only takes 16 bits, so a bigger number is LOADed as its high half,
+    /// shifted up by multiplying by 256 twice, plus its low half
+    fn load_integer(&mut self, reg: u8, value: u32) {
+        let (high, low) = (value >> 16, value & 0xFFFF);
+        if high == 0 {
+            self.assembly.push(format!("LOAD ${} #{}", reg, low));
+            return;
+        }
+        self.assembly.push(format!("LOAD ${} #{}", reg, high));
+        self.assembly.push(format!("LOAD ${} #256", SCRATCH));
+        for _ in 0..2 {
+            self.assembly
+                .push(format!("MUL ${} ${} ${}", reg, SCRATCH, reg));
+        }
+        if low != 0 {
+            self.assembly.push(format!("LOAD ${} #{}", SCRATCH, low));
+            self.assembly
+                .push(format!("ADD ${} ${} ${}", reg, SCRATCH, reg));
+        }
+    }
+
+    /// There's no MOV, so this subtracts a register from itself for a zero, and adds that
+    fn copy(&mut self, from: u8, to: u8) {
+        if from == to {
+            return;
+        }
+        self.assembly
+            .push(format!("SUB ${} ${} ${}", from, from, to));
+        self.assembly.push(format!("ADD ${} ${} ${}", from, to, to));
+    }
+
+    /// A register for a new value. When none are free, the oldest value in one is
+    /// spilled onto the VM stack, as it's the one that will be needed last.
+    fn allocate(&mut self) -> u8 {
+        if let Some(reg) = self.free_registers.pop() {
+            return reg;
+        }
+        let oldest = self
+            .used_registers
+            .iter()
+            .enumerate()
+            .find_map(|(index, operand)| match operand {
+                Operand::Register(reg) if !self.symbols.owns_register(*reg) => Some((index, *reg)),
+                _ => None,
+            });
+        match oldest {
+            Some((index, reg)) => {
+                self.assembly.push(format!("PUSH ${}", reg));
+                self.used_registers[index] = Operand::Spilled(reg);
+                reg
+            }
+            None => {
+                self.errors.push(CompileError::OutOfRegisters {
+                    span: self.statement,
+                });
+                SCRATCH
+            }
+        }
+    }
+
+    fn push_operand(&mut self, reg: u8) {
+        self.used_registers.push(Operand::Register(reg));
+    }
+
+    /// Takes the last value off the operands, popping it back off the VM stack if it was spilled
+    fn take_operand(&mut self) -> u8 {
+        match self.used_registers.pop() {
+            Some(Operand::Register(reg)) => reg,
+            Some(Operand::Spilled(_)) => match self.free_registers.pop() {
+                Some(reg) => {
+                    self.assembly.push(format!("POP ${}", reg));
+                    reg
+                }
+                None => {
+                    // making room may spill another value, which has to go on top of this one
+                    self.assembly.push(format!("POP ${}", SCRATCH));
+                    let reg = self.allocate();
+                    self.copy(SCRATCH, reg);
+                    reg
+                }
+            },
+            None => unreachable!("every expression leaves a value"),
+        }
+    }
+
+    fn spilled(&self) -> usize {
+        self.used_registers
+            .iter()
+            .filter(|operand| matches!(operand, Operand::Spilled(_)))
+            .count()
+    }
+
+    /// Pops values spilled since there were `depth` of them back into their registers,
+    /// so that every path to a label leaves the values where the label expects them.
+    /// Whatever took those registers is done with them by the time it jumps.
+    fn unspill_to(&mut self, depth: usize) {
+        while self.spilled() > depth {
+            let (index, reg) = self
+                .used_registers
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(index, operand)| match operand {
+                    Operand::Spilled(reg) => Some((index, *reg)),
+                    _ => None,
+                })
+                .unwrap();
+            let Some(free) = self.free_registers.iter().position(|free| *free == reg) else {
+                self.errors.push(CompileError::OutOfRegisters {
+                    span: self.statement,
+                });
+                return;
+            };
+            self.free_registers.remove(free);
+            self.assembly.push(format!("POP ${}", reg));
+            self.used_registers[index] = Operand::Register(reg);
+        }
+    }
+
+    /// Makes a register free again, unless a variable lives in it
+    fn release(&mut self, reg: u8) {
+        if reg != SCRATCH && !self.symbols.owns_register(reg) {
+            self.free_registers.push(reg);
+        }
+    }
+
+    /// Gives every register back, for a function that starts with nothing in use
+    fn reset_registers(&mut self) {
+        let fresh = Compiler::new();
+        self.free_registers = fresh.free_registers;
+        self.used_registers = fresh.used_registers;
+        self.symbols = fresh.symbols;
+    }
+
+    /// Collects the functions up front, so they can be called before they're defined
+    fn declare_functions(&mut self, functions: &[&Function]) {
+        for function in functions {
+            let name = &function.name;
+            if name == "main" {
+                if !function.params.is_empty() {
+                    self.errors.push(CompileError::ArityMismatch {
+                        name: name.clone(),
+                        expected: 0,
+                        found: function.params.len(),
+                        span: function.span,
+                    });
+                }
+                continue;
+            }
+            if self.functions.contains_key(name) {
+                self.errors.push(CompileError::RedefinedFunction {
+                    name: name.clone(),
+                    span: function.span,
+                });
+                continue;
+            }
+            let label = self.new_label("fn");
+            self.functions.insert(
+                name.clone(),
+                Signature {
+                    label,
+                    arity: function.params.len(),
+                },
+            );
+        }
+    }
+
+    fn visit_main(&mut self, body: &[Stmt]) {
+        self.reset_registers();
+        self.in_function = false;
+        self.place_label("main");
+        self.visit_statements(body, true);
+        self.move_result_to_zero();
+        self.assembly.push("HLT".to_string());
+    }
+
+    fn visit_function(&mut self, function: &Function) {
+        self.reset_registers();
+        self.in_function = true;
+        self.statement = function.span;
+        let label = self.functions[&function.name].label.clone();
+        self.place_label(&label);
+        // the last argument was pushed last
+        for param in function.params.iter().rev() {
+            let param_reg = self.allocate();
+            self.assembly.push(format!("POP ${}", param_reg));
+            if !self.symbols.declare(&param.name, param_reg) {
+                self.errors.push(CompileError::RedeclaredVariable {
+                    name: param.name.clone(),
+                    span: param.span,
+                });
+            }
+        }
+        self.visit_statements(&function.body, false);
+        // falling off the end returns 0
+        self.visit_stmt(&Stmt {
+            kind: StmtKind::Return { value: None },
+            span: function.span,
+        });
+    }
+
+    fn visit_call(&mut self, name: &str, arguments: &[Expr], span: Span) {
+        let label = match self.functions.get(name) {
+            Some(signature) => {
+                if signature.arity != arguments.len() {
+                    self.errors.push(CompileError::ArityMismatch {
+                        name: name.to_string(),
+                        expected: signature.arity,
+                        found: arguments.len(),
+                        span,
+                    });
+                }
+                signature.label.clone()
+            }
+            None => {
+                self.errors.push(CompileError::UndefinedFunction {
+                    name: name.to_string(),
+                    span,
+                });
+                String::new()
+            }
+        };
+
+        let live: Vec<u8> = (0..SCRATCH)
+            .filter(|reg| !self.free_registers.contains(reg))
+            .collect();
+        for reg in &live {
+            self.assembly.push(format!("PUSH ${}", reg));
+        }
+        // once saved, the arguments can reuse those registers, except for the
+        // variables they may read
+        let operands = std::mem::take(&mut self.used_registers);
+        let free_registers = self.free_registers.clone();
+        for reg in &live {
+            self.release(*reg);
+        }
+        for argument in arguments {
+            let argument_reg = self.evaluate(argument);
+            self.assembly.push(format!("PUSH ${}", argument_reg));
+            self.release(argument_reg);
+        }
+        self.assembly.push(format!("LOAD ${} @{}", SCRATCH, label));
+        self.assembly.push(format!("CALL ${}", SCRATCH));
+
+        // the result is on top of the saved registers
+        self.assembly.push(format!("POP ${}", SCRATCH));
+        self.used_registers = operands;
+        self.free_registers = free_registers;
+        for reg in live.iter().rev() {
+            self.assembly.push(format!("POP ${}", reg));
+        }
+        let result_reg = self.allocate();
+        self.copy(SCRATCH, result_reg);
+        self.push_operand(result_reg);
+    }
+
+    /// Compiles an expression, returning the register its value is in
+    fn evaluate(&mut self, expr: &Expr) -> u8 {
+        self.visit_expr(expr);
+        self.take_operand()
+    }
+
+    /// Only the last statement of the program keeps its value, as the program's result
+    fn visit_statements(&mut self, statements: &[Stmt], keep_last: bool) {
+        for (i, statement) in statements.iter().enumerate() {
+            let live = self.used_registers.len();
+            self.visit_stmt(statement);
+            if keep_last && i + 1 == statements.len() {
+                break;
+            }
+            while self.used_registers.len() > live {
+                let reg = self.take_operand();
+                self.release(reg);
+            }
+        }
+    }
+
+    /// The variables declared in a block go away at its end
+    fn visit_block(&mut self, body: &[Stmt]) {
+        self.symbols.enter_scope();
+        self.visit_statements(body, false);
+        let registers = self.symbols.exit_scope();
+        self.free_registers.extend(registers);
+    }
+
+    /// A label for the assembly that no other part of the program uses
+    fn new_label(&mut self, prefix: &str) -> String {
+        self.labels += 1;
+        format!("{}{}", prefix, self.labels)
+    }
+
+    /// Labels have to go on an instruction, so they get a NOP of their own
+    fn place_label(&mut self, label: &str) {
+        self.assembly.push(format!("{}: NOP", label));
+    }
+
+    /// The label of a string in the data, putting it there the first time
+    fn string_label(&mut self, text: &str) -> String {
+        if let Some(label) = self.strings.get(text) {
+            return label.clone();
         }
+        let label = self.new_label("str");
+        let escaped = text
+            .replace('\\', "\\\\")
+            .replace('\'', "\\'")
+            .replace('\n', "\\n")
+            .replace('\t', "\\t");
+        self.data.push(format!("{}: .asciiz '{}'", label, escaped));
+        self.strings.insert(text.to_string(), label.clone());
+        label
     }
 
-    pub fn compile(&self) {
-        // self.visit_token(self.assembly);
+    /// `print`, with the strings next to each other, and the newline, printed together
+    fn visit_print(&mut self, values: &[Expr]) {
+        let mut text = String::new();
+        for value in values {
+            match &value.kind {
+                ExprKind::String { value } => text.push_str(value),
+                _ => {
+                    self.print_string(&std::mem::take(&mut text));
+                    let value_reg = self.evaluate(value);
+                    let label = match &self.print_number {
+                        Some(label) => label.clone(),
+                        None => {
+                            let label = self.new_label("printnumber");
+                            self.print_number = Some(label.clone());
+                            label
+                        }
+                    };
+                    self.assembly.push(format!("PUSH ${}", value_reg));
+                    self.assembly.push(format!("LOAD ${} @{}", SCRATCH, label));
+                    self.assembly.push(format!("CALL ${}", SCRATCH));
+                    self.release(value_reg);
+                }
+            }
+        }
+        text.push('\n');
+        self.print_string(&text);
+    }
+
+    fn print_string(&mut self, text: &str) {
+        if !text.is_empty() {
+            let label = self.string_label(text);
+            self.assembly.push(format!("PRTS @{}", label));
+        }
+    }
+
+    /// The routine that prints the number on top of the stack. It leaves every
+    /// register but the scratch one as it found them.
+    ///
+    /// PRTS only prints the string at the address it's given, so the digits are
+    /// worked out onto the stack and each is printed by calling into a table of
+    /// `PRTS @digit` and `RET`, which is 4 bytes an entry. Working with the number
+    /// below zero means the most negative one doesn't need negating.
+    fn visit_print_number(&mut self, label: &str) {
+        let digits: Vec<String> = (0..10)
+            .map(|digit| self.string_label(&digit.to_string()))
+            .collect();
+        let minus = self.string_label("-");
+        let negative = self.new_label("negative");
+        let divide = self.new_label("divide");
+        let next_digit = self.new_label("nextdigit");
+        let print = self.new_label("printdigit");
+        let table = self.new_label("digits");
+        let lines = [
+            format!("{}: NOP", label),
+            format!("POP ${}", SCRATCH),
+            "PUSH $0".to_string(),
+            "PUSH $1".to_string(),
+            "PUSH $2".to_string(),
+            "PUSH $3".to_string(),
+            "LOAD $1 #0".to_string(),
+            format!("ADD ${} $1 $0", SCRATCH),
+            "LT $0 $1".to_string(),
+            format!("LOAD ${} @{}", SCRATCH, negative),
+            format!("JEQ ${}", SCRATCH),
+            "SUB $1 $0 $0".to_string(),
+            format!("LOAD ${} @{}", SCRATCH, divide),
+            format!("JMP ${}", SCRATCH),
+            format!("{}: PRTS @{}", negative, minus),
+            // $2 counts the digits pushed, each from the remainder of a division by 10
+            format!("{}: LOAD $1 #10", divide),
+            "LOAD $2 #0".to_string(),
+            format!("{}: DIV $0 $1 $0", next_digit),
+            "REM $3".to_string(),
+            "PUSH $3".to_string(),
+            "INC $2".to_string(),
+            "LOAD $1 #0".to_string(),
+            "NEQ $0 $1".to_string(),
+            "LOAD $1 #10".to_string(),
+            format!("LOAD ${} @{}", SCRATCH, next_digit),
+            format!("JEQ ${}", SCRATCH),
+            format!("{}: POP $3", print),
+            "LOAD $1 #0".to_string(),
+            "SUB $1 $3 $3".to_string(),
+            "LOAD $1 #4".to_string(),
+            "MUL $3 $1 $3".to_string(),
+            format!("LOAD ${} @{}", SCRATCH, table),
+            format!("ADD ${} $3 ${}", SCRATCH, SCRATCH),
+            format!("CALL ${}", SCRATCH),
+            "DEC $2".to_string(),
+            "LOAD $1 #0".to_string(),
+            "NEQ $2 $1".to_string(),
+            format!("LOAD ${} @{}", SCRATCH, print),
+            format!("JEQ ${}", SCRATCH),
+            "POP $3".to_string(),
+            "POP $2".to_string(),
+            "POP $1".to_string(),
+            "POP $0".to_string(),
+            "RET".to_string(),
+        ];
+        self.assembly.extend(lines);
+        for (digit, string) in digits.iter().enumerate() {
+            let line = format!("PRTS @{}", string);
+            match digit {
+                0 => self.assembly.push(format!("{}: {}", table, line)),
+                _ => self.assembly.push(line),
+            }
+            self.assembly.push("RET".to_string());
+        }
+    }
+
+    fn jump(&mut self, label: &str) {
+        self.assembly.push(format!("LOAD ${} @{}", SCRATCH, label));
+        self.assembly.push(format!("JMP ${}", SCRATCH));
+    }
+
+    /// Jumps to the label if the condition is `when`, where anything but 0 is true.
+    /// Comparisons set the VM's flag directly, and `&&`/`||` stop at the first
+    /// operand that decides them.
+    fn jump_if(&mut self, condition: &Expr, label: &str, when: bool) {
+        let depth = self.spilled();
+        match &condition.kind {
+            ExprKind::Unary {
+                operator: UnaryOperator::Not,
+                value,
+            } => return self.jump_if(value, label, !when),
+            ExprKind::Binary {
+                operator: operator @ (BinaryOperator::And | BinaryOperator::Or),
+                left,
+                right,
+            } => {
+                // `&&` is decided early by a false operand and `||` by a true one
+                let decides = *operator == BinaryOperator::Or;
+                if decides == when {
+                    self.jump_if(left, label, when);
+                    self.jump_if(right, label, when);
+                } else {
+                    let skip = self.new_label("skip");
+                    self.jump_if(left, &skip, decides);
+                    self.jump_if(right, label, when);
+                    self.place_label(&skip);
+                }
+                return;
+            }
+            ExprKind::Binary {
+                operator,
+                left,
+                right,
+            } if operator.is_comparison() => {
+                self.visit_expr(left);
+                self.visit_expr(right);
+                let right_reg = self.take_operand();
+                let left_reg = self.take_operand();
+                let line = format!(
+                    "{} ${} ${}",
+                    comparison_opcode(*operator),
+                    left_reg,
+                    right_reg
+                );
+                self.assembly.push(line);
+                self.release(left_reg);
+                self.release(right_reg);
+            }
+            _ => {
+                let value_reg = self.evaluate(condition);
+                self.assembly.push(format!("LOAD ${} #0", SCRATCH));
+                self.assembly
+                    .push(format!("NEQ ${} ${}", value_reg, SCRATCH));
+                self.release(value_reg);
+            }
+        }
+        // POP leaves the flag alone
+        self.unspill_to(depth);
+        self.assembly.push(format!("LOAD ${} @{}", SCRATCH, label));
+        let jump = if when { "JEQ" } else { "JNEQ" };
+        self.assembly.push(format!("{} ${}", jump, SCRATCH));
+    }
+
+    /// Turns a condition into 1 or 0 in a register
+    fn visit_condition(&mut self, condition: &Expr) {
+        let result_reg = self.allocate();
+        let end = self.new_label("cond");
+        self.assembly.push(format!("LOAD ${} #1", result_reg));
+        self.jump_if(condition, &end, true);
+        self.assembly.push(format!("LOAD ${} #0", result_reg));
+        self.place_label(&end);
+        self.push_operand(result_reg);
+    }
+}
+
+/// The instruction setting the VM's flag when the comparison holds
+fn comparison_opcode(operator: BinaryOperator) -> &'static str {
+    match operator {
+        BinaryOperator::Equal => "EQ",
+        BinaryOperator::NotEqual => "NEQ",
+        BinaryOperator::GreaterThan => "GT",
+        BinaryOperator::GreaterThanOrEqual => "GTE",
+        BinaryOperator::LessThan => "LT",
+        BinaryOperator::LessThanOrEqual => "LTE",
+        _ => unreachable!("{:?} is not a comparison", operator),
+    }
+}
+
+/// The instruction for an arithmetic operator
+fn arithmetic_opcode(operator: BinaryOperator) -> &'static str {
+    match operator {
+        BinaryOperator::Add => "ADD",
+        BinaryOperator::Subtract => "SUB",
+        BinaryOperator::Multiply => "MUL",
+        // the remainder is left behind by a DIV, to be taken with REM
+        BinaryOperator::Divide | BinaryOperator::Remainder => "DIV",
+        _ => unreachable!("{:?} is not arithmetic", operator),
     }
 }
 
 impl Visitor for Compiler {
-    fn visit_token(&mut self, node: &crate::token::Token) {
-        match node {
-            Token::AdditionOperator => {
-                println!("Visiting AdditionOperator");
-                let result_reg = self.free_registers.pop().unwrap();
-                let left_reg = self.used_registers.pop().unwrap();
-                let right_reg = self.used_registers.pop().unwrap();
-                let line = format!("ADD ${} ${} ${}", left_reg, right_reg, result_reg);
-                self.assembly.push(line);
-                self.used_registers.push(result_reg);
-                self.free_registers.push(left_reg);
-                self.free_registers.push(right_reg);
-            }
-            Token::SubtractionOperator => {
-                println!("Visiting SubstractionOperator");
-                let result_reg = self.free_registers.pop().unwrap();
-                let left_reg = self.used_registers.pop().unwrap();
-                let right_reg = self.used_registers.pop().unwrap();
-                let line = format!("SUB ${} ${} ${}", right_reg, left_reg, result_reg);
-                self.assembly.push(line);
-                self.used_registers.push(result_reg);
-                self.free_registers.push(left_reg);
-                self.free_registers.push(right_reg);
-            }
-            Token::MultiplicationOperator => {
-                println!("Visiting MultiplicationOperator");
-                let result_reg = self.free_registers.pop().unwrap();
-                let left_reg = self.used_registers.pop().unwrap();
-                let right_reg = self.used_registers.pop().unwrap();
-                let line = format!("MUL ${} ${} ${}", right_reg, left_reg, result_reg);
-                self.assembly.push(line);
-                self.used_registers.push(result_reg);
-                self.free_registers.push(left_reg);
-                self.free_registers.push(right_reg);
-            }
-            Token::DivisionOperator => {
-                println!("Visiting DivisionOperator");
-                let result_reg = self.free_registers.pop().unwrap();
-                let left_reg = self.used_registers.pop().unwrap();
-                let right_reg = self.used_registers.pop().unwrap();
-                let line = format!("DIV ${} ${} ${}", right_reg, left_reg, result_reg);
-                self.assembly.push(line);
-                self.used_registers.push(result_reg);
-                self.free_registers.push(left_reg);
-                self.free_registers.push(right_reg);
+    fn visit_program(&mut self, program: &Program) {
+        let mut functions = vec![];
+        let mut statements = vec![];
+        for item in &program.items {
+            match item {
+                Item::Function(function) => functions.push(function),
+                Item::Statement(statement) => statements.push(statement.clone()),
+            }
+        }
+        self.declare_functions(&functions);
+        if functions.iter().all(|function| function.name != "main") {
+            self.visit_main(&statements);
+        } else if let Some(statement) = statements.first() {
+            self.errors.push(CompileError::StatementsOutsideMain {
+                span: statement.span,
+            });
+        }
+        for function in functions {
+            if function.name == "main" {
+                self.visit_main(&function.body);
+            } else {
+                self.visit_function(function);
             }
+        }
+        if let Some(label) = self.print_number.clone() {
+            self.visit_print_number(&label);
+        }
+    }
 
-            Token::Integer { value } => {
-                println!("Visited integer: {:#?}", value);
-                let next_reg = self.free_registers.pop().unwrap();
-                self.used_registers.push(next_reg);
-                let line = format!("LOAD ${} #{}", next_reg, value);
-                self.assembly.push(line);
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        self.statement = stmt.span;
+        match &stmt.kind {
+            StmtKind::Let { name, value } => {
+                self.visit_expr(value);
+                let mut value_reg = self.take_operand();
+                // `let y = x;` mustn't share x's register
+                if self.symbols.owns_register(value_reg) {
+                    let next_reg = self.allocate();
+                    self.copy(value_reg, next_reg);
+                    value_reg = next_reg;
+                }
+                if !self.symbols.declare(name, value_reg) {
+                    self.errors.push(CompileError::RedeclaredVariable {
+                        name: name.clone(),
+                        span: stmt.span,
+                    });
+                }
+                self.push_operand(value_reg);
             }
-            Token::Float { value } => {
-                println!("Visited float: {:#?}", value);
-                let next_reg = self.free_registers.pop().unwrap();
-                self.used_registers.push(next_reg);
-                let line = format!("LOAD ${} #{}", next_reg, value);
-                self.assembly.push(line);
+            StmtKind::Assignment { name, value } => {
+                self.visit_expr(value);
+                let value_reg = self.take_operand();
+                match self
+                    .symbols
+                    .lookup(name)
+                    .map(|variable| variable.register())
+                {
+                    Some(variable_reg) => {
+                        self.copy(value_reg, variable_reg);
+                        self.release(value_reg);
+                        self.push_operand(variable_reg);
+                    }
+                    None => {
+                        self.errors.push(CompileError::UndeclaredVariable {
+                            name: name.clone(),
+                            span: stmt.span,
+                        });
+                        self.push_operand(value_reg);
+                    }
+                }
+            }
+            StmtKind::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                let else_label = self.new_label("else");
+                self.jump_if(condition, &else_label, false);
+                self.visit_block(body);
+                match else_body {
+                    Some(else_body) => {
+                        let end = self.new_label("endif");
+                        self.jump(&end);
+                        self.place_label(&else_label);
+                        self.visit_block(else_body);
+                        self.place_label(&end);
+                    }
+                    None => self.place_label(&else_label),
+                }
             }
-            //
-            Token::Factor { value } => {
-                println!("Begin visiting factor");
-                self.visit_token(&value);
-                println!("Done visiting factor");
+            StmtKind::While { condition, body } => {
+                let start = self.new_label("while");
+                let end = self.new_label("endwhile");
+                self.place_label(&start);
+                self.jump_if(condition, &end, false);
+                self.visit_block(body);
+                self.jump(&start);
+                self.place_label(&end);
             }
-            Token::Term { left, right } => {
-                println!("Begin visiting term");
-                // 1
-                self.visit_token(&left);
-                // *1*2*3...
-                for expr in right {
-                    self.visit_token(&expr.1);
-                    self.visit_token(&expr.0);
+            StmtKind::Return { value } => {
+                let value_reg = match value {
+                    Some(value) => self.evaluate(value),
+                    None => {
+                        self.assembly.push(format!("LOAD ${} #0", SCRATCH));
+                        SCRATCH
+                    }
+                };
+                if self.in_function {
+                    self.assembly.push(format!("PUSH ${}", value_reg));
+                    self.assembly.push("RET".to_string());
+                } else {
+                    self.copy(value_reg, 0);
+                    self.assembly.push("HLT".to_string());
                 }
-                println!("Done visiting term");
+                self.release(value_reg);
             }
+            StmtKind::Print { values } => self.visit_print(values),
+            StmtKind::Expression { value } => self.visit_expr(value),
+        }
+    }
 
-            Token::Expression { left, right } => {
-                println!("Begin visiting expression");
-                // post order traversal
-                // (4*3)-1
-                self.visit_token(&left);
-                for expr in right {
-                    self.visit_token(&expr.1);
-                    self.visit_token(&expr.0);
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Integer { value } => {
+                if !(0..=MAX_INTEGER).contains(value) {
+                    self.errors.push(CompileError::IntegerOutOfRange {
+                        value: *value,
+                        span: expr.span,
+                    });
                 }
-                println!("Done visiting expression");
+                let next_reg = self.allocate();
+                self.push_operand(next_reg);
+                self.load_integer(next_reg, *value as u32);
+            }
+            ExprKind::Float { .. } => {
+                // the VM has float registers, but no instructions to load them
+                self.errors.push(CompileError::Unsupported {
+                    feature: "Floats",
+                    span: expr.span,
+                });
+                let next_reg = self.allocate();
+                self.push_operand(next_reg);
             }
-            Token::Program { expressions } => {
-                println!("Visiting program");
-                for exp in expressions {
-                    self.visit_token(exp);
+            ExprKind::String { .. } => {
+                self.errors.push(CompileError::Unsupported {
+                    feature: "Strings anywhere but print",
+                    span: expr.span,
+                });
+                let next_reg = self.allocate();
+                self.push_operand(next_reg);
+            }
+            ExprKind::Variable { name } => match self.symbols.lookup(name) {
+                Some(variable) => {
+                    let variable_reg = variable.register();
+                    self.push_operand(variable_reg);
+                }
+                None => {
+                    self.errors.push(CompileError::UndeclaredVariable {
+                        name: name.clone(),
+                        span: expr.span,
+                    });
+                    let next_reg = self.allocate();
+                    self.push_operand(next_reg);
                 }
-                println!("Done visiting program");
+            },
+            ExprKind::Binary {
+                operator,
+                left,
+                right,
+            } if operator.is_arithmetic() => {
+                self.visit_expr(left);
+                self.visit_expr(right);
+                let right_reg = self.take_operand();
+                let left_reg = self.take_operand();
+                let result_reg = self.allocate();
+                let line = format!(
+                    "{} ${} ${} ${}",
+                    arithmetic_opcode(*operator),
+                    left_reg,
+                    right_reg,
+                    result_reg
+                );
+                self.assembly.push(line);
+                if *operator == BinaryOperator::Remainder {
+                    self.assembly.push(format!("REM ${}", result_reg));
+                }
+                self.push_operand(result_reg);
+                self.release(left_reg);
+                self.release(right_reg);
             }
+            ExprKind::Unary {
+                operator: UnaryOperator::Negate,
+                value,
+            } => {
+                self.visit_expr(value);
+                let value_reg = self.take_operand();
+                let result_reg = self.allocate();
+                self.assembly.push(format!("LOAD ${} #0", SCRATCH));
+                let line = format!("SUB ${} ${} ${}", SCRATCH, value_reg, result_reg);
+                self.assembly.push(line);
+                self.push_operand(result_reg);
+                self.release(value_reg);
+            }
+            ExprKind::Binary { .. } | ExprKind::Unary { .. } => self.visit_condition(expr),
+            ExprKind::Call { name, arguments } => self.visit_call(name, arguments, expr.span),
         }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use nom::types::CompleteStr;
+    use crate::ast::UnaryOperator;
+    use crate::parser::BLOCK_LEVELS;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use vm::output::Output;
+    use vm::vm::{VMError, VMEventType, VM};
+
+    fn generate_test_program(source: &str) -> Program {
+        parse(source).unwrap()
+    }
+
+    /// Compiles and runs a program, returning $0
+    fn run(source: &str) -> i32 {
+        try_run(source).unwrap()
+    }
+
+    /// Compiles and runs a program, returning $0 or why the VM stopped it
+    fn try_run(source: &str) -> Result<i32, VMError> {
+        try_run_at(source, OptimizationLevel::O0)
+    }
 
-    fn generate_test_program(str: &str) -> Token {
-        let source = CompleteStr(str);
-        let (_, tree) = program(source).unwrap();
-        tree
+    fn try_run_at(source: &str, optimization: OptimizationLevel) -> Result<i32, VMError> {
+        let mut compiler = Compiler::new();
+        compiler.set_optimization(optimization);
+        let mut vm = VM::new();
+        vm.add_bytes(compiler.compile(source).unwrap());
+        let events = vm.run();
+        match events.last().unwrap().event() {
+            VMEventType::GracefulStop { code: 1 } => Ok(vm.registers[0]),
+            VMEventType::Crash { error } => Err(error.clone()),
+            event => panic!("{} stopped with {:?}", source, event),
+        }
     }
 
     #[test]
     fn test_visit_addition_token() {
         let mut compiler = Compiler::new();
         let test_program = generate_test_program("1+2");
-        compiler.visit_token(&test_program);
+        compiler.visit_program(&test_program);
+        assert_eq!(
+            compiler.assembly[..4],
+            ["main: NOP", "LOAD $0 #1", "LOAD $1 #2", "ADD $0 $1 $2"]
+        );
     }
 
     #[test]
     fn test_nested_operators() {
         let mut compiler = Compiler::new();
-        let test_program = generate_test_program("(4*3)-1");
-        println!("{:#?}", test_program);
-        compiler.visit_token(&test_program);
-        println!("{:#?}", compiler.assembly);
-        let bytecode = compiler.compile();
+        let assembly = compiler.compile_to_assembly("(4*3)-1").unwrap();
+        assert!(assembly.starts_with(".data\n.code\n"));
+        assert!(assembly.ends_with("HLT\n"));
+    }
+
+    #[test]
+    fn test_compile_and_run() {
+        assert_eq!(run("1+2"), 3);
+        assert_eq!(run("(4*3)-1"), 11);
+        assert_eq!(run("7"), 7);
+        // the same compiler can be reused
+        let mut compiler = Compiler::new();
+        compiler.compile("1").unwrap();
+        assert!(compiler.compile("2*3").is_ok());
+    }
+
+    #[test]
+    fn test_unary_and_remainder() {
+        let table = [
+            ("8-2-1", 5),
+            ("100/10/5", 2),
+            ("2*7%4/3", 0),
+            ("-5", -5),
+            ("-(1+2)", -3),
+            ("--4", 4),
+            ("-2 * -3", 6),
+            ("10 - -3", 13),
+            ("7 % 3", 1),
+            ("-7 % 3", -1),
+            ("7 % -3", 1),
+            ("2 + 3 * 4 % 5", 4),
+            ("!-1", 0),
+            ("-(2 < 3)", -1),
+            ("let x = 5; -x + 10", 5),
+            ("let x = 9; x % 4 == 1", 1),
+        ];
+        for (source, expected) in table {
+            assert_eq!(run(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let mut compiler = Compiler::new();
+        assert!(matches!(
+            compiler.compile("1+2 )"),
+            Err(CompileError::UnexpectedToken { found: Token::RightParen, span, .. })
+                if span.column == 5
+        ));
+        assert!(matches!(
+            compiler.compile("2147483648"),
+            Err(CompileError::IntegerOutOfRange {
+                value: 2147483648,
+                ..
+            })
+        ));
+        assert!(matches!(
+            compiler.compile(""),
+            Err(CompileError::UnexpectedToken {
+                found: Token::EndOfInput,
+                ..
+            })
+        ));
+        assert!(matches!(
+            compiler.compile("let x = 1; x + y"),
+            Err(CompileError::UndeclaredVariable { name, .. }) if name == "y"
+        ));
+        assert!(matches!(
+            compiler.compile("y = 1;"),
+            Err(CompileError::UndeclaredVariable { name, .. }) if name == "y"
+        ));
+        assert!(matches!(
+            compiler.compile("let x = 1; let x = 2;"),
+            Err(CompileError::RedeclaredVariable { name, .. }) if name == "x"
+        ));
+    }
+
+    #[test]
+    fn test_errors_point_at_the_source() {
+        let error = Compiler::new()
+            .compile("let x = 1;\nlet y = x +\n    z * 2;")
+            .unwrap_err();
+        assert!(
+            matches!(&error, CompileError::UndeclaredVariable { span, .. } if span.line == 3 && span.column == 5)
+        );
+        assert_eq!(
+            error.to_string(),
+            "line 3, column 5: Variable z is used before being declared with let"
+        );
+        let error = Compiler::new().compile("1 + 2\n  let 3 = 4;").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2, column 7: Expected a name, found `3`"
+        );
+        let error = Compiler::new()
+            .compile("fn f(a) { }\nf(1) + f()")
+            .unwrap_err();
+        assert_eq!(
+            error
+                .span()
+                .map(|span| (span.line, span.column, span.end - span.start)),
+            Some((2, 8, 3))
+        );
+        // numbers too big for the lexer are errors rather than panics
+        assert!(matches!(
+            Compiler::new().compile("1 + 123456789012345678901234567890"),
+            Err(CompileError::InvalidNumber { span, .. }) if span.column == 5
+        ));
+        assert!(matches!(
+            Compiler::new().compile("let x = 1.5;"),
+            Err(CompileError::Unsupported { span, .. }) if span.column == 9
+        ));
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(run("let x = 4; x * 3"), 12);
+        assert_eq!(run("let x = 4; x = x + 1; x"), 5);
+        assert_eq!(run("let x = 2; let y = x; x = 10; y"), 2);
+        assert_eq!(run("let a = 6; let b = 3; a / b + a"), 8);
+        assert_eq!(run("let x = 1; x = 7;"), 7);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let table = [
+            ("1 == 1", 1),
+            ("1 == 2", 0),
+            ("1 != 2", 1),
+            ("3 > 2", 1),
+            ("2 > 2", 0),
+            ("2 >= 2", 1),
+            ("1 < 2", 1),
+            ("2 < 1", 0),
+            ("2 <= 1", 0),
+            ("1 + 1 == 2", 1),
+            ("!(1 == 1)", 0),
+            ("!0", 1),
+            ("!7", 0),
+        ];
+        for (source, expected) in table {
+            assert_eq!(run(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_logic_short_circuits() {
+        assert_eq!(run("1 < 2 && 2 < 3"), 1);
+        assert_eq!(run("1 < 2 && 3 < 2"), 0);
+        assert_eq!(run("2 < 1 || 2 < 3"), 1);
+        assert_eq!(run("2 < 1 || 3 < 2"), 0);
+        assert_eq!(run("0 || 0 || 5"), 1);
+        assert_eq!(run("!(1 && 0)"), 1);
+        // the right side would divide by zero if it ran
+        assert_eq!(run("let x = 0; x != 0 && 10 / x > 1"), 0);
+        assert_eq!(run("let x = 0; x == 0 || 10 / x > 1"), 1);
+    }
+
+    #[test]
+    fn test_if_else() {
+        let source = "let x = 5; let y = 0; if x > 3 { y = 1; } else { y = 2; } y";
+        assert_eq!(run(source), 1);
+        let source = "let x = 1; let y = 0; if x > 3 { y = 1; } else { y = 2; } y";
+        assert_eq!(run(source), 2);
+        let source = "let x = 1; if x > 3 { x = 0; } x";
+        assert_eq!(run(source), 1);
+        let source = "let x = 4; let y = 0;
+            if x == 1 { y = 10; } else if x == 4 { y = 40; } else { y = 99; } y";
+        assert_eq!(run(source), 40);
+    }
+
+    #[test]
+    fn test_while() {
+        let source = "let i = 0; let sum = 0; while i < 10 { i = i + 1; sum = sum + i; } sum";
+        assert_eq!(run(source), 55);
+        // blocks get their own variables, whose registers are reused afterwards
+        let source = "let n = 0; while n < 3 { let twice = n * 2; n = twice + 1; } n";
+        assert_eq!(run(source), 3);
+        assert!(matches!(
+            Compiler::new().compile("while 0 { let y = 1; } y"),
+            Err(CompileError::UndeclaredVariable { name, .. }) if name == "y"
+        ));
+    }
+
+    #[test]
+    fn test_functions() {
+        let source = "fn add(a, b) { return a + b; } add(2, 3) * 2";
+        assert_eq!(run(source), 10);
+        // called before it's defined, with the caller's registers surviving the call
+        let source = "let x = 7; let y = twice(x) + x; y fn twice(n) { return n * 2; }";
+        assert_eq!(run(source), 21);
+        let source = "fn nothing() { } nothing() + 1";
+        assert_eq!(run(source), 1);
+        let source = "fn f(a) { return a + 1; } fn g(a, b) { return f(a) * f(b); } g(f(1), 3)";
+        assert_eq!(run(source), 12);
+    }
+
+    #[test]
+    fn test_recursion() {
+        let source = "
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn main() { return fib(15); }
+        ";
+        assert_eq!(run(source), 610);
+        let source = "fn fact(n) { if n == 0 { return 1; } return n * fact(n - 1); } fact(7)";
+        assert_eq!(run(source), 5040);
+    }
+
+    #[test]
+    fn test_main_is_the_entry_point() {
+        let source = "fn helper() { return 1; } fn main() { let x = 41; return x + helper(); }";
+        let program = Compiler::new().compile(source).unwrap();
+        // main comes after helper, and the header says to start there
+        let entry = u32::from_le_bytes(program[4..8].try_into().unwrap());
+        assert!(entry > 0);
+        assert_eq!(run(source), 42);
+        // a main that runs off its end gives its last expression, like a program without one
+        assert_eq!(run("fn main() { 5; }"), 5);
+    }
+
+    #[test]
+    fn test_function_errors() {
+        let mut compiler = Compiler::new();
+        assert!(matches!(
+            compiler.compile("fn f(a) { return a; } f(1, 2)"),
+            Err(CompileError::ArityMismatch { name, expected: 1, found: 2, .. }) if name == "f"
+        ));
+        assert!(matches!(
+            compiler.compile("g(1)"),
+            Err(CompileError::UndefinedFunction { name, .. }) if name == "g"
+        ));
+        assert!(matches!(
+            compiler.compile("fn main() { return main(); }"),
+            Err(CompileError::UndefinedFunction { name, .. }) if name == "main"
+        ));
+        assert!(matches!(
+            compiler.compile("fn f() { } fn f() { }"),
+            Err(CompileError::RedefinedFunction { name, .. }) if name == "f"
+        ));
+        assert!(matches!(
+            compiler.compile("fn main(x) { }"),
+            Err(CompileError::ArityMismatch {
+                expected: 0,
+                found: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            compiler.compile("let x = 1; fn main() { }"),
+            Err(CompileError::StatementsOutsideMain { .. })
+        ));
+        // functions don't see the variables of their caller
+        assert!(matches!(
+            compiler.compile("let x = 1; fn f() { return x; } f()"),
+            Err(CompileError::UndeclaredVariable { name, .. }) if name == "x"
+        ));
+    }
+
+    /// `((((1+2)*3)-4)/5)...`, cycling through the operators, and its value
+    fn left_nested(depth: i32) -> (String, i32) {
+        let mut source = "1".to_string();
+        let mut value = 1;
+        for i in 2..=depth {
+            let (operator, next) = match i % 4 {
+                0 => ('+', value + i),
+                1 => ('*', value * i),
+                2 => ('-', value - i),
+                _ => ('/', value / i),
+            };
+            source = format!("({}{}{})", source, operator, i);
+            value = next;
+        }
+        (source, value)
+    }
+
+    /// `1+(2+(3+...))`, which keeps every number in a register until the end
+    fn right_nested(depth: i32) -> String {
+        let mut source = depth.to_string();
+        for i in (1..depth).rev() {
+            source = format!("{}+({})", i, source);
+        }
+        source
+    }
+
+    #[test]
+    fn test_deeply_nested_expressions() {
+        let (source, value) = left_nested(40);
+        assert_eq!(run(&source), value);
+        // more live values than registers
+        let assembly = Compiler::new()
+            .compile_to_assembly(&right_nested(40))
+            .unwrap();
+        assert!(assembly.contains("PUSH $"));
+        assert_eq!(run(&right_nested(40)), 820);
+        assert_eq!(run(&right_nested(50)), 1275);
+        assert_eq!(run(&right_nested(100)), 5050);
+        let source = format!("({}) - ({})", right_nested(35), right_nested(34));
+        assert_eq!(run(&source), 35);
+        let source = format!("let x = 2; let y = 3; x * ({}) + y", right_nested(50));
+        assert_eq!(run(&source), 2553);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let sum = vec!["1"; MAX_NESTING].join("+");
+        let blocks = MAX_NESTING / BLOCK_LEVELS;
+        let ifs = format!(
+            "let x = 0; {} x = 7; {}",
+            "if 1 { ".repeat(blocks),
+            "}".repeat(blocks)
+        );
+        for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+            assert_eq!(try_run_at(&sum, optimization), Ok(MAX_NESTING as i32));
+            let source = right_nested(MAX_NESTING as i32);
+            assert_eq!(try_run_at(&source, optimization), Ok(32896));
+            assert_eq!(try_run_at(&ifs, optimization), Ok(0));
+        }
+        // errors rather than stack overflows, pointing at where the limit is passed
+        let too_deep = [
+            (vec!["1"; 6000].join("+"), 512),
+            (format!("{}1{}", "(".repeat(2000), ")".repeat(2000)), 258),
+            (format!("{}1", "-".repeat(2000)), 258),
+            (format!("f({}1", "f(".repeat(2000)), 514),
+            (format!("if 1 {{ {}", "1 + (".repeat(2000)), 1273),
+            (
+                format!("{}{}", "if 1 { ".repeat(2000), "}".repeat(2000)),
+                454,
+            ),
+        ];
+        for (source, column) in too_deep {
+            for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+                let mut compiler = Compiler::new();
+                compiler.set_optimization(optimization);
+                let error = compiler.compile(&source).unwrap_err();
+                assert!(
+                    matches!(error, CompileError::NestedTooDeeply { span } if span.column == column),
+                    "{}",
+                    error
+                );
+            }
+        }
+    }
+
+    /// Programs right at the limit, on a thread with the 2 MiB stack threads get by
+    /// default, so a parser or compiler with bigger frames fails here rather than for
+    /// someone's program. Frames are biggest in a debug build, which `cargo test` is.
+    /// Overflowing the stack aborts the whole test run instead of failing this test.
+    #[test]
+    fn test_nesting_limit_fits_default_stack() {
+        let levels = MAX_NESTING;
+        let blocks = MAX_NESTING / BLOCK_LEVELS;
+        let programs = [
+            (vec!["1"; levels].join("+"), levels as i32),
+            (right_nested(levels as i32), 32896),
+            (format!("{}1{}", "(".repeat(levels), ")".repeat(levels)), 1),
+            (format!("{}1", "-".repeat(levels)), 1),
+            (
+                format!(
+                    "fn f(a) {{ return a; }} {}1{}",
+                    "f(".repeat(levels),
+                    ")".repeat(levels)
+                ),
+                1,
+            ),
+            (
+                format!(
+                    "let x = 0; {} x = 7; {}",
+                    "if 1 { ".repeat(blocks),
+                    "}".repeat(blocks)
+                ),
+                0,
+            ),
+        ];
+        std::thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(move || {
+                for (source, value) in &programs {
+                    for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+                        assert_eq!(try_run_at(source, optimization), Ok(*value), "{}", source);
+                    }
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_spilling_around_calls_and_jumps() {
+        let source = format!("fn f(a) {{ return a + 1; }} f({}) + f(1)", right_nested(40));
+        assert_eq!(run(&source), 823);
+        let source = format!(
+            "fn f(a) {{ return a * 2; }} {} + f(3)",
+            right_nested(40).replace("40", "f(20)")
+        );
+        assert_eq!(run(&source), 826);
+        // the condition spills values that the jumps have to leave spilled on every path
+        let deep = right_nested(33);
+        let source = format!("let x = 1; {} + (x == 1 && {} > 0)", deep, deep);
+        assert_eq!(run(&source), 562);
+        let source = format!("let x = 1; {} + (x == 2 || {} > 0)", deep, deep);
+        assert_eq!(run(&source), 562);
+        let source = format!("let x = 1; {} + (x == 2 && {} > 0)", deep, deep);
+        assert_eq!(run(&source), 561);
+        let source = format!("let i = 0; while ({}) > i {{ i = i + 100; }} i", deep);
+        assert_eq!(run(&source), 600);
+    }
+
+    #[test]
+    fn test_out_of_registers() {
+        let mut source = String::new();
+        for i in 0..40 {
+            source.push_str(&format!("let x{} = {};", i, i));
+        }
+        assert!(matches!(
+            Compiler::new().compile(&source),
+            Err(CompileError::OutOfRegisters { .. })
+        ));
+        let params: Vec<String> = (0..40).map(|i| format!("p{}", i)).collect();
+        let source = format!("fn f({}) {{ }}", params.join(", "));
+        assert!(matches!(
+            Compiler::new().compile(&source),
+            Err(CompileError::OutOfRegisters { .. })
+        ));
+    }
+
+    #[test]
+    fn test_labels_are_unique() {
+        let assembly = Compiler::new()
+            .compile_to_assembly("let i = 0; while i < 2 { if i == 0 { i = 1; } else { i = 2; } }")
+            .unwrap();
+        let mut labels: Vec<&str> = assembly
+            .lines()
+            .filter_map(|line| line.split_once(':').map(|(label, _)| label))
+            .collect();
+        let count = labels.len();
+        labels.sort();
+        labels.dedup();
+        assert_eq!(labels.len(), count);
+        // and main
+        assert_eq!(count, 5);
+    }
+
+    /// What an expression should give, worked out in Rust rather than on the VM.
+    /// None if it divides by zero. Arithmetic wraps like the VM's.
+    fn evaluate(expr: &Expr, variables: &HashMap<String, i32>) -> Option<i32> {
+        let value = match &expr.kind {
+            ExprKind::Integer { value } => *value as i32,
+            ExprKind::Variable { name } => variables[name],
+            ExprKind::Unary { operator, value } => {
+                let value = evaluate(value, variables)?;
+                match operator {
+                    UnaryOperator::Negate => value.wrapping_neg(),
+                    UnaryOperator::Not => (value == 0) as i32,
+                }
+            }
+            ExprKind::Binary {
+                operator: operator @ (BinaryOperator::And | BinaryOperator::Or),
+                left,
+                right,
+            } => {
+                let left = evaluate(left, variables)? != 0;
+                // the right operand isn't evaluated when the left one decides
+                if left == (*operator == BinaryOperator::Or) {
+                    left as i32
+                } else {
+                    (evaluate(right, variables)? != 0) as i32
+                }
+            }
+            ExprKind::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = evaluate(left, variables)?;
+                let right = evaluate(right, variables)?;
+                match operator {
+                    BinaryOperator::Add => left.wrapping_add(right),
+                    BinaryOperator::Subtract => left.wrapping_sub(right),
+                    BinaryOperator::Multiply => left.wrapping_mul(right),
+                    BinaryOperator::Divide | BinaryOperator::Remainder if right == 0 => {
+                        return None
+                    }
+                    BinaryOperator::Divide => left.wrapping_div(right),
+                    BinaryOperator::Remainder => left.wrapping_rem(right),
+                    BinaryOperator::Equal => (left == right) as i32,
+                    BinaryOperator::NotEqual => (left != right) as i32,
+                    BinaryOperator::GreaterThan => (left > right) as i32,
+                    BinaryOperator::GreaterThanOrEqual => (left >= right) as i32,
+                    BinaryOperator::LessThan => (left < right) as i32,
+                    BinaryOperator::LessThanOrEqual => (left <= right) as i32,
+                    BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+                }
+            }
+            _ => panic!("no reference for {:?}", expr),
+        };
+        Some(value)
+    }
+
+    /// The reference for programs of `let`s followed by an expression
+    fn evaluate_program(source: &str) -> Option<i32> {
+        let mut variables = HashMap::new();
+        let mut result = 0;
+        for item in parse(source).unwrap().items {
+            match item {
+                Item::Statement(Stmt {
+                    kind: StmtKind::Let { name, value },
+                    ..
+                }) => {
+                    let value = evaluate(&value, &variables)?;
+                    variables.insert(name, value);
+                }
+                Item::Statement(Stmt {
+                    kind: StmtKind::Expression { value },
+                    ..
+                }) => result = evaluate(&value, &variables)?,
+                item => panic!("no reference for {:?}", item),
+            }
+        }
+        Some(result)
+    }
+
+    /// How tightly an expression binds, to know where it needs parentheses
+    fn precedence(expr: &Expr) -> u8 {
+        match &expr.kind {
+            ExprKind::Binary { operator, .. } => match operator {
+                BinaryOperator::Or => 1,
+                BinaryOperator::And => 2,
+                BinaryOperator::Add | BinaryOperator::Subtract => 4,
+                BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Remainder => 5,
+                _ => 3,
+            },
+            _ => 6,
+        }
+    }
+
+    /// Writes an expression out with only the parentheses it needs
+    fn to_source(expr: &Expr) -> String {
+        let wrap = |expr: &Expr, parenthesise: bool| {
+            if parenthesise {
+                format!("({})", to_source(expr))
+            } else {
+                to_source(expr)
+            }
+        };
+        match &expr.kind {
+            ExprKind::Integer { value } => value.to_string(),
+            ExprKind::Variable { name } => name.clone(),
+            ExprKind::Unary { operator, value } => {
+                let symbol = match operator {
+                    UnaryOperator::Negate => "-",
+                    UnaryOperator::Not => "!",
+                };
+                format!("{}{}", symbol, wrap(value, precedence(value) < 6))
+            }
+            ExprKind::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let symbol = match operator {
+                    BinaryOperator::Add => "+",
+                    BinaryOperator::Subtract => "-",
+                    BinaryOperator::Multiply => "*",
+                    BinaryOperator::Divide => "/",
+                    BinaryOperator::Remainder => "%",
+                    BinaryOperator::Equal => "==",
+                    BinaryOperator::NotEqual => "!=",
+                    BinaryOperator::GreaterThan => ">",
+                    BinaryOperator::GreaterThanOrEqual => ">=",
+                    BinaryOperator::LessThan => "<",
+                    BinaryOperator::LessThanOrEqual => "<=",
+                    BinaryOperator::And => "&&",
+                    BinaryOperator::Or => "||",
+                };
+                let own = precedence(expr);
+                // everything is left associative, and comparisons don't chain
+                let left_needs = precedence(left) < own || (own == 3 && precedence(left) == 3);
+                let right_needs = precedence(right) <= own;
+                format!(
+                    "{} {} {}",
+                    wrap(left, left_needs),
+                    symbol,
+                    wrap(right, right_needs)
+                )
+            }
+            _ => panic!("can't write {:?}", expr),
+        }
+    }
+
+    const OPERATORS: [BinaryOperator; 13] = [
+        BinaryOperator::Add,
+        BinaryOperator::Subtract,
+        BinaryOperator::Multiply,
+        BinaryOperator::Divide,
+        BinaryOperator::Remainder,
+        BinaryOperator::Equal,
+        BinaryOperator::NotEqual,
+        BinaryOperator::GreaterThan,
+        BinaryOperator::GreaterThanOrEqual,
+        BinaryOperator::LessThan,
+        BinaryOperator::LessThanOrEqual,
+        BinaryOperator::And,
+        BinaryOperator::Or,
+    ];
+
+    fn random_expr(rng: &mut StdRng, depth: u32) -> Expr {
+        let kind = if depth == 0 || rng.gen_bool(0.25) {
+            match rng.gen_range(0, 10) {
+                0..=3 => ExprKind::Variable {
+                    name: ["a", "b", "c"][rng.gen_range(0, 3)].to_string(),
+                },
+                // big enough to overflow when multiplied
+                4 => ExprKind::Integer {
+                    value: rng.gen_range(0, 65536),
+                },
+                // too big for a single LOAD
+                5 => ExprKind::Integer {
+                    value: rng.gen_range(65536, MAX_INTEGER + 1),
+                },
+                _ => ExprKind::Integer {
+                    value: rng.gen_range(0, 10),
+                },
+            }
+        } else if rng.gen_bool(0.15) {
+            ExprKind::Unary {
+                operator: if rng.gen() {
+                    UnaryOperator::Negate
+                } else {
+                    UnaryOperator::Not
+                },
+                value: Box::new(random_expr(rng, depth - 1)),
+            }
+        } else {
+            ExprKind::Binary {
+                operator: OPERATORS[rng.gen_range(0, OPERATORS.len())],
+                left: Box::new(random_expr(rng, depth - 1)),
+                right: Box::new(random_expr(rng, depth - 1)),
+            }
+        };
+        Expr {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    #[test]
+    fn test_codegen_table() {
+        let table = [
+            ("8 - 2 - 1", 5),
+            ("8 - (2 - 1)", 7),
+            ("20 - 5 - 4 - 3 - 2 - 1", 5),
+            ("1 - 2 - 3", -4),
+            ("100 / 10 / 5", 2),
+            ("100 / (10 / 5)", 50),
+            ("1000 / 2 / 5 / 10", 10),
+            ("7 / 2", 3),
+            ("-7 / 2", -3),
+            ("7 / -2", -3),
+            ("2 - 3 * 4", -10),
+            ("2 * 3 - 4", 2),
+            ("12 / 4 * 3", 9),
+            ("12 / (4 * 3)", 1),
+            ("12 - 4 / 2", 10),
+            ("(12 - 4) / 2", 4),
+            ("17 % 5 % 3", 2),
+            ("17 % (5 % 3)", 1),
+            ("10 - 7 % 4", 7),
+            ("-7 % -3", -1),
+            ("0 - 5 - -5", 0),
+            ("---3", -3),
+            ("1 + 2 * 3 - 4 / 2 + 5 % 3", 7),
+            ("(1 + 2) * (3 - 4) / (2 + 5) % 3", 0),
+            ("65535 * 65535", -131071),
+            ("65535 * 65535 * 65535", 196607),
+            ("65536", 65536),
+            ("70000 - 1", 69999),
+            ("16777216 + 255", 16777471),
+            ("2147483647", i32::MAX),
+            ("-2147483647 - 1", i32::MIN),
+            ("2147483647 + 1", i32::MIN),
+            ("123456789 * 10", 1234567890),
+            ("3 - 5 < 0", 1),
+            ("10 / 3 == 3", 1),
+            ("10 % 3 != 1", 0),
+            ("(1 < 2) == 1", 1),
+            ("2 - 1 >= 1 - 2", 1),
+            ("1 && 2", 1),
+            ("0 || -3", 1),
+            ("1 || 1 / 0", 1),
+            ("0 && 1 / 0", 0),
+            ("!(3 - 3) + !!5", 2),
+            ("let a = 9; let b = 4; a - b - 1", 4),
+            ("let a = 9; let b = 4; a / b / 2", 1),
+            ("let a = 9; let b = 4; b - a", -5),
+            ("let a = 9; let b = 4; a % b - b % a", -3),
+            ("let a = 9; let b = -4; a / b * b + a % b", 9),
+            ("let a = 2; a - a * a - a", -4),
+        ];
+        for (source, expected) in table {
+            assert_eq!(evaluate_program(source), Some(expected), "{}", source);
+            for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+                assert_eq!(try_run_at(source, optimization), Ok(expected), "{}", source);
+            }
+        }
+        // whatever the statements leave in the registers, those with no value give 0
+        let statements = [
+            ("let x = 2 + 3; if x > 1 { x = 9; }", 0),
+            ("let x = 2; if x > 5 { x = 9; } else { x = 7; }", 0),
+            ("let i = 0; while i < 3 { i = i + 1; }", 0),
+            ("let a = 6; let b = a * 7; while 0 { }", 0),
+            ("let x = 4; x = x * 3;", 12),
+        ];
+        for (source, expected) in statements {
+            for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+                let result = try_run_at(source, optimization);
+                assert_eq!(result, Ok(expected), "{:?} {}", optimization, source);
+            }
+        }
+        for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+            for source in ["1 / 0", "let a = 3; a % (a - 3)", "let a = 3; a * 1 / 0"] {
+                let result = try_run_at(source, optimization);
+                assert_eq!(result, Err(VMError::DivisionByZero), "{}", source);
+            }
+        }
+    }
+
+    /// Random expressions give the same on the VM as in Rust
+    #[test]
+    fn test_codegen_against_reference() {
+        let mut rng = StdRng::seed_from_u64(48);
+        for case in 0..400 {
+            let expr = random_expr(&mut rng, 5);
+            let values: Vec<i32> = (0..3).map(|_| rng.gen_range(-100, 100)).collect();
+            let variables: HashMap<String, i32> = ["a", "b", "c"]
+                .iter()
+                .map(|name| name.to_string())
+                .zip(values.iter().copied())
+                .collect();
+            let mut expected = evaluate(&expr, &variables).ok_or(VMError::DivisionByZero);
+            // some as a function, so the operands are parameters, and some ending in a
+            // statement, which leaves the value behind but not in $0
+            let source = match case % 3 {
+                0 => format!(
+                    "let a = {}; let b = {}; let c = {}; {}",
+                    values[0],
+                    values[1],
+                    values[2],
+                    to_source(&expr)
+                ),
+                1 => format!(
+                    "fn f(a, b, c) {{ return {}; }} f({}, {}, {})",
+                    to_source(&expr),
+                    values[0],
+                    values[1],
+                    values[2]
+                ),
+                _ => {
+                    expected = expected.map(|_| 0);
+                    format!(
+                        "let a = {}; let b = {}; let c = {}; if a < 1000 {{ a = {}; }}",
+                        values[0],
+                        values[1],
+                        values[2],
+                        to_source(&expr)
+                    )
+                }
+            };
+            for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+                let result = try_run_at(&source, optimization);
+                assert_eq!(result, expected, "{:?} {}", optimization, source);
+            }
+        }
+    }
+
+    fn optimized_assembly(source: &str) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.set_optimization(OptimizationLevel::O1);
+        let assembly = compiler.compile_to_assembly(source).unwrap();
+        assembly.lines().skip(3).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_optimization() {
+        assert_eq!(
+            optimized_assembly("1+2*3"),
+            ["main: NOP", "LOAD $0 #7", "HLT"]
+        );
+        // folded below zero, it's a negation of what a LOAD can take
+        assert_eq!(
+            optimized_assembly("2-5"),
+            [
+                "main: NOP",
+                "LOAD $0 #3",
+                "LOAD $31 #0",
+                "SUB $31 $0 $1",
+                "SUB $1 $1 $0",
+                "ADD $1 $0 $0",
+                "HLT"
+            ]
+        );
+        assert_eq!(try_run_at("2-5", OptimizationLevel::O1), Ok(-3));
+        let identities = optimized_assembly("let x = 4; (x * 1 + 0) / 1 - 0");
+        assert!(
+            identities.iter().all(|line| !line.starts_with("MUL")
+                && !line.starts_with("DIV")
+                && !line.contains("#1")),
+            "{:?}",
+            identities
+        );
+        // both negations LOAD a 0 into the scratch register
+        let loads = |assembly: &[String]| {
+            assembly
+                .iter()
+                .filter(|line| line.as_str() == "LOAD $31 #0")
+                .count()
+        };
+        let source = "let x = 3; -x + -x";
+        let unoptimized = Compiler::new().compile_to_assembly(source).unwrap();
+        let unoptimized: Vec<String> = unoptimized.lines().map(String::from).collect();
+        assert_eq!(loads(&unoptimized), 2);
+        assert_eq!(loads(&optimized_assembly(source)), 1);
+        assert_eq!(try_run_at(source, OptimizationLevel::O1), Ok(-6));
+        // errors are the same, whether or not the code is optimized
+        let mut compiler = Compiler::new();
+        compiler.set_optimization(OptimizationLevel::O1);
+        assert!(matches!(
+            compiler.compile("2147483648 * 0"),
+            Err(CompileError::IntegerOutOfRange {
+                value: 2147483648,
+                ..
+            })
+        ));
+        assert!(matches!(
+            compiler.compile("y * 1"),
+            Err(CompileError::UndeclaredVariable { .. })
+        ));
+    }
+
+    /// Compiles and runs a program at both levels, returning what it printed
+    fn printed(source: &str) -> String {
+        let mut outputs = vec![];
+        for optimization in [OptimizationLevel::O0, OptimizationLevel::O1] {
+            let mut compiler = Compiler::new();
+            compiler.set_optimization(optimization);
+            let (output, capture) = Output::capture();
+            let mut vm = VM::new();
+            vm.set_output(output);
+            vm.add_bytes(compiler.compile(source).unwrap());
+            let events = vm.run();
+            assert!(
+                matches!(
+                    events.last().unwrap().event(),
+                    VMEventType::GracefulStop { code: 1 }
+                ),
+                "{} stopped with {:?}",
+                source,
+                events.last().unwrap().event()
+            );
+            outputs.push(capture.take());
+        }
+        assert_eq!(outputs[0], outputs[1], "{}", source);
+        outputs.remove(0)
+    }
+
+    #[test]
+    fn test_print() {
+        assert_eq!(printed("print \"Hello\";"), "Hello\n");
+        assert_eq!(printed("print;"), "\n");
+        assert_eq!(printed("print \"x = \", 6 * 7;"), "x = 42\n");
+        assert_eq!(printed("print 1, \" \", 2, \"\", 3;"), "1 23\n");
+        assert_eq!(
+            printed(r#"print "say \"hi\"\tit's a \\ \n";"#),
+            "say \"hi\"\tit's a \\ \n\n"
+        );
+        let source = "let i = 0; while i < 3 { print \"i: \", i; i = i + 1; }";
+        assert_eq!(printed(source), "i: 0\ni: 1\ni: 2\n");
+        let source = "fn show(n) { print n; return n; } fn main() { print show(5) + 1; }";
+        assert_eq!(printed(source), "5\n6\n");
+    }
+
+    #[test]
+    fn test_print_numbers() {
+        for (source, expected) in [
+            ("0", "0"),
+            ("7", "7"),
+            ("10", "10"),
+            ("-1", "-1"),
+            ("-30010", "-30010"),
+            ("2147483647", "2147483647"),
+            ("-2147483647 - 1", "-2147483648"),
+        ] {
+            assert_eq!(
+                printed(&format!("print {};", source)),
+                format!("{}\n", expected),
+                "{}",
+                source
+            );
+        }
+        // the routine leaves the registers it uses as they were
+        let source = "let a = 11; let b = 22; let c = 33; print a * 1000; print a, b, c; a + b + c";
+        assert_eq!(printed(source), "11000\n112233\n");
+        assert_eq!(run(source), 66);
+    }
+
+    #[test]
+    fn test_strings_in_the_data() {
+        let assembly = Compiler::new()
+            .compile_to_assembly("print \"a\"; print \"a\", 1; print \"a\";")
+            .unwrap();
+        let data: Vec<&str> = assembly
+            .lines()
+            .skip(1)
+            .take_while(|line| *line != ".code")
+            .collect();
+        // "a\n" once, "a" and "\n" around the number, and each digit and the
+        // minus sign for the routine
+        assert_eq!(data.len(), 3 + 10 + 1, "{:?}", data);
+        assert_eq!(data[0], r"str1: .asciiz 'a\n'");
+        assert!(matches!(
+            Compiler::new().compile("let s = \"a\";"),
+            Err(CompileError::Unsupported {
+                feature: "Strings anywhere but print",
+                ..
+            })
+        ));
     }
 }
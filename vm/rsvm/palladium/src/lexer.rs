@@ -0,0 +1,333 @@
+use crate::compiler::CompileError;
+use crate::token::{Span, Token};
+
+/// Splits a program into its tokens, each with where it is.
+/// The last one is always `Token::EndOfInput`.
+pub fn tokenize(source: &str) -> Result<Vec<(Token, Span)>, CompileError> {
+    let mut lexer = Lexer {
+        source,
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut tokens = vec![];
+    loop {
+        lexer.skip_whitespace();
+        let start = lexer.span();
+        let token = match lexer.peek() {
+            None => {
+                tokens.push((Token::EndOfInput, start));
+                return Ok(tokens);
+            }
+            Some(c) if c.is_ascii_digit() => lexer.number(start)?,
+            Some(c) if c.is_alphabetic() || c == '_' => lexer.word(),
+            Some('"') => lexer.string(start)?,
+            Some(c) => lexer.symbol(c, start)?,
+        };
+        tokens.push((token, lexer.finish(start)));
+    }
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Takes the next character if it's `expected`
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.offset;
+        while self.peek().is_some_and(&predicate) {
+            self.bump();
+        }
+        &self.source[start..self.offset]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(char::is_whitespace);
+    }
+
+    /// An empty span where the lexer is
+    fn span(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    /// The span from `start` to where the lexer is
+    fn finish(&self, start: Span) -> Span {
+        Span {
+            end: self.offset,
+            ..start
+        }
+    }
+
+    fn number(&mut self, start: Span) -> Result<Token, CompileError> {
+        self.take_while(|c| c.is_ascii_digit());
+        let rest = &self.source[self.offset..];
+        let is_float = rest.starts_with('.') && rest[1..].starts_with(|c: char| c.is_ascii_digit());
+        if is_float {
+            self.bump();
+            self.take_while(|c| c.is_ascii_digit());
+        }
+        let text = &self.source[start.start..self.offset];
+        let invalid = || CompileError::InvalidNumber {
+            text: text.to_string(),
+            span: self.finish(start),
+        };
+        if is_float {
+            let value = text.parse::<f64>().map_err(|_| invalid())?;
+            Ok(Token::Float { value })
+        } else {
+            let value = text.parse::<i64>().map_err(|_| invalid())?;
+            Ok(Token::Integer { value })
+        }
+    }
+
+    /// A keyword or a name
+    fn word(&mut self) -> Token {
+        let word = self.take_while(|c| c.is_alphanumeric() || c == '_');
+        Token::keyword(word).unwrap_or_else(|| Token::Identifier {
+            name: word.to_string(),
+        })
+    }
+
+    /// Text in double quotes, where `\n`, `\t`, `\"` and `\\` stand for what they escape
+    fn string(&mut self, start: Span) -> Result<Token, CompileError> {
+        self.bump();
+        let mut value = String::new();
+        loop {
+            let escape = self.span();
+            match self.bump() {
+                Some('"') => return Ok(Token::String { value }),
+                Some('\\') => match self.bump() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some(character) => {
+                        return Err(CompileError::InvalidEscape {
+                            character,
+                            span: self.finish(escape),
+                        })
+                    }
+                    None => break,
+                },
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        Err(CompileError::UnterminatedString {
+            span: self.finish(start),
+        })
+    }
+
+    fn symbol(&mut self, c: char, start: Span) -> Result<Token, CompileError> {
+        self.bump();
+        let token = match c {
+            '+' => Token::AdditionOperator,
+            '-' => Token::SubtractionOperator,
+            '*' => Token::MultiplicationOperator,
+            '/' => Token::DivisionOperator,
+            '%' => Token::RemainderOperator,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            ',' => Token::Comma,
+            ';' => Token::Semicolon,
+            '=' if self.eat('=') => Token::EqualOperator,
+            '=' => Token::AssignmentOperator,
+            '!' if self.eat('=') => Token::NotEqualOperator,
+            '!' => Token::NotOperator,
+            '>' if self.eat('=') => Token::GreaterThanOrEqualOperator,
+            '>' => Token::GreaterThanOperator,
+            '<' if self.eat('=') => Token::LessThanOrEqualOperator,
+            '<' => Token::LessThanOperator,
+            '&' if self.eat('&') => Token::AndOperator,
+            '|' if self.eat('|') => Token::OrOperator,
+            _ => {
+                return Err(CompileError::UnexpectedCharacter {
+                    character: c,
+                    span: self.finish(start),
+                })
+            }
+        };
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokens("let x=1.5 <= y2;"),
+            vec![
+                Token::Let,
+                Token::Identifier {
+                    name: "x".to_string()
+                },
+                Token::AssignmentOperator,
+                Token::Float { value: 1.5 },
+                Token::LessThanOrEqualOperator,
+                Token::Identifier {
+                    name: "y2".to_string()
+                },
+                Token::Semicolon,
+                Token::EndOfInput,
+            ]
+        );
+        assert_eq!(
+            tokens("a==!b&&c||d!=e%2"),
+            vec![
+                Token::Identifier {
+                    name: "a".to_string()
+                },
+                Token::EqualOperator,
+                Token::NotOperator,
+                Token::Identifier {
+                    name: "b".to_string()
+                },
+                Token::AndOperator,
+                Token::Identifier {
+                    name: "c".to_string()
+                },
+                Token::OrOperator,
+                Token::Identifier {
+                    name: "d".to_string()
+                },
+                Token::NotEqualOperator,
+                Token::Identifier {
+                    name: "e".to_string()
+                },
+                Token::RemainderOperator,
+                Token::Integer { value: 2 },
+                Token::EndOfInput,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keywords_and_names() {
+        for name in ["x", "total_2", "_tmp", "letter", "iffy"] {
+            assert_eq!(
+                tokens(name)[0],
+                Token::Identifier {
+                    name: name.to_string()
+                }
+            );
+        }
+        assert_eq!(tokens("while")[0], Token::While);
+        // a name can't start with a digit
+        assert_eq!(tokens("2x")[0], Token::Integer { value: 2 });
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("let x = 10;\n  x").unwrap();
+        let (token, span) = &tokens[3];
+        assert_eq!(token, &Token::Integer { value: 10 });
+        assert_eq!(
+            (span.start, span.end, span.line, span.column),
+            (8, 10, 1, 9)
+        );
+        let (_, span) = &tokens[5];
+        assert_eq!((span.line, span.column), (2, 3));
+        let (token, span) = tokens.last().unwrap();
+        assert_eq!(token, &Token::EndOfInput);
+        assert_eq!((span.line, span.column), (2, 4));
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            tokens(r#"print "Hi, \"you\"\n\tx\\y" ;"#),
+            vec![
+                Token::Print,
+                Token::String {
+                    value: "Hi, \"you\"\n\tx\\y".to_string()
+                },
+                Token::Semicolon,
+                Token::EndOfInput,
+            ]
+        );
+        assert_eq!(
+            tokens("\"\""),
+            vec![
+                Token::String {
+                    value: String::new()
+                },
+                Token::EndOfInput
+            ]
+        );
+        let tokens = tokenize("\"a\nb\" x").unwrap();
+        assert_eq!((tokens[1].1.line, tokens[1].1.column), (2, 4));
+        assert!(matches!(
+            tokenize(r#"print "a\qb";"#),
+            Err(CompileError::InvalidEscape { character: 'q', span }) if span.column == 9
+        ));
+        assert!(matches!(
+            tokenize("print \"open;\nx"),
+            Err(CompileError::UnterminatedString { span }) if span.column == 7
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            tokenize("1 +\n  a & b"),
+            Err(CompileError::UnexpectedCharacter { character: '&', span })
+                if span.line == 2 && span.column == 5
+        ));
+        assert!(matches!(
+            tokenize("99999999999999999999"),
+            Err(CompileError::InvalidNumber { text, .. }) if text == "99999999999999999999"
+        ));
+        // `1.` is an integer followed by a dot
+        assert!(matches!(
+            tokenize("1."),
+            Err(CompileError::UnexpectedCharacter { character: '.', .. })
+        ));
+    }
+}
@@ -1,7 +1,12 @@
+#[macro_use]
+extern crate nom;
+
+pub mod ast;
 pub mod compiler;
+pub mod lexer;
+pub mod optimizer;
 pub mod parser;
+pub mod peephole;
+pub mod symbol_table;
 pub mod token;
 pub mod visitor;
-
-#[macro_use]
-extern crate nom;
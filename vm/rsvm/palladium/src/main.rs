@@ -1,4 +1,57 @@
+use std::{env, fs, process};
+
+use palladium::compiler::Compiler;
+use palladium::optimizer::OptimizationLevel;
+use vm::vm::{VMEventType, VM};
+
+const USAGE: &str = "Usage: palladium run [-O0|-O1] <file.pd>";
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["run", path] => run(path, OptimizationLevel::O0),
+        ["run", flag, path] => match OptimizationLevel::from_flag(flag) {
+            Some(optimization) => run(path, optimization),
+            None => usage(),
+        },
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+/// Compiles the file and runs it on a VM, which prints to stdout as it goes, then
+/// prints the value it ends with in $0
+fn run(path: &str, optimization: OptimizationLevel) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let mut compiler = Compiler::new();
+    compiler.set_optimization(optimization);
+    let program = match compiler.compile(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut vm = VM::new();
+    vm.add_bytes(program);
+    let events = vm.run();
+    match events.last().map(|event| event.event()) {
+        Some(VMEventType::GracefulStop { .. }) => println!("{}", vm.registers[0]),
+        Some(VMEventType::Crash { error }) => {
+            eprintln!("{}: {}", path, error);
+            process::exit(error.code() as i32);
+        }
+        _ => process::exit(1),
+    }
 }
@@ -0,0 +1,277 @@
+use crate::ast::{
+    BinaryOperator, Expr, ExprKind, Function, Item, Program, Stmt, StmtKind, UnaryOperator,
+};
+use crate::compiler::MAX_INTEGER;
+use crate::token::Span;
+
+/// How much the compiler works at making programs smaller and faster
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptimizationLevel {
+    /// The code as it's written, one instruction or so per operator
+    #[default]
+    O0,
+    /// Folds constants in the AST and cleans up the assembly with a peephole pass
+    O1,
+}
+
+impl OptimizationLevel {
+    /// The level for a command line flag like `-O1`
+    pub fn from_flag(flag: &str) -> Option<OptimizationLevel> {
+        match flag {
+            "-O0" => Some(OptimizationLevel::O0),
+            "-O1" => Some(OptimizationLevel::O1),
+            _ => None,
+        }
+    }
+}
+
+/// Folds constant expressions like `1+2*3` into one number, and takes out
+/// operations that don't change their operand, like `x*1` and `x+0`.
+/// Nothing with a side effect is dropped, so `x*0` and `1/0` stay as they are.
+pub fn optimize(program: Program) -> Program {
+    let items = program
+        .items
+        .into_iter()
+        .map(|item| match item {
+            Item::Function(function) => Item::Function(Function {
+                body: optimize_statements(function.body),
+                ..function
+            }),
+            Item::Statement(statement) => Item::Statement(optimize_statement(statement)),
+        })
+        .collect();
+    Program { items }
+}
+
+fn optimize_statements(statements: Vec<Stmt>) -> Vec<Stmt> {
+    statements.into_iter().map(optimize_statement).collect()
+}
+
+fn optimize_statement(statement: Stmt) -> Stmt {
+    let kind = match statement.kind {
+        StmtKind::Let { name, value } => StmtKind::Let {
+            name,
+            value: optimize_expr(value),
+        },
+        StmtKind::Assignment { name, value } => StmtKind::Assignment {
+            name,
+            value: optimize_expr(value),
+        },
+        StmtKind::If {
+            condition,
+            body,
+            else_body,
+        } => StmtKind::If {
+            condition: optimize_expr(condition),
+            body: optimize_statements(body),
+            else_body: else_body.map(optimize_statements),
+        },
+        StmtKind::While { condition, body } => StmtKind::While {
+            condition: optimize_expr(condition),
+            body: optimize_statements(body),
+        },
+        StmtKind::Return { value } => StmtKind::Return {
+            value: value.map(optimize_expr),
+        },
+        StmtKind::Print { values } => StmtKind::Print {
+            values: values.into_iter().map(optimize_expr).collect(),
+        },
+        StmtKind::Expression { value } => StmtKind::Expression {
+            value: optimize_expr(value),
+        },
+    };
+    Stmt { kind, ..statement }
+}
+
+fn optimize_expr(expr: Expr) -> Expr {
+    let span = expr.span;
+    let kind = match expr.kind {
+        ExprKind::Unary { operator, value } => {
+            let value = optimize_expr(*value);
+            if let Some(value) = constant(&value) {
+                return match operator {
+                    UnaryOperator::Negate => number(value.wrapping_neg(), span),
+                    UnaryOperator::Not => number((value == 0) as i32, span),
+                };
+            }
+            match (operator, value.kind) {
+                // `--x` is x, even for the most negative number
+                (
+                    UnaryOperator::Negate,
+                    ExprKind::Unary {
+                        operator: UnaryOperator::Negate,
+                        value,
+                    },
+                ) => return *value,
+                (operator, kind) => ExprKind::Unary {
+                    operator,
+                    value: Box::new(Expr {
+                        kind,
+                        span: value.span,
+                    }),
+                },
+            }
+        }
+        ExprKind::Binary {
+            operator,
+            left,
+            right,
+        } => {
+            let left = optimize_expr(*left);
+            let right = optimize_expr(*right);
+            if let (Some(l), Some(r)) = (constant(&left), constant(&right)) {
+                if let Some(value) = fold(operator, l, r) {
+                    return number(value, span);
+                }
+            }
+            match (operator, constant(&left), constant(&right)) {
+                (BinaryOperator::Add, Some(0), _) | (BinaryOperator::Multiply, Some(1), _) => {
+                    return right
+                }
+                (BinaryOperator::Add | BinaryOperator::Subtract, _, Some(0))
+                | (BinaryOperator::Multiply | BinaryOperator::Divide, _, Some(1)) => return left,
+                _ => ExprKind::Binary {
+                    operator,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+            }
+        }
+        ExprKind::Call { name, arguments } => ExprKind::Call {
+            name,
+            arguments: arguments.into_iter().map(optimize_expr).collect(),
+        },
+        kind => kind,
+    };
+    Expr { kind, span }
+}
+
+/// The value of an expression that's only numbers, the way the compiler writes them:
+/// an integer literal it takes, or the negation of one
+fn constant(expr: &Expr) -> Option<i32> {
+    match &expr.kind {
+        ExprKind::Integer { value } if (0..=MAX_INTEGER).contains(value) => Some(*value as i32),
+        ExprKind::Unary {
+            operator: UnaryOperator::Negate,
+            value,
+        } => match value.kind {
+            ExprKind::Integer { value } if (0..=MAX_INTEGER).contains(&value) => {
+                Some(-(value as i32))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What the VM would work out for two numbers, if it would finish
+fn fold(operator: BinaryOperator, left: i32, right: i32) -> Option<i32> {
+    let value = match operator {
+        BinaryOperator::Add => left.wrapping_add(right),
+        BinaryOperator::Subtract => left.wrapping_sub(right),
+        BinaryOperator::Multiply => left.wrapping_mul(right),
+        // left for the VM to stop the program on
+        BinaryOperator::Divide | BinaryOperator::Remainder if right == 0 => return None,
+        BinaryOperator::Divide => left.wrapping_div(right),
+        BinaryOperator::Remainder => left.wrapping_rem(right),
+        BinaryOperator::Equal => (left == right) as i32,
+        BinaryOperator::NotEqual => (left != right) as i32,
+        BinaryOperator::GreaterThan => (left > right) as i32,
+        BinaryOperator::GreaterThanOrEqual => (left >= right) as i32,
+        BinaryOperator::LessThan => (left < right) as i32,
+        BinaryOperator::LessThanOrEqual => (left <= right) as i32,
+        BinaryOperator::And => (left != 0 && right != 0) as i32,
+        BinaryOperator::Or => (left != 0 || right != 0) as i32,
+    };
+    // the smallest i32 has no literal to negate
+    (value != i32::MIN).then_some(value)
+}
+
+/// A folded number as an expression, negated if it's below zero
+fn number(value: i32, span: Span) -> Expr {
+    let integer = Expr {
+        kind: ExprKind::Integer {
+            value: value.unsigned_abs() as i64,
+        },
+        span,
+    };
+    if value >= 0 {
+        return integer;
+    }
+    Expr {
+        kind: ExprKind::Unary {
+            operator: UnaryOperator::Negate,
+            value: Box::new(integer),
+        },
+        span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::expression_parser::expression;
+    use crate::parser::parse_all;
+
+    fn optimized(source: &str) -> ExprKind {
+        optimize_expr(parse_all(source, |tokens| expression(tokens, 0)).unwrap()).kind
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(optimized("1+2*3"), ExprKind::Integer { value: 7 });
+        assert_eq!(optimized("8-2-1"), ExprKind::Integer { value: 5 });
+        assert_eq!(
+            optimized("-7 % 3 + (2 < 3)"),
+            ExprKind::Integer { value: 0 }
+        );
+        assert_eq!(optimized("!(4 == 4 && 0)"), ExprKind::Integer { value: 1 });
+        let folded = optimized("2-5");
+        assert!(
+            matches!(&folded, ExprKind::Unary { operator: UnaryOperator::Negate, value }
+                if value.kind == ExprKind::Integer { value: 3 }),
+            "{:?}",
+            folded
+        );
+        assert!(matches!(optimized("-(-(-4))"), ExprKind::Unary { .. }));
+        // what a LOAD can't take is built by the compiler
+        assert_eq!(optimized("300 * 300"), ExprKind::Integer { value: 90000 });
+        // wrapping like the VM
+        assert!(matches!(optimized("2147483647 + 2"),
+            ExprKind::Unary { operator: UnaryOperator::Negate, value }
+                if value.kind == ExprKind::Integer { value: 2147483647 }));
+    }
+
+    #[test]
+    fn test_what_is_not_folded() {
+        // the VM has to stop the program
+        assert!(matches!(optimized("1/0"), ExprKind::Binary { .. }));
+        assert!(matches!(optimized("5 % (2 - 2)"), ExprKind::Binary { .. }));
+        // too big for a register, whether written or worked out
+        assert!(matches!(
+            optimized("2147483648 * 0"),
+            ExprKind::Binary { .. }
+        ));
+        assert!(matches!(
+            optimized("2147483647 + 1"),
+            ExprKind::Binary { .. }
+        ));
+        // dropping x would drop its errors and calls
+        assert!(matches!(optimized("x * 0"), ExprKind::Binary { .. }));
+        assert!(matches!(optimized("0 && f()"), ExprKind::Binary { .. }));
+    }
+
+    #[test]
+    fn test_identities() {
+        let x = ExprKind::Variable {
+            name: "x".to_string(),
+        };
+        for source in ["x + 0", "0 + x", "x - 0", "x * 1", "1 * x", "x / 1", "--x"] {
+            assert_eq!(optimized(source), x, "{}", source);
+        }
+        assert_eq!(optimized("(x * (3 - 2)) + (1 - 1)"), x);
+        // 0 - x is a negation, not x
+        assert!(matches!(optimized("0 - x"), ExprKind::Binary { .. }));
+        assert!(matches!(optimized("x / 1 * 2"), ExprKind::Binary { .. }));
+    }
+}
@@ -1,46 +1,351 @@
-use crate::parser::operator_parser::addition_operator;
-use crate::parser::operator_parser::substraction_operator;
-use crate::parser::term_parser::term;
-
-use super::Token;
-use nom::types::CompleteStr;
-
-named!(pub expression<CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            left: term>>
-            right: many0!(
-                tuple!(
-                    alt!(
-                        addition_operator |
-                        substraction_operator
-                    ),
-                    term
-                )
-            )>>
-            (
-                Token::Expression {
-                    left: Box::new( left),
-                    right: right,
-                }
-            )
-        )
+use nom::{Err, ErrorKind};
+
+use super::function_parser::call;
+use super::{deeper, expect, failure, token, ParseError, ParseResult, Tokens, MAX_NESTING};
+use crate::ast::{BinaryOperator, Expr, ExprKind, UnaryOperator};
+use crate::token::{Span, Token};
+
+/// A whole expression: arithmetic, with comparisons and then `&&` and `||` binding looser
+pub fn expression(input: Tokens, depth: usize) -> ParseResult<Expr> {
+    binary_expression(input, 1, depth)
+}
+
+/// An expression that has to be there, as what comes before it needs one
+pub fn required_expression(input: Tokens, depth: usize) -> ParseResult<Expr> {
+    cut!(
+        input,
+        ParseError::Expected("an expression"),
+        call!(expression, depth)
+    )
+}
+
+/// Operators binding at least as tight as `min_precedence`, by precedence climbing.
+/// They're all left associative, so the right operand only takes tighter ones:
+/// `8-2-1` is `(8-2)-1`. Comparisons don't chain, so `1 < 2 < 3` is an error.
+/// A chain is built by the loop rather than by recursing, but it deepens the tree
+/// all the same, so its height counts against `MAX_NESTING` too.
+fn binary_expression(input: Tokens, min_precedence: u8, depth: usize) -> ParseResult<Expr> {
+    let (input, left) = unary(input, depth)?;
+    operations(input, left, min_precedence, depth)
+}
+
+/// The operators and right operands following `left`, apart from `binary_expression`
+/// so that operands that nest without any operators keep its frame off the stack
+fn operations(
+    mut input: Tokens,
+    mut left: Expr,
+    min_precedence: u8,
+    depth: usize,
+) -> ParseResult<Expr> {
+    let mut left_height = height(&left);
+    let mut compared = false;
+    while let Ok((rest, (operator, precedence))) = binary_operator(input) {
+        if precedence < min_precedence {
+            break;
+        }
+        if operator.is_comparison() {
+            if compared {
+                return failure(
+                    input,
+                    ParseError::Expected("`&&` or `||` before another comparison"),
+                );
+            }
+            compared = true;
+        }
+        let (rest, right) = cut!(
+            rest,
+            ParseError::Expected("an expression"),
+            call!(binary_expression, precedence + 1, depth)
+        )?;
+        left_height = left_height.max(height(&right)) + 1;
+        if depth + left_height > MAX_NESTING {
+            return failure(input, ParseError::NestedTooDeeply);
+        }
+        left = binary(operator, left, right);
+        input = rest;
+    }
+    Ok((input, left))
+}
+
+/// An operand, with any `-` or `!` in front of it binding tighter than a binary
+/// operator: a number, a string, a variable, a call or an expression in parentheses.
+/// Each is a function of its own, which keeps the frames of the nesting ones small.
+fn unary(input: Tokens, depth: usize) -> ParseResult<Expr> {
+    alt!(
+        input,
+        call!(unary_operation, depth) | call!(call, depth) | literal | call!(parenthesised, depth)
+    )
+}
+
+/// `-x` or `!x`
+fn unary_operation(input: Tokens, depth: usize) -> ParseResult<Expr> {
+    do_parse!(
+        input,
+        operator: unary_operator
+            >> depth: call!(deeper, depth, 1)
+            >> value: cut!(ParseError::Expected("an expression"), call!(unary, depth))
+            >> (Expr {
+                span: operator.1.to(value.span),
+                kind: ExprKind::Unary {
+                    operator: operator.0,
+                    value: Box::new(value),
+                },
+            })
     )
-);
+}
+
+/// `(expression)`, its span taking in the parentheses
+fn parenthesised(input: Tokens, depth: usize) -> ParseResult<Expr> {
+    do_parse!(
+        input,
+        start: call!(token, &Token::LeftParen)
+            >> depth: call!(deeper, depth, 1)
+            >> inner: call!(required_expression, depth)
+            >> end: call!(expect, &Token::RightParen)
+            >> (Expr {
+                kind: inner.kind,
+                span: start.to(end),
+            })
+    )
+}
+
+/// A number, a string or a variable
+fn literal(input: Tokens) -> ParseResult<Expr> {
+    let (token, span) = &input[0];
+    let kind = match token {
+        Token::Integer { value } => ExprKind::Integer { value: *value },
+        Token::Float { value } => ExprKind::Float { value: *value },
+        Token::String { value } => ExprKind::String {
+            value: value.clone(),
+        },
+        Token::Identifier { name } => ExprKind::Variable { name: name.clone() },
+        _ => return Err(Err::Error(error_position!(input, ErrorKind::Alt))),
+    };
+    Ok((&input[1..], Expr { kind, span: *span }))
+}
+
+fn unary_operator(input: Tokens) -> ParseResult<(UnaryOperator, Span)> {
+    let operator = match input[0].0 {
+        Token::SubtractionOperator => UnaryOperator::Negate,
+        Token::NotOperator => UnaryOperator::Not,
+        _ => return Err(Err::Error(error_position!(input, ErrorKind::Alt))),
+    };
+    Ok((&input[1..], (operator, input[0].1)))
+}
+
+/// The operator the next token is and how tightly it binds, higher binding tighter
+fn binary_operator(input: Tokens) -> ParseResult<(BinaryOperator, u8)> {
+    let operator = match input[0].0 {
+        Token::OrOperator => (BinaryOperator::Or, 1),
+        Token::AndOperator => (BinaryOperator::And, 2),
+        Token::EqualOperator => (BinaryOperator::Equal, 3),
+        Token::NotEqualOperator => (BinaryOperator::NotEqual, 3),
+        Token::GreaterThanOperator => (BinaryOperator::GreaterThan, 3),
+        Token::GreaterThanOrEqualOperator => (BinaryOperator::GreaterThanOrEqual, 3),
+        Token::LessThanOperator => (BinaryOperator::LessThan, 3),
+        Token::LessThanOrEqualOperator => (BinaryOperator::LessThanOrEqual, 3),
+        Token::AdditionOperator => (BinaryOperator::Add, 4),
+        Token::SubtractionOperator => (BinaryOperator::Subtract, 4),
+        Token::MultiplicationOperator => (BinaryOperator::Multiply, 5),
+        Token::DivisionOperator => (BinaryOperator::Divide, 5),
+        Token::RemainderOperator => (BinaryOperator::Remainder, 5),
+        _ => return Err(Err::Error(error_position!(input, ErrorKind::Alt))),
+    };
+    Ok((&input[1..], operator))
+}
+
+/// How many levels the tree goes down, found without recursing since it may be
+/// too deep to recurse over
+fn height(expr: &Expr) -> usize {
+    let mut deepest = 0;
+    let mut pending = vec![(expr, 1)];
+    while let Some((expr, depth)) = pending.pop() {
+        deepest = deepest.max(depth);
+        match &expr.kind {
+            ExprKind::Unary { value, .. } => pending.push((value, depth + 1)),
+            ExprKind::Binary { left, right, .. } => {
+                pending.push((left, depth + 1));
+                pending.push((right, depth + 1));
+            }
+            ExprKind::Call { arguments, .. } => {
+                pending.extend(arguments.iter().map(|argument| (argument, depth + 1)))
+            }
+            _ => {}
+        }
+    }
+    deepest
+}
+
+fn binary(operator: BinaryOperator, left: Expr, right: Expr) -> Expr {
+    Expr {
+        span: left.span.to(right.span),
+        kind: ExprKind::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        },
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::CompileError;
+    use crate::parser::parse_all;
+
+    fn expression(source: &str) -> Result<Expr, CompileError> {
+        parse_all(source, |tokens| super::expression(tokens, 0))
+    }
+
+    fn operator(expr: &Expr) -> BinaryOperator {
+        match &expr.kind {
+            ExprKind::Binary { operator, .. } => *operator,
+            _ => panic!("expected a binary operation, got {:?}", expr),
+        }
+    }
+
+    fn operands(expr: &Expr) -> (&Expr, &Expr) {
+        match &expr.kind {
+            ExprKind::Binary { left, right, .. } => (left, right),
+            _ => panic!("expected a binary operation, got {:?}", expr),
+        }
+    }
 
     #[test]
     fn test_parse_expression() {
-        let result = expression(CompleteStr("3*4"));
-        assert_eq!(result.is_ok(), true);
+        let expr = expression("3*4").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Multiply);
+        // * binds tighter than +
+        let expr = expression("1 + 3*4").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Add);
+        assert_eq!(operator(operands(&expr).1), BinaryOperator::Multiply);
     }
 
     #[test]
     fn test_parse_nested_expression() {
-        let result = expression(CompleteStr("(3*4)+1"));
-        assert_eq!(result.is_ok(), true);
+        let expr = expression("((3*4)*2)+1").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Add);
+        let (left, _) = operands(&expr);
+        // the span takes in the parentheses
+        assert_eq!((left.span.start, left.span.end), (0, 9));
+        assert!(expression("(1 + 2").is_err());
+    }
+
+    fn unary(expr: &Expr) -> (UnaryOperator, &Expr) {
+        match &expr.kind {
+            ExprKind::Unary { operator, value } => (*operator, value),
+            _ => panic!("expected a unary operation, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_parse_numbers() {
+        for (source, value) in [("0", 0), ("1", 1)] {
+            assert_eq!(
+                expression(source).unwrap().kind,
+                ExprKind::Integer { value }
+            );
+        }
+        for (source, value) in [("100.4", 100.4), ("1.02", 1.02)] {
+            assert_eq!(expression(source).unwrap().kind, ExprKind::Float { value });
+        }
+        // a `-` is never part of the number
+        let expr = expression("-1").unwrap();
+        let (op, value) = unary(&expr);
+        assert_eq!(op, UnaryOperator::Negate);
+        assert_eq!(value.kind, ExprKind::Integer { value: 1 });
+        assert_eq!((expr.span.start, expr.span.end), (0, 2));
+    }
+
+    #[test]
+    fn test_parse_not() {
+        let expr = expression("!(x < 1)").unwrap();
+        let (op, value) = unary(&expr);
+        assert_eq!(op, UnaryOperator::Not);
+        assert_eq!(operator(value), BinaryOperator::LessThan);
+    }
+
+    #[test]
+    fn test_parse_negation() {
+        let expr = expression("-(1+2)").unwrap();
+        let (op, value) = unary(&expr);
+        assert_eq!(op, UnaryOperator::Negate);
+        assert_eq!(operator(value), BinaryOperator::Add);
+        // unary operators bind tighter than any binary one
+        let expr = expression("-x * 2").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Multiply);
+        assert_eq!(unary(operands(&expr).0).0, UnaryOperator::Negate);
+        let expr = expression("2 - -3").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Subtract);
+        assert_eq!(unary(operands(&expr).1).0, UnaryOperator::Negate);
+        let expr = expression("!-1").unwrap();
+        let (op, value) = unary(&expr);
+        assert_eq!(op, UnaryOperator::Not);
+        assert_eq!(unary(value).0, UnaryOperator::Negate);
+        let expr = expression("--1").unwrap();
+        let (_, value) = unary(unary(&expr).1);
+        assert_eq!(value.kind, ExprKind::Integer { value: 1 });
+    }
+
+    #[test]
+    fn test_parse_associativity() {
+        // `8-2-1` is `(8-2)-1`
+        for (source, expected) in [
+            ("8-2-1", BinaryOperator::Subtract),
+            ("8/4/2", BinaryOperator::Divide),
+            ("9%5%3", BinaryOperator::Remainder),
+        ] {
+            let expr = expression(source).unwrap();
+            assert_eq!(operator(&expr), expected);
+            let (left, right) = operands(&expr);
+            assert_eq!(operator(left), expected);
+            assert!(matches!(right.kind, ExprKind::Integer { .. }), "{}", source);
+        }
+        // operators as tight as each other go left to right
+        let expr = expression("2 * 7 % 4 / 3").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Divide);
+        let (left, _) = operands(&expr);
+        assert_eq!(operator(left), BinaryOperator::Remainder);
+        assert_eq!(operator(operands(left).0), BinaryOperator::Multiply);
+        // `%` binds tighter than `+`
+        let expr = expression("2 + 3 % 4").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Add);
+        assert_eq!(operator(operands(&expr).1), BinaryOperator::Remainder);
+    }
+
+    #[test]
+    fn test_parse_comparison() {
+        let expr = expression("x + 1 <= 3").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::LessThanOrEqual);
+        assert!(matches!(
+            crate::parser::parse("1 < 2 < 3"),
+            Err(CompileError::UnexpectedToken {
+                found: Token::LessThanOperator,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_and_or() {
+        // && binds tighter than ||
+        let expr = expression("a < 1 || b > 2 && c == 3").unwrap();
+        assert_eq!(operator(&expr), BinaryOperator::Or);
+        let (left, right) = operands(&expr);
+        assert_eq!(operator(left), BinaryOperator::LessThan);
+        assert_eq!(operator(right), BinaryOperator::And);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            expression("1 + * 2"),
+            Err(CompileError::UnexpectedToken { expected, found: Token::MultiplicationOperator, span })
+                if expected == "an expression" && span.column == 5
+        ));
+        assert!(matches!(
+            expression("1 * -"),
+            Err(CompileError::UnexpectedToken { found: Token::EndOfInput, span, .. }) if span.column == 6
+        ));
     }
 }
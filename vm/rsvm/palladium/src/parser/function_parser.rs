@@ -0,0 +1,87 @@
+use super::expression_parser::required_expression;
+use super::statement_parser::block;
+use super::{deeper, name, parenthesised_list, spanned, token, ParseError, ParseResult, Tokens};
+use crate::ast::{Expr, ExprKind, Function, Param};
+use crate::token::Token;
+
+/// `fn name(params) { body }`
+pub fn function(input: Tokens) -> ParseResult<Function> {
+    map!(
+        input,
+        call!(spanned, |input| do_parse!(
+            input,
+            call!(token, &Token::Fn)
+                >> name: cut!(ParseError::Expected("a name"), name)
+                >> params: call!(parenthesised_list, param)
+                >> body: call!(block, 0)
+                >> ((name.0, params.0, body))
+        )),
+        |((name, params, body), span)| Function {
+            name,
+            params,
+            body,
+            span,
+        }
+    )
+}
+
+fn param(input: Tokens) -> ParseResult<Param> {
+    let (rest, (name, span)) = cut!(input, ParseError::Expected("a name"), name)?;
+    Ok((rest, Param { name, span }))
+}
+
+/// A name and its arguments in parentheses
+pub(super) fn call(input: Tokens, depth: usize) -> ParseResult<Expr> {
+    do_parse!(
+        input,
+        name: name
+            >> peek!(call!(token, &Token::LeftParen))
+            >> depth: call!(deeper, depth, 1)
+            >> arguments: call!(parenthesised_list, |input| required_expression(input, depth))
+            >> (Expr {
+                span: name.1.to(arguments.1),
+                kind: ExprKind::Call {
+                    name: name.0,
+                    arguments: arguments.0,
+                },
+            })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileError;
+    use crate::parser::expression_parser::expression;
+    use crate::parser::parse_all;
+
+    #[test]
+    fn test_parse_function() {
+        let function = parse_all("fn add(a, b) { return a + b; }", function).unwrap();
+        assert_eq!(function.name, "add");
+        let params: Vec<&str> = function.params.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(params, vec!["a", "b"]);
+        assert_eq!(function.params[1].span.column, 11);
+        assert_eq!(function.body.len(), 1);
+        assert!(parse_all("fn main() { }", super::function).is_ok());
+        assert!(matches!(
+            parse_all("fn (a) { }", super::function),
+            Err(CompileError::UnexpectedToken { expected, .. }) if expected == "a name"
+        ));
+        assert!(parse_all("fn f(a,) { }", super::function).is_err());
+    }
+
+    #[test]
+    fn test_parse_call() {
+        let expr = parse_all("add(1, f(2) * 3)", |tokens| expression(tokens, 0)).unwrap();
+        assert!(
+            matches!(expr.kind, ExprKind::Call { name, arguments } if name == "add" && arguments.len() == 2)
+        );
+        assert_eq!((expr.span.start, expr.span.end), (0, 16));
+        let expr = parse_all("now()", |tokens| expression(tokens, 0)).unwrap();
+        assert!(matches!(expr.kind, ExprKind::Call { arguments, .. } if arguments.is_empty()));
+        // a plain name is a variable
+        let expr = parse_all("x + 1", |tokens| expression(tokens, 0)).unwrap();
+        assert!(matches!(expr.kind, ExprKind::Binary { .. }));
+    }
+}
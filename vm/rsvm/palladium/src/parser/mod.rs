@@ -1,8 +1,198 @@
+//! The grammar, as nom parsers over the lexer's tokens rather than over the text, so
+//! every node knows its span and the rules don't have to skip whitespace. The rules
+//! are spread over the modules of this one. Those that nest take how deep they are,
+//! so they can stop at `MAX_NESTING`.
+
+/// Runs a parser, turning it not matching into an error in the program, reported at
+/// the token it stopped on as `ParseError` `$expected`. Unlike an `Err::Error`, the
+/// `Err::Failure` this gives isn't backtracked over by `alt!`, `opt!` or `many0!`.
+macro_rules! cut (
+    ($i:expr, $expected:expr, $submac:ident!( $($args:tt)* )) => (
+        $crate::parser::cut($submac!($i, $($args)*), $expected)
+    );
+    ($i:expr, $expected:expr, $f:expr) => (
+        cut!($i, $expected, call!($f))
+    );
+);
+
 pub mod expression_parser;
-pub mod factor_parser;
-pub mod operand_parser;
-pub mod operator_parser;
+pub mod function_parser;
 pub mod program_parser;
-pub mod term_parser;
+pub mod statement_parser;
+
+use nom::{Context, Err, ErrorKind, IResult};
+
+use self::program_parser::program;
+use crate::ast::Program;
+use crate::compiler::CompileError;
+use crate::lexer::tokenize;
+use crate::token::{Span, Token};
+
+/// How deep expressions and blocks may nest. The parser and the compiler recurse
+/// once per level, so without a limit a long enough program overflows the stack.
+pub const MAX_NESTING: usize = 256;
+
+/// The levels of `MAX_NESTING` a block takes up, as parsing and compiling one
+/// takes several times the stack of an expression
+pub(crate) const BLOCK_LEVELS: usize = 4;
+
+/// The tokens left to parse, ending in the lexer's `Token::EndOfInput`. No rule
+/// takes that one, so there's always a token to report an error at.
+pub type Tokens<'a> = &'a [(Token, Span)];
+
+/// What every rule gives, nom's result with `ParseError` for its errors
+pub type ParseResult<'a, T> = IResult<Tokens<'a>, T, ParseError>;
+
+/// Why a program doesn't parse, carried in nom's errors as `ErrorKind::Custom`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Something other than the next token was needed, described as in "an expression"
+    Expected(&'static str),
+    ExpectedToken(Token),
+    NestedTooDeeply,
+}
+
+/// Parses a whole program
+pub fn parse(source: &str) -> Result<Program, CompileError> {
+    let tokens = tokenize(source)?;
+    let (_, program) = program(&tokens).map_err(compile_error)?;
+    Ok(program)
+}
+
+/// The error to report for where the parsers stopped
+fn compile_error(error: Err<Tokens, ParseError>) -> CompileError {
+    let (rest, kind) = match error {
+        Err::Error(Context::Code(rest, kind)) | Err::Failure(Context::Code(rest, kind)) => {
+            (rest, kind)
+        }
+        Err::Incomplete(_) => unreachable!("the tokens are all there up front"),
+    };
+    let (found, span) = rest[0].clone();
+    let expected = match kind {
+        ErrorKind::Custom(ParseError::NestedTooDeeply) => {
+            return CompileError::NestedTooDeeply { span }
+        }
+        ErrorKind::Custom(ParseError::Expected(expected)) => expected.to_string(),
+        ErrorKind::Custom(ParseError::ExpectedToken(token)) => token.to_string(),
+        // a rule that didn't match at all, which `program` cuts before it gets here
+        _ => "a statement".to_string(),
+    };
+    CompileError::UnexpectedToken {
+        expected,
+        found,
+        span,
+    }
+}
+
+/// See `cut!`
+pub(crate) fn cut<T>(result: ParseResult<T>, expected: ParseError) -> ParseResult<T> {
+    result.map_err(|e| match e {
+        Err::Error(Context::Code(rest, _)) => {
+            Err::Failure(Context::Code(rest, ErrorKind::Custom(expected)))
+        }
+        e => e,
+    })
+}
+
+fn failure<T>(input: Tokens, error: ParseError) -> ParseResult<T> {
+    Err(Err::Failure(error_position!(
+        input,
+        ErrorKind::Custom(error)
+    )))
+}
+
+/// The next token if it's `expected`, giving its span
+pub(crate) fn token<'a>(input: Tokens<'a>, expected: &Token) -> ParseResult<'a, Span> {
+    match &input[0] {
+        (token, span) if token == expected && *token != Token::EndOfInput => {
+            Ok((&input[1..], *span))
+        }
+        _ => Err(Err::Error(error_position!(input, ErrorKind::Tag))),
+    }
+}
+
+/// The next token, which has to be `expected`
+pub(crate) fn expect<'a>(input: Tokens<'a>, expected: &Token) -> ParseResult<'a, Span> {
+    cut(
+        token(input, expected),
+        ParseError::ExpectedToken(expected.clone()),
+    )
+}
+
+/// Matches without taking anything at the end of the tokens
+pub(crate) fn end_of_input(input: Tokens) -> ParseResult<()> {
+    match input[0].0 {
+        Token::EndOfInput => Ok((input, ())),
+        _ => Err(Err::Error(error_position!(input, ErrorKind::Eof))),
+    }
+}
+
+pub(crate) fn name(input: Tokens) -> ParseResult<(String, Span)> {
+    match &input[0] {
+        (Token::Identifier { name }, span) => Ok((&input[1..], (name.clone(), *span))),
+        _ => Err(Err::Error(error_position!(input, ErrorKind::Alpha))),
+    }
+}
+
+/// The depth of a rule `levels` inside one at `depth`, failing at the next token
+/// once that's deeper than `MAX_NESTING`
+pub(crate) fn deeper(input: Tokens, depth: usize, levels: usize) -> ParseResult<usize> {
+    if depth + levels > MAX_NESTING {
+        return failure(input, ParseError::NestedTooDeeply);
+    }
+    Ok((input, depth + levels))
+}
+
+/// Runs `rule`, which takes at least one token, and gives the span of the tokens it took
+pub(crate) fn spanned<'a, T>(
+    input: Tokens<'a>,
+    rule: impl FnOnce(Tokens<'a>) -> ParseResult<'a, T>,
+) -> ParseResult<'a, (T, Span)> {
+    let (rest, value) = rule(input)?;
+    let last = input.len() - rest.len() - 1;
+    Ok((rest, (value, input[0].1.to(input[last].1))))
+}
+
+/// `(a, b, ...)`, with each item parsed by `item`, giving the items and the span of the `)`
+pub(crate) fn parenthesised_list<'a, T>(
+    input: Tokens<'a>,
+    item: impl Fn(Tokens<'a>) -> ParseResult<'a, T>,
+) -> ParseResult<'a, (Vec<T>, Span)> {
+    pair!(
+        input,
+        preceded!(call!(expect, &Token::LeftParen), call!(list_items, &item)),
+        call!(expect, &Token::RightParen)
+    )
+}
+
+/// The items of a list up to its `)`. It's a loop rather than `separated_list!` as
+/// calls nest through it, and in a debug build the macro's frame is twice the size.
+fn list_items<'a, T>(
+    mut input: Tokens<'a>,
+    item: impl Fn(Tokens<'a>) -> ParseResult<'a, T>,
+) -> ParseResult<'a, Vec<T>> {
+    let mut items = vec![];
+    if token(input, &Token::RightParen).is_ok() {
+        return Ok((input, items));
+    }
+    loop {
+        let (rest, value) = item(input)?;
+        items.push(value);
+        match token(rest, &Token::Comma) {
+            Ok((rest, _)) => input = rest,
+            Err(_) => return Ok((rest, items)),
+        }
+    }
+}
 
-pub use super::token::Token;
+/// Parses all of `source` with `rule`
+#[cfg(test)]
+pub(crate) fn parse_all<T>(
+    source: &str,
+    rule: impl FnOnce(Tokens) -> ParseResult<T>,
+) -> Result<T, CompileError> {
+    let tokens = tokenize(source)?;
+    let (rest, value) = rule(&tokens).map_err(compile_error)?;
+    assert_eq!(rest[0].0, Token::EndOfInput, "{}", source);
+    Ok(value)
+}
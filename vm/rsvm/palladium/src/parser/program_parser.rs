@@ -1,23 +1,49 @@
-use super::{expression_parser::expression, Token};
-use nom::{named, types::CompleteStr, ws};
+use super::function_parser::function;
+use super::statement_parser::statement;
+use super::{end_of_input, ParseResult, Tokens};
+use crate::ast::{Item, Program};
 
-named!(pub program<CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            expressions: many1!(expression) >>
-            (
-                Token::Program { expressions }
-            )
-        )
+/// Functions and statements up to the end of the input, at least one of them
+pub fn program(input: Tokens) -> ParseResult<Program> {
+    do_parse!(
+        input,
+        first: item
+            >> rest: many0!(preceded!(not!(end_of_input), item))
+            >> (Program {
+                items: Some(first).into_iter().chain(rest).collect(),
+            })
     )
-);
+}
 
+fn item(input: Tokens) -> ParseResult<Item> {
+    alt!(
+        input,
+        map!(function, Item::Function) | map!(call!(statement, 0), Item::Statement)
+    )
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::CompileError;
+    use crate::parser::parse;
+    use crate::token::Token;
+
     #[test]
     fn test_parse_program() {
-        let test_program = CompleteStr("1+2");
-        let result = program(test_program);
-        assert_eq!(result.is_ok(), true);
+        let program = parse("1+2").unwrap();
+        assert_eq!(program.items.len(), 1);
+        let program = parse("fn f() { } let x = f(); x").unwrap();
+        assert!(matches!(
+            &program.items[..],
+            [Item::Function(_), Item::Statement(_), Item::Statement(_)]
+        ));
+        assert!(matches!(
+            parse(""),
+            Err(CompileError::UnexpectedToken {
+                found: Token::EndOfInput,
+                ..
+            })
+        ));
     }
 }
@@ -0,0 +1,255 @@
+use super::expression_parser::{expression, required_expression};
+use super::{
+    deeper, end_of_input, expect, name, spanned, token, ParseError, ParseResult, Tokens,
+    BLOCK_LEVELS,
+};
+use crate::ast::{Stmt, StmtKind};
+use crate::token::Token;
+
+pub fn statement(input: Tokens, depth: usize) -> ParseResult<Stmt> {
+    map!(
+        input,
+        call!(spanned, |input| statement_kind(input, depth)),
+        |(kind, span)| Stmt { kind, span }
+    )
+}
+
+fn statement_kind(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    alt!(
+        input,
+        call!(if_statement, depth)
+            | call!(while_statement, depth)
+            | call!(return_statement, depth)
+            | call!(print_statement, depth)
+            | call!(let_statement, depth)
+            | call!(assignment, depth)
+            | call!(expression_statement, depth)
+    )
+}
+
+/// `{ statements }`
+pub fn block(input: Tokens, depth: usize) -> ParseResult<Vec<Stmt>> {
+    do_parse!(
+        input,
+        depth: call!(deeper, depth, BLOCK_LEVELS)
+            >> call!(expect, &Token::LeftBrace)
+            // a missing `}` is reported at the end rather than as a statement
+            >> statements:
+                many0!(do_parse!(
+                    not!(call!(token, &Token::RightBrace))
+                        >> not!(end_of_input)
+                        >> statement: call!(statement, depth)
+                        >> (statement)
+                ))
+            >> call!(expect, &Token::RightBrace)
+            >> (statements)
+    )
+}
+
+fn if_statement(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        call!(token, &Token::If)
+            >> condition: call!(required_expression, depth)
+            >> body: call!(block, depth)
+            >> else_body: opt!(preceded!(call!(token, &Token::Else), call!(else_body, depth)))
+            >> (StmtKind::If {
+                condition,
+                body,
+                else_body,
+            })
+    )
+}
+
+/// What follows `else`, where an `else if` nests in the `else` as if in a block
+fn else_body(input: Tokens, depth: usize) -> ParseResult<Vec<Stmt>> {
+    alt!(
+        input,
+        do_parse!(
+            peek!(call!(token, &Token::If))
+                >> depth: call!(deeper, depth, 1)
+                >> statement: call!(spanned, |input| if_statement(input, depth))
+                >> (vec![Stmt {
+                    kind: statement.0,
+                    span: statement.1,
+                }])
+        ) | call!(block, depth)
+    )
+}
+
+fn while_statement(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        call!(token, &Token::While)
+            >> condition: call!(required_expression, depth)
+            >> body: call!(block, depth)
+            >> (StmtKind::While { condition, body })
+    )
+}
+
+fn return_statement(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        call!(token, &Token::Return)
+            >> value: opt!(call!(expression, depth))
+            >> call!(expect, &Token::Semicolon)
+            >> (StmtKind::Return { value })
+    )
+}
+
+/// `print "x is ", x;`, where `print;` prints an empty line
+fn print_statement(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        call!(token, &Token::Print)
+            >> values:
+                alt!(
+                    map!(peek!(call!(token, &Token::Semicolon)), |_| vec![])
+                        | separated_nonempty_list!(
+                            call!(token, &Token::Comma),
+                            call!(required_expression, depth)
+                        )
+                )
+            >> call!(expect, &Token::Semicolon)
+            >> (StmtKind::Print { values })
+    )
+}
+
+fn let_statement(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        call!(token, &Token::Let)
+            >> name: cut!(ParseError::Expected("a name"), name)
+            >> call!(expect, &Token::AssignmentOperator)
+            >> value: call!(required_expression, depth)
+            >> call!(expect, &Token::Semicolon)
+            >> (StmtKind::Let { name: name.0, value })
+    )
+}
+
+fn assignment(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        name: name
+            >> call!(token, &Token::AssignmentOperator)
+            >> value: call!(required_expression, depth)
+            >> call!(expect, &Token::Semicolon)
+            >> (StmtKind::Assignment { name: name.0, value })
+    )
+}
+
+/// Any expression, where one that can't even start is an error in the program
+fn expression_statement(input: Tokens, depth: usize) -> ParseResult<StmtKind> {
+    do_parse!(
+        input,
+        value: call!(required_expression, depth)
+            >> opt!(call!(token, &Token::Semicolon))
+            >> (StmtKind::Expression { value })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ExprKind;
+    use crate::compiler::CompileError;
+    use crate::parser::parse_all;
+
+    fn statement(source: &str) -> Result<Stmt, CompileError> {
+        parse_all(source, |tokens| super::statement(tokens, 0))
+    }
+
+    #[test]
+    fn test_parse_let() {
+        let stmt = statement("let x = 1 + 2;").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::Let { name, .. } if name == "x"));
+        assert_eq!((stmt.span.start, stmt.span.end), (0, 14));
+        assert!(matches!(
+            statement("let x = 1"),
+            Err(CompileError::UnexpectedToken { expected, found: Token::EndOfInput, .. })
+                if expected == "`;`"
+        ));
+        // a variable named `letter` is assigned to, not declared
+        let stmt = statement("letter = 1;").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::Assignment { name, .. } if name == "letter"));
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        let stmt = statement("x = x * 2;").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::Assignment { name, .. } if name == "x"));
+        // `==` compares instead
+        let stmt = statement("x == 2").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::Expression { .. }));
+    }
+
+    #[test]
+    fn test_parse_expression_statement() {
+        let stmt = statement("x + 1;").unwrap();
+        assert!(matches!(
+            stmt.kind,
+            StmtKind::Expression { value } if matches!(value.kind, ExprKind::Binary { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_if_else() {
+        let stmt = statement("if x < 1 { x = 1; } else if x > 5 { x = 5; } else { }").unwrap();
+        match stmt.kind {
+            StmtKind::If {
+                body,
+                else_body: Some(else_body),
+                ..
+            } => {
+                assert_eq!(body.len(), 1);
+                assert!(matches!(
+                    &else_body[..],
+                    [Stmt { kind: StmtKind::If { else_body: Some(last), .. }, .. }] if last.is_empty()
+                ));
+            }
+            _ => panic!("expected an if, got {:?}", stmt),
+        }
+        assert!(matches!(
+            statement("if x { x = 1;"),
+            Err(CompileError::UnexpectedToken { expected, .. }) if expected == "`}`"
+        ));
+    }
+
+    #[test]
+    fn test_parse_return() {
+        let stmt = statement("return x + 1;").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::Return { value: Some(_) }));
+        let stmt = statement("return;").unwrap();
+        assert_eq!(stmt.kind, StmtKind::Return { value: None });
+    }
+
+    #[test]
+    fn test_parse_print() {
+        let stmt = statement("print \"x is \", x + 1;").unwrap();
+        match stmt.kind {
+            StmtKind::Print { values } => {
+                assert_eq!(values.len(), 2);
+                assert_eq!(
+                    values[0].kind,
+                    crate::ast::ExprKind::String {
+                        value: "x is ".to_string()
+                    }
+                );
+            }
+            kind => panic!("expected a print, got {:?}", kind),
+        }
+        let stmt = statement("print;").unwrap();
+        assert_eq!(stmt.kind, StmtKind::Print { values: vec![] });
+        assert!(statement("print \"a\" x;").is_err());
+        assert!(statement("print 1,;").is_err());
+    }
+
+    #[test]
+    fn test_parse_while() {
+        let stmt = statement("while i < 10 { i = i + 1; }").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::While { body, .. } if body.len() == 1));
+        // keywords need a space after them
+        let stmt = statement("whilex").unwrap();
+        assert!(matches!(stmt.kind, StmtKind::Expression { .. }));
+    }
+}
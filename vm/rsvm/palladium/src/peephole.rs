@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet};
+
+/// Cleans up the Iridium assembly the compiler emits: LOADs of what a register
+/// already holds, and stores that are overwritten before anything reads them.
+/// It only looks within the straight runs of code between labels, jumps and calls.
+pub fn optimize(mut lines: Vec<String>) -> Vec<String> {
+    loop {
+        let count = lines.len();
+        lines = remove_dead_stores(remove_redundant_loads(lines));
+        if lines.len() == count {
+            return lines;
+        }
+    }
+}
+
+/// What a line of assembly does to the registers
+#[derive(Debug, PartialEq)]
+enum Effect {
+    /// Reads some registers and writes one, and nothing else, so it can go
+    /// when nothing needs what it writes
+    Pure { write: u8, reads: Vec<u8> },
+    /// Has to stay, for the stack, the flag or the VM stopping on a zero
+    Kept { write: Option<u8>, reads: Vec<u8> },
+    /// A label, jump, call or anything else other code can come from or go to
+    Boundary,
+}
+
+fn effect(line: &str) -> Effect {
+    if line.contains(':') {
+        return Effect::Boundary;
+    }
+    let mut words = line.split_whitespace();
+    let opcode = words.next().unwrap_or_default();
+    let registers: Vec<u8> = words
+        .filter_map(|word| word.strip_prefix('$')?.parse().ok())
+        .collect();
+    match (opcode, registers.as_slice()) {
+        ("LOAD", &[write]) | ("REM", &[write]) => Effect::Pure {
+            write,
+            reads: vec![],
+        },
+        ("ADD" | "SUB" | "MUL", &[left, right, write]) => Effect::Pure {
+            write,
+            reads: vec![left, right],
+        },
+        ("DIV", &[left, right, write]) => Effect::Kept {
+            write: Some(write),
+            reads: vec![left, right],
+        },
+        ("POP", &[write]) => Effect::Kept {
+            write: Some(write),
+            reads: vec![],
+        },
+        ("PUSH" | "EQ" | "NEQ" | "GT" | "GTE" | "LT" | "LTE", _) => Effect::Kept {
+            write: None,
+            reads: registers,
+        },
+        _ => Effect::Boundary,
+    }
+}
+
+/// Drops a LOAD when the register still holds what the same LOAD put in it
+fn remove_redundant_loads(lines: Vec<String>) -> Vec<String> {
+    let mut loaded: HashMap<u8, String> = HashMap::new();
+    let mut kept = vec![];
+    for line in lines {
+        match effect(&line) {
+            Effect::Pure { write, .. } if line.starts_with("LOAD") => {
+                if loaded.get(&write) == Some(&line) {
+                    continue;
+                }
+                loaded.insert(write, line.clone());
+            }
+            Effect::Pure { write, .. }
+            | Effect::Kept {
+                write: Some(write), ..
+            } => {
+                loaded.remove(&write);
+            }
+            Effect::Kept { write: None, .. } => {}
+            Effect::Boundary => loaded.clear(),
+        }
+        kept.push(line);
+    }
+    kept
+}
+
+/// Drops instructions whose register is written again before it's read.
+/// Goes backwards, knowing which registers are about to be overwritten.
+fn remove_dead_stores(lines: Vec<String>) -> Vec<String> {
+    let mut overwritten = HashSet::new();
+    let mut kept = vec![];
+    for line in lines.into_iter().rev() {
+        match effect(&line) {
+            Effect::Pure { write, .. } if overwritten.contains(&write) => continue,
+            Effect::Pure { write, reads }
+            | Effect::Kept {
+                write: Some(write),
+                reads,
+            } => {
+                overwritten.insert(write);
+                for read in reads {
+                    overwritten.remove(&read);
+                }
+            }
+            Effect::Kept { write: None, reads } => {
+                for read in reads {
+                    overwritten.remove(&read);
+                }
+            }
+            Effect::Boundary => overwritten.clear(),
+        }
+        kept.push(line);
+    }
+    kept.reverse();
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(assembly: &[&str]) -> Vec<String> {
+        assembly.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_effect() {
+        assert_eq!(
+            effect("SUB $31 $2 $3"),
+            Effect::Pure {
+                write: 3,
+                reads: vec![31, 2]
+            }
+        );
+        assert_eq!(
+            effect("LOAD $31 @while1"),
+            Effect::Pure {
+                write: 31,
+                reads: vec![]
+            }
+        );
+        assert_eq!(
+            effect("NEQ $4 $31"),
+            Effect::Kept {
+                write: None,
+                reads: vec![4, 31]
+            }
+        );
+        assert_eq!(effect("else2: NOP"), Effect::Boundary);
+        assert_eq!(effect("CALL $31"), Effect::Boundary);
+    }
+
+    #[test]
+    fn test_redundant_loads() {
+        let assembly = lines(&[
+            "LOAD $31 #0",
+            "SUB $31 $1 $2",
+            "LOAD $31 #0",
+            "SUB $31 $2 $3",
+            "LOAD $31 #1",
+            "LOAD $31 #0",
+        ]);
+        assert_eq!(
+            remove_redundant_loads(assembly),
+            lines(&[
+                "LOAD $31 #0",
+                "SUB $31 $1 $2",
+                "SUB $31 $2 $3",
+                "LOAD $31 #1",
+                "LOAD $31 #0",
+            ])
+        );
+        // other code can jump to a label with anything in the register
+        let assembly = lines(&["LOAD $1 #5", "loop1: NOP", "LOAD $1 #5"]);
+        assert_eq!(remove_redundant_loads(assembly.clone()), assembly);
+        let assembly = lines(&["LOAD $1 #5", "POP $1", "LOAD $1 #5"]);
+        assert_eq!(remove_redundant_loads(assembly.clone()), assembly);
+    }
+
+    #[test]
+    fn test_dead_stores() {
+        let assembly = lines(&["LOAD $1 #5", "LOAD $2 #6", "LOAD $1 #6", "ADD $1 $2 $3"]);
+        assert_eq!(
+            remove_dead_stores(assembly),
+            lines(&["LOAD $2 #6", "LOAD $1 #6", "ADD $1 $2 $3"])
+        );
+        // read before it's overwritten
+        let assembly = lines(&["LOAD $1 #5", "ADD $1 $1 $1"]);
+        assert_eq!(remove_dead_stores(assembly.clone()), assembly);
+        // DIV stops the program on a zero, so it stays even when its result doesn't
+        let assembly = lines(&["DIV $1 $2 $3", "LOAD $3 #0"]);
+        assert_eq!(remove_dead_stores(assembly.clone()), assembly);
+        // what's left at a label or the end may still be read
+        let assembly = lines(&["LOAD $1 #5", "end1: NOP", "LOAD $1 #6", "LOAD $2 #1"]);
+        assert_eq!(remove_dead_stores(assembly.clone()), assembly);
+    }
+
+    #[test]
+    fn test_optimize() {
+        // taking out the second LOAD makes the first SUB's result dead
+        let assembly = lines(&[
+            "LOAD $31 #0",
+            "SUB $31 $1 $2",
+            "LOAD $31 #0",
+            "SUB $31 $1 $2",
+            "HLT",
+        ]);
+        assert_eq!(
+            optimize(assembly),
+            lines(&["LOAD $31 #0", "SUB $31 $1 $2", "HLT"])
+        );
+    }
+}
@@ -0,0 +1,103 @@
+/// A declared variable and the register it lives in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    name: String,
+    register: u8,
+}
+
+impl Variable {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn register(&self) -> u8 {
+        self.register
+    }
+}
+
+/// The variables in scope at the point the compiler is at
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    variables: Vec<Variable>,
+    /// Where the variables of each block being compiled start
+    scopes: Vec<usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            variables: vec![],
+            scopes: vec![],
+        }
+    }
+
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(self.variables.len());
+    }
+
+    /// Forgets the variables declared since the matching `enter_scope`,
+    /// returning the registers they lived in
+    pub fn exit_scope(&mut self) -> Vec<u8> {
+        let start = self.scopes.pop().unwrap_or(0);
+        self.variables
+            .drain(start..)
+            .map(|variable| variable.register)
+            .collect()
+    }
+
+    /// False if there already is a variable with that name in scope,
+    /// as inner blocks can't shadow outer variables
+    pub fn declare(&mut self, name: &str, register: u8) -> bool {
+        if self.lookup(name).is_some() {
+            return false;
+        }
+        self.variables.push(Variable {
+            name: name.to_string(),
+            register,
+        });
+        true
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.variables.iter().find(|variable| variable.name == name)
+    }
+
+    /// Whether a variable lives in the register, so it must not be reused
+    pub fn owns_register(&self, register: u8) -> bool {
+        self.variables
+            .iter()
+            .any(|variable| variable.register == register)
+    }
+
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declare_and_lookup() {
+        let mut symbols = SymbolTable::new();
+        assert!(symbols.declare("x", 3));
+        assert!(!symbols.declare("x", 4));
+        assert_eq!(symbols.lookup("x").unwrap().register(), 3);
+        assert!(symbols.lookup("y").is_none());
+        assert!(symbols.owns_register(3));
+        assert!(!symbols.owns_register(4));
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut symbols = SymbolTable::new();
+        symbols.declare("x", 0);
+        symbols.enter_scope();
+        assert!(!symbols.declare("x", 1));
+        assert!(symbols.declare("y", 2));
+        assert_eq!(symbols.exit_scope(), vec![2]);
+        assert!(symbols.lookup("y").is_none());
+        assert!(symbols.lookup("x").is_some());
+    }
+}
@@ -1,30 +1,173 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+/// Where something is in the source. Lines and columns count from 1, in characters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offsets of the start and the end, which is exclusive
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// From the start of this span to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// The words, numbers and symbols a program is made of
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     AdditionOperator,
     SubtractionOperator,
     MultiplicationOperator,
     DivisionOperator,
-    //
+    RemainderOperator,
+    EqualOperator,
+    NotEqualOperator,
+    GreaterThanOperator,
+    GreaterThanOrEqualOperator,
+    LessThanOperator,
+    LessThanOrEqualOperator,
+    AndOperator,
+    OrOperator,
+    NotOperator,
+    AssignmentOperator,
     Integer {
         value: i64,
     },
     Float {
         value: f64,
     },
-    //
-    Factor {
-        value: Box<Token>,
-    },
-    Term {
-        left: Box<Token>,
-        right: Vec<(Token, Token)>,
+    Identifier {
+        name: String,
     },
-    //
-    Expression {
-        left: Box<Token>,
-        right: Vec<(Token, Token)>,
-    },
-    Program {
-        expressions: Vec<Token>,
+    /// Its text with the escapes worked out
+    String {
+        value: String,
     },
+    Let,
+    If,
+    Else,
+    While,
+    Fn,
+    Return,
+    Print,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Semicolon,
+    EndOfInput,
+}
+
+impl Token {
+    /// The keyword a word is, if it is one
+    pub fn keyword(word: &str) -> Option<Token> {
+        match word {
+            "let" => Some(Token::Let),
+            "if" => Some(Token::If),
+            "else" => Some(Token::Else),
+            "while" => Some(Token::While),
+            "fn" => Some(Token::Fn),
+            "return" => Some(Token::Return),
+            "print" => Some(Token::Print),
+            _ => None,
+        }
+    }
+
+    /// How the token is written, for the ones that are always written the same
+    fn text(&self) -> &'static str {
+        match self {
+            Token::AdditionOperator => "+",
+            Token::SubtractionOperator => "-",
+            Token::MultiplicationOperator => "*",
+            Token::DivisionOperator => "/",
+            Token::RemainderOperator => "%",
+            Token::EqualOperator => "==",
+            Token::NotEqualOperator => "!=",
+            Token::GreaterThanOperator => ">",
+            Token::GreaterThanOrEqualOperator => ">=",
+            Token::LessThanOperator => "<",
+            Token::LessThanOrEqualOperator => "<=",
+            Token::AndOperator => "&&",
+            Token::OrOperator => "||",
+            Token::NotOperator => "!",
+            Token::AssignmentOperator => "=",
+            Token::Let => "let",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::Fn => "fn",
+            Token::Return => "return",
+            Token::Print => "print",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::Comma => ",",
+            Token::Semicolon => ";",
+            Token::Integer { .. }
+            | Token::Float { .. }
+            | Token::Identifier { .. }
+            | Token::String { .. }
+            | Token::EndOfInput => "",
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Integer { value } => write!(f, "`{}`", value),
+            Token::Float { value } => write!(f, "`{:?}`", value),
+            Token::Identifier { name } => write!(f, "`{}`", name),
+            Token::String { value } => write!(f, "{:?}", value),
+            Token::EndOfInput => f.write_str("the end of the program"),
+            _ => write!(f, "`{}`", self.text()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_to() {
+        let first = Span {
+            start: 4,
+            end: 5,
+            line: 2,
+            column: 1,
+        };
+        let last = Span {
+            start: 10,
+            end: 12,
+            line: 2,
+            column: 7,
+        };
+        assert_eq!(
+            first.to(last),
+            Span {
+                start: 4,
+                end: 12,
+                line: 2,
+                column: 1
+            }
+        );
+        assert_eq!(first.to(last).to_string(), "line 2, column 1");
+    }
 }
@@ -1,5 +1,7 @@
-use super::token::Token;
+use crate::ast::{Expr, Program, Stmt};
 
 pub trait Visitor {
-    fn visit_token(&mut self, node: &Token);
+    fn visit_program(&mut self, program: &Program);
+    fn visit_stmt(&mut self, stmt: &Stmt);
+    fn visit_expr(&mut self, expr: &Expr);
 }
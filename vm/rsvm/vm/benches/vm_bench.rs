@@ -11,41 +11,26 @@ mod arithmetic {
 
     use super::*;
 
-    fn execute_add(c: &mut Criterion) {
-        let clos = {
-            let mut test_vm = VM::new_with_non_zero_registers();
-            test_vm.program = vec![Opcode::ADD.into(), 0, 1, 2];
-            test_vm.run_once();
-        };
+    fn execute_once(c: &mut Criterion, name: &str, opcode: Opcode) {
+        let mut test_vm = VM::new_with_non_zero_registers();
+        test_vm.program = vec![opcode.into(), 0, 1, 2];
 
-        c.bench_function("execute_add", move |b| b.iter(|| clos));
+        c.bench_function(name, move |b| {
+            b.iter_with_setup(|| test_vm.clone(), |mut vm| vm.run_once())
+        });
     }
-    fn execute_sub(c: &mut Criterion) {
-        let clos = {
-            let mut test_vm = VM::new_with_non_zero_registers();
-            test_vm.program = vec![Opcode::SUB.into(), 0, 1, 2];
-            test_vm.run_once();
-        };
 
-        c.bench_function("execute_sub", move |b| b.iter(|| clos));
+    fn execute_add(c: &mut Criterion) {
+        execute_once(c, "execute_add", Opcode::ADD);
+    }
+    fn execute_sub(c: &mut Criterion) {
+        execute_once(c, "execute_sub", Opcode::SUB);
     }
     fn execute_mul(c: &mut Criterion) {
-        let clos = {
-            let mut test_vm = VM::new_with_non_zero_registers();
-            test_vm.program = vec![Opcode::MUL.into(), 0, 1, 2];
-            test_vm.run_once();
-        };
-
-        c.bench_function("execute_mul", move |b| b.iter(|| clos));
+        execute_once(c, "execute_mul", Opcode::MUL);
     }
     fn execute_div(c: &mut Criterion) {
-        let clos = {
-            let mut test_vm = VM::new_with_non_zero_registers();
-            test_vm.program = vec![Opcode::DIV.into(), 0, 1, 2];
-            test_vm.run_once();
-        };
-
-        c.bench_function("execute_div", move |b| b.iter(|| clos));
+        execute_once(c, "execute_div", Opcode::DIV);
     }
 
     criterion_group! {
@@ -55,4 +40,66 @@ mod arithmetic {
     }
 }
 
-criterion_main!(arithmetic::arithmetic);
+mod dispatch {
+
+    use criterion::Fun;
+    use vm::instruction::Opcode;
+
+    use super::*;
+
+    const ITERATIONS: u16 = 10_000;
+
+    /// Hand assembled, since a `jeq` that is not taken only steps over 2 bytes.
+    /// The byte after it is a `hlt` so the loop exits cleanly.
+    fn counting_loop(body: &[[u8; 4]]) -> VM {
+        let mut vm = VM::new_with_header();
+        let loop_start = (VM::get_header_offset() + 12) as u16;
+        let [limit_hi, limit_lo] = ITERATIONS.to_be_bytes();
+        let [start_hi, start_lo] = loop_start.to_be_bytes();
+
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 1, limit_hi, limit_lo]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 2, start_hi, start_lo]);
+        for instruction in body {
+            vm.add_bytes(instruction.to_vec());
+        }
+        vm.add_bytes(vec![Opcode::INC.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::LT.into(), 0, 1, 0]);
+        vm.add_bytes(vec![Opcode::JEQ.into(), 2, Opcode::HLT.into(), 0]);
+        vm
+    }
+
+    fn compare(c: &mut Criterion, name: &str, vm: VM) {
+        let interpreted = vm.clone();
+        let decoded = Fun::new("decoded", move |b, _: &u16| {
+            b.iter_with_setup(|| vm.clone(), |mut vm| vm.run())
+        });
+        let interpreted = Fun::new("interpreted", move |b, _: &u16| {
+            b.iter_with_setup(|| interpreted.clone(), |mut vm| vm.run_interpreted())
+        });
+        c.bench_functions(name, vec![decoded, interpreted], ITERATIONS);
+    }
+
+    fn empty_loop(c: &mut Criterion) {
+        compare(c, "dispatch_loop", counting_loop(&[]));
+    }
+
+    fn arithmetic_loop(c: &mut Criterion) {
+        let body = [
+            [Opcode::LOAD.into(), 3, 0, 7],
+            [Opcode::ADD.into(), 0, 3, 4],
+            [Opcode::MUL.into(), 4, 3, 5],
+            [Opcode::SUB.into(), 5, 0, 6],
+            [Opcode::DIV.into(), 6, 3, 7],
+        ];
+        compare(c, "dispatch_arithmetic", counting_loop(&body));
+    }
+
+    criterion_group! {
+       name = dispatch;
+       config = Criterion::default();
+       targets = empty_loop, arithmetic_loop,
+    }
+}
+
+criterion_main!(arithmetic::arithmetic, dispatch::dispatch);
@@ -10,6 +10,8 @@ pub enum AssemblerError {
     SymbolAlreadyDeclared,
     UnknownDirectiveFound { directive: String },
     NonOpcodeInOpcodeField,
+    EntryLabelNotFound { name: String },
+    InvalidOperand { operand: String },
 }
 
 impl fmt::Display for AssemblerError {
@@ -30,6 +32,8 @@ impl fmt::Display for AssemblerError {
             AssemblerError::NonOpcodeInOpcodeField => f.write_str("An non-opcode was found in an opcode field"),
             AssemblerError::InsufficientSections => f.write_str("Less than two sections/segments were found in the code"),
             AssemblerError::ParseError { ref error } => f.write_str(&format!("There was an error parsing the code: {}", error)),
+            AssemblerError::EntryLabelNotFound { ref name } => f.write_str(&format!("The entry point {} is not a label in the code", name)),
+            AssemblerError::InvalidOperand { ref operand } => f.write_str(&format!("{} can't be used as an operand here", operand)),
 
         }
     }
@@ -45,6 +49,8 @@ impl Error for AssemblerError {
             AssemblerError::NonOpcodeInOpcodeField => "A non-opcode was found in an opcode field",
             AssemblerError::InsufficientSections => "Less than two sections/segments were found in the code",
             AssemblerError::ParseError { .. } => "There was an error parsing the code",
+            AssemblerError::EntryLabelNotFound { .. } => "The entry point is not a label in the code",
+            AssemblerError::InvalidOperand { .. } => "An operand can't be used there",
         }
     }
 }
@@ -7,6 +7,7 @@ use super::label_parser::label_declaration;
 use super::opcode_parsers::opcode;
 use super::operand_parsers::operand;
 
+use super::assembler_error::AssemblerError;
 use super::symbol::SymbolTable;
 use super::Token;
 
@@ -53,34 +54,52 @@ named!(pub instruction_combined<CompleteStr, AssemblerInstruction>,
 );
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut ret = vec![];
         match self.opcode {
-            Some(Token::Op { code }) => match code {
-                _ => {
-                    ret.push(code.into());
-                }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field! ");
-                std::process::exit(1);
+            Some(Token::Op { code }) => {
+                ret.push(code.into());
             }
+            _ => return Err(AssemblerError::NonOpcodeInOpcodeField),
         }
 
         // TODO & FIXME: load $0 100 will assume as load $0 None None, and the asembled result will be load $0 0 0, and will be think of valid
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut ret, symbols);
-            }
-        }
-        while ret.len() < 4 {
-            ret.push(0)
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            AssemblerInstruction::extract_operand(token, &mut ret, symbols)?;
         }
+        ret.resize(self.len_in_bytes(), 0);
+        Ok(ret)
+    }
 
-        ret
+    /// How many bytes `to_bytes` turns this instruction into
+    pub fn len_in_bytes(&self) -> usize {
+        let operands: usize = [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+            .map(|token| match token {
+                Token::Register { .. } => 1,
+                Token::IntegerOperand { .. } | Token::LabelUsage { .. } => 2,
+                _ => 0,
+            })
+            .sum();
+        // pad to what the VM consumes, so the next instruction starts where it looks
+        let width = match self.opcode {
+            Some(Token::Op { code }) => code.width(),
+            _ => 4,
+        };
+        (1 + operands).max(width)
     }
 
-    fn extract_operand(token: &Token, ret: &mut Vec<u8>, symbols: &SymbolTable) {
+    fn extract_operand(
+        token: &Token,
+        ret: &mut Vec<u8>,
+        symbols: &SymbolTable,
+    ) -> Result<(), AssemblerError> {
         match token {
             Token::Register { reg_num } => {
                 ret.push(*reg_num);
@@ -97,7 +116,7 @@ impl AssemblerInstruction {
                 ret.push(wtr[1]);
                 ret.push(wtr[0]);
             }
-            Token::LabelUsage { name } => match symbols.symbol_value(&name) {
+            Token::LabelUsage { name } => match symbols.symbol_value(name) {
                 Some(v) => {
                     let mut wtr = vec![];
                     wtr.write_u32::<LittleEndian>(v).unwrap();
@@ -109,21 +128,19 @@ impl AssemblerInstruction {
                 }
             },
             _ => {
-                println!("Opcode found in operand field: {:?}", token);
-                // std::process::exit(1);
+                return Err(AssemblerError::InvalidOperand {
+                    operand: format!("{:?}", token),
+                })
             }
         }
+        Ok(())
     }
 
     pub fn is_label(&self) -> bool {
-        match &self.label {
-            Some(label) => match label {
-                Token::LabelDeclaration { name: _ } => true,
-                Token::LabelUsage { name: _ } => true,
-                _ => false,
-            },
-            None => false,
-        }
+        matches!(
+            &self.label,
+            Some(Token::LabelDeclaration { .. }) | Some(Token::LabelUsage { .. })
+        )
     }
 
     pub fn is_directive(&self) -> bool {
@@ -132,21 +149,15 @@ impl AssemblerInstruction {
 
     pub fn get_label_name(&self) -> Option<String> {
         match &self.label {
-            Some(label) => match label {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 
     pub(crate) fn get_directive_name(&self) -> Option<String> {
         match &self.directive {
-            Some(directive) => match directive {
-                Token::Directive { name } => Some(name.to_string()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::Directive { name }) => Some(name.to_string()),
+            _ => None,
         }
     }
 
@@ -160,21 +171,15 @@ impl AssemblerInstruction {
 
     pub(crate) fn get_string_constant(&self) -> Option<String> {
         match &self.operand1 {
-            Some(token) => match token {
-                Token::IrString { name } => Some(name.to_string()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::IrString { name }) => Some(name.to_string()),
+            _ => None,
         }
     }
 
     pub(crate) fn get_i32_constant(&self) -> Option<i32> {
         match &self.operand1 {
-            Some(token) => match token {
-                Token::IntegerOperand { value } => Some(value.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::IntegerOperand { value }) => Some(*value),
+            _ => None,
         }
     }
 }
@@ -207,10 +212,11 @@ mod tests {
     #[test]
     fn test_parse_instruction_from_two() {
         let result = instruction_combined("hlt\n".into());
+        // the line break goes with it, so the next instruction can be parsed
         assert_eq!(
             result,
             Ok((
-                CompleteStr("\n"),
+                CompleteStr(""),
                 AssemblerInstruction {
                     opcode: Some(Token::Op { code: Opcode::HLT }),
                     operand1: None,
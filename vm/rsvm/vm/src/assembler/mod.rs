@@ -24,8 +24,15 @@ pub mod symbol;
 
 /// Magic number that begins every bytecode file prefix. These spell out EPIE in ASCII, if you were wondering.
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-/// Constant that determines how long the header is. There are 60 zeros left after the prefix, for later usage if needed.
+/// Constant that determines how long the header is. After the prefix come where the program starts, counting from
+/// the end of the header, how long the read-only data is, and then a byte for the format version. That data follows
+/// the header, and the code follows it. The rest is zeros, left for later usage if needed.
 pub const PIE_HEADER_LENGTH: usize = 64;
+/// Which layout the header describes, in the byte after the read-only data length. Images from before there was a
+/// version have a 0 there, and their code follows the header directly.
+pub const PIE_FORMAT_VERSION: u8 = 1;
+/// Where the format version is in the header
+pub const PIE_VERSION_OFFSET: usize = 12;
 
 #[derive(PartialEq, Debug)]
 pub enum Token {
@@ -40,16 +47,22 @@ pub enum Token {
     Comment,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum AssemblerPhase {
+    #[default]
     First,
     Second,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum AssemblerSection {
-    Data { startting_instruction: Option<u32> },
-    Code { startting_instruction: Option<u32> },
+    Data {
+        startting_instruction: Option<u32>,
+    },
+    Code {
+        startting_instruction: Option<u32>,
+    },
+    #[default]
     Unknown,
 }
 
@@ -65,6 +78,10 @@ pub struct Assembler {
 
     /// Tracks current offset of RO secton
     ro_offset: u32,
+    /// Tracks where the next instruction lands in the program, which is the address of its label
+    code_offset: u32,
+    /// The label given to `.entry`, where the program starts instead of its first instruction
+    entry: Option<String>,
 
     sections: Vec<AssemblerSection>,
     current_section: Option<AssemblerSection>,
@@ -76,6 +93,12 @@ pub struct Assembler {
     // buf: [u8; 4],
 }
 
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Assembler {
     pub fn new() -> Assembler {
         Assembler {
@@ -84,6 +107,8 @@ impl Assembler {
             ro: vec![],
             bytecode: vec![],
             ro_offset: 0,
+            code_offset: PIE_HEADER_LENGTH as u32,
+            entry: None,
             sections: vec![],
             current_section: None,
             current_instructon: 0,
@@ -94,7 +119,15 @@ impl Assembler {
 
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
         // pass to parser
-        match program(CompleteStr(&raw)) {
+        match program(CompleteStr(raw)) {
+            // a line the parser stops at would otherwise be dropped along with the rest
+            Ok((remainder, _)) if !remainder.trim().is_empty() => {
+                let line = remainder.trim_start().lines().next().unwrap_or_default();
+                self.errors.push(AssemblerError::ParseError {
+                    error: format!("unable to parse from: {}", line),
+                });
+                Err(self.errors.clone())
+            }
             Ok((_remainder, program)) => {
                 // 1
                 self.process_first_phase(&program);
@@ -103,27 +136,40 @@ impl Assembler {
                     return Err(self.errors.clone());
                 }
 
+                if let Some(name) = &self.entry {
+                    if self.symbols.symbol_value(name).is_none() {
+                        self.errors
+                            .push(AssemblerError::EntryLabelNotFound { name: name.clone() });
+                        return Err(self.errors.clone());
+                    }
+                }
+
                 if self.sections.len() != 2 {
-                    println!("Required at least 2 sections.");
                     self.errors.push(AssemblerError::InsufficientSections);
                     return Err(self.errors.clone());
                 }
+                // the code goes after the read-only data
+                self.symbols.relocate_labels(self.ro.len() as u32);
                 // 2
-                let mut body = self.process_second_phase(&program);
+                let mut body = match self.process_second_phase(&program) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        self.errors.push(e);
+                        return Err(self.errors.clone());
+                    }
+                };
 
                 // header
                 let mut assembled_program = self.write_pie_header();
 
+                assembled_program.extend_from_slice(&self.ro);
                 assembled_program.append(&mut body);
                 Ok(assembled_program)
             }
 
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError {
-                    error: e.to_string(),
-                }])
-            }
+            Err(e) => Err(vec![AssemblerError::ParseError {
+                error: e.to_string(),
+            }]),
         }
     }
 
@@ -141,7 +187,7 @@ impl Assembler {
         for i in &p.instructions {
             if i.is_label() {
                 if self.current_section.is_some() {
-                    self.process_label_declaration(&i);
+                    self.process_label_declaration(i);
                 } else {
                     self.errors.push(AssemblerError::NoSegmentDeclarationFound {
                         instruction: self.current_instructon,
@@ -153,42 +199,50 @@ impl Assembler {
                 self.process_directive(i);
             }
 
+            if i.is_opcode() {
+                self.code_offset += i.len_in_bytes() as u32;
+            }
             self.current_instructon += 1;
         }
 
         self.phase = AssemblerPhase::Second;
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    fn process_second_phase(&mut self, p: &Program) -> Result<Vec<u8>, AssemblerError> {
         // reset
         self.current_instructon = 0;
         let mut program = vec![];
         for i in &p.instructions {
             if i.is_opcode() {
-                let mut bytes = i.to_bytes(&self.symbols);
+                let mut bytes = i.to_bytes(&self.symbols)?;
                 program.append(&mut bytes);
             }
             if i.is_directive() {
                 self.process_directive(i);
             }
             self.current_instructon += 1;
-
-            // println!("{:?}", i);
-            // println!("{:?}", program.to_ascii_lowercase());
-            // println!("The new length: {}", program.len());
         }
-        program
+        Ok(program)
     }
 
     fn write_pie_header(&self) -> Vec<u8> {
         let mut header = vec![];
-        PIE_HEADER_PREFIX
-            .iter()
-            .for_each(|b| header.push(b.clone()));
-
-        // directly write the start point after the header 4 bytes
+        PIE_HEADER_PREFIX.iter().for_each(|b| header.push(*b));
+
+        // directly write the start point after the header 4 bytes, which is the first
+        // instruction after the read-only data unless there's an `.entry`
+        let entry = match &self.entry {
+            Some(name) => self
+                .symbols
+                .symbol_value(name)
+                .unwrap_or_default()
+                .saturating_sub(PIE_HEADER_LENGTH as u32),
+            None => self.ro.len() as u32,
+        };
         let mut wtr: Vec<u8> = vec![];
+        wtr.write_u32::<LittleEndian>(entry).unwrap();
         wtr.write_u32::<LittleEndian>(self.ro.len() as u32).unwrap();
+        wtr.push(PIE_FORMAT_VERSION);
         header.append(&mut wtr);
 
         while header.len() < PIE_HEADER_LENGTH {
@@ -215,31 +269,23 @@ impl Assembler {
             return;
         }
 
-        let symbol = Symbol::new(name.to_string(), SymbolType::Label);
+        // constants get their offset into the RO section once the directive is handled
+        let symbol = match i.get_directive_name().as_deref() {
+            Some("asciiz") => Symbol::new(name, SymbolType::IrString),
+            Some("integer") => Symbol::new(name, SymbolType::Integer),
+            _ => Symbol::new_with_offset(name, SymbolType::Label, self.code_offset),
+        };
         self.symbols.add_symbol(symbol);
     }
 
-    fn extract_labels(&mut self, p: &Program) {
-        let mut c = 0;
-        for i in &p.instructions {
-            if i.is_label() {
-                match i.get_label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new_with_offset(name, SymbolType::Label, c);
-                        self.symbols.add_symbol(symbol);
-                    }
-                    None => {}
-                }
-            }
-            c += 4;
-        }
-    }
     fn process_directive(&mut self, i: &AssemblerInstruction) {
         // First let’s make sure we have a parseable name
         let directive_name = match i.get_directive_name() {
             Some(name) => name,
             None => {
-                println!("Directive has an invalid name: {:?}", i);
+                self.errors.push(AssemblerError::ParseError {
+                    error: format!("directive without a name: {:?}", i),
+                });
                 return;
             }
         };
@@ -255,11 +301,16 @@ impl Assembler {
                 "integer" => {
                     self.handle_directive_integer(i);
                 }
+                "entry" => match &i.operand1 {
+                    Some(Token::LabelUsage { name }) => self.entry = Some(name.clone()),
+                    _ => self.errors.push(AssemblerError::UnknownDirectiveFound {
+                        directive: directive_name.clone(),
+                    }),
+                },
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound {
                         directive: directive_name.clone(),
                     });
-                    return;
                 }
             }
         } else {
@@ -271,10 +322,9 @@ impl Assembler {
     fn process_section_header(&mut self, header_name: &str) {
         let new_section: AssemblerSection = header_name.into();
         if new_section == AssemblerSection::Unknown {
-            println!(
-                "Found an section header that is unknown: {:#?}",
-                header_name
-            );
+            self.errors.push(AssemblerError::UnknownDirectiveFound {
+                directive: header_name.to_string(),
+            });
             return;
         }
         self.sections.push(new_section.clone());
@@ -295,7 +345,10 @@ impl Assembler {
                         // Needing a label
                         // This would be someone typing:
                         // .asciiz 'Hello'
-                        println!("Found a string constant with no associated label!");
+                        self.errors
+                            .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                                instruction: self.current_instructon,
+                            });
                         return;
                     }
                 };
@@ -307,9 +360,9 @@ impl Assembler {
                 self.ro.push(0);
                 self.ro_offset += 1;
             }
-            None => {
-                println!("String constant following an .asciiz was empty");
-            }
+            None => self.errors.push(AssemblerError::InvalidOperand {
+                operand: format!("{:?}", i.operand1),
+            }),
         }
     }
 
@@ -327,7 +380,10 @@ impl Assembler {
                         // Needing a label
                         // This would be someone typing:
                         // .integer #100
-                        println!("Found a ingeger constant with no associated label!");
+                        self.errors
+                            .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                                instruction: self.current_instructon,
+                            });
                         return;
                     }
                 };
@@ -341,25 +397,13 @@ impl Assembler {
                 self.ro.push(0);
                 self.ro_offset += 1;
             }
-            None => {
-                println!("Integer constant following an .integer was empty");
-            }
+            None => self.errors.push(AssemblerError::InvalidOperand {
+                operand: format!("{:?}", i.operand1),
+            }),
         }
     }
 }
 
-impl Default for AssemblerPhase {
-    fn default() -> Self {
-        AssemblerPhase::First
-    }
-}
-
-impl Default for AssemblerSection {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 impl<'a> From<&'a str> for AssemblerSection {
     fn from(value: &'a str) -> Self {
         match value {
@@ -378,6 +422,7 @@ impl<'a> From<&'a str> for AssemblerSection {
 mod tests {
     use super::*;
 
+    use crate::output::Output;
     use crate::vm::VM;
 
     #[test]
@@ -396,8 +441,9 @@ mod tests {
         hlt
         ";
         let program = asm.assemble(test_string).unwrap();
-        // just the PIE_HEADER_PREFIX.len() and instructions without the directive ".*" 4 + 7 * 4
-        let len_should_be = 4 * 7 + VM::get_header_offset();
+        // the header, then each instruction as wide as the VM reads it:
+        // 5 * 4, the jeq's opcode and label, and the hlt
+        let len_should_be = 4 * 5 + 3 + 1 + VM::get_header_offset();
         assert_eq!(program.len(), len_should_be);
 
         let mut vm = VM::new();
@@ -423,7 +469,42 @@ mod tests {
         ";
         let program = asm.assemble(test_string);
         assert!(program.is_ok());
-        assert_eq!(program.unwrap()[PIE_HEADER_PREFIX.len()], 6);
+        let program = program.unwrap();
+        assert_eq!(program[PIE_HEADER_PREFIX.len()], 6);
+        // followed by how long the read-only data is, and the data itself
+        assert_eq!(program[PIE_HEADER_PREFIX.len() + 4], 6);
+        assert_eq!(program[PIE_VERSION_OFFSET], PIE_FORMAT_VERSION);
+        assert_eq!(
+            &program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 6],
+            b"Hello\0"
+        );
+    }
+
+    #[test]
+    /// Strings in the read-only data can be printed, and jumps land past them
+    fn test_run_with_ro_data() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        hello: .asciiz 'Hello '
+        bye: .asciiz 'Bye'
+        .code
+        .entry @start
+        skipped: prts @bye
+        start: prts @hello
+        load $0 @end
+        jmp $0
+        prts @bye
+        end: prts @bye
+        hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        let (output, capture) = Output::capture();
+        vm.set_output(output);
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(capture.take(), "Hello Bye");
     }
 
     #[test]
@@ -441,6 +522,65 @@ mod tests {
         assert_eq!(v.is_some(), false);
     }
 
+    #[test]
+    /// Instructions are as wide as the VM reads them, even without operands mid program
+    fn test_assemble_widths() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\nnop\nload $0 @end\njmp $0\nend: hlt\n";
+        let program = asm.assemble(test_string).unwrap();
+        let header = VM::get_header_offset();
+        assert_eq!(program.len(), header + 4 + 4 + 2 + 1);
+        assert_eq!(asm.symbols.symbol_value("end"), Some(header as u32 + 10));
+
+        let mut asm = Assembler::new();
+        assert!(matches!(
+            asm.assemble(".data\n.code\nhlt\n% nonsense\n").unwrap_err()[..],
+            [AssemblerError::ParseError { .. }]
+        ));
+    }
+
+    #[test]
+    /// The header points at the `.entry` label, where the VM starts running
+    fn test_entry_directive() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\n.entry @start\nload $0 #1\nstart: load $0 #2\nhlt\n";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program[PIE_HEADER_PREFIX.len()], 4);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers[0], 2);
+
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble(".data\n.code\n.entry @nowhere\nhlt\n")
+            .unwrap_err();
+        assert!(matches!(
+            &errors[..],
+            [AssemblerError::EntryLabelNotFound { name }] if name == "nowhere"
+        ));
+    }
+
+    #[test]
+    /// Labels point at the address of their instruction in the assembled program
+    fn test_label_offsets() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        hello: .asciiz 'Hi'
+        .code
+        load $0 #100
+        test: inc $0
+        other: hlt
+        ";
+        assert!(asm.assemble(test_string).is_ok());
+        // the code comes after the 3 bytes of 'Hi'
+        let code = PIE_HEADER_LENGTH as u32 + 3;
+        assert_eq!(asm.symbols.symbol_value("test"), Some(code + 4));
+        assert_eq!(asm.symbols.symbol_value("other"), Some(code + 8));
+        assert_eq!(asm.symbols.symbol_value("hello"), Some(0));
+    }
+
     #[test]
     /// Simple test of data that goes into the read only section
     fn test_ro_data_asciiz() {
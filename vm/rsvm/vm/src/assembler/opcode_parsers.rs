@@ -5,10 +5,13 @@ use nom::types::CompleteStr;
 use super::Token;
 
 named!(pub opcode < CompleteStr, Token> ,
-    do_parse!(
-        opcode: alpha1 >>
-        (
-            Token::Op{code: Opcode::from(opcode)}
+    // instructions without operands leave the line break before the next opcode
+    ws!(
+        do_parse!(
+            opcode: alpha1 >>
+            (
+                Token::Op{code: Opcode::from(opcode)}
+            )
         )
     )
 );
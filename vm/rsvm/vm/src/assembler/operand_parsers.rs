@@ -25,13 +25,23 @@ named!(integer_operand < CompleteStr, Token>,
     )
 );
 
+// `\n`, `\t`, `\'` and `\\` inside the quotes stand for what they escape
 named!(irstring<CompleteStr,Token>,
 do_parse!(
     tag!("'")>>
-    content: take_until!("'")>>
+    content: opt!(escaped_transform!(
+        is_not!("\\'"),
+        '\\',
+        alt!(
+            tag!("n") => { |_| "\n" } |
+            tag!("t") => { |_| "\t" } |
+            tag!("'") => { |_| "'" } |
+            tag!("\\") => { |_| "\\" }
+        )
+    ))>>
     tag!("'")>>
     (
-        Token::IrString{name:content.to_string()}
+        Token::IrString{name:content.unwrap_or_default()}
     )
 
 ));
@@ -65,4 +75,25 @@ mod tests {
             }
         )
     }
+
+    #[test]
+    fn test_parse_escaped_string_operand() {
+        let result = irstring(CompleteStr(r"'It\'s\n\tdone \\o/' rest"));
+        assert_eq!(
+            result.unwrap(),
+            (
+                CompleteStr(" rest"),
+                Token::IrString {
+                    name: "It's\n\tdone \\o/".to_string()
+                }
+            )
+        );
+        assert!(irstring(CompleteStr("'unterminated")).is_err());
+        assert_eq!(
+            irstring(CompleteStr("''")).unwrap().1,
+            Token::IrString {
+                name: String::new()
+            }
+        );
+    }
 }
@@ -1,6 +1,7 @@
 use nom::types::CompleteStr;
 
 use super::{
+    assembler_error::AssemblerError,
     instruction_parser::{instruction, AssemblerInstruction},
     symbol::SymbolTable,
 };
@@ -21,12 +22,12 @@ named!(pub program<CompleteStr, Program>,
 );
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
         for inst in &self.instructions {
-            program.append(&mut inst.to_bytes(symbols));
+            program.append(&mut inst.to_bytes(symbols)?);
         }
-        program
+        Ok(program)
     }
 }
 
@@ -55,9 +56,8 @@ mod tests {
         assert!(result.is_ok());
         let (_, p) = result.unwrap();
         let symbols = SymbolTable::new();
-        let bytecodes = p.to_bytes(&symbols);
+        let bytecodes = p.to_bytes(&symbols).unwrap();
         assert_eq!(bytecodes.len(), 4);
-        println!("{:?}", bytecodes);
     }
 
     #[test]
@@ -27,7 +27,7 @@ mod tests {
         assert!(result.is_ok());
 
         match result.unwrap().1 {
-            Token::Register { reg_num } => assert_eq!(reg_num, 0.into()),
+            Token::Register { reg_num } => assert_eq!(reg_num, 0),
             _ => assert!(false),
         }
 
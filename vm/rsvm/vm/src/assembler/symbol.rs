@@ -1,4 +1,4 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SymbolType {
     Label,
     Integer,
@@ -33,6 +33,24 @@ impl Symbol {
             offset: Some(offset),
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SymbolTable {
@@ -44,6 +62,15 @@ impl SymbolTable {
         self.symbols.push(s)
     }
 
+    /// Moves the code labels along, past what's placed before the code
+    pub fn relocate_labels(&mut self, by: u32) {
+        for symbol in &mut self.symbols {
+            if symbol.symbol_type == SymbolType::Label {
+                symbol.offset = symbol.offset.map(|offset| offset + by);
+            }
+        }
+    }
+
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == s {
@@ -1,97 +1,222 @@
-use std::sync::Arc;
-use std::{fs::File, io::Read, path::Path};
+use std::{
+    env,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-use clap::{load_yaml, App};
+use clap::{load_yaml, App, ArgMatches};
 use log::info;
+use vm::config::{self, Config, ConfigError};
+use vm::remote::auth::{self, Auth};
+use vm::ssh::{self, SshConfig};
+use vm::trace::{Profiler, Tracer};
 use vm::vm::VM;
 use vm::{assembler, remote, repl};
 
+/// Command line flags that override a config setting: (argument, flag, key)
+const CONFIG_FLAGS: &[(&str, &str, &str)] = &[
+    ("THREADS", "--threads", "threads"),
+    ("LISTEN_HOST", "--bind-host", "bind_host"),
+    ("HISTORY_FILE", "--history-file", "history_file"),
+    (
+        "ENABLE_REMOTE_ACCESS",
+        "--enable-remote-access",
+        "remote.enabled",
+    ),
+    ("LISTEN_PORT", "--remote-port", "remote.port"),
+    ("REMOTE_TOKEN", "--remote-token", "remote.token"),
+    (
+        "REMOTE_PASSWORD_FILE",
+        "--remote-password-file",
+        "remote.password_file",
+    ),
+    (
+        "MAX_CONNECTIONS",
+        "--max-connections",
+        "remote.max_connections",
+    ),
+    ("ENABLE_SSH", "--enable-ssh", "ssh.enabled"),
+    ("SSH_PORT", "--ssh-port", "ssh.port"),
+    ("SSH_DIR", "--ssh-dir", "ssh.dir"),
+    (
+        "SSH_MAX_CONNECTIONS",
+        "--ssh-max-connections",
+        "ssh.max_connections",
+    ),
+    ("MAX_HEAP", "--max-heap", "limits.max_heap_bytes"),
+    (
+        "MAX_INSTRUCTIONS",
+        "--max-instructions",
+        "limits.max_instructions",
+    ),
+    ("MAX_STACK", "--max-stack", "limits.max_stack_depth"),
+    ("MAX_OUTPUT", "--max-output", "limits.max_output_bytes"),
+    ("CLUSTER_BIND", "--cluster-bind", "cluster.bind"),
+    ("SEED", "--seed", "cluster.seeds"),
+    ("CLUSTER_SECRET", "--cluster-secret", "cluster.secret"),
+    ("CLUSTER_MAX_JOBS", "--cluster-max-jobs", "cluster.max_jobs"),
+    ("INCLUDE", "--include", "include_paths"),
+    ("SNAPSHOT_DIR", "--snapshot-dir", "snapshot_dir"),
+];
+
 fn main() {
     env_logger::init();
     info!("Starting logging!");
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
+    let config = load_config(&matches);
 
-    let num_threads = match matches.value_of("THREADS") {
-        Some(num) => match num.parse::<usize>() {
-            Ok(v) => v,
-            Err(_e) => {
-                let default = num_cpus::get();
-                println!(
-                    "Invalid argmument for number of threads: {}. using default dtected: {}.",
-                    num, default
-                );
-                default
-            }
-        },
-        None => num_cpus::get(),
+    let num_threads = config.threads.unwrap_or_else(num_cpus::get);
+    let limits = config.limits;
+    let ssh_config = match &config.ssh.dir {
+        Some(dir) => SshConfig::in_dir(dir),
+        None => SshConfig::default(),
     };
 
-    if matches.is_present("add-ssh-key") {
-        println!("User tried to add SSH key!");
+    if let Some(sub) = matches.subcommand_matches("add-ssh-keys") {
+        let pub_key_file = sub.value_of("PUB_KEY_FILE").unwrap();
+        add_ssh_keys(&ssh_config, pub_key_file);
         std::process::exit(0);
     }
-    if matches.is_present("ENABLE_REMOTE_ACCESS") {
-        let port = matches.value_of("LISTEN_PORT").unwrap_or("2223");
-        let host = matches.value_of("LISTEN_HOST").unwrap_or("127.0.0.1");
-        start_remote_server(host.to_string(), port.to_string());
+    if let Some(sub) = matches.subcommand_matches("add-remote-user") {
+        let user = sub.value_of("USER").unwrap();
+        add_remote_user(config.remote.password_file.as_deref(), user);
+        std::process::exit(0);
     }
 
-    if matches.is_present("ENABLE_SSH") {
-        println!("User wants to enable SSH!");
-        if matches.is_present("SSH_PORT") {
-            let port = match matches.value_of("ssh_port") {
-                Some(p) => match p.parse::<u32>() {
-                    Ok(port) => port,
-                    Err(_) => {
-                        println!("Parse failed of input {}, use default...", p);
-                        2223
-                    }
-                },
-                None => 2223,
-            };
-            println!("They'd like to use port {:#?}", port);
-            start_ssh_server(port)
-        }
+    let host = config.bind_host.clone();
+    if config.remote.enabled {
+        let port = config.remote.port.to_string();
+        let mut server = remote::server::Server::new(host.clone(), port.clone());
+        server.limits = limits;
+        server.auth = match (&config.remote.token, &config.remote.password_file) {
+            (Some(token), _) => Auth::Token(token.clone()),
+            (None, Some(path)) => Auth::PasswordFile(path.clone()),
+            (None, None) => Auth::LocalOnly,
+        };
+        server.max_connections = config.remote.max_connections;
+        server.snapshot_dir = config.snapshot_dir.clone();
+        println!("Listening on {}:{}", host, port);
+        start_remote_server(server);
+    }
+
+    if config.ssh.enabled {
+        let port = config.ssh.port.to_string();
+        let mut server = ssh::Server::new(host.clone(), port.clone(), ssh_config);
+        server.limits = limits;
+        server.snapshot_dir = config.snapshot_dir.clone();
+        server.max_connections = config.ssh.max_connections;
+        println!("SSH listening on {}:{}", host, port);
+        start_ssh_server(server);
     }
 
     let target_file = matches.value_of("INPUT_FILE");
 
     match target_file {
         Some(filename) => {
-            let program = read_file(filename);
+            let filename = config::find_file(Path::new(filename), &config.include_paths);
+            let program = read_file(&filename);
             let mut asm = assembler::Assembler::new();
             let mut vm = VM::new();
             vm.logical_cores = num_threads;
+            vm.set_limits(limits);
 
             let program = asm.assemble(&program);
-            match program {
-                Ok(p) => {
-                    vm.add_bytes(p);
-                    let events = vm.run();
-                    println!("VM Events...");
-                    println!("-----------------------------------------");
-                    for ev in &events {
-                        println!("{:#?}", ev);
+            if let Ok(p) = program {
+                vm.add_bytes(p);
+                if let Some(path) = matches.value_of("TRACE_FILE") {
+                    match Tracer::to_file(path) {
+                        Ok(tracer) => vm.set_tracer(Some(tracer)),
+                        Err(e) => println!("Unable to open trace file {}: {}", path, e),
                     }
-                    std::process::exit(0);
                 }
-                Err(_) => {}
+                if matches.is_present("PROFILE") {
+                    vm.set_profiler(Some(Profiler::with_symbols(&asm.symbols)));
+                }
+                let events = vm.run();
+                println!("VM Events...");
+                println!("-----------------------------------------");
+                for ev in &events {
+                    println!("{}", serde_json::to_string(ev).unwrap());
+                }
+                std::process::exit(0);
             }
         }
         None => {
-            start_repl();
+            let history_file = config
+                .history_file
+                .clone()
+                .unwrap_or_else(repl::default_history_file);
+            start_repl(&config, &history_file);
+        }
+    }
+}
+
+/// The config file, overridden by `IRIDIUM_*` variables and then by flags.
+/// Exits listing every problem if the result isn't valid.
+fn load_config(matches: &ArgMatches) -> Config {
+    let path = matches
+        .value_of_os("CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os(config::CONFIG_ENV).map(PathBuf::from));
+    let mut config = match Config::find(path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut errors = config.apply_env(env::vars());
+    for (arg, flag, key) in CONFIG_FLAGS {
+        let value = match matches.values_of(arg) {
+            Some(values) if *arg == "INCLUDE" => match env::join_paths(values) {
+                Ok(paths) => paths.to_string_lossy().into_owned(),
+                Err(e) => {
+                    errors.push(ConfigError::BadValue {
+                        origin: flag.to_string(),
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            },
+            Some(values) => values.collect::<Vec<_>>().join(","),
+            // flags that take no value
+            None if matches.is_present(arg) => "true".to_string(),
+            None => continue,
+        };
+        if let Err(reason) = config.set(key, &value) {
+            errors.push(ConfigError::BadValue {
+                origin: flag.to_string(),
+                reason,
+            });
         }
     }
+    errors.extend(config.validate());
+    if !errors.is_empty() {
+        for error in errors {
+            println!("{}", error);
+        }
+        std::process::exit(1);
+    }
+    config
 }
 
-fn start_repl() {
+fn start_repl(config: &Config, history_file: &Path) {
     let mut repl = repl::REPL::new();
-    repl.run();
+    repl.set_limits(config.limits);
+    repl.set_include_paths(config.include_paths.clone());
+    if let Some(cluster) = config.cluster_config() {
+        let bind = cluster.bind.clone();
+        if let Err(e) = repl.join_cluster(cluster) {
+            println!("Unable to listen for cluster nodes on {}: {}", bind, e);
+            std::process::exit(1);
+        }
+    }
+    repl.run_interactive(Some(history_file));
 }
-fn read_file(tmp: &str) -> String {
-    let filename = Path::new(&tmp);
-    match File::open(&filename) {
+fn read_file<P: AsRef<Path>>(filename: P) -> String {
+    match File::open(filename) {
         Ok(mut file) => {
             let mut contents = String::new();
             match file.read_to_string(&mut contents) {
@@ -109,23 +234,62 @@ fn read_file(tmp: &str) -> String {
     }
 }
 
-fn start_ssh_server(port: u32) {
+fn add_ssh_keys(config: &SshConfig, pub_key_file: &str) {
+    let contents = read_file(pub_key_file);
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        match ssh::keys::add_authorized_key(&config.authorized_keys, line) {
+            Ok(fingerprint) => println!(
+                "Authorized {} in {}",
+                fingerprint,
+                config.authorized_keys.display()
+            ),
+            Err(e) => {
+                println!("Unable to add key from {}: {}", pub_key_file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn start_ssh_server(mut server: ssh::Server) {
     let _t = std::thread::spawn(move || {
-        println!("TODO...");
-        unimplemented!();
-        // let mut config = thrussh::server::Config::default();
-        // config.connection_timeout = Some(std::time::Duration::from_secs(500));
-        // config.auth_rejection_time = std::time::Duration::from_secs(3);
-        // let config = Arc::new(config);
-        // let sh = vm::ssh::Server {};
-        // thrussh::server::run(config, format!("127.0.0.1:{}", port).as_str(), sh);
+        if let Err(e) = server.listen() {
+            println!("SSH server stopped: {}", e);
+        }
     });
 }
 
-fn start_remote_server(host: String, port: String) {
-    println!("Listening on {}:{}", host, port);
+fn start_remote_server(mut server: remote::server::Server) {
     let _t = std::thread::spawn(move || {
-        let mut sh = remote::server::Server::new(host, port);
-        sh.listen();
+        if let Err(e) = server.listen() {
+            println!("Unable to start remote server: {}", e);
+        }
     });
 }
+
+fn add_remote_user(password_file: Option<&Path>, user: &str) {
+    let password_file = match password_file {
+        Some(path) => path,
+        None => {
+            println!("Give the password file to add the user to with --remote-password-file or remote.password_file");
+            std::process::exit(1);
+        }
+    };
+    let mut password = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut password) {
+        println!("Unable to read password: {}", e);
+        std::process::exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        println!("Password can't be empty");
+        std::process::exit(1);
+    }
+    match auth::add_user(password_file, user, password) {
+        Ok(()) => println!("Added {} to {}", user, password_file.display()),
+        Err(e) => {
+            println!("Unable to add {}: {}", user, e);
+            std::process::exit(1);
+        }
+    }
+}
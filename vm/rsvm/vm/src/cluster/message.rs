@@ -0,0 +1,76 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::vm::{VMEvent, VMLimits};
+
+/// What nodes say to each other, one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Message {
+    /// The first message on every connection, sent by both ends.
+    /// `addr` is where the sender listens for other nodes.
+    Hello {
+        node_id: Uuid,
+        addr: SocketAddr,
+        /// Random bytes for the other end to prove it knows the cluster secret with
+        nonce: Vec<u8>,
+    },
+    /// The answer to the other end's `Hello` when the cluster has a secret: the
+    /// HMAC-SHA256 of its nonce and the sender's id, keyed by the secret
+    Auth {
+        proof: Vec<u8>,
+    },
+    Heartbeat,
+    /// Where the other nodes the sender knows listen, so the receiver can join them too
+    Peers {
+        addrs: Vec<SocketAddr>,
+    },
+    /// Run a PIE program, sending its events back tagged with `job`
+    Spawn {
+        job: u64,
+        program: Vec<u8>,
+        limits: VMLimits,
+    },
+    Event {
+        job: u64,
+        event: VMEvent,
+    },
+}
+
+impl Message {
+    /// The message as a line to send
+    pub fn encode(&self) -> Vec<u8> {
+        let mut line = serde_json::to_vec(self).expect("messages always serialize");
+        line.push(b'\n');
+        line
+    }
+
+    pub fn decode(line: &str) -> Result<Message, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let message = Message::Spawn {
+            job: 3,
+            program: vec![45, 50, 49, 45],
+            limits: VMLimits {
+                max_instructions: Some(100),
+                ..VMLimits::default()
+            },
+        };
+        let line = message.encode();
+        assert_eq!(line.last(), Some(&b'\n'));
+        let line = std::str::from_utf8(&line).unwrap();
+        assert!(line.starts_with(r#"{"type":"Spawn","job":3,"#));
+        assert_eq!(Message::decode(line.trim_end()).unwrap(), message);
+        assert!(Message::decode(r#"{"type":"Shout"}"#).is_err());
+    }
+}
@@ -0,0 +1,800 @@
+pub mod message;
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use self::message::Message;
+use crate::remote::auth::constant_time_eq;
+use crate::vm::{VMError, VMEvent, VMEventType, VMLimits, VM};
+
+/// How a node joins the cluster. Every node runs whatever its peers send it, so
+/// set a secret to keep out nodes that don't know it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterConfig {
+    /// Where to listen for other nodes, like `127.0.0.1:2224`
+    pub bind: String,
+    /// Nodes to connect to at startup, and again whenever they drop out
+    pub seeds: Vec<SocketAddr>,
+    pub heartbeat_interval: Duration,
+    /// Peers that weren't heard from for this long are dropped
+    pub heartbeat_timeout: Duration,
+    /// Shared by every node. Peers that can't prove they know it aren't let in.
+    pub secret: Option<String>,
+    /// Applied to programs peers run here. They can ask for lower limits, not higher.
+    pub limits: VMLimits,
+    /// Programs peers may run here at once. Any more crash straight away.
+    pub max_jobs: Option<usize>,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            bind: "127.0.0.1:2224".to_string(),
+            seeds: vec![],
+            heartbeat_interval: Duration::from_secs(1),
+            heartbeat_timeout: Duration::from_secs(5),
+            secret: None,
+            limits: VMLimits::default(),
+            max_jobs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClusterError {
+    /// No peer's id starts with this
+    UnknownNode(String),
+    /// More than one peer's id starts with this
+    AmbiguousNode(String),
+    SendFailed(String),
+}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterError::UnknownNode(id) => write!(f, "No node {}", id),
+            ClusterError::AmbiguousNode(id) => {
+                write!(
+                    f,
+                    "More than one node starts with {}, give more of the id",
+                    id
+                )
+            }
+            ClusterError::SendFailed(reason) => write!(f, "Unable to reach node: {}", reason),
+        }
+    }
+}
+
+impl Error for ClusterError {}
+
+/// A peer as listed by `!nodes`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub id: Uuid,
+    pub addr: SocketAddr,
+    /// Time since the last message from it
+    pub last_seen: Duration,
+}
+
+/// One TCP connection to a peer, in either direction
+struct Connection {
+    id: u64,
+    stream: Mutex<TcpStream>,
+    /// Whether this node dialed it
+    outbound: bool,
+}
+
+impl Connection {
+    fn send(&self, message: &Message) -> io::Result<()> {
+        self.stream.lock().unwrap().write_all(&message.encode())
+    }
+
+    fn close(&self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+struct Peer {
+    addr: SocketAddr,
+    connection: Arc<Connection>,
+    last_seen: Instant,
+}
+
+/// A program shipped to a peer, waiting for its events
+struct Job {
+    /// The connection its events come back on, as they can't once it drops
+    connection: u64,
+    on_event: Arc<dyn Fn(&VMEvent) + Send + Sync>,
+}
+
+/// State shared between the accept loop, the heartbeat thread and one thread per connection
+struct Shared {
+    id: Uuid,
+    addr: SocketAddr,
+    config: ClusterConfig,
+    peers: Mutex<BTreeMap<Uuid, Peer>>,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_connection: AtomicU64,
+    next_job: AtomicU64,
+    /// Programs running here for peers
+    running_jobs: AtomicUsize,
+    shutting_down: AtomicBool,
+}
+
+/// Counts as one of `running_jobs` until dropped
+struct JobSlot(Arc<Shared>);
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        self.0.running_jobs.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// This VM's place in a mesh of Iridium nodes. Connects to the seeds and to every node
+/// they know, keeps the connections alive with heartbeats, and runs programs for peers.
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.shared.id)
+            .field("addr", &self.shared.addr)
+            .finish()
+    }
+}
+
+impl Node {
+    /// Starts listening on `config.bind` as node `id`, then joins the seeds in the background
+    pub fn start(id: Uuid, config: ClusterConfig) -> io::Result<Node> {
+        let listener = TcpListener::bind(&config.bind)?;
+        let mut addr = listener.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let shared = Arc::new(Shared {
+            id,
+            addr,
+            config,
+            peers: Mutex::new(BTreeMap::new()),
+            jobs: Mutex::new(BTreeMap::new()),
+            next_connection: AtomicU64::new(0),
+            next_job: AtomicU64::new(1),
+            running_jobs: AtomicUsize::new(0),
+            shutting_down: AtomicBool::new(false),
+        });
+
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let shared = accepting.clone();
+                        thread::spawn(move || run_connection(&shared, stream, false));
+                    }
+                    Err(e) => error!("Unable to accept node connection: {}", e),
+                }
+            }
+        });
+
+        let beating = shared.clone();
+        thread::spawn(move || {
+            while !beating.shutting_down.load(Ordering::SeqCst) {
+                heartbeat(&beating);
+                thread::sleep(beating.config.heartbeat_interval);
+            }
+        });
+        info!("Node {} listening on {}", id, addr);
+        Ok(Node { shared })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.shared.id
+    }
+
+    /// Where this node listens for others
+    pub fn addr(&self) -> SocketAddr {
+        self.shared.addr
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.shared
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, peer)| PeerInfo {
+                id: *id,
+                addr: peer.addr,
+                last_seen: peer.last_seen.elapsed(),
+            })
+            .collect()
+    }
+
+    /// The peer whose id starts with `prefix`, as ids are long to type
+    pub fn find_peer(&self, prefix: &str) -> Result<Uuid, ClusterError> {
+        let peers = self.shared.peers.lock().unwrap();
+        let mut matches = peers.keys().filter(|id| id.to_string().starts_with(prefix));
+        match (matches.next(), matches.next()) {
+            (Some(id), None) => Ok(*id),
+            (Some(_), Some(_)) => Err(ClusterError::AmbiguousNode(prefix.to_string())),
+            (None, _) => Err(ClusterError::UnknownNode(prefix.to_string())),
+        }
+    }
+
+    /// Ships a PIE program to `node` to run on a new VM there. Its events are passed to
+    /// `on_event` as they come back, on a thread of this node, ending with a crash if
+    /// the connection to `node` drops first. Returns the job id.
+    pub fn spawn<F: Fn(&VMEvent) + Send + Sync + 'static>(
+        &self,
+        node: Uuid,
+        program: Vec<u8>,
+        limits: VMLimits,
+        on_event: F,
+    ) -> Result<u64, ClusterError> {
+        let connection = match self.shared.peers.lock().unwrap().get(&node) {
+            Some(peer) => peer.connection.clone(),
+            None => return Err(ClusterError::UnknownNode(node.to_string())),
+        };
+        let job = self.shared.next_job.fetch_add(1, Ordering::SeqCst);
+        self.shared.jobs.lock().unwrap().insert(
+            job,
+            Job {
+                connection: connection.id,
+                on_event: Arc::new(on_event),
+            },
+        );
+        let message = Message::Spawn {
+            job,
+            program,
+            limits,
+        };
+        if let Err(e) = connection.send(&message) {
+            self.shared.jobs.lock().unwrap().remove(&job);
+            return Err(ClusterError::SendFailed(e.to_string()));
+        }
+        Ok(job)
+    }
+
+    /// Hangs up on every peer and stops listening
+    pub fn shutdown(&self) {
+        self.shared.shutting_down.store(true, Ordering::SeqCst);
+        for peer in self.shared.peers.lock().unwrap().values() {
+            peer.connection.close();
+        }
+        // wake up the accept loop so it sees the flag
+        let _ = TcpStream::connect(self.shared.addr);
+    }
+}
+
+/// Sends every peer a heartbeat, drops the ones gone quiet and dials seeds not connected
+fn heartbeat(shared: &Arc<Shared>) {
+    let timeout = shared.config.heartbeat_timeout;
+    let mut alive = vec![];
+    let mut known = vec![];
+    shared.peers.lock().unwrap().retain(|id, peer| {
+        if peer.last_seen.elapsed() > timeout {
+            warn!("Node {} at {} stopped responding", id, peer.addr);
+            peer.connection.close();
+            return false;
+        }
+        alive.push(peer.connection.clone());
+        known.push(peer.addr);
+        true
+    });
+    for connection in alive {
+        let _ = connection.send(&Message::Heartbeat);
+    }
+    for seed in &shared.config.seeds {
+        if *seed != shared.addr && !known.contains(seed) {
+            connect(shared, *seed);
+        }
+    }
+}
+
+/// Dials another node in the background
+fn connect(shared: &Arc<Shared>, addr: SocketAddr) {
+    let shared = shared.clone();
+    thread::spawn(move || {
+        match TcpStream::connect_timeout(&addr, shared.config.heartbeat_interval) {
+            Ok(stream) => run_connection(&shared, stream, true),
+            Err(e) => debug!("Unable to reach node at {}: {}", addr, e),
+        }
+    });
+}
+
+/// Greets the other end, then handles its messages until the connection drops
+fn run_connection(shared: &Arc<Shared>, stream: TcpStream, outbound: bool) {
+    if shared.shutting_down.load(Ordering::SeqCst) {
+        return;
+    }
+    let connection = match stream.try_clone() {
+        Ok(writer) => Arc::new(Connection {
+            id: shared.next_connection.fetch_add(1, Ordering::SeqCst),
+            stream: Mutex::new(writer),
+            outbound,
+        }),
+        Err(e) => {
+            error!("Unable to set up node connection: {}", e);
+            return;
+        }
+    };
+    let mut nonce = vec![0; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let hello = Message::Hello {
+        node_id: shared.id,
+        addr: shared.addr,
+        nonce: nonce.clone(),
+    };
+    if connection.send(&hello).is_err() {
+        return;
+    }
+
+    // a peer that goes quiet before it's let in is dropped like any other
+    let _ = stream.set_read_timeout(Some(shared.config.heartbeat_timeout));
+    let mut lines = BufReader::new(stream).lines();
+    let mut next_message = || {
+        lines
+            .next()
+            .and_then(|line| Message::decode(&line.ok()?).ok())
+    };
+    let (peer_id, peer_addr, peer_nonce) = match next_message() {
+        Some(Message::Hello {
+            node_id,
+            addr,
+            nonce,
+        }) if node_id != shared.id => (node_id, addr, nonce),
+        _ => {
+            connection.close();
+            return;
+        }
+    };
+    if let Some(secret) = &shared.config.secret {
+        let answer = proof(secret, &peer_nonce, shared.id);
+        if connection.send(&Message::Auth { proof: answer }).is_err() {
+            return;
+        }
+        let expected = proof(secret, &nonce, peer_id);
+        if !matches!(next_message(), Some(Message::Auth { proof }) if constant_time_eq(&proof, &expected))
+        {
+            warn!(
+                "Node {} at {} doesn't know the cluster secret",
+                peer_id, peer_addr
+            );
+            connection.close();
+            return;
+        }
+    }
+    let _ = connection.stream.lock().unwrap().set_read_timeout(None);
+    if !register(shared, peer_id, peer_addr, &connection) {
+        connection.close();
+        return;
+    }
+    info!("Node {} at {} joined", peer_id, peer_addr);
+
+    for line in lines {
+        let message = match line.map(|line| Message::decode(&line)) {
+            Ok(Ok(message)) => message,
+            Ok(Err(e)) => {
+                warn!("Bad message from node {}: {}", peer_id, e);
+                break;
+            }
+            Err(_) => break,
+        };
+        if let Some(peer) = shared.peers.lock().unwrap().get_mut(&peer_id) {
+            peer.last_seen = Instant::now();
+        }
+        match message {
+            Message::Hello { .. } | Message::Auth { .. } | Message::Heartbeat => {}
+            Message::Peers { addrs } => {
+                let known: Vec<SocketAddr> = shared
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .map(|peer| peer.addr)
+                    .collect();
+                for addr in addrs {
+                    if addr != shared.addr && !known.contains(&addr) {
+                        connect(shared, addr);
+                    }
+                }
+            }
+            Message::Spawn {
+                job,
+                program,
+                limits,
+            } => run_job(shared, peer_id, connection.clone(), job, program, limits),
+            Message::Event { job, event } => {
+                let finished = matches!(
+                    event.event(),
+                    VMEventType::GracefulStop { .. } | VMEventType::Crash { .. }
+                );
+                let mut jobs = shared.jobs.lock().unwrap();
+                let on_event = jobs.get(&job).map(|job| job.on_event.clone());
+                if finished {
+                    jobs.remove(&job);
+                }
+                drop(jobs);
+                if let Some(on_event) = on_event {
+                    on_event(&event);
+                }
+            }
+        }
+    }
+
+    connection.close();
+    let mut peers = shared.peers.lock().unwrap();
+    if peers
+        .get(&peer_id)
+        .is_some_and(|peer| peer.connection.id == connection.id)
+    {
+        peers.remove(&peer_id);
+    }
+    drop(peers);
+
+    let mut lost = vec![];
+    shared.jobs.lock().unwrap().retain(|_, job| {
+        if job.connection == connection.id {
+            lost.push(job.on_event.clone());
+        }
+        job.connection != connection.id
+    });
+    info!(
+        "Node {} left with {} of our jobs running",
+        peer_id,
+        lost.len()
+    );
+    let crash = VMEvent::new(
+        VMEventType::Crash {
+            error: VMError::NodeLost,
+        },
+        peer_id,
+    );
+    for on_event in lost {
+        on_event(&crash);
+    }
+}
+
+/// Shows the node with id `node_id` knows `secret`, answering the other end's `nonce`
+fn proof(secret: &str, nonce: &[u8], node_id: Uuid) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(nonce);
+    mac.update(node_id.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Adds the peer, telling it about the other nodes. When both ends dialed each other at
+/// once, both keep the connection dialed by the node with the lower id.
+fn register(
+    shared: &Shared,
+    peer_id: Uuid,
+    peer_addr: SocketAddr,
+    connection: &Arc<Connection>,
+) -> bool {
+    let dialer = |outbound: bool| if outbound { shared.id } else { peer_id };
+    let mut peers = shared.peers.lock().unwrap();
+    if let Some(existing) = peers.get(&peer_id) {
+        if dialer(connection.outbound) > dialer(existing.connection.outbound) {
+            return false;
+        }
+        existing.connection.close();
+    }
+    let addrs = peers
+        .iter()
+        .filter(|(id, _)| **id != peer_id)
+        .map(|(_, peer)| peer.addr)
+        .collect();
+    peers.insert(
+        peer_id,
+        Peer {
+            addr: peer_addr,
+            connection: connection.clone(),
+            last_seen: Instant::now(),
+        },
+    );
+    drop(peers);
+    let _ = connection.send(&Message::Peers { addrs });
+    true
+}
+
+/// Runs a program for a peer on a new thread, under this node's limits, streaming its
+/// events back. It crashes straight away when `max_jobs` are already running.
+fn run_job(
+    shared: &Arc<Shared>,
+    peer_id: Uuid,
+    connection: Arc<Connection>,
+    job: u64,
+    program: Vec<u8>,
+    limits: VMLimits,
+) {
+    let running = shared.running_jobs.fetch_add(1, Ordering::SeqCst);
+    let slot = JobSlot(shared.clone());
+    if let Some(limit) = shared.config.max_jobs.filter(|max| running >= *max) {
+        warn!(
+            "Refused job {} for node {}, {} running",
+            job, peer_id, limit
+        );
+        send_crash(
+            &connection,
+            job,
+            VMError::JobLimitExceeded { limit },
+            shared.id,
+        );
+        return;
+    }
+    let limits = shared.config.limits.tightest(limits);
+    thread::spawn(move || {
+        let _slot = slot;
+        let mut vm = VM::new();
+        let vm_id = vm.id();
+        vm.add_bytes(program);
+        vm.set_limits(limits);
+        let events = connection.clone();
+        vm.subscribe(move |event| {
+            let _ = events.send(&Message::Event {
+                job,
+                event: event.clone(),
+            });
+        });
+        info!("Running job {} for node {} on VM {}", job, peer_id, vm_id);
+        if panic::catch_unwind(AssertUnwindSafe(|| vm.run())).is_err() {
+            error!("VM {} running job {} panicked", vm_id, job);
+            send_crash(&connection, job, VMError::Panicked, vm_id);
+        }
+    });
+}
+
+/// Tells the peer its job ended with `error` without the VM saying so itself
+fn send_crash(connection: &Connection, job: u64, error: VMError, vm_id: Uuid) {
+    let event = VMEvent::new(VMEventType::Crash { error }, vm_id);
+    let _ = connection.send(&Message::Event { job, event });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+
+    fn config(seeds: &[&Node]) -> ClusterConfig {
+        ClusterConfig {
+            bind: "127.0.0.1:0".to_string(),
+            seeds: seeds.iter().map(|node| node.addr()).collect(),
+            heartbeat_interval: Duration::from_millis(50),
+            heartbeat_timeout: Duration::from_millis(500),
+            ..ClusterConfig::default()
+        }
+    }
+
+    fn with_secret(secret: &str, seeds: &[&Node]) -> ClusterConfig {
+        ClusterConfig {
+            secret: Some(secret.to_string()),
+            ..config(seeds)
+        }
+    }
+
+    /// Records the types of the events of a job
+    fn recorder() -> (
+        Arc<Mutex<Vec<VMEventType>>>,
+        impl Fn(&VMEvent) + Send + Sync + 'static,
+    ) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = events.clone();
+        (events, move |event: &VMEvent| {
+            seen.lock().unwrap().push(event.event().clone())
+        })
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_nodes_find_each_other_through_seeds() {
+        let a = Node::start(Uuid::new_v4(), config(&[])).unwrap();
+        let b = Node::start(Uuid::new_v4(), config(&[&a])).unwrap();
+        // c only knows b, and hears about a from it
+        let c = Node::start(Uuid::new_v4(), config(&[&b])).unwrap();
+        for node in [&a, &b, &c] {
+            wait_until(|| node.peers().len() == 2);
+        }
+        let peer = a
+            .peers()
+            .into_iter()
+            .find(|peer| peer.id == c.id())
+            .unwrap();
+        assert_eq!(peer.addr, c.addr());
+
+        assert_eq!(a.find_peer(&c.id().to_string()[..8]), Ok(c.id()));
+        assert_eq!(
+            a.find_peer("nope"),
+            Err(ClusterError::UnknownNode("nope".to_string()))
+        );
+        assert!(matches!(
+            a.find_peer(""),
+            Err(ClusterError::AmbiguousNode(_))
+        ));
+
+        // heartbeats keep quiet peers around, and a dead one gets dropped
+        thread::sleep(Duration::from_millis(700));
+        assert_eq!(a.peers().len(), 2);
+        c.shutdown();
+        wait_until(|| a.peers().len() == 1 && b.peers().len() == 1);
+        a.shutdown();
+        b.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_on_peer_streams_events_back() {
+        let a = Node::start(Uuid::new_v4(), config(&[])).unwrap();
+        let b = Node::start(Uuid::new_v4(), config(&[&a])).unwrap();
+        wait_until(|| b.peers().len() == 1);
+
+        let mut program = VM::new_with_header().program;
+        program.extend([Opcode::LOAD.into(), 0, 1, 244]);
+        program.extend([Opcode::YIELD.into(), 0, 0, 0]);
+        program.push(Opcode::HLT.into());
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = events.clone();
+        let job = b
+            .spawn(a.id(), program, VMLimits::default(), move |event| {
+                seen.lock().unwrap().push(event.event().clone())
+            })
+            .unwrap();
+        assert_eq!(job, 1);
+        wait_until(|| events.lock().unwrap().len() == 3);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                VMEventType::Start,
+                VMEventType::Yield,
+                VMEventType::GracefulStop { code: 1 }
+            ]
+        );
+        assert!(b.shared.jobs.lock().unwrap().is_empty());
+
+        // a program that isn't a PIE crashes over there, not here
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = events.clone();
+        b.spawn(a.id(), vec![1, 2], VMLimits::default(), move |event| {
+            seen.lock().unwrap().push(event.event().clone())
+        })
+        .unwrap();
+        wait_until(|| events.lock().unwrap().len() == 2);
+        assert!(matches!(
+            events.lock().unwrap()[1],
+            VMEventType::Crash { .. }
+        ));
+
+        assert_eq!(
+            b.spawn(b.id(), vec![], VMLimits::default(), |_| {}),
+            Err(ClusterError::UnknownNode(b.id().to_string()))
+        );
+        a.shutdown();
+        b.shutdown();
+    }
+
+    #[test]
+    fn test_secret_keeps_out_other_nodes() {
+        let a = Node::start(Uuid::new_v4(), with_secret("s3cret", &[])).unwrap();
+        let b = Node::start(Uuid::new_v4(), with_secret("s3cret", &[&a])).unwrap();
+        wait_until(|| a.peers().len() == 1 && b.peers().len() == 1);
+
+        let wrong = Node::start(Uuid::new_v4(), with_secret("guess", &[&a])).unwrap();
+        let none = Node::start(Uuid::new_v4(), config(&[&a])).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(a.peers().len(), 1);
+        assert!(wrong.peers().is_empty());
+        for node in [a, b, wrong, none] {
+            node.shutdown();
+        }
+    }
+
+    #[test]
+    fn test_jobs_run_under_local_limits() {
+        let a = Node::start(
+            Uuid::new_v4(),
+            ClusterConfig {
+                limits: VMLimits {
+                    max_instructions: Some(10),
+                    ..VMLimits::default()
+                },
+                max_jobs: Some(1),
+                ..config(&[])
+            },
+        )
+        .unwrap();
+        let b = Node::start(Uuid::new_v4(), config(&[&a])).unwrap();
+        wait_until(|| b.peers().len() == 1);
+
+        let mut program = VM::new_with_header().program;
+        for _ in 0..20 {
+            program.extend([Opcode::NOP.into(), 0, 0, 0]);
+        }
+        program.push(Opcode::HLT.into());
+        let crash = |error| VMEventType::Crash { error };
+
+        // asking for no limits doesn't lift a's, asking for lower ones does lower them
+        for (requested, limit) in [(None, 10), (Some(100), 10), (Some(5), 5)] {
+            let (events, on_event) = recorder();
+            let limits = VMLimits {
+                max_instructions: requested,
+                ..VMLimits::default()
+            };
+            b.spawn(a.id(), program.clone(), limits, on_event).unwrap();
+            wait_until(|| events.lock().unwrap().len() == 2);
+            assert_eq!(
+                events.lock().unwrap()[1],
+                crash(VMError::InstructionLimitExceeded { limit })
+            );
+        }
+
+        // a is busy with as many jobs as it takes
+        a.shared.running_jobs.fetch_add(1, Ordering::SeqCst);
+        let (events, on_event) = recorder();
+        b.spawn(a.id(), program, VMLimits::default(), on_event)
+            .unwrap();
+        wait_until(|| events.lock().unwrap().len() == 1);
+        assert_eq!(
+            events.lock().unwrap()[0],
+            crash(VMError::JobLimitExceeded { limit: 1 })
+        );
+        assert!(b.shared.jobs.lock().unwrap().is_empty());
+        a.shutdown();
+        b.shutdown();
+    }
+
+    #[test]
+    fn test_lost_node_crashes_its_jobs() {
+        let a = Node::start(Uuid::new_v4(), config(&[])).unwrap();
+        let b = Node::start(Uuid::new_v4(), config(&[&a])).unwrap();
+        wait_until(|| b.peers().len() == 1);
+
+        // a job a never answers, as if it was still running when a went away
+        let (events, on_event) = recorder();
+        let connection = b.shared.peers.lock().unwrap()[&a.id()].connection.id;
+        b.shared.jobs.lock().unwrap().insert(
+            99,
+            Job {
+                connection,
+                on_event: Arc::new(on_event),
+            },
+        );
+        a.shutdown();
+        wait_until(|| events.lock().unwrap().len() == 1);
+        assert_eq!(
+            events.lock().unwrap()[0],
+            VMEventType::Crash {
+                error: VMError::NodeLost
+            }
+        );
+        assert!(b.shared.jobs.lock().unwrap().is_empty());
+        b.shutdown();
+    }
+}
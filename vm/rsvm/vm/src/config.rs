@@ -0,0 +1,554 @@
+use std::{
+    env, fmt, fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{cluster::ClusterConfig, vm::VMLimits};
+
+/// Names the config file to use instead of `~/.iridium/config.toml`
+pub const CONFIG_ENV: &str = "IRIDIUM_CONFIG";
+
+/// Every setting that can also be given as `IRIDIUM_<KEY>` or on the command line.
+/// Sections are separated by dots, e.g. `remote.port` is `IRIDIUM_REMOTE_PORT`.
+pub const KEYS: &[&str] = &[
+    "threads",
+    "bind_host",
+    "history_file",
+    "include_paths",
+    "snapshot_dir",
+    "remote.enabled",
+    "remote.port",
+    "remote.token",
+    "remote.password_file",
+    "remote.max_connections",
+    "ssh.enabled",
+    "ssh.port",
+    "ssh.dir",
+    "ssh.max_connections",
+    "limits.max_heap_bytes",
+    "limits.max_instructions",
+    "limits.max_stack_depth",
+    "limits.max_output_bytes",
+    "cluster.bind",
+    "cluster.seeds",
+    "cluster.secret",
+    "cluster.max_jobs",
+];
+
+/// Settings of the vm binary. Read from a TOML file, then overridden by `IRIDIUM_*`
+/// environment variables, then by command line flags.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Number of OS threads the VM will utilize, all cores if not set
+    pub threads: Option<usize>,
+    /// Address the remote and SSH servers listen on
+    pub bind_host: String,
+    pub history_file: Option<PathBuf>,
+    /// Where to look for programs given by a relative path that isn't in the current directory
+    pub include_paths: Vec<PathBuf>,
+    /// Where remote and SSH users may load programs from and `!save` and `!restore` snapshots.
+    /// They can't use any files without it.
+    pub snapshot_dir: Option<PathBuf>,
+    pub remote: RemoteSettings,
+    pub ssh: SshSettings,
+    pub limits: VMLimits,
+    /// Joins a cluster when set
+    pub cluster: Option<ClusterSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteSettings {
+    pub enabled: bool,
+    pub port: u16,
+    pub token: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshSettings {
+    pub enabled: bool,
+    pub port: u16,
+    /// Holds the host key and authorized keys, `~/.iridium` if not set
+    pub dir: Option<PathBuf>,
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterSettings {
+    pub bind: String,
+    #[serde(default)]
+    pub seeds: Vec<String>,
+    /// Every node of the cluster needs the same one
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Programs peers may run on this node at once, under `limits`
+    #[serde(default)]
+    pub max_jobs: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            threads: None,
+            bind_host: "127.0.0.1".to_string(),
+            history_file: None,
+            include_paths: vec![],
+            snapshot_dir: None,
+            remote: RemoteSettings::default(),
+            ssh: SshSettings::default(),
+            limits: VMLimits::default(),
+            cluster: None,
+        }
+    }
+}
+
+impl Default for RemoteSettings {
+    fn default() -> Self {
+        RemoteSettings {
+            enabled: false,
+            port: 2223,
+            token: None,
+            password_file: None,
+            max_connections: None,
+        }
+    }
+}
+
+impl Default for SshSettings {
+    fn default() -> Self {
+        SshSettings {
+            enabled: false,
+            port: 2222,
+            dir: None,
+            max_connections: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        reason: String,
+    },
+    /// Not TOML, or a key that doesn't exist or has the wrong type
+    Parse {
+        path: PathBuf,
+        reason: String,
+    },
+    /// A flag or environment variable that doesn't fit its setting.
+    /// `origin` is the flag or variable as the user typed it.
+    BadValue {
+        origin: String,
+        reason: String,
+    },
+    /// The settings don't make sense, wherever they came from
+    Invalid {
+        key: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, reason } => {
+                write!(f, "Unable to read {}: {}", path.display(), reason)
+            }
+            ConfigError::Parse { path, reason } => {
+                write!(f, "Invalid config file {}: {}", path.display(), reason)
+            }
+            ConfigError::BadValue { origin, reason } => write!(f, "{}: {}", origin, reason),
+            ConfigError::Invalid { key, reason } => write!(f, "{}: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads `path`, which has to exist. Without one, reads `~/.iridium/config.toml`
+    /// if that exists, or else starts from the defaults.
+    pub fn find(path: Option<&Path>) -> Result<Config, ConfigError> {
+        match path {
+            Some(path) => Config::load(path),
+            None => {
+                let default = default_config_file();
+                if default.is_file() {
+                    Config::load(&default)
+                } else {
+                    Ok(Config::default())
+                }
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            reason: e.message().to_string(),
+        })
+    }
+
+    /// Overrides settings from `IRIDIUM_*` variables among `vars`, e.g. `env::vars()`.
+    /// They are set in the order of `KEYS`, whatever order they come in, so that
+    /// `cluster.bind` is set before the cluster settings that need it.
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> Vec<ConfigError> {
+        let vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("IRIDIUM_") && name != CONFIG_ENV)
+            .collect();
+        let mut errors = vec![];
+        for key in KEYS {
+            let name = env_name(key);
+            if let Some((_, value)) = vars.iter().find(|(var, _)| *var == name) {
+                if let Err(reason) = self.set(key, value) {
+                    errors.push(ConfigError::BadValue {
+                        origin: name,
+                        reason,
+                    });
+                }
+            }
+        }
+        for (name, _) in vars {
+            if !KEYS.iter().any(|key| env_name(key) == name) {
+                errors.push(ConfigError::BadValue {
+                    origin: name,
+                    reason: "not a setting".to_string(),
+                });
+            }
+        }
+        errors
+    }
+
+    /// Sets one of the `KEYS` from text. Lists are comma separated,
+    /// except for paths which are separated like `PATH`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "threads" => self.threads = Some(parse(value)?),
+            "bind_host" => self.bind_host = value.to_string(),
+            "history_file" => self.history_file = Some(PathBuf::from(value)),
+            "include_paths" => self.include_paths = env::split_paths(value).collect(),
+            "snapshot_dir" => self.snapshot_dir = Some(PathBuf::from(value)),
+            "remote.enabled" => self.remote.enabled = parse_bool(value)?,
+            "remote.port" => self.remote.port = parse(value)?,
+            "remote.token" => self.remote.token = Some(value.to_string()),
+            "remote.password_file" => self.remote.password_file = Some(PathBuf::from(value)),
+            "remote.max_connections" => self.remote.max_connections = Some(parse(value)?),
+            "ssh.enabled" => self.ssh.enabled = parse_bool(value)?,
+            "ssh.port" => self.ssh.port = parse(value)?,
+            "ssh.dir" => self.ssh.dir = Some(PathBuf::from(value)),
+            "ssh.max_connections" => self.ssh.max_connections = Some(parse(value)?),
+            "limits.max_heap_bytes" => self.limits.max_heap_bytes = Some(parse(value)?),
+            "limits.max_instructions" => self.limits.max_instructions = Some(parse(value)?),
+            "limits.max_stack_depth" => self.limits.max_stack_depth = Some(parse(value)?),
+            "limits.max_output_bytes" => self.limits.max_output_bytes = Some(parse(value)?),
+            "cluster.bind" => match &mut self.cluster {
+                Some(cluster) => cluster.bind = value.to_string(),
+                None => {
+                    self.cluster = Some(ClusterSettings {
+                        bind: value.to_string(),
+                        seeds: vec![],
+                        secret: None,
+                        max_jobs: None,
+                    })
+                }
+            },
+            "cluster.seeds" | "cluster.secret" | "cluster.max_jobs" => {
+                let cluster = match &mut self.cluster {
+                    Some(cluster) => cluster,
+                    None => return Err("set cluster.bind to join a cluster first".to_string()),
+                };
+                match key {
+                    "cluster.seeds" => {
+                        cluster.seeds = value.split(',').map(str::to_string).collect()
+                    }
+                    "cluster.secret" => cluster.secret = Some(value.to_string()),
+                    _ => cluster.max_jobs = Some(parse(value)?),
+                }
+            }
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Everything wrong with the settings once they are all merged
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = vec![];
+        let mut invalid = |key, reason: &str| {
+            errors.push(ConfigError::Invalid {
+                key,
+                reason: reason.to_string(),
+            })
+        };
+        if self.threads == Some(0) {
+            invalid("threads", "must be at least 1");
+        }
+        if self.remote.enabled && self.remote.port == 0 {
+            invalid("remote.port", "must be between 1 and 65535");
+        }
+        if self.ssh.enabled && self.ssh.port == 0 {
+            invalid("ssh.port", "must be between 1 and 65535");
+        }
+        if self.remote.enabled && self.ssh.enabled && self.remote.port == self.ssh.port {
+            invalid("ssh.port", "is the same as remote.port");
+        }
+        if self.remote.token.is_some() && self.remote.password_file.is_some() {
+            invalid(
+                "remote.token",
+                "set only one of remote.token and remote.password_file",
+            );
+        }
+        if self.remote.max_connections == Some(0) {
+            invalid("remote.max_connections", "must be at least 1");
+        }
+        if self.ssh.max_connections == Some(0) {
+            invalid("ssh.max_connections", "must be at least 1");
+        }
+        for path in &self.include_paths {
+            if !path.is_dir() {
+                invalid(
+                    "include_paths",
+                    &format!("{} is not a directory", path.display()),
+                );
+            }
+        }
+        if let Some(dir) = &self.snapshot_dir {
+            if !dir.is_dir() {
+                invalid(
+                    "snapshot_dir",
+                    &format!("{} is not a directory", dir.display()),
+                );
+            }
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.max_jobs == Some(0) {
+                invalid("cluster.max_jobs", "must be at least 1");
+            }
+            for seed in &cluster.seeds {
+                if seed.to_socket_addrs().is_err() {
+                    invalid(
+                        "cluster.seeds",
+                        &format!("{} is not an address like 127.0.0.1:2224", seed),
+                    );
+                }
+            }
+        }
+        errors
+    }
+
+    /// How to join the cluster, if at all. Seeds that don't resolve are left out.
+    pub fn cluster_config(&self) -> Option<ClusterConfig> {
+        let cluster = self.cluster.as_ref()?;
+        Some(ClusterConfig {
+            bind: cluster.bind.clone(),
+            seeds: cluster
+                .seeds
+                .iter()
+                .filter_map(|seed| seed.to_socket_addrs().ok())
+                .flatten()
+                .collect(),
+            secret: cluster.secret.clone(),
+            limits: self.limits,
+            max_jobs: cluster.max_jobs,
+            ..ClusterConfig::default()
+        })
+    }
+}
+
+/// `~/.iridium/config.toml`
+pub fn default_config_file() -> PathBuf {
+    let home = env::var_os("HOME").map_or_else(PathBuf::new, PathBuf::from);
+    home.join(".iridium").join("config.toml")
+}
+
+/// `path` itself if it exists or is absolute, else the first of `include_paths` holding it
+pub fn find_file(path: &Path, include_paths: &[PathBuf]) -> PathBuf {
+    if path.exists() || path.is_absolute() {
+        return path.to_path_buf();
+    }
+    include_paths
+        .iter()
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+fn env_name(key: &str) -> String {
+    format!("IRIDIUM_{}", key.replace('.', "_").to_uppercase())
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, got {}", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(format!("expected true or false, got {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_file_then_env_then_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+threads = 2
+include_paths = ["lib"]
+
+[remote]
+enabled = true
+port = 3000
+
+[limits]
+max_instructions = 1000
+
+[cluster]
+bind = "127.0.0.1:2224"
+seeds = ["127.0.0.1:2225"]
+secret = "shh"
+"#,
+        )
+        .unwrap();
+        let mut config = Config::find(Some(&path)).unwrap();
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.remote.port, 3000);
+        assert_eq!(config.ssh, SshSettings::default());
+        assert_eq!(config.limits.max_instructions, Some(1000));
+
+        let errors = config.apply_env(vars(&[
+            ("IRIDIUM_REMOTE_PORT", "4000"),
+            ("IRIDIUM_LIMITS_MAX_HEAP_BYTES", "64"),
+            ("IRIDIUM_CLUSTER_MAX_JOBS", "3"),
+            ("IRIDIUM_CONFIG", "ignored.toml"),
+            ("HOME", "/nowhere"),
+        ]));
+        assert!(errors.is_empty());
+        assert_eq!(config.remote.port, 4000);
+        assert_eq!(config.limits.max_heap_bytes, Some(64));
+
+        // what the binary does with --threads 8
+        config.set("threads", "8").unwrap();
+        assert_eq!(config.threads, Some(8));
+        let cluster = config.cluster_config().unwrap();
+        assert_eq!(cluster.seeds, ["127.0.0.1:2225".parse().unwrap()]);
+        assert_eq!(cluster.secret.as_deref(), Some("shh"));
+        assert_eq!(cluster.max_jobs, Some(3));
+        // peers' programs run under the same limits as local ones
+        assert_eq!(cluster.limits, config.limits);
+    }
+
+    #[test]
+    fn test_example_config() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.example.toml");
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "thread = 2\n").unwrap();
+        assert!(matches!(
+            Config::load(&path),
+            Err(ConfigError::Parse { reason, .. }) if reason.contains("unknown field `thread`")
+        ));
+        fs::write(&path, "[ssh]\nport = \"high\"\n").unwrap();
+        assert!(matches!(
+            Config::load(&path),
+            Err(ConfigError::Parse { .. })
+        ));
+        assert!(matches!(
+            Config::find(Some(&dir.path().join("missing.toml"))),
+            Err(ConfigError::Read { .. })
+        ));
+
+        let mut config = Config::default();
+        let errors = config.apply_env(vars(&[
+            ("IRIDIUM_THREADS", "many"),
+            ("IRIDIUM_COLOR", "blue"),
+        ]));
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "IRIDIUM_THREADS: expected a number, got many",
+                "IRIDIUM_COLOR: not a setting"
+            ]
+        );
+
+        // the environment lists variables in no particular order
+        let mut joined = Config::default();
+        let errors = joined.apply_env(vars(&[
+            ("IRIDIUM_CLUSTER_SEEDS", "127.0.0.1:2225"),
+            ("IRIDIUM_CLUSTER_BIND", "127.0.0.1:2224"),
+        ]));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(joined.cluster.unwrap().seeds, ["127.0.0.1:2225"]);
+
+        config.threads = Some(0);
+        config.remote.enabled = true;
+        config.ssh.enabled = true;
+        config.ssh.port = config.remote.port;
+        config.include_paths = vec![path.clone()];
+        config.snapshot_dir = Some(path);
+        let keys: Vec<_> = config
+            .validate()
+            .into_iter()
+            .map(|error| match error {
+                ConfigError::Invalid { key, .. } => key,
+                other => panic!("{}", other),
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            ["threads", "ssh.port", "include_paths", "snapshot_dir"]
+        );
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn test_find_file_in_include_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.iasm"), "").unwrap();
+        let include_paths = vec![PathBuf::from("/nowhere"), dir.path().to_path_buf()];
+        assert_eq!(
+            find_file(Path::new("lib.iasm"), &include_paths),
+            dir.path().join("lib.iasm")
+        );
+        assert_eq!(
+            find_file(Path::new("other.iasm"), &include_paths),
+            Path::new("other.iasm")
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use core::fmt;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapError {
+    /// Growing the heap for this allocation would take it past `limit` bytes
+    LimitExceeded { requested: usize, limit: usize },
+    /// The pointer is not the start of a live block
+    InvalidFree { pointer: usize },
+}
+
+/// Numbers shown by the REPL's `!heap`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub live_blocks: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Heap size:          {} bytes", self.size)?;
+        writeln!(
+            f,
+            "In use:             {} bytes in {} blocks",
+            self.used, self.live_blocks
+        )?;
+        writeln!(
+            f,
+            "Free:               {} bytes in {} blocks",
+            self.free, self.free_blocks
+        )?;
+        writeln!(f, "Largest free block: {} bytes", self.largest_free_block)?;
+        write!(
+            f,
+            "Allocations:        {} ({} freed)",
+            self.allocations, self.frees
+        )
+    }
+}
+
+/// First-fit free-list allocator over the VM's heap memory.
+/// Freed blocks are merged with their free neighbours, and free space at the
+/// end of the heap is given back, so a long running program only keeps as
+/// much memory as its live blocks need.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Heap {
+    memory: Vec<u8>,
+    /// Live blocks, start -> size
+    blocks: BTreeMap<usize, usize>,
+    /// Free blocks inside `memory`, start -> size
+    free: BTreeMap<usize, usize>,
+    allocations: u64,
+    frees: u64,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    /// Rebuild a heap from its parts, as saved in a snapshot. None when the blocks
+    /// overlap or run past the end of `memory`, which a snapshot can't be trusted not to do.
+    pub fn from_parts(memory: Vec<u8>, blocks: Vec<(usize, usize)>) -> Option<Heap> {
+        let count = blocks.len();
+        let mut heap = Heap {
+            memory,
+            blocks: blocks.into_iter().collect(),
+            ..Heap::default()
+        };
+        if heap.blocks.len() != count {
+            return None;
+        }
+        // everything between live blocks is free
+        let mut cursor = 0;
+        let live: Vec<(usize, usize)> = heap.blocks.iter().map(|(s, l)| (*s, *l)).collect();
+        for (start, size) in live {
+            if start < cursor {
+                return None;
+            }
+            if start > cursor {
+                heap.free.insert(cursor, start - cursor);
+            }
+            cursor = start.checked_add(size)?;
+        }
+        if cursor > heap.memory.len() {
+            return None;
+        }
+        if heap.memory.len() > cursor {
+            heap.free.insert(cursor, heap.memory.len() - cursor);
+        }
+        Some(heap)
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Live blocks as (start, size), in address order
+    pub fn blocks(&self) -> Vec<(usize, usize)> {
+        self.blocks.iter().map(|(s, l)| (*s, *l)).collect()
+    }
+
+    /// Reserve `size` zeroed bytes and return where they start.
+    /// The heap only grows when no free block is big enough, and never past `limit`.
+    pub fn alloc(&mut self, size: usize, limit: Option<usize>) -> Result<usize, HeapError> {
+        // every block needs its own address
+        let size = size.max(1);
+
+        let fit = self
+            .free
+            .iter()
+            .find(|(_, free_size)| **free_size >= size)
+            .map(|(start, free_size)| (*start, *free_size));
+
+        let start = match fit {
+            Some((start, free_size)) => {
+                self.free.remove(&start);
+                if free_size > size {
+                    self.free.insert(start + size, free_size - size);
+                }
+                start
+            }
+            None => {
+                // a free block at the very end only needs topping up
+                let start = match self.free.iter().next_back() {
+                    Some((start, free_size)) if start + free_size == self.memory.len() => *start,
+                    _ => self.memory.len(),
+                };
+                let new_len = start + size;
+                if let Some(limit) = limit {
+                    if new_len > limit {
+                        return Err(HeapError::LimitExceeded {
+                            requested: new_len,
+                            limit,
+                        });
+                    }
+                }
+                self.free.remove(&start);
+                self.memory.resize(new_len, 0);
+                start
+            }
+        };
+
+        self.memory[start..start + size].fill(0);
+        self.blocks.insert(start, size);
+        self.allocations += 1;
+        Ok(start)
+    }
+
+    pub fn free(&mut self, pointer: usize) -> Result<(), HeapError> {
+        let mut size = self
+            .blocks
+            .remove(&pointer)
+            .ok_or(HeapError::InvalidFree { pointer })?;
+        let mut start = pointer;
+        self.frees += 1;
+
+        // merge with the free block right after
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+        // and the one right before
+        let previous = self.free.range(..start).next_back().map(|(s, l)| (*s, *l));
+        if let Some((previous_start, previous_size)) = previous {
+            if previous_start + previous_size == start {
+                self.free.remove(&previous_start);
+                start = previous_start;
+                size += previous_size;
+            }
+        }
+
+        if start + size == self.memory.len() {
+            self.memory.truncate(start);
+        } else {
+            self.free.insert(start, size);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let used = self.blocks.values().sum();
+        HeapStats {
+            size: self.memory.len(),
+            used,
+            free: self.memory.len() - used,
+            live_blocks: self.blocks.len(),
+            free_blocks: self.free.len(),
+            largest_free_block: self.free.values().copied().max().unwrap_or(0),
+            allocations: self.allocations,
+            frees: self.frees,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_grows_heap() {
+        let mut heap = Heap::new();
+        assert_eq!(heap.alloc(16, None), Ok(0));
+        assert_eq!(heap.alloc(8, None), Ok(16));
+        assert_eq!(heap.len(), 24);
+    }
+
+    #[test]
+    fn test_freed_block_is_reused() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(16, None).unwrap();
+        let _b = heap.alloc(16, None).unwrap();
+        heap.free(a).unwrap();
+        assert_eq!(heap.alloc(8, None), Ok(a));
+        assert_eq!(heap.alloc(8, None), Ok(8));
+        assert_eq!(heap.len(), 32);
+    }
+
+    #[test]
+    fn test_free_coalesces_and_shrinks() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(10, None).unwrap();
+        let b = heap.alloc(10, None).unwrap();
+        let c = heap.alloc(10, None).unwrap();
+        heap.free(a).unwrap();
+        heap.free(b).unwrap();
+        assert_eq!(heap.stats().free_blocks, 1);
+        assert_eq!(heap.stats().largest_free_block, 20);
+
+        // freeing the last block hands all the trailing free space back
+        heap.free(c).unwrap();
+        assert!(heap.is_empty());
+        assert_eq!(heap.stats().free_blocks, 0);
+    }
+
+    #[test]
+    fn test_invalid_free() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(4, None).unwrap();
+        assert_eq!(
+            heap.free(a + 1),
+            Err(HeapError::InvalidFree { pointer: a + 1 })
+        );
+        heap.free(a).unwrap();
+        assert_eq!(heap.free(a), Err(HeapError::InvalidFree { pointer: a }));
+    }
+
+    #[test]
+    fn test_limit_only_counts_growth() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(60, Some(64)).unwrap();
+        let _b = heap.alloc(4, Some(64)).unwrap();
+        assert!(heap.alloc(1, Some(64)).is_err());
+        heap.free(a).unwrap();
+        assert!(heap.alloc(32, Some(64)).is_ok());
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(8, None).unwrap();
+        let _b = heap.alloc(8, None).unwrap();
+        heap.free(a).unwrap();
+
+        let rebuilt = Heap::from_parts(heap.memory().to_vec(), heap.blocks()).unwrap();
+        assert_eq!(rebuilt.stats().used, heap.stats().used);
+        assert_eq!(rebuilt.stats().free_blocks, 1);
+
+        // overlapping, repeated, past the end, and overflowing
+        for blocks in [
+            vec![(0, 8), (4, 8)],
+            vec![(0, 4), (0, 4)],
+            vec![(8, 9)],
+            vec![(1, usize::MAX)],
+        ] {
+            assert_eq!(
+                Heap::from_parts(vec![0; 16], blocks.clone()),
+                None,
+                "{:?}",
+                blocks
+            );
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use nom::types::CompleteStr;
 
 macro_rules! declare_opcodes {
@@ -6,17 +8,22 @@ macro_rules! declare_opcodes {
             ($instruction:ident, $binary:tt)
         ), +
     ) => {
-        #[derive(Debug, PartialEq, Clone, Copy)]
+        #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
         pub enum Opcode{
             $($instruction,)+
             IGL,
         }
 
-        impl Into<u8> for Opcode {
-            fn into(self) -> u8 {
-                match self{
-                    $(Self::$instruction => $binary,)+
-                    _ => {
+        impl Opcode {
+            /// Upper case names of every real opcode, i.e. all but IGL
+            pub const MNEMONICS: &'static [&'static str] = &[$(stringify!($instruction),)+];
+        }
+
+        impl From<Opcode> for u8 {
+            fn from(value: Opcode) -> u8 {
+                match value {
+                    $(Opcode::$instruction => $binary,)+
+                    Opcode::IGL => {
                         // println!("Non-opcode found in opcode field! ");
                         // std::process::exit(1);
                         255
@@ -89,6 +96,7 @@ declare_opcodes!(
     (DIV, 13),
     (INC, 14),
     (DEC, 15),
+    (REM, 16),
     //
     (EQ, 20),
     (NEQ, 21),
@@ -101,28 +109,152 @@ declare_opcodes!(
     (JNEQ, 27),
     // (DJMPE, 28),
     //
+    (PUSH, 30),
+    (POP, 31),
+    //
     (JMPB, 79),
     (JMP, 80),
     (JMPF, 81),
+    (CALL, 82),
+    (RET, 83),
     //
     (NOP, 98),
     (ALOC, 100),
     //
     (PRTS, 101),
+    (FREE, 102),
+    (YIELD, 103),
     //
     (HLT, 254) // IGL -> 255
 );
 
+impl Opcode {
+    /// How many bytes the VM consumes for this opcode, the opcode byte included.
+    /// Not every instruction uses the whole 4 bytes the assembler emits.
+    pub fn width(self) -> usize {
+        match self {
+            Opcode::LOAD => 4,
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => 4,
+            Opcode::INC | Opcode::DEC | Opcode::REM => 4,
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => 4,
+            Opcode::JEQ | Opcode::JNEQ => 2,
+            Opcode::JMPB | Opcode::JMP | Opcode::JMPF => 2,
+            Opcode::PUSH | Opcode::POP => 4,
+            Opcode::CALL => 2,
+            Opcode::RET => 1,
+            Opcode::NOP => 4,
+            Opcode::ALOC | Opcode::FREE | Opcode::YIELD => 4,
+            Opcode::PRTS => 3,
+            Opcode::HLT | Opcode::IGL => 1,
+        }
+    }
+
+    /// How many of the leading operand bytes name a register
+    pub fn register_operands(self) -> usize {
+        match self {
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => 3,
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => 2,
+            Opcode::ALOC => 2,
+            Opcode::LOAD | Opcode::INC | Opcode::DEC | Opcode::REM | Opcode::FREE => 1,
+            Opcode::JEQ | Opcode::JNEQ | Opcode::JMPB | Opcode::JMP | Opcode::JMPF => 1,
+            Opcode::PUSH | Opcode::POP | Opcode::CALL => 1,
+            Opcode::RET => 0,
+            Opcode::PRTS | Opcode::NOP | Opcode::YIELD | Opcode::HLT | Opcode::IGL => 0,
+        }
+    }
+}
+
+/// An instruction decoded out of the bytecode, so the VM can dispatch on it
+/// without going through the raw bytes again.
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
-    opcode: Opcode,
+    pub opcode: Opcode,
+    /// Operand bytes following the opcode, zero filled past `width`
+    pub operands: [u8; 3],
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            operands: [0; 3],
+        }
+    }
+
+    /// Decode the instruction at the beginning of `bytes`.
+    /// Returns `None` if there are not enough bytes left for its operands.
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        let opcode = Opcode::from(*bytes.first()?);
+        let width = opcode.width();
+        if bytes.len() < width {
+            return None;
+        }
+        let mut operands = [0; 3];
+        operands[..width - 1].copy_from_slice(&bytes[1..width]);
+        Some(Instruction { opcode, operands })
+    }
+
+    pub fn width(&self) -> usize {
+        self.opcode.width()
+    }
+
+    /// Operand `n` as a register index
+    pub fn register(&self, n: usize) -> usize {
+        self.operands[n] as usize
+    }
+
+    /// The operand bytes that name registers, the rest being numbers or padding
+    pub fn registers(&self) -> &[u8] {
+        &self.operands[..self.opcode.register_operands()]
+    }
+
+    /// Two operand bytes starting from `n`, read as a big endian number
+    pub fn operand_u16(&self, n: usize) -> u16 {
+        ((self.operands[n] as u16) << 8) | self.operands[n + 1] as u16
+    }
+}
+
+/// Assembly text the instruction could have been assembled from, e.g. `load $1 #1000`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = format!("{:?}", self.opcode).to_lowercase();
+        let [a, b, c] = self.operands;
+        match self.opcode {
+            Opcode::LOAD => write!(f, "{} ${} #{}", mnemonic, a, self.operand_u16(1)),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                write!(f, "{} ${} ${} ${}", mnemonic, a, b, c)
+            }
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => {
+                write!(f, "{} ${} ${}", mnemonic, a, b)
+            }
+            Opcode::ALOC => write!(f, "{} ${} ${}", mnemonic, a, b),
+            Opcode::INC | Opcode::DEC | Opcode::REM | Opcode::FREE => {
+                write!(f, "{} ${}", mnemonic, a)
+            }
+            Opcode::PUSH | Opcode::POP | Opcode::CALL => write!(f, "{} ${}", mnemonic, a),
+            Opcode::JEQ | Opcode::JNEQ | Opcode::JMPB | Opcode::JMP | Opcode::JMPF => {
+                write!(f, "{} ${}", mnemonic, a)
+            }
+            Opcode::PRTS => write!(f, "{} #{}", mnemonic, self.operand_u16(0)),
+            Opcode::NOP | Opcode::YIELD | Opcode::RET | Opcode::HLT | Opcode::IGL => {
+                f.write_str(&mnemonic)
+            }
+        }
     }
 }
 
+/// Decodes `bytes` the way the VM steps through them, pairing every instruction with
+/// its offset. Stops at an instruction that runs past the end.
+pub fn disassemble(bytes: &[u8]) -> Vec<(usize, Instruction)> {
+    let mut offset = 0;
+    let mut instructions = vec![];
+    while let Some(instruction) = Instruction::decode(&bytes[offset..]) {
+        instructions.push((offset, instruction));
+        offset += instruction.width();
+    }
+    instructions
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,11 +275,30 @@ mod tests {
     #[test]
     fn test_corresponding_enum_with_number() {
         let a: u8 = 200;
-        let b = a.try_into().unwrap_or(Opcode::IGL);
+        let b = Opcode::from(a);
 
         assert_eq!(b, IGL);
     }
 
+    #[test]
+    fn test_decode_instruction() {
+        let bytes = [Opcode::LOAD.into(), 1, 3, 232];
+        let instruction = Instruction::decode(&bytes).unwrap();
+        assert_eq!(instruction.opcode, Opcode::LOAD);
+        assert_eq!(instruction.register(0), 1);
+        assert_eq!(instruction.operand_u16(1), 1000);
+
+        // jumps only take the register byte
+        let bytes = [Opcode::JMP.into(), 2, 9, 9];
+        let instruction = Instruction::decode(&bytes).unwrap();
+        assert_eq!(instruction.width(), 2);
+        assert_eq!(instruction.operands, [2, 0, 0]);
+
+        let bytes = [Opcode::ADD.into(), 0];
+        assert!(Instruction::decode(&bytes).is_none());
+        assert!(Instruction::decode(&[]).is_none());
+    }
+
     #[test]
     fn test_str_to_opcode() {
         let opcode = Opcode::from(CompleteStr("load"));
@@ -155,4 +306,29 @@ mod tests {
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_disassemble() {
+        let bytes = [
+            Opcode::LOAD.into(),
+            1,
+            3,
+            232,
+            Opcode::ADD.into(),
+            0,
+            1,
+            2,
+            Opcode::JMP.into(),
+            2,
+            Opcode::HLT.into(),
+        ];
+        let text: Vec<String> = disassemble(&bytes)
+            .iter()
+            .map(|(offset, instruction)| format!("{} {}", offset, instruction))
+            .collect();
+        assert_eq!(
+            text,
+            ["0 load $1 #1000", "4 add $0 $1 $2", "8 jmp $2", "10 hlt"]
+        );
+    }
 }
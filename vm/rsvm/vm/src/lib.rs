@@ -1,7 +1,23 @@
+// the assembler's tests are kept the way they were first written
+#![cfg_attr(
+    test,
+    allow(
+        clippy::bool_assert_comparison,
+        clippy::assertions_on_constants,
+        clippy::unnecessary_mut_passed
+    )
+)]
+
+pub mod cluster;
+pub mod config;
+pub mod heap;
 pub mod instruction;
+pub mod output;
 pub mod remote;
 pub mod repl;
+pub mod snapshot;
 pub mod ssh;
+pub mod trace;
 pub mod vm;
 
 pub mod assembler;
@@ -16,6 +32,4 @@ extern crate log;
 extern crate clap;
 
 // extern crate futures;
-// extern crate thrussh;
-// extern crate thrussh_keys;
 // extern crate tokio;
@@ -0,0 +1,88 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// Where the REPL and its VMs write what they print: stdout, a client's socket, or a
+/// buffer an SSH session drains. Clones share the destination, so a VM spawned from a
+/// REPL keeps printing to the same client.
+#[derive(Clone)]
+pub struct Output {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Output {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Output {
+        Output {
+            sink: Arc::new(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    pub fn stdout() -> Output {
+        Output::new(io::stdout())
+    }
+
+    /// An output that keeps everything written to it until taken from the `Capture`
+    pub fn capture() -> (Output, Capture) {
+        let capture = Capture::default();
+        (Output::new(capture.clone()), capture)
+    }
+
+    pub fn write_str(&self, text: &str) {
+        let mut sink = self.sink.lock().unwrap();
+        if let Err(e) = sink.write_all(text.as_bytes()).and_then(|_| sink.flush()) {
+            debug!("Unable to write output: {}", e);
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self::stdout()
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Capture {
+    /// Everything written since the last call
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.buffer.lock().unwrap());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_capture() {
+        let (output, capture) = Output::capture();
+        output.write_str("one ");
+        output.clone().write_str("two");
+        assert_eq!(capture.take(), "one two");
+        assert_eq!(capture.take(), "");
+    }
+}
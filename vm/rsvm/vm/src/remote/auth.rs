@@ -0,0 +1,275 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Names the way passwords are hashed in the password file
+const KDF: &str = "pbkdf2-sha256";
+/// PBKDF2 rounds for new passwords, as recommended by OWASP. The count is stored with
+/// every hash, so raising it leaves old passwords working.
+const KDF_ITERATIONS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+
+/// How clients of the remote server prove who they are
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Auth {
+    /// No login, but only clients on this machine may connect
+    #[default]
+    LocalOnly,
+    /// Any user name, with a secret shared by everyone
+    Token(String),
+    /// `user:pbkdf2-sha256:iterations:salt:hash` lines, with salt and hash in hex.
+    /// Read on every login, so `add_user` takes effect straight away.
+    PasswordFile(PathBuf),
+}
+
+impl Auth {
+    pub fn requires_login(&self) -> bool {
+        !matches!(self, Auth::LocalOnly)
+    }
+
+    /// Whether a client connecting from `peer` may connect at all
+    pub fn allows_peer(&self, peer: IpAddr) -> bool {
+        self.requires_login() || peer.is_loopback()
+    }
+
+    pub fn check(&self, user: &str, secret: &str) -> bool {
+        match self {
+            Auth::LocalOnly => true,
+            Auth::Token(token) => constant_time_eq(token.as_bytes(), secret.as_bytes()),
+            Auth::PasswordFile(path) => match read_users(path) {
+                Ok(users) => match users.iter().find(|entry| entry.user == user) {
+                    Some(entry) => entry.matches(secret),
+                    // hash anyway, so an unknown user takes as long to turn away as a wrong password
+                    None => {
+                        hash_password(&[0; 16], secret, KDF_ITERATIONS);
+                        false
+                    }
+                },
+                Err(e) => {
+                    error!("Unable to read password file {}: {}", path.display(), e);
+                    false
+                }
+            },
+        }
+    }
+}
+
+/// Adds `user` to the password file, replacing their old password if they had one
+pub fn add_user(path: &Path, user: &str, password: &str) -> io::Result<()> {
+    if user.is_empty() || user.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "User names can't be empty or contain ':' or spaces",
+        ));
+    }
+    let mut users = match read_users(path) {
+        Ok(users) => users,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    users.retain(|entry| entry.user != user);
+
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    users.push(PasswordEntry {
+        user: user.to_string(),
+        iterations: KDF_ITERATIONS,
+        hash: hash_password(&salt, password, KDF_ITERATIONS),
+        salt: salt.to_vec(),
+    });
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // the hashes can be attacked offline, so only the owner may read them
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // files written before this was made private
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    let mut file = options.open(path)?;
+    for entry in users {
+        writeln!(
+            file,
+            "{}:{}:{}:{}:{}",
+            entry.user,
+            KDF,
+            entry.iterations,
+            hex(&entry.salt),
+            hex(&entry.hash)
+        )?;
+    }
+    Ok(())
+}
+
+/// A line of the password file
+struct PasswordEntry {
+    user: String,
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: [u8; 32],
+}
+
+impl PasswordEntry {
+    fn parse(line: &str) -> Option<PasswordEntry> {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        match fields[..] {
+            [user, KDF, iterations, salt, hash] => Some(PasswordEntry {
+                user: user.to_string(),
+                iterations: iterations.parse().ok().filter(|&n| n > 0)?,
+                salt: unhex(salt)?,
+                hash: unhex(hash)?.try_into().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        let hash = hash_password(&self.salt, password, self.iterations);
+        constant_time_eq(&hash, &self.hash)
+    }
+}
+
+fn read_users(path: &Path) -> io::Result<Vec<PasswordEntry>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let entry = PasswordEntry::parse(line);
+            if entry.is_none() {
+                warn!(
+                    "Ignoring a line of {} that isn't user:{}:iterations:salt:hash, add the user again",
+                    path.display(),
+                    KDF
+                );
+            }
+            entry
+        })
+        .collect())
+}
+
+/// PBKDF2 with HMAC-SHA256 (RFC 8018)
+fn hash_password(salt: &[u8], password: &str, iterations: u32) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), salt, iterations)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Doesn't give away how much of a guess was right by how long it takes to reject it
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let auth = Auth::Token("s3cret".to_string());
+        assert!(auth.check("anyone", "s3cret"));
+        assert!(!auth.check("anyone", "s3cre"));
+        assert!(!auth.check("anyone", ""));
+    }
+
+    #[test]
+    fn test_password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passwords");
+        let auth = Auth::PasswordFile(path.clone());
+        assert!(!auth.check("alice", "hunter2"));
+
+        add_user(&path, "alice", "hunter2").unwrap();
+        add_user(&path, "bob", "letmein").unwrap();
+        assert!(auth.check("alice", "hunter2"));
+        assert!(!auth.check("alice", "letmein"));
+        assert!(!auth.check("carol", "hunter2"));
+
+        // a new password replaces the old one
+        add_user(&path, "alice", "correct horse").unwrap();
+        assert!(!auth.check("alice", "hunter2"));
+        assert!(auth.check("alice", "correct horse"));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        assert!(add_user(&path, "mal:lory", "x").is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // the iteration count is read from the file, and old style lines don't log in
+        fs::write(
+            &path,
+            "carol:pbkdf2-sha256:2:73616c74:\
+             ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43\n\
+             dave:73616c74:0123456789abcdef\n",
+        )
+        .unwrap();
+        assert!(auth.check("carol", "password"));
+        assert!(!auth.check("carol", "passwore"));
+        assert!(!auth.check("dave", ""));
+    }
+
+    #[test]
+    fn test_pbkdf2() {
+        // the published PBKDF2-HMAC-SHA256 vectors for "password" and "salt"
+        let cases = [
+            (
+                1,
+                "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b",
+            ),
+            (
+                2,
+                "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43",
+            ),
+            (
+                4096,
+                "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a",
+            ),
+        ];
+        for (iterations, expected) in cases {
+            assert_eq!(
+                hex(&hash_password(b"salt", "password", iterations)),
+                expected
+            );
+        }
+        assert_eq!(unhex("00ff7f"), Some(vec![0, 255, 127]));
+        assert_eq!(unhex("0"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+
+    #[test]
+    fn test_local_only() {
+        let auth = Auth::LocalOnly;
+        assert!(auth.allows_peer("127.0.0.1".parse().unwrap()));
+        assert!(!auth.allows_peer("192.168.1.20".parse().unwrap()));
+        assert!(Auth::Token("t".into()).allows_peer("192.168.1.20".parse().unwrap()));
+    }
+}
@@ -1,8 +1,20 @@
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::TcpStream;
-use std::thread::{self};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
+use super::auth::Auth;
+use crate::output::Output;
 use crate::repl;
+use crate::vm::VMLimits;
+
+/// Tries a client gets before being disconnected
+pub(super) const LOGIN_ATTEMPTS: usize = 3;
+/// How long a client has to log in, all tries together. It holds one of the server's
+/// connections until then, so this is kept short.
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(15);
 
 pub struct Client {
     reader: BufReader<TcpStream>,
@@ -12,43 +24,75 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
-        // TODO: Handle this better
-        let reader = stream.try_clone().unwrap();
-        let writer = stream.try_clone().unwrap();
-        let repl = repl::REPL::new();
+    pub fn new(stream: TcpStream) -> io::Result<Client> {
+        let reader = stream.try_clone()?;
+        let writer = stream.try_clone()?;
+        let mut repl = repl::REPL::new();
+        repl.set_output(Output::new(stream.try_clone()?));
 
-        Client {
+        Ok(Client {
             reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
             raw_stream: stream,
-            repl: repl,
-        }
+            repl,
+        })
     }
 
-    pub fn run(&mut self) {
-        self.receive_loop();
-        let mut buf = String::new();
-        let banner = repl::REMOTE_BANNER.to_owned() + "\n" + repl::PROMPT;
-        self.w(&banner);
-        loop {
-            match self.reader.read_line(&mut buf) {
-                Ok(_) => {
-                    // Here check the trim_end will change it self or not
-                    // let cloned = buf.clone();
-                    let _ = buf.trim_end();
-                    // assert!(cloned != buf);
+    pub fn set_limits(&mut self, limits: VMLimits) {
+        self.repl.set_limits(limits);
+    }
 
-                    self.repl.run_single(&buf);
-                    buf.clear();
+    /// See `REPL::restrict_files`
+    pub fn set_snapshot_dir(&mut self, dir: Option<PathBuf>) {
+        self.repl.restrict_files(dir);
+    }
+
+    /// Asks for `login <user> <secret>` until one is accepted. Returns the user name,
+    /// or None when the client gave up, ran out of tries or took longer than `timeout`.
+    pub fn login(&mut self, auth: &Auth, timeout: Duration) -> Option<String> {
+        if !auth.requires_login() {
+            return Some("local".to_string());
+        }
+        // A read timeout would restart with every byte, so a client sending one now and
+        // then could keep its connection forever. Hang up on it at the deadline instead.
+        let stream = match self.raw_stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Unable to set login timeout: {}", e);
+                return None;
+            }
+        };
+        let (logged_in, deadline) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = deadline.recv_timeout(timeout) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+
+        self.w("Log in with: login <user> <password>\n");
+        for _ in 0..LOGIN_ATTEMPTS {
+            let mut buf = String::new();
+            match self.reader.read_line(&mut buf) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            // the password is the rest of the line, spaces and all
+            let args: Vec<&str> = buf.trim_end_matches(['\r', '\n']).splitn(3, ' ').collect();
+            if let ["login", user, secret] = args[..] {
+                if auth.check(user, secret) {
+                    drop(logged_in);
+                    return Some(user.to_string());
                 }
-                Err(e) => println!("Errror receiving: {:#?}", e),
             }
+            self.w("Login failed\n");
         }
+        None
     }
 
-    fn write_prompt(&mut self) {
-        self.w(repl::PROMPT);
+    /// Feeds lines to the REPL until the client sends `!quit` or goes away
+    pub fn run(&mut self) {
+        self.repl.run(&mut self.reader);
+        let _ = self.raw_stream.shutdown(Shutdown::Both);
     }
 
     fn w(&mut self, msg: &str) -> bool {
@@ -56,35 +100,14 @@ impl Client {
             Ok(_) => match self.writer.flush() {
                 Ok(_) => true,
                 Err(e) => {
-                    println!("Error flushing to client: {}", e);
+                    error!("Error flushing to client: {}", e);
                     false
                 }
             },
             Err(e) => {
-                println!("Error writing to client: {}", e);
+                error!("Error writing to client: {}", e);
                 false
             }
         }
     }
-
-    // currently read the msg from the sender of tx_pipe of repl (Message pipe from repl)
-    // THen do write/echo to client by tcp stream
-    fn receive_loop(&mut self) {
-        let rx = self.repl.rx_pipe.take();
-        let mut writer = self.raw_stream.try_clone().unwrap();
-        let t = thread::spawn(move || {
-            let chan = rx.unwrap();
-            loop {
-                match chan.recv() {
-                    Ok(msg) => {
-                        let _ = writer.write_all(msg.as_bytes());
-                        let _ = writer.flush();
-                    }
-                    Err(e) => {
-                        println!("Error: {:#?}", e);
-                    }
-                }
-            }
-        });
-    }
 }
@@ -1,9 +1,59 @@
-use super::client::Client;
-use std::{net::TcpListener, thread};
+use super::{
+    auth::Auth,
+    client::{Client, LOGIN_TIMEOUT},
+};
+use crate::vm::VMLimits;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// A logged in user
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRecord {
+    pub id: u64,
+    pub user: String,
+    pub peer: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// None until the client logged in
+    session: Option<SessionRecord>,
+}
+
+/// State shared between the accept loop, client threads and shutdown handles
+#[derive(Default)]
+struct Shared {
+    connections: Mutex<BTreeMap<u64, Connection>>,
+    next_id: AtomicU64,
+    shutting_down: AtomicBool,
+    local_addr: Mutex<Option<SocketAddr>>,
+}
 
 pub struct Server {
     bind_hostname: String,
     bind_port: String,
+    /// Applied to the VM of every connected client
+    pub limits: VMLimits,
+    pub auth: Auth,
+    /// Connections past this many are turned away, logged in or not
+    pub max_connections: Option<usize>,
+    /// Clients that haven't logged in by then are hung up on, freeing their connection
+    pub login_timeout: Duration,
+    /// The only place clients may load programs from and `!save` and `!restore` snapshots
+    pub snapshot_dir: Option<PathBuf>,
+    shared: Arc<Shared>,
 }
 
 impl Server {
@@ -11,19 +61,404 @@ impl Server {
         Server {
             bind_hostname,
             bind_port,
+            limits: VMLimits::default(),
+            auth: Auth::default(),
+            max_connections: None,
+            login_timeout: LOGIN_TIMEOUT,
+            snapshot_dir: None,
+            shared: Arc::new(Shared::default()),
         }
     }
 
-    pub fn listen(&mut self) {
-        println!("Initializing TCP server...");
+    pub fn listen(&mut self) -> io::Result<()> {
+        info!("Initializing TCP server...");
         let listener =
-            TcpListener::bind(self.bind_hostname.clone() + ":" + self.bind_port.as_str()).unwrap();
+            TcpListener::bind(self.bind_hostname.clone() + ":" + self.bind_port.as_str())?;
+        self.serve(listener);
+        Ok(())
+    }
+
+    /// Runs a client on its own thread for every connection to `listener`, until shut down
+    pub fn serve(&self, listener: TcpListener) {
+        *self.shared.local_addr.lock().unwrap() = listener.local_addr().ok();
+        if !self.auth.requires_login() {
+            warn!("Remote access has no login, only local clients can connect");
+        }
+
         for stream in listener.incoming() {
-            let stream = stream.unwrap();
-            thread::spawn(|| {
-                let mut client = Client::new(stream);
-                client.run();
+            if self.shared.shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Unable to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    error!("Unable to get address of client: {}", e);
+                    continue;
+                }
+            };
+            if !self.auth.allows_peer(peer.ip()) {
+                info!("Refused connection from {}, login is not set up", peer);
+                let _ = stream.write_all(b"Remote access is only open to local clients\n");
+                continue;
+            }
+            let id = match self.register(&stream) {
+                Some(id) => id,
+                None => {
+                    info!("Refused connection from {}, server is full", peer);
+                    let _ = stream.write_all(b"Server is full, try again later\n");
+                    continue;
+                }
+            };
+
+            let settings = ClientSettings {
+                limits: self.limits,
+                auth: self.auth.clone(),
+                login_timeout: self.login_timeout,
+                snapshot_dir: self.snapshot_dir.clone(),
+            };
+            let shared = self.shared.clone();
+            thread::spawn(move || {
+                let result = handle_client(stream, peer, id, settings, &shared);
+                if let Err(e) = result {
+                    error!("Connection from {} failed: {}", peer, e);
+                }
+                shared.connections.lock().unwrap().remove(&id);
             });
         }
     }
+
+    fn register(&self, stream: &TcpStream) -> Option<u64> {
+        let mut connections = self.shared.connections.lock().unwrap();
+        if self
+            .max_connections
+            .is_some_and(|max| connections.len() >= max)
+        {
+            return None;
+        }
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let stream = stream.try_clone().ok()?;
+        connections.insert(
+            id,
+            Connection {
+                stream,
+                session: None,
+            },
+        );
+        Some(id)
+    }
+
+    /// Everyone logged in right now
+    pub fn sessions(&self) -> Vec<SessionRecord> {
+        sessions(&self.shared)
+    }
+
+    /// Lets another thread stop the server once `listen` or `serve` took over this one
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+fn sessions(shared: &Shared) -> Vec<SessionRecord> {
+    shared
+        .connections
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(|connection| connection.session.clone())
+        .collect()
+}
+
+/// The server's settings a client thread needs, copied so it doesn't borrow the server
+struct ClientSettings {
+    limits: VMLimits,
+    auth: Auth,
+    login_timeout: Duration,
+    snapshot_dir: Option<PathBuf>,
+}
+
+fn handle_client(
+    stream: TcpStream,
+    peer: SocketAddr,
+    id: u64,
+    settings: ClientSettings,
+    shared: &Shared,
+) -> io::Result<()> {
+    let mut client = Client::new(stream)?;
+    client.set_limits(settings.limits);
+    client.set_snapshot_dir(settings.snapshot_dir);
+    let user = match client.login(&settings.auth, settings.login_timeout) {
+        Some(user) => user,
+        None => {
+            info!("{} did not log in", peer);
+            return Ok(());
+        }
+    };
+
+    info!("{} logged in from {}", user, peer);
+    if let Some(connection) = shared.connections.lock().unwrap().get_mut(&id) {
+        connection.session = Some(SessionRecord {
+            id,
+            user: user.clone(),
+            peer,
+            connected_at: Utc::now(),
+        });
+    }
+    client.run();
+    info!("{} from {} disconnected", user, peer);
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and hangs up on every client
+    pub fn shutdown(&self) {
+        self.shared.shutting_down.store(true, Ordering::SeqCst);
+        for connection in self.shared.connections.lock().unwrap().values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        // wake up the accept loop so it sees the flag
+        if let Some(mut addr) = *self.shared.local_addr.lock().unwrap() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(Ipv4Addr::LOCALHOST.into());
+            }
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    pub fn sessions(&self) -> Vec<SessionRecord> {
+        sessions(&self.shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{BufRead, BufReader},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::remote::client::LOGIN_ATTEMPTS;
+
+    fn start(server: Server) -> (u16, ShutdownHandle, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.serve(listener));
+        (port, handle, thread)
+    }
+
+    struct TestClient {
+        reader: BufReader<TcpStream>,
+        stream: TcpStream,
+    }
+
+    impl TestClient {
+        fn connect(port: u16) -> TestClient {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            TestClient {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+            }
+        }
+
+        fn send(&mut self, line: &str) {
+            self.stream.write_all(line.as_bytes()).unwrap();
+            self.stream.write_all(b"\n").unwrap();
+        }
+
+        fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line
+        }
+
+        /// Reads lines until one contains `text`, or the connection closes
+        fn wait_for(&mut self, text: &str) -> bool {
+            loop {
+                let line = self.line();
+                if line.is_empty() {
+                    return false;
+                }
+                if line.contains(text) {
+                    return true;
+                }
+            }
+        }
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_login_and_session_records() {
+        let mut server = Server::new("127.0.0.1".into(), "0".into());
+        server.auth = Auth::Token("s3cret".into());
+        let (port, handle, _) = start(server);
+
+        let mut client = TestClient::connect(port);
+        assert!(client.line().starts_with("Log in with"));
+        client.send("login alice wrong");
+        assert_eq!(client.line(), "Login failed\n");
+        assert!(handle.sessions().is_empty());
+
+        client.send("login alice s3cret");
+        assert!(client.wait_for(crate::repl::REMOTE_BANNER));
+        wait_until(|| handle.sessions().len() == 1);
+        assert_eq!(handle.sessions()[0].user, "alice");
+
+        client.send("!registers");
+        assert!(client.wait_for("Listing registers"));
+
+        client.send("!quit");
+        assert!(client.wait_for("Farewell"));
+        wait_until(|| handle.sessions().is_empty());
+    }
+
+    #[test]
+    fn test_too_many_failed_logins() {
+        let mut server = Server::new("127.0.0.1".into(), "0".into());
+        server.auth = Auth::Token("s3cret".into());
+        let (port, _, _) = start(server);
+
+        let mut client = TestClient::connect(port);
+        client.line();
+        for _ in 0..LOGIN_ATTEMPTS {
+            client.send("login alice guess");
+            assert_eq!(client.line(), "Login failed\n");
+        }
+        // hung up on
+        assert_eq!(client.line(), "");
+    }
+
+    #[test]
+    fn test_disconnect_ends_session() {
+        let server = Server::new("127.0.0.1".into(), "0".into());
+        let (port, handle, _) = start(server);
+
+        let mut client = TestClient::connect(port);
+        assert!(client.wait_for(crate::repl::REMOTE_BANNER));
+        wait_until(|| handle.sessions().len() == 1);
+        client.stream.shutdown(Shutdown::Both).unwrap();
+        wait_until(|| handle.sessions().is_empty());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut server = Server::new("127.0.0.1".into(), "0".into());
+        server.max_connections = Some(1);
+        let (port, handle, _) = start(server);
+
+        let mut first = TestClient::connect(port);
+        assert!(first.wait_for(crate::repl::REMOTE_BANNER));
+        let mut second = TestClient::connect(port);
+        assert_eq!(second.line(), "Server is full, try again later\n");
+
+        first.send("!quit");
+        wait_until(|| handle.sessions().is_empty());
+        let mut third = TestClient::connect(port);
+        assert!(third.wait_for(crate::repl::REMOTE_BANNER));
+    }
+
+    #[test]
+    fn test_login_timeout_frees_connection() {
+        let mut server = Server::new("127.0.0.1".into(), "0".into());
+        server.auth = Auth::Token("s3cret".into());
+        server.max_connections = Some(1);
+        server.login_timeout = Duration::from_millis(300);
+        let (port, _, _) = start(server);
+
+        let mut idle = TestClient::connect(port);
+        assert!(idle.line().starts_with("Log in with"));
+        let mut second = TestClient::connect(port);
+        assert_eq!(second.line(), "Server is full, try again later\n");
+
+        // sending something now and then doesn't put off the deadline
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(600) {
+            if idle.stream.write_all(b"l").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(idle.line(), "");
+        wait_until(|| {
+            let mut client = TestClient::connect(port);
+            client.line().starts_with("Log in with")
+        });
+    }
+
+    #[test]
+    fn test_files_outside_snapshot_dir_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = dir.path().join("snapshots");
+        fs::create_dir(&snapshots).unwrap();
+        fs::write(
+            snapshots.join("ok.iasm"),
+            ".data\n.code\nload $0 #42\nhlt\n",
+        )
+        .unwrap();
+        let secret = dir.path().join("secret.txt");
+        fs::write(&secret, "root:x:0:0\n").unwrap();
+
+        let mut server = Server::new("127.0.0.1".into(), "0".into());
+        server.snapshot_dir = Some(snapshots);
+        let (port, _, _) = start(server);
+        let mut client = TestClient::connect(port);
+        assert!(client.wait_for(crate::repl::REMOTE_BANNER));
+
+        for command in ["!load_file", "!spawn"] {
+            client.send(&format!("{} {}", command, secret.display()));
+            let line = client.line();
+            assert!(line.contains("Programs can only be named"), "{}", line);
+            client.send(&format!("{} ../secret.txt", command));
+            let line = client.line();
+            assert!(line.contains("Programs can only be named"), "{}", line);
+        }
+        client.send("!load_file ok.iasm");
+        client.send("!registers");
+        assert!(client.wait_for("$0  42"));
+
+        // without a snapshot directory there are no files to load
+        let (port, _, _) = start(Server::new("127.0.0.1".into(), "0".into()));
+        let mut client = TestClient::connect(port);
+        assert!(client.wait_for(crate::repl::REMOTE_BANNER));
+        client.send(&format!("!load_file {}", secret.display()));
+        let line = client.line();
+        assert!(line.contains("Programs are turned off"), "{}", line);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let server = Server::new("127.0.0.1".into(), "0".into());
+        let (port, handle, thread) = start(server);
+
+        let mut client = TestClient::connect(port);
+        assert!(client.wait_for(crate::repl::REMOTE_BANNER));
+        handle.shutdown();
+        thread.join().unwrap();
+        assert!(!client.wait_for("anything"));
+    }
 }
@@ -1,7 +1,308 @@
+use std::{collections::HashMap, fmt, ops::RangeInclusive};
+
+/// What a positional argument or flag value has to look like
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    /// Anything, e.g. a path. Quote it to include spaces.
+    Text,
+    /// A decimal number, or hexadecimal with `0x`
+    Number,
+    /// `$3` or `3`
+    Register,
+    /// A register, or an inclusive range of them like `0-7` or `$0-$7`
+    Registers,
+}
+
+impl ArgKind {
+    fn parse(self, arg: &str) -> Result<Value, String> {
+        match self {
+            ArgKind::Text => Ok(Value::Text(arg.to_string())),
+            ArgKind::Number => parse_number(arg)
+                .map(Value::Number)
+                .ok_or(format!("Expected a number, got {}", arg)),
+            ArgKind::Register => parse_register(arg)
+                .map(Value::Register)
+                .ok_or(format!("Expected a register like $3, got {}", arg)),
+            ArgKind::Registers => {
+                let range = match arg.split_once('-') {
+                    Some((first, last)) => parse_register(first)
+                        .zip(parse_register(last))
+                        .filter(|(first, last)| first <= last)
+                        .map(|(first, last)| first..=last),
+                    None => parse_register(arg).map(|register| register..=register),
+                };
+                range
+                    .map(Value::Registers)
+                    .ok_or(format!("Expected registers like 0-7, got {}", arg))
+            }
+        }
+    }
+}
+
+/// A positional argument of a command
+#[derive(Debug)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+}
+
+/// A `--flag` of a command, which takes a value unless `kind` is None
+#[derive(Debug, Clone, Copy)]
+pub struct Flag {
+    pub name: &'static str,
+    pub kind: Option<ArgKind>,
+    pub help: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(i64),
+    Register(usize),
+    Registers(RangeInclusive<usize>),
+    /// A flag that was given and takes no value
+    Set,
+}
+
+/// The arguments of one command line, checked against the command's params and flags
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    values: HashMap<&'static str, Value>,
+    /// The arguments as typed, for code that does its own parsing
+    raw: Vec<String>,
+}
+
+impl Args {
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.values.get(name) {
+            Some(Value::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<i64> {
+        match self.values.get(name) {
+            Some(Value::Number(number)) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn register(&self, name: &str) -> Option<usize> {
+        match self.values.get(name) {
+            Some(Value::Register(register)) => Some(*register),
+            _ => None,
+        }
+    }
+
+    pub fn registers(&self, name: &str) -> Option<RangeInclusive<usize>> {
+        match self.values.get(name) {
+            Some(Value::Registers(range)) => Some(range.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn is_set(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn raw(&self) -> Vec<&str> {
+        self.raw.iter().map(String::as_str).collect()
+    }
+}
+
+/// Why a command line couldn't be understood
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    UnterminatedQuote,
+    UnknownFlag(String),
+    MissingValue(&'static str),
+    MissingArgument(&'static str),
+    TooManyArguments,
+    Invalid { name: &'static str, reason: String },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::UnterminatedQuote => write!(f, "Missing closing quote"),
+            CommandError::UnknownFlag(flag) => write!(f, "Unknown flag {}", flag),
+            CommandError::MissingValue(flag) => write!(f, "Missing value for {}", flag),
+            CommandError::MissingArgument(name) => write!(f, "Missing argument <{}>", name),
+            CommandError::TooManyArguments => write!(f, "Too many arguments"),
+            CommandError::Invalid { name, reason } => write!(f, "Invalid {}: {}", name, reason),
+        }
+    }
+}
+
 pub struct CommandParser {}
 
 impl CommandParser {
-    pub fn tokenize(input: &str) -> Vec<&str> {
-        input.split_whitespace().collect()
+    /// Splits a line on whitespace, keeping anything in single or double quotes together.
+    /// A backslash escapes the next character inside double quotes.
+    pub fn tokenize(input: &str) -> Result<Vec<String>, CommandError> {
+        let mut tokens = vec![];
+        let mut token: Option<String> = None;
+        let mut chars = input.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' | '\'' => {
+                    let token = token.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some(close) if close == c => break,
+                            Some('\\') if c == '"' => {
+                                token.push(chars.next().ok_or(CommandError::UnterminatedQuote)?)
+                            }
+                            Some(other) => token.push(other),
+                            None => return Err(CommandError::UnterminatedQuote),
+                        }
+                    }
+                }
+                c if c.is_whitespace() => tokens.extend(token.take()),
+                c => token.get_or_insert_with(String::new).push(c),
+            }
+        }
+        tokens.extend(token);
+        Ok(tokens)
+    }
+
+    /// Matches `args` up with `params` in order, and with `flags` wherever they appear
+    pub fn parse(args: &[String], params: &[Param], flags: &[Flag]) -> Result<Args, CommandError> {
+        let mut parsed = Args {
+            values: HashMap::new(),
+            raw: args.to_vec(),
+        };
+        let mut params = params.iter();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                let flag = flags
+                    .iter()
+                    .find(|flag| flag.name == arg)
+                    .ok_or_else(|| CommandError::UnknownFlag(arg.clone()))?;
+                let value = match flag.kind {
+                    Some(kind) => {
+                        let value = args.next().ok_or(CommandError::MissingValue(flag.name))?;
+                        parse_value(flag.name, kind, value)?
+                    }
+                    None => Value::Set,
+                };
+                parsed.values.insert(flag.name, value);
+            } else {
+                let param = params.next().ok_or(CommandError::TooManyArguments)?;
+                parsed
+                    .values
+                    .insert(param.name, parse_value(param.name, param.kind, arg)?);
+            }
+        }
+        if let Some(param) = params.find(|param| param.required) {
+            return Err(CommandError::MissingArgument(param.name));
+        }
+        Ok(parsed)
+    }
+}
+
+fn parse_value(name: &'static str, kind: ArgKind, arg: &str) -> Result<Value, CommandError> {
+    kind.parse(arg)
+        .map_err(|reason| CommandError::Invalid { name, reason })
+}
+
+fn parse_number(arg: &str) -> Option<i64> {
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, arg),
+    };
+    let number = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative { -number } else { number })
+}
+
+fn parse_register(arg: &str) -> Option<usize> {
+    let register: usize = arg.strip_prefix('$').unwrap_or(arg).parse().ok()?;
+    (register < 32).then_some(register)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<String> {
+        CommandParser::tokenize(input).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokens("!load_file  a.iasm\n"), ["!load_file", "a.iasm"]);
+        assert_eq!(
+            tokens(r#"!load_file "my programs/a.iasm" 'it''s'"#),
+            ["!load_file", "my programs/a.iasm", "its"]
+        );
+        assert_eq!(
+            tokens(r#"!save "say \"hi\"" """#),
+            ["!save", "say \"hi\"", ""]
+        );
+        assert_eq!(
+            CommandParser::tokenize("!save \"oops"),
+            Err(CommandError::UnterminatedQuote)
+        );
+    }
+
+    const PARAMS: &[Param] = &[
+        Param {
+            name: "path",
+            kind: ArgKind::Text,
+            required: true,
+        },
+        Param {
+            name: "registers",
+            kind: ArgKind::Registers,
+            required: false,
+        },
+    ];
+    const FLAGS: &[Flag] = &[
+        Flag {
+            name: "--threads",
+            kind: Some(ArgKind::Number),
+            help: "",
+        },
+        Flag {
+            name: "--disasm",
+            kind: None,
+            help: "",
+        },
+    ];
+
+    fn parse(input: &str) -> Result<Args, CommandError> {
+        CommandParser::parse(&tokens(input), PARAMS, FLAGS)
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = parse("a.iasm --threads 0x10 $2-$7 --disasm").unwrap();
+        assert_eq!(args.text("path"), Some("a.iasm"));
+        assert_eq!(args.number("--threads"), Some(16));
+        assert_eq!(args.registers("registers"), Some(2..=7));
+        assert!(args.is_set("--disasm"));
+
+        let args = parse("a.iasm 31").unwrap();
+        assert_eq!(args.registers("registers"), Some(31..=31));
+        assert!(!args.is_set("--disasm"));
+
+        assert_eq!(parse(""), Err(CommandError::MissingArgument("path")));
+        assert_eq!(parse("a 1 2"), Err(CommandError::TooManyArguments));
+        assert_eq!(
+            parse("a --threads"),
+            Err(CommandError::MissingValue("--threads"))
+        );
+        assert_eq!(
+            parse("a --fast"),
+            Err(CommandError::UnknownFlag("--fast".into()))
+        );
+        assert!(parse("a 7-2").is_err());
+        assert!(parse("a 32").is_err());
+        assert!(parse("a --threads two").is_err());
     }
 }
@@ -0,0 +1,297 @@
+use super::{
+    command_parser::{ArgKind, Args, Flag, Param},
+    REPL,
+};
+
+/// A `!command` of the REPL: how it's called, what it's for, and the method that runs it
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub params: &'static [Param],
+    pub flags: &'static [Flag],
+    pub(super) run: fn(&mut REPL, &Args),
+}
+
+const PATH: Param = Param {
+    name: "path",
+    kind: ArgKind::Text,
+    required: true,
+};
+
+const PID: Param = Param {
+    name: "pid",
+    kind: ArgKind::Number,
+    required: true,
+};
+
+/// `--max-heap` and friends, as understood by `VMLimits::with_args`. They can only lower
+/// the limits the REPL was started with.
+const LIMIT_FLAGS: [Flag; 4] = [
+    Flag {
+        name: "--max-heap",
+        kind: Some(ArgKind::Number),
+        help: "Maximum number of heap bytes the program may allocate",
+    },
+    Flag {
+        name: "--max-instructions",
+        kind: Some(ArgKind::Number),
+        help: "Maximum number of instructions the program may execute",
+    },
+    Flag {
+        name: "--max-stack",
+        kind: Some(ArgKind::Number),
+        help: "Maximum depth the VM stack may grow to",
+    },
+    Flag {
+        name: "--max-output",
+        kind: Some(ArgKind::Number),
+        help: "Maximum number of bytes the program may print",
+    },
+];
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "!help",
+        usage: "!help [command]",
+        help: "List the commands, or explain one of them",
+        params: &[Param {
+            name: "command",
+            kind: ArgKind::Text,
+            required: false,
+        }],
+        flags: &[],
+        run: REPL::help,
+    },
+    Command {
+        name: "!mode",
+        usage: "!mode [hex|asm]",
+        help: "Switch between typing assembly and raw hex bytecode, or show which is on",
+        params: &[Param {
+            name: "mode",
+            kind: ArgKind::Text,
+            required: false,
+        }],
+        flags: &[],
+        run: REPL::mode,
+    },
+    Command {
+        name: "!quit",
+        usage: "!quit",
+        help: "End the session",
+        params: &[],
+        flags: &[],
+        run: REPL::quit,
+    },
+    Command {
+        name: "!history",
+        usage: "!history",
+        help: "List the lines typed so far",
+        params: &[],
+        flags: &[],
+        run: REPL::history,
+    },
+    Command {
+        name: "!program",
+        usage: "!program [--disasm]",
+        help: "List the bytes of the VM's program",
+        params: &[],
+        flags: &[Flag {
+            name: "--disasm",
+            kind: None,
+            help: "Show instructions instead of bytes",
+        }],
+        run: REPL::program,
+    },
+    Command {
+        name: "!registers",
+        usage: "!registers [first[-last]]",
+        help: "List the contents of the registers, all 32 unless given a range like 0-7",
+        params: &[Param {
+            name: "registers",
+            kind: ArgKind::Registers,
+            required: false,
+        }],
+        flags: &[],
+        run: REPL::registers,
+    },
+    Command {
+        name: "!clear_program",
+        usage: "!clear_program",
+        help: "Empty the VM's program",
+        params: &[],
+        flags: &[],
+        run: REPL::clear_program,
+    },
+    Command {
+        name: "!clear_registers",
+        usage: "!clear_registers",
+        help: "Set every register to 0",
+        params: &[],
+        flags: &[],
+        run: REPL::clear_registers,
+    },
+    Command {
+        name: "!symbols",
+        usage: "!symbols",
+        help: "List the assembler's symbol table",
+        params: &[],
+        flags: &[],
+        run: REPL::symbols,
+    },
+    Command {
+        name: "!heap",
+        usage: "!heap",
+        help: "Show how the VM's heap is used",
+        params: &[],
+        flags: &[],
+        run: REPL::heap,
+    },
+    Command {
+        name: "!load_file",
+        usage: "!load_file [path]",
+        help: "Assemble a program and run it on the REPL's VM, asking for the path if not given",
+        params: &[Param {
+            required: false,
+            ..PATH
+        }],
+        flags: &[],
+        run: REPL::load_file,
+    },
+    Command {
+        name: "!spawn",
+        usage: "!spawn [path] [--threads N] [--node ID] [--max-heap N] [--max-instructions N] [--max-stack N] [--max-output N]",
+        help: "Assemble a program and run it on new VMs in the background, asking for the path if not given. Limits can be lowered but not raised",
+        params: &[Param {
+            required: false,
+            ..PATH
+        }],
+        flags: &[
+            Flag {
+                name: "--threads",
+                kind: Some(ArgKind::Number),
+                help: "How many VMs to run the program on, 1 by default",
+            },
+            Flag {
+                name: "--node",
+                kind: Some(ArgKind::Text),
+                help: "Run it on this cluster node instead, given the start of its id",
+            },
+            LIMIT_FLAGS[0],
+            LIMIT_FLAGS[1],
+            LIMIT_FLAGS[2],
+            LIMIT_FLAGS[3],
+        ],
+        run: REPL::spawn,
+    },
+    Command {
+        name: "!nodes",
+        usage: "!nodes",
+        help: "List the cluster nodes this one is connected to",
+        params: &[],
+        flags: &[],
+        run: REPL::nodes,
+    },
+    Command {
+        name: "!ps",
+        usage: "!ps",
+        help: "List the processes started by !spawn",
+        params: &[],
+        flags: &[],
+        run: REPL::ps,
+    },
+    Command {
+        name: "!kill",
+        usage: "!kill <pid>",
+        help: "Stop a process before its next instruction",
+        params: &[PID],
+        flags: &[],
+        run: REPL::kill,
+    },
+    Command {
+        name: "!pause",
+        usage: "!pause <pid>",
+        help: "Stop a process before its next instruction until !resume",
+        params: &[PID],
+        flags: &[],
+        run: REPL::pause,
+    },
+    Command {
+        name: "!resume",
+        usage: "!resume <pid>",
+        help: "Let a paused process carry on",
+        params: &[PID],
+        flags: &[],
+        run: REPL::resume,
+    },
+    Command {
+        name: "!break",
+        usage: "!break [offset] [--clear]",
+        help: "Pause processes spawned afterwards when they reach the instruction at an offset from !program --disasm, or list the breakpoints",
+        params: &[Param {
+            name: "offset",
+            kind: ArgKind::Number,
+            required: false,
+        }],
+        flags: &[Flag {
+            name: "--clear",
+            kind: None,
+            help: "Remove the breakpoint instead",
+        }],
+        run: REPL::breakpoint,
+    },
+    Command {
+        name: "!watch",
+        usage: "!watch [on|off]",
+        help: "Print every event of the VM and of spawned processes as a line of JSON, or show whether that's on",
+        params: &[Param {
+            name: "state",
+            kind: ArgKind::Text,
+            required: false,
+        }],
+        flags: &[],
+        run: REPL::watch,
+    },
+    Command {
+        name: "!wait",
+        usage: "!wait <pid>",
+        help: "Wait for a process to stop, then list its events and forget it",
+        params: &[PID],
+        flags: &[],
+        run: REPL::wait,
+    },
+    Command {
+        name: "!events",
+        usage: "!events <pid> [--json]",
+        help: "List the events of a process so far",
+        params: &[PID],
+        flags: &[Flag {
+            name: "--json",
+            kind: None,
+            help: "One line of JSON per event",
+        }],
+        run: REPL::events,
+    },
+    Command {
+        name: "!save",
+        usage: "!save <path>",
+        help: "Write a snapshot of the VM to a file",
+        params: &[PATH],
+        flags: &[],
+        run: REPL::save,
+    },
+    Command {
+        name: "!restore",
+        usage: "!restore <path>",
+        help: "Replace the VM with one read back from a snapshot file",
+        params: &[PATH],
+        flags: &[],
+        run: REPL::restore,
+    },
+];
+
+/// The command called `name`, with or without its `!`
+pub fn find(name: &str) -> Option<&'static Command> {
+    let name = name.strip_prefix('!').unwrap_or(name);
+    COMMANDS.iter().find(|command| &command.name[1..] == name)
+}
@@ -0,0 +1,97 @@
+use rustyline::{
+    completion::Completer, highlight::Highlighter, hint::Hinter, validate::Validator, Context,
+    Helper,
+};
+
+use super::commands::COMMANDS;
+use crate::instruction::Opcode;
+
+/// Tab completion for the local REPL: `!commands`, mnemonics at the start of a line
+/// and labels from the assembler's symbol table everywhere else
+#[derive(Debug, Default)]
+pub struct EditorHelper {
+    /// Refreshed by the REPL before every line, as assembling may define new ones
+    pub labels: Vec<String>,
+}
+
+impl EditorHelper {
+    /// Everything `word` could be completed to, given whether it starts the line
+    pub fn candidates(&self, word: &str, first: bool) -> Vec<String> {
+        if word.starts_with('!') {
+            return COMMANDS
+                .iter()
+                .filter(|command| command.name.starts_with(word))
+                .map(|command| command.name.to_string())
+                .collect();
+        }
+        if let Some(label) = word.strip_prefix('@') {
+            return self
+                .labels
+                .iter()
+                .filter(|name| name.starts_with(label))
+                .map(|name| format!("@{}", name))
+                .collect();
+        }
+        if !first {
+            return vec![];
+        }
+        let upper = word.to_uppercase();
+        Opcode::MNEMONICS
+            .iter()
+            .filter(|mnemonic| mnemonic.starts_with(&upper))
+            .map(|mnemonic| mnemonic.to_lowercase())
+            .collect()
+    }
+}
+
+impl Completer for EditorHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map_or(0, |space| space + 1);
+        // a label definition like `loop: inc $0` still has its mnemonic first
+        let before = line[..start].trim();
+        let first = before.is_empty() || (before.ends_with(':') && !before.contains(' '));
+        Ok((start, self.candidates(&line[start..pos], first)))
+    }
+}
+
+impl Hinter for EditorHelper {
+    type Hint = String;
+}
+
+impl Highlighter for EditorHelper {}
+
+impl Validator for EditorHelper {}
+
+impl Helper for EditorHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let helper = EditorHelper {
+            labels: vec!["loop".to_string(), "hello".to_string()],
+        };
+        assert_eq!(
+            helper.candidates("!cl", true),
+            ["!clear_program", "!clear_registers"]
+        );
+        assert_eq!(helper.candidates("lo", true), ["load"]);
+        assert_eq!(
+            helper.candidates("J", true),
+            ["jeq", "jneq", "jmpb", "jmp", "jmpf"]
+        );
+        assert_eq!(helper.candidates("@l", false), ["@loop"]);
+        assert!(helper.candidates("lo", false).is_empty());
+    }
+}
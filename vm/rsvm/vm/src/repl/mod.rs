@@ -1,26 +1,61 @@
 pub mod command_parser;
+pub mod commands;
+pub mod editor;
 
 use std::{
-    fmt::format,
-    fs::File,
-    io::{self, Read, Write},
+    env, fs,
+    io::{self, BufRead, IsTerminal},
     num::ParseIntError,
-    path::Path,
-    sync::mpsc,
-    sync::mpsc::{Receiver, Sender},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
+use rustyline::{error::ReadlineError, history::DefaultHistory, Editor};
+use uuid::Uuid;
+
 use crate::{
-    assembler::{program_parser::program, Assembler},
+    assembler::{program_parser::program, symbol::SymbolType, Assembler, PIE_HEADER_PREFIX},
+    cluster::{ClusterConfig, Node},
+    config::find_file,
+    instruction::{disassemble, Instruction, Opcode},
+    output::Output,
     scheduler::Scheduler,
-    vm::VM,
+    vm::{VMEvent, VMLimits, VM},
 };
 
-use self::command_parser::CommandParser;
+use self::{
+    command_parser::{Args, CommandParser},
+    editor::EditorHelper,
+};
 
-pub static REMOTE_BANNER: &'static str = "Welcome to Irdium! Let's be productive!";
-pub static PROMPT: &'static str = "it> ";
-pub static COMMAND_PREFIX: &'static str = "!";
+pub static REMOTE_BANNER: &str = "Welcome to Irdium! Let's be productive!";
+pub static PROMPT: &str = "it> ";
+pub static COMMAND_PREFIX: &str = "!";
+static LOAD_PROMPT: &str = "Enter the path to the file you want to load: ";
+/// Shown while a `.data`/`.code` block is being typed or pasted
+static BLOCK_PROMPT: &str = "... ";
+static HEX_PROMPT: &str = "it(hex)> ";
+
+/// How the REPL reads lines that aren't commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Assembly, one instruction per line
+    Asm,
+    /// Raw bytecode as hex bytes, like `00 01 03 E8`, each instruction as wide as its opcode
+    Hex,
+}
+
+/// A command waiting for the user to answer its prompt on the next line
+#[derive(Debug)]
+enum Pending {
+    LoadFile,
+    Spawn {
+        limits: VMLimits,
+        threads: usize,
+        /// A peer to run the program on instead of this VM
+        node: Option<Uuid>,
+    },
+}
 
 #[derive(Debug)]
 pub struct REPL {
@@ -28,9 +63,37 @@ pub struct REPL {
     vm: VM,
     asm: Assembler,
     scheduler: Scheduler,
+    /// Applied to the REPL's VM and, unless overridden, to spawned ones
+    limits: VMLimits,
+    /// Everything the REPL and its VMs print goes here
+    output: Output,
+    pending: Option<Pending>,
+    /// Lines of a program that started with a section directive, run once a blank line ends it
+    block: Option<String>,
+    /// Set by `!quit`
+    quitting: bool,
+    mode: Mode,
+    /// Where `!watch` streams events to while it's on
+    watch: Arc<Mutex<Option<Output>>>,
+    /// Set once the REPL joined a cluster
+    cluster: Option<Node>,
+    /// Searched for files to load that aren't in the current directory
+    include_paths: Vec<PathBuf>,
+    /// Which files `!load_file`, `!spawn`, `!save` and `!restore` may use
+    files: FileAccess,
+}
 
-    pub tx_pipe: Option<Box<Sender<String>>>,
-    pub rx_pipe: Option<Box<Receiver<String>>>,
+/// Which files the REPL's commands may read and write. Remote and SSH users must not
+/// get at every file the server can.
+#[derive(Debug, Clone, Default, PartialEq)]
+enum FileAccess {
+    /// Any path, for a REPL on the local terminal
+    #[default]
+    Anywhere,
+    /// Only files directly in the directory, by name
+    In(PathBuf),
+    /// None, for a remote REPL without a snapshot directory
+    Nowhere,
 }
 
 impl Default for REPL {
@@ -41,267 +104,1124 @@ impl Default for REPL {
 
 impl REPL {
     pub fn new() -> REPL {
-        let (tx, rx): (Sender<_>, Receiver<_>) = mpsc::channel();
-        REPL {
+        let mut repl = REPL {
             vm: VM::new(),
             command_buffer: vec![],
             asm: Assembler::new(),
             scheduler: Scheduler::new(),
-            tx_pipe: Some(Box::new(tx)),
-            rx_pipe: Some(Box::new(rx)),
+            limits: VMLimits::default(),
+            output: Output::stdout(),
+            pending: None,
+            block: None,
+            quitting: false,
+            mode: Mode::Asm,
+            watch: Arc::new(Mutex::new(None)),
+            cluster: None,
+            include_paths: vec![],
+            files: FileAccess::default(),
+        };
+        repl.watch_vm();
+        repl
+    }
+
+    pub fn set_include_paths(&mut self, include_paths: Vec<PathBuf>) {
+        self.include_paths = include_paths;
+    }
+
+    /// Keeps the programs and snapshots the REPL reads and writes to files named directly
+    /// in `dir`, or turns files off without one. For REPLs whose users shouldn't reach the
+    /// rest of the server's files.
+    pub fn restrict_files(&mut self, dir: Option<PathBuf>) {
+        self.files = match dir {
+            Some(dir) => FileAccess::In(dir),
+            None => FileAccess::Nowhere,
+        };
+    }
+
+    /// Where the file at `path` is, if the REPL may use it. `what` names the files for
+    /// the error message, like `Snapshots`.
+    fn file_path(&self, path: &str, what: &str) -> Result<PathBuf, String> {
+        match &self.files {
+            FileAccess::Anywhere => Ok(PathBuf::from(path)),
+            FileAccess::In(dir) => {
+                let mut components = Path::new(path).components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(name)), None) => Ok(dir.join(name)),
+                    _ => Err(format!(
+                        "{} can only be named, not given a path like {}",
+                        what, path
+                    )),
+                }
+            }
+            FileAccess::Nowhere => Err(format!(
+                "{} are turned off here, set snapshot_dir to allow them",
+                what
+            )),
         }
     }
 
-    pub fn run(&mut self) -> ! {
-        println!("{}", REMOTE_BANNER.to_string());
+    /// Makes this REPL a cluster node, named after its VM, that `!spawn --node` can ship
+    /// programs from
+    pub fn join_cluster(&mut self, config: ClusterConfig) -> io::Result<()> {
+        self.cluster = Some(Node::start(self.vm.id(), config)?);
+        Ok(())
+    }
+
+    /// Streams the events of the REPL's VM, and of every process forked off it, to `!watch`
+    fn watch_vm(&mut self) {
+        let watch = self.watch.clone();
+        self.vm.subscribe(move |event| {
+            if let Some(output) = watch.lock().unwrap().as_ref() {
+                output.write_str(&format!("{}\n", event_json(event)));
+            }
+        });
+    }
+
+    /// Sends the REPL's messages and the output of its programs to `output` instead of stdout
+    pub fn set_output(&mut self, output: Output) {
+        self.vm.set_output(output.clone());
+        self.output = output;
+    }
+
+    pub fn set_limits(&mut self, limits: VMLimits) {
+        self.limits = limits;
+        self.vm.set_limits(limits);
+    }
 
-        let stdin = io::stdin();
+    /// Reads lines from `input` until it runs dry or the user quits
+    pub fn run<R: BufRead>(&mut self, mut input: R) {
+        self.send_message(REMOTE_BANNER.to_string());
         loop {
+            self.output.write_str(self.prompt());
             let mut buffer = String::new();
+            match input.read_line(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Unable to read line from user: {}", e);
+                    break;
+                }
+            }
+            if self.run_single(&buffer) {
+                break;
+            }
+        }
+    }
+
+    /// Like `run` on stdin, but with line editing, tab completion and the history in
+    /// `history_file` when stdin is a terminal
+    pub fn run_interactive(&mut self, history_file: Option<&Path>) {
+        if !io::stdin().is_terminal() {
+            return self.run(io::stdin().lock());
+        }
+        let mut editor = match Editor::<EditorHelper, DefaultHistory>::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                error!("Unable to start line editor: {}", e);
+                return self.run(io::stdin().lock());
+            }
+        };
+        editor.set_helper(Some(EditorHelper::default()));
+        if let Some(path) = history_file {
+            // there is no history yet the first time round
+            if editor.load_history(path).is_ok() {
+                self.command_buffer
+                    .extend(editor.history().iter().map(|line| format!("{}\n", line)));
+            }
+        }
 
-            print!("{}", PROMPT.to_string());
-            io::stdout().flush().expect("Unable to flush stdout");
+        self.send_message(REMOTE_BANNER.to_string());
+        loop {
+            if let Some(helper) = editor.helper_mut() {
+                helper.labels = self.labels();
+            }
+            let line = match editor.readline(self.prompt()) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => {
+                    self.cancel();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    error!("Unable to read line from user: {}", e);
+                    break;
+                }
+            };
+            if !line.trim().is_empty() {
+                let _ = editor.add_history_entry(line.as_str());
+            }
+            let quit = self.run_single(&format!("{}\n", line));
+            if let Some(path) = history_file {
+                if let Err(e) = save_history(&mut editor, path) {
+                    warn!("Unable to save history to {}: {}", path.display(), e);
+                }
+            }
+            if quit {
+                break;
+            }
+        }
+    }
+
+    /// What to show the user before they type the next line
+    pub fn prompt(&self) -> &'static str {
+        if self.pending.is_some() {
+            LOAD_PROMPT
+        } else if self.block.is_some() {
+            BLOCK_PROMPT
+        } else if self.mode == Mode::Hex {
+            HEX_PROMPT
+        } else {
+            PROMPT
+        }
+    }
 
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
+    /// Drops a half typed block or a prompt waiting for an answer, as on ^C
+    pub fn cancel(&mut self) {
+        self.pending = None;
+        self.block = None;
+    }
 
-            let history_copy = buffer.clone();
-            self.command_buffer.push(history_copy);
+    /// Names of the labels the assembler knows about
+    pub fn labels(&self) -> Vec<String> {
+        self.asm
+            .symbols
+            .symbols
+            .iter()
+            .filter(|symbol| *symbol.symbol_type() == SymbolType::Label)
+            .map(|symbol| symbol.name().to_string())
+            .collect()
+    }
 
-            if buffer.starts_with(COMMAND_PREFIX) {
-                self.execute_command(&buffer);
+    /// Runs one line of input. True when the user asked to quit, which is up to the
+    /// caller to act on, as it only ends their session.
+    pub fn run_single(&mut self, buffer: &str) -> bool {
+        if let Some(pending) = self.pending.take() {
+            self.answer(pending, buffer.trim());
+            return false;
+        }
+        if let Some(block) = &mut self.block {
+            if buffer.trim().is_empty() {
+                let block = self.block.take().unwrap();
+                self.command_buffer.push(block.clone());
+                self.run_source(&block, Pending::LoadFile);
             } else {
-                let program = match program(nom::types::CompleteStr(&buffer)) {
-                    Ok((_, program)) => program,
-                    Err(_) => {
-                        self.send_message(format!("Unable to  parse input"));
-                        continue;
-                    }
-                };
-                self.vm
-                    .program
-                    .append(&mut program.to_bytes(&self.asm.symbols));
-                self.vm.run_once();
+                block.push_str(buffer);
             }
+            return false;
         }
-    }
+        let section =
+            buffer.trim_start().starts_with(".data") || buffer.trim_start().starts_with(".code");
+        if section && self.mode == Mode::Asm {
+            self.block = Some(buffer.to_string());
+            return false;
+        }
+        self.command_buffer.push(buffer.to_string());
 
-    pub fn run_single(&mut self, buffer: &str) -> Option<String> {
         if buffer.starts_with(COMMAND_PREFIX) {
-            self.execute_command(&buffer);
-            return None;
+            return self.execute_command(buffer);
+        }
+        if self.mode == Mode::Hex {
+            self.run_hex(buffer);
+            return false;
         }
 
-        match program(nom::types::CompleteStr(&buffer)) {
-            Ok((_, program)) => {
-                let mut bytes = program.to_bytes(&self.asm.symbols);
-                self.vm.program.append(&mut bytes);
-                self.vm.run_once();
-                None
-            }
+        match program(nom::types::CompleteStr(buffer)) {
+            Ok((_, program)) => match program.to_bytes(&self.asm.symbols) {
+                Ok(mut bytes) => {
+                    self.vm.program.append(&mut bytes);
+                    self.vm.run_once();
+                }
+                Err(e) => self.send_message(format!("Unable to assemble input: {}", e)),
+            },
             Err(e) => {
                 self.send_message(format!("Unable to  parse input : {:?}", e));
-                None
             }
         }
+        false
     }
 
-    fn execute_command(&mut self, input: &str) {
-        let args = CommandParser::tokenize(input);
-        match args[0] {
-            "!quit" => self.quit(&args[1..]),
-            "!history" => self.history(&args[1..]),
-            "!program" => self.program(&args[1..]),
-            "!registers" => self.registers(&args[1..]),
-            "!clear_program" => self.clear_program(&args[1..]),
-            "!clear_registers" => self.clear_registers(&args[1..]),
-            "!symbols" => self.symbols(&args[1..]),
-            "!load_file" => self.load_file(&args[1..]),
-            "!spawn" => self.spawn(&args[1..]),
-            _ => println!("Invalid Command!"),
+    fn execute_command(&mut self, input: &str) -> bool {
+        let tokens = match CommandParser::tokenize(input) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                self.send_message(e.to_string());
+                return false;
+            }
+        };
+        let command = match commands::find(&tokens[0]) {
+            Some(command) => command,
+            None => {
+                self.send_message("Invalid Command! Try !help".to_string());
+                return false;
+            }
+        };
+        match CommandParser::parse(&tokens[1..], command.params, command.flags) {
+            Ok(args) => (command.run)(self, &args),
+            Err(e) => self.send_message(format!("{}\nUsage: {}", e, command.usage)),
         }
+        std::mem::take(&mut self.quitting)
     }
 
-    fn quit(&mut self, _args: &[&str]) {
-        self.send_message(format!("Farewell! Have a great day!"));
-        std::process::exit(0);
+    /// !help [command]
+    fn help(&mut self, args: &Args) {
+        let name = match args.text("command") {
+            Some(name) => name,
+            None => {
+                let width = commands::COMMANDS
+                    .iter()
+                    .map(|command| command.name.len())
+                    .max()
+                    .unwrap_or(0);
+                for command in commands::COMMANDS {
+                    self.send_message(format!("{:width$}  {}", command.name, command.help));
+                }
+                return;
+            }
+        };
+        let command = match commands::find(name) {
+            Some(command) => command,
+            None => {
+                self.send_message(format!("No such command: {}", name));
+                return;
+            }
+        };
+        self.send_message(format!("Usage: {}\n{}", command.usage, command.help));
+        for flag in command.flags {
+            self.send_message(format!("  {}  {}", flag.name, flag.help));
+        }
     }
 
-    fn history(&mut self, _args: &[&str]) {
+    /// !mode [hex|asm]
+    fn mode(&mut self, args: &Args) {
+        match args.text("mode") {
+            Some("hex") => self.mode = Mode::Hex,
+            Some("asm") => self.mode = Mode::Asm,
+            Some(other) => {
+                self.send_message(format!("Unknown mode {}, use hex or asm", other));
+                return;
+            }
+            None => {}
+        }
+        let mode = match self.mode {
+            Mode::Asm => "asm",
+            Mode::Hex => "hex",
+        };
+        self.send_message(format!("Mode: {}", mode));
+    }
+
+    fn quit(&mut self, _args: &Args) {
+        self.send_message("Farewell! Have a great day!".to_string());
+        self.quitting = true;
+    }
+
+    fn history(&mut self, _args: &Args) {
         let mut results = vec![];
         for command in &self.command_buffer {
             results.push(command.clone());
         }
         self.send_message(format!("{:#?}", results));
-        self.send_prompt();
     }
 
-    fn program(&mut self, _args: &[&str]) {
-        self.send_message(format!(
-            "Listing instructions currently in VM's program vector:"
-        ));
-        let mut results = vec![];
-        for instruction in &self.vm.program {
-            results.push(instruction.clone())
+    /// !program [--disasm]
+    fn program(&mut self, args: &Args) {
+        self.send_message("Listing instructions currently in VM's program vector:".to_string());
+        if args.is_set("--disasm") {
+            let program = &self.vm.program;
+            let start = if program.starts_with(&PIE_HEADER_PREFIX) {
+                self.vm.code_offset()
+            } else {
+                0
+            };
+            let mut listing = String::new();
+            for (offset, instruction) in disassemble(program.get(start..).unwrap_or(&[])) {
+                listing += &format!("{:04}: {}\n", start + offset, instruction);
+            }
+            self.output.write_str(&listing);
+        } else {
+            let mut results = vec![];
+            for instruction in &self.vm.program {
+                results.push(*instruction)
+            }
+            self.send_message(format!("{:#?}", results));
         }
-        self.send_message(format!("{:#?}", results));
-        self.send_message(format!("End of Program Listing"));
-        self.send_prompt();
+        self.send_message("End of Program Listing".to_string());
     }
 
-    fn registers(&mut self, _args: &[&str]) {
-        self.send_message(format!("Listing registers and all contents:"));
-        let mut results = vec![];
-        for register in &self.vm.registers {
-            results.push(register.clone());
+    /// !registers [first[-last]]
+    fn registers(&mut self, args: &Args) {
+        let range = args
+            .registers("registers")
+            .unwrap_or(0..=self.vm.registers.len() - 1);
+        self.send_message("Listing registers and all contents:".to_string());
+        let mut listing = String::new();
+        for register in range {
+            listing += &format!("${:<2} {}\n", register, self.vm.registers[register]);
         }
-        self.send_message(format!("{:#?}", results));
-        self.send_message(format!("End of Register Listing"));
-        self.send_prompt();
+        self.output.write_str(&listing);
+        self.send_message("End of Register Listing".to_string());
     }
 
-    fn clear_program(&mut self, _args: &[&str]) {
-        self.send_message(format!("Clearing all program.."));
+    fn clear_program(&mut self, _args: &Args) {
+        self.send_message("Clearing all program..".to_string());
         self.vm.program.clear();
-        self.send_message(format!("Done!"));
-        self.send_prompt();
+        self.send_message("Done!".to_string());
     }
 
-    fn clear_registers(&mut self, _args: &[&str]) {
-        self.send_message(format!("Setting all registers to 0"));
+    fn clear_registers(&mut self, _args: &Args) {
+        self.send_message("Setting all registers to 0".to_string());
         for i in 0..self.vm.registers.len() {
             self.vm.registers[i] = 0;
         }
-        self.send_message(format!("Done!"));
-        self.send_prompt();
+        self.send_message("Done!".to_string());
     }
 
-    fn symbols(&mut self, _args: &[&str]) {
+    fn symbols(&mut self, _args: &Args) {
         let mut results = vec![];
         for symbol in &self.asm.symbols.symbols {
             results.push(symbol.clone());
         }
-        self.send_message(format!("Listing symbols table:"));
+        self.send_message("Listing symbols table:".to_string());
         self.send_message(format!("{:#?}", results));
-        self.send_message(format!("End of Symbols Listing"));
-        self.send_prompt();
-    }
-
-    fn load_file(&mut self, _args: &[&str]) {
-        let raw_content = self.get_data_from_load();
-        match raw_content {
-            Some(raw_content) => {
-                let contents = self.asm.assemble(&raw_content);
-                match contents {
-                    Ok(mut assembled_program) => {
-                        self.send_message(format!("Sending assembled program to VM"));
-                        self.vm.program.append(&mut assembled_program);
-                        self.vm.run();
-                    }
-                    Err(errors) => {
-                        for error in errors {
-                            self.send_message(format!("Unable to parse input: {}", error));
-                            self.send_prompt();
+        self.send_message("End of Symbols Listing".to_string());
+    }
+
+    fn heap(&mut self, _args: &Args) {
+        self.send_message(self.vm.heap_stats().to_string());
+    }
+
+    /// !load_file [path]
+    fn load_file(&mut self, args: &Args) {
+        match args.text("path") {
+            Some(path) => self.answer(Pending::LoadFile, path),
+            None => self.pending = Some(Pending::LoadFile),
+        }
+    }
+
+    /// !spawn [path] [--threads N] [--max-heap N] [--max-instructions N] [--max-stack N] [--max-output N]
+    fn spawn(&mut self, args: &Args) {
+        let limits = match self.limits.with_args(&args.raw()) {
+            Ok(limits) => limits,
+            Err(e) => {
+                self.send_message(e);
+                return;
+            }
+        };
+        let threads = match args.number("--threads").unwrap_or(1) {
+            threads @ 1..=64 => threads as usize,
+            threads => {
+                self.send_message(format!("Can spawn 1 to 64 threads, not {}", threads));
+                return;
+            }
+        };
+        let node = match (args.text("--node"), &self.cluster) {
+            (Some(prefix), Some(cluster)) => match cluster.find_peer(prefix) {
+                Ok(node) => Some(node),
+                Err(e) => {
+                    self.send_message(e.to_string());
+                    return;
+                }
+            },
+            (Some(_), None) => {
+                self.send_message("Not in a cluster, start with --cluster-bind".to_string());
+                return;
+            }
+            (None, _) => None,
+        };
+        let pending = Pending::Spawn {
+            limits,
+            threads,
+            node,
+        };
+        match args.text("path") {
+            Some(path) => self.answer(pending, path),
+            None => self.pending = Some(pending),
+        }
+    }
+
+    /// Finishes a command that was waiting for a file path
+    fn answer(&mut self, pending: Pending, path: &str) {
+        if let Some(contents) = self.get_data_from_load(path) {
+            self.run_source(&contents, pending);
+        }
+    }
+
+    /// Assembles a whole program and runs it here, or on a new thread for `!spawn`
+    fn run_source(&mut self, source: &str, pending: Pending) {
+        let mut assembled_program = match self.asm.assemble(source) {
+            Ok(assembled_program) => assembled_program,
+            Err(errors) => {
+                for error in errors {
+                    self.send_message(format!("Unable to parse input: {}", error));
+                }
+                return;
+            }
+        };
+        if let Pending::Spawn {
+            limits,
+            threads,
+            node: Some(node),
+        } = pending
+        {
+            self.spawn_on_node(node, assembled_program, limits, threads);
+            return;
+        }
+        self.send_message("Sending assembled program to VM".to_string());
+        self.vm.program.append(&mut assembled_program);
+        match pending {
+            Pending::LoadFile => {
+                self.vm.run();
+            }
+            Pending::Spawn {
+                limits, threads, ..
+            } => {
+                for _ in 0..threads {
+                    let mut vm = self.vm.fork();
+                    vm.set_limits(limits);
+                    match self.scheduler.spawn(vm) {
+                        Some(pid) => self.send_message(format!("Started process {}", pid)),
+                        None => {
+                            self.send_message("Too many processes".to_string());
+                            break;
                         }
                     }
                 }
             }
-            None => {}
         }
     }
 
-    fn spawn(&mut self, _args: &[&str]) {
-        let contents = self.get_data_from_load();
-        if contents.is_none() {
-            return;
+    /// Ships a program to a peer `threads` times, printing its events as they come back
+    fn spawn_on_node(&mut self, node: Uuid, program: Vec<u8>, limits: VMLimits, threads: usize) {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => return,
+        };
+        let mut messages = vec![];
+        for _ in 0..threads {
+            let output = self.output.clone();
+            let on_event = move |event: &VMEvent| {
+                output.write_str(&format!("{}\n", event_json(event)));
+            };
+            match cluster.spawn(node, program.clone(), limits, on_event) {
+                Ok(job) => messages.push(format!("Started job {} on node {}", job, node)),
+                Err(e) => {
+                    messages.push(e.to_string());
+                    break;
+                }
+            }
         }
-        match self.asm.assemble(&contents.unwrap()) {
-            Ok(mut assembled_program) => {
-                self.send_message(format!("Sending assembled program to VM"));
-                self.vm.program.append(&mut assembled_program);
-                println!("{:#?}", self.vm.program);
-                self.scheduler.get_thread(self.vm.clone());
+        for message in messages {
+            self.send_message(message);
+        }
+    }
+
+    /// !nodes
+    fn nodes(&mut self, _args: &Args) {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => {
+                self.send_message("Not in a cluster, start with --cluster-bind".to_string());
+                return;
             }
-            Err(errors) => {
-                for err in errors {
-                    self.send_message(format!("Unable to parse input: {}", err));
-                    self.send_prompt();
+        };
+        let mut listing = format!("This node: {} on {}\n", cluster.id(), cluster.addr());
+        listing += &format!("{:<36}  {:<21}  {:>9}\n", "NODE", "ADDRESS", "LAST SEEN");
+        for peer in cluster.peers() {
+            listing += &format!(
+                "{:<36}  {:<21}  {:>8.1}s\n",
+                peer.id.to_string(),
+                peer.addr.to_string(),
+                peer.last_seen.as_secs_f64()
+            );
+        }
+        self.output.write_str(&listing);
+    }
+
+    /// !ps
+    fn ps(&mut self, _args: &Args) {
+        let mut listing = format!(
+            "{:>6}  {:<12}  {:>14}  {:>10}\n",
+            "PID", "STATE", "INSTRUCTIONS", "UPTIME"
+        );
+        for process in self.scheduler.processes() {
+            listing += &format!(
+                "{:>6}  {:<12}  {:>14}  {:>9.1}s\n",
+                process.pid,
+                process.state.to_string(),
+                process.instructions,
+                process.uptime.as_secs_f64()
+            );
+        }
+        self.output.write_str(&listing);
+    }
+
+    /// !kill <pid>
+    fn kill(&mut self, args: &Args) {
+        let pid = pid_arg(args);
+        if self.scheduler.kill(pid) {
+            self.send_message(format!("Killed process {}", pid));
+        } else {
+            self.send_message(format!("No process {}", pid));
+        }
+    }
+
+    /// !pause <pid>
+    fn pause(&mut self, args: &Args) {
+        let pid = pid_arg(args);
+        if self.scheduler.pause(pid) {
+            self.send_message(format!("Paused process {}", pid));
+        } else {
+            self.send_message(format!("No process {}", pid));
+        }
+    }
+
+    /// !resume <pid>
+    fn resume(&mut self, args: &Args) {
+        let pid = pid_arg(args);
+        if self.scheduler.resume(pid) {
+            self.send_message(format!("Resumed process {}", pid));
+        } else {
+            self.send_message(format!("No process {}", pid));
+        }
+    }
+
+    /// !break [offset] [--clear]
+    fn breakpoint(&mut self, args: &Args) {
+        let offset = match args.number("offset") {
+            Some(offset) if offset >= 0 => offset as usize,
+            Some(offset) => {
+                self.send_message(format!("Invalid offset {}", offset));
+                return;
+            }
+            None => {
+                let mut listing = String::new();
+                for offset in self.vm.breakpoints() {
+                    listing += &format!("{:04}\n", offset);
                 }
+                self.output.write_str(&listing);
                 return;
             }
+        };
+        if args.is_set("--clear") {
+            self.vm.clear_breakpoint(offset);
+            self.send_message(format!("Cleared breakpoint at {:04}", offset));
+        } else {
+            self.vm.set_breakpoint(offset);
+            self.send_message(format!("Set breakpoint at {:04}", offset));
         }
     }
 
-    #[doc = r"accept hexdecimal string withoud start with `0x`"]
-    /**
-     * @return vec<u8>
-     * examle a load command： 00 01 03 E8 // LOAD $1  0x03e8 or 0xe803?
-     */
-    ///
-    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = i.split(' ').collect::<Vec<&str>>();
-        let mut results = vec![];
+    /// !watch [on|off]
+    fn watch(&mut self, args: &Args) {
+        let mut watch = self.watch.lock().unwrap();
+        match args.text("state") {
+            Some("on") => *watch = Some(self.output.clone()),
+            Some("off") => *watch = None,
+            Some(other) => {
+                drop(watch);
+                self.send_message(format!("Unknown state {}, use on or off", other));
+                return;
+            }
+            None => {}
+        }
+        let state = if watch.is_some() { "on" } else { "off" };
+        drop(watch);
+        self.send_message(format!("Watching events: {}", state));
+    }
+
+    /// !wait <pid>: block until the process stops, then list and forget it
+    fn wait(&mut self, args: &Args) {
+        let pid = pid_arg(args);
+        match self.scheduler.wait(pid) {
+            Some(events) => self.send_events(&events),
+            None => self.send_message(format!("No process {}", pid)),
+        }
+    }
 
-        for hex_str in split {
-            let byte = u8::from_str_radix(&hex_str, 16); // 0x
-            match byte {
-                Ok(res) => {
-                    results.push(res);
+    /// !events <pid> [--json]
+    fn events(&mut self, args: &Args) {
+        let pid = pid_arg(args);
+        match self.scheduler.events(pid) {
+            Some(events) if args.is_set("--json") => {
+                let mut listing = String::new();
+                for event in &events {
+                    listing += &format!("{}\n", event_json(event));
                 }
-                Err(err) => return Err(err),
+                self.output.write_str(&listing);
             }
+            Some(events) => self.send_events(&events),
+            None => self.send_message(format!("No process {}", pid)),
         }
+    }
 
-        Ok(results)
+    fn send_events(&mut self, events: &[VMEvent]) {
+        let mut listing = String::new();
+        for event in events {
+            listing += &format!("{:?}\n", event);
+        }
+        self.output.write_str(&listing);
     }
 
-    fn get_data_from_load(&mut self) -> Option<String> {
-        let stdin = io::stdin();
-        print!("Enter the path to the file you want to load: ");
-        io::stdout().flush().expect("Unable to flush stdout");
-        let mut tmp = String::new();
+    /// !save <path>: write a snapshot of the VM to a file
+    fn save(&mut self, args: &Args) {
+        let path = args.text("path").unwrap_or_default();
+        let file = match self.file_path(path, "Snapshots") {
+            Ok(file) => file,
+            Err(e) => return self.send_message(e),
+        };
+        match fs::write(file, self.vm.snapshot()) {
+            Ok(_) => self.send_message(format!("Saved VM snapshot to {}", path)),
+            Err(e) => self.send_message(format!("Unable to write snapshot: {}", e)),
+        }
+    }
 
-        stdin
-            .read_line(&mut tmp)
-            .expect("Unable  to read line from user");
-        println!("Attemping to load progream from file...");
+    /// !restore <path>: replace the VM with one read back from a snapshot file
+    fn restore(&mut self, args: &Args) {
+        let path = args.text("path").unwrap_or_default();
+        let file = match self.file_path(path, "Snapshots") {
+            Ok(file) => file,
+            Err(e) => return self.send_message(e),
+        };
+        let snapshot = match fs::read(file) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.send_message(format!("Unable to read snapshot: {}", e));
+                return;
+            }
+        };
+        match VM::restore(&snapshot) {
+            Ok(vm) => {
+                self.vm = vm;
+                // the limits of this REPL win over whatever the snapshot carried
+                self.vm.set_limits(self.limits);
+                self.vm.set_output(self.output.clone());
+                self.watch_vm();
+                self.send_message(format!("Restored VM snapshot from {}", path));
+            }
+            Err(e) => self.send_message(format!("Unable to restore snapshot: {}", e)),
+        }
+    }
 
-        let tmp = tmp.trim();
-        let filename = Path::new(&tmp);
+    /// Runs a line of hex mode input, once it's known to hold only whole, valid instructions
+    fn run_hex(&mut self, line: &str) {
+        let bytes = match self.parse_hex(line) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.send_message(format!("Invalid hex byte: {}", e));
+                return;
+            }
+        };
+        let instructions = match validate_instructions(&bytes) {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                self.send_message(e);
+                return;
+            }
+        };
+        let start = self.vm.program.len();
+        let mut listing = String::new();
+        for (offset, instruction) in &instructions {
+            listing += &format!("{:04}: {}\n", start + offset, instruction);
+        }
+        self.output.write_str(&listing);
+
+        self.vm.program.extend_from_slice(&bytes);
+        for _ in &instructions {
+            self.vm.run_once();
+        }
+    }
 
-        let mut f = match File::open(filename) {
-            Ok(f) => f,
+    /// Accepts hexadecimal bytes without a leading `0x`, separated by whitespace.
+    /// A load command looks like `00 01 03 E8`, i.e. LOAD $1 #1000.
+    fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
+        i.split_whitespace()
+            .map(|hex_str| u8::from_str_radix(hex_str, 16))
+            .collect()
+    }
+
+    fn get_data_from_load(&mut self, path: &str) -> Option<String> {
+        let file = match self.file_path(path, "Programs") {
+            // only a local REPL searches the include paths
+            Ok(file) if self.files == FileAccess::Anywhere => find_file(&file, &self.include_paths),
+            Ok(file) => file,
             Err(e) => {
-                println!("Unable to open file: {:?}", e);
+                self.send_message(e);
                 return None;
             }
         };
-
-        let mut content = String::new();
-        match f.read_to_string(&mut content) {
-            Ok(_num_read) => Some(content),
+        self.send_message("Attemping to load progream from file...".to_string());
+        match fs::read_to_string(file) {
+            Ok(content) => Some(content),
             Err(e) => {
-                println!("Error on reading this file: {:?}", e);
+                self.send_message(format!("Unable to open file: {:?}", e));
                 None
             }
         }
     }
 
     pub fn send_message(&mut self, msg: String) {
-        match &self.tx_pipe {
-            Some(pipe) => {
-                pipe.send(msg + "\n");
-            }
-            None => {}
+        self.output.write_str(&(msg + "\n"));
+    }
+}
+
+/// Splits hex mode input into instructions as the VM would, rejecting input that ends
+/// part way through one, unknown opcodes and registers past the last one
+fn validate_instructions(bytes: &[u8]) -> Result<Vec<(usize, Instruction)>, String> {
+    if bytes.is_empty() {
+        return Err("Expected at least one instruction".to_string());
+    }
+    let instructions = disassemble(bytes);
+    for (offset, instruction) in &instructions {
+        if instruction.opcode == Opcode::IGL {
+            return Err(format!(
+                "Instruction at byte {}: unknown opcode {:02X}",
+                offset, bytes[*offset]
+            ));
+        }
+        if let Some(register) = instruction.registers().iter().find(|r| **r >= 32) {
+            return Err(format!(
+                "Instruction at byte {}: there is no register ${}",
+                offset, register
+            ));
         }
     }
-    pub fn send_prompt(&mut self) {
-        match &self.tx_pipe {
-            Some(pipe) => {
-                pipe.send(PROMPT.to_owned());
-            }
-            None => {}
+    let end = instructions
+        .last()
+        .map_or(0, |(offset, instruction)| offset + instruction.width());
+    if end != bytes.len() {
+        let opcode = Opcode::from(bytes[end]);
+        return Err(format!(
+            "Instruction at byte {} is cut off: {:?} takes {} bytes, only {} given",
+            end,
+            opcode,
+            opcode.width(),
+            bytes.len() - end
+        ));
+    }
+    Ok(instructions)
+}
+
+/// One line of JSON, as `!watch` and `!events --json` print them
+fn event_json(event: &VMEvent) -> String {
+    serde_json::to_string(event).expect("events always serialize")
+}
+
+/// The `pid` argument, which the command spec makes required. PIDs that don't fit are
+/// never handed out, so they come out as one that doesn't exist.
+fn pid_arg(args: &Args) -> u32 {
+    args.number("pid")
+        .and_then(|pid| u32::try_from(pid).ok())
+        .unwrap_or(0)
+}
+
+/// `~/.iridium/history`
+pub fn default_history_file() -> PathBuf {
+    let home = env::var_os("HOME").map_or_else(PathBuf::new, PathBuf::from);
+    home.join(".iridium").join("history")
+}
+
+fn save_history(editor: &mut Editor<EditorHelper, DefaultHistory>, path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    editor.save_history(path).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::scheduler::ProcessState;
+
+    fn repl() -> (REPL, crate::output::Capture) {
+        let mut repl = REPL::new();
+        let (output, capture) = Output::capture();
+        repl.set_output(output);
+        (repl, capture)
+    }
+
+    #[test]
+    fn test_quit_ends_run() {
+        let (mut repl, capture) = repl();
+        repl.run(Cursor::new("!nonsense\n!quit\n!registers\n"));
+        let output = capture.take();
+        assert!(output.starts_with(REMOTE_BANNER));
+        assert!(output.contains("Invalid Command!"));
+        assert!(output.contains("Farewell!"));
+        assert!(!output.contains("Listing registers"));
+    }
+
+    #[test]
+    fn test_load_file_prompts_on_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("program.iasm");
+        fs::write(
+            &path,
+            ".data\nhello: .asciiz 'Hello'\n.code\nload $0 #100\nprts @hello\nhlt\n",
+        )
+        .unwrap();
+
+        let (mut repl, capture) = repl();
+        repl.set_include_paths(vec![dir.path().to_path_buf()]);
+        assert!(!repl.run_single("!load_file\n"));
+        assert_eq!(repl.prompt(), LOAD_PROMPT);
+        // found through the include paths
+        assert!(!repl.run_single("program.iasm\n"));
+        assert_eq!(repl.prompt(), PROMPT);
+        assert_eq!(repl.vm.registers[0], 100);
+        // the program's own output goes to the same place as the REPL's
+        assert!(capture.take().contains("Hello"));
+    }
+
+    #[test]
+    fn test_command_arguments() {
+        let (mut repl, capture) = repl();
+        repl.vm.registers[1] = 5;
+        repl.run_single("!registers $0-1\n");
+        let output = capture.take();
+        assert!(output.contains("$0  0\n$1  5\n"));
+        assert!(!output.contains("$2 "));
+
+        repl.run_single("!registers 9-3\n");
+        assert!(capture.take().contains("Usage: !registers [first[-last]]"));
+
+        repl.run_single("!save\n");
+        assert!(capture.take().starts_with("Missing argument <path>"));
+
+        repl.run_single("!help spawn\n");
+        assert!(capture.take().contains("--threads"));
+        repl.run_single("!nonsense\n");
+        assert!(capture.take().contains("Invalid Command!"));
+    }
+
+    #[test]
+    fn test_restricted_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut repl, capture) = repl();
+        repl.restrict_files(Some(dir.path().to_path_buf()));
+        repl.vm.registers[3] = 7;
+        repl.run_single("!save vm.snap\n");
+        assert!(capture.take().contains("Saved VM snapshot to vm.snap"));
+        assert!(dir.path().join("vm.snap").is_file());
+        repl.vm.registers[3] = 0;
+        repl.run_single("!restore vm.snap\n");
+        assert_eq!(repl.vm.registers[3], 7);
+
+        let outside = dir.path().join("outside.snap");
+        for path in ["../outside.snap", outside.to_str().unwrap(), "sub/vm.snap"] {
+            repl.run_single(&format!("!save {}\n", path));
+            assert!(capture.take().contains("can only be named"), "{}", path);
         }
+        assert!(!outside.exists());
+
+        repl.restrict_files(None);
+        repl.run_single("!restore vm.snap\n");
+        assert!(capture.take().contains("Snapshots are turned off"));
+    }
+
+    #[test]
+    fn test_program_disassembly() {
+        let (mut repl, capture) = repl();
+        repl.run_single("load $1 #1000\n");
+        repl.run_single("inc $1\n");
+        repl.run_single("!program --disasm\n");
+        let output = capture.take();
+        assert!(output.contains("0000: load $1 #1000\n0004: inc $1\n"));
+    }
+
+    #[test]
+    fn test_line_without_opcode_is_reported() {
+        let (mut repl, capture) = repl();
+        assert!(!repl.run_single("test: .asciiz 'hi'\n"));
+        let output = capture.take();
+        assert!(output.contains("Unable to assemble input"), "{}", output);
+        assert!(repl.vm.program.is_empty());
+
+        repl.run_single("load $0 'hi'\n");
+        assert!(capture.take().contains("can't be used as an operand"));
+    }
+
+    #[test]
+    fn test_hex_mode() {
+        let (mut repl, capture) = repl();
+        repl.run_single("!mode hex\n");
+        assert_eq!(repl.prompt(), HEX_PROMPT);
+        capture.take();
+
+        repl.run_single("00 01 03 E8 0E 01 00 00\n");
+        assert_eq!(capture.take(), "0000: load $1 #1000\n0004: inc $1\n");
+        assert_eq!(repl.vm.registers[1], 1001);
+
+        // jeq is 2 bytes wide, so dec starts at 14
+        repl.run_single("0E 01 00 00 1A 03 0F 01 00 00\n");
+        assert_eq!(capture.take(), "0008: inc $1\n0012: jeq $3\n0014: dec $1\n");
+        assert_eq!(repl.vm.registers[1], 1001);
+
+        for (input, error) in [
+            ("\n", "Expected at least one instruction"),
+            (
+                "00 01 03\n",
+                "byte 0 is cut off: LOAD takes 4 bytes, only 3 given",
+            ),
+            (
+                "1A 03 0E 01\n",
+                "byte 2 is cut off: INC takes 4 bytes, only 2 given",
+            ),
+            // padding a 2 byte instruction to 4 leaves 2 bytes of the next one
+            (
+                "1A 03 00 00\n",
+                "byte 2 is cut off: LOAD takes 4 bytes, only 2 given",
+            ),
+            ("00 01 03 ZZ\n", "Invalid hex byte"),
+            ("C8 00 00 00\n", "byte 0: unknown opcode C8"),
+            ("1A 03 0A 01 02 20\n", "byte 2: there is no register $32"),
+        ] {
+            repl.run_single(input);
+            assert!(capture.take().contains(error), "{:?}", input);
+        }
+        assert_eq!(repl.vm.program.len(), 18);
+
+        // commands still work, and asm mode comes back
+        repl.run_single("!mode asm\n");
+        assert_eq!(repl.prompt(), PROMPT);
+    }
+
+    #[test]
+    fn test_process_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("program.iasm");
+        fs::write(&path, ".data\n.code\nload $0 #100\nhlt\n").unwrap();
+
+        let (mut repl, capture) = repl();
+        repl.run_single(&format!("!spawn {} --threads 2\n", path.display()));
+        let output = capture.take();
+        // the processes may already be printing in between
+        assert!(output.contains("Started process 1\n"));
+        assert!(output.contains("Started process 2\n"));
+
+        repl.run_single("!wait 1\n");
+        let output = capture.take();
+        assert!(output.contains("Start"));
+        assert!(output.contains("GracefulStop"));
+
+        repl.run_single("!ps\n");
+        let output = capture.take();
+        assert!(output.starts_with("   PID  STATE"));
+        assert!(output.contains("\n     2  "));
+        assert!(!output.contains("\n     1  "));
+
+        repl.run_single("!kill 1\n");
+        assert_eq!(capture.take(), "No process 1\n");
+        repl.run_single("!events two\n");
+        assert!(capture.take().contains("Usage: !events <pid>"));
+    }
+
+    #[test]
+    fn test_watch_streams_json_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("program.iasm");
+        fs::write(&path, ".data\n.code\nload $0 #100\nhlt\n").unwrap();
+
+        let (mut repl, capture) = repl();
+        repl.run_single("!watch on\n");
+        assert_eq!(capture.take(), "Watching events: on\n");
+        repl.run_single(&format!("!load_file {}\n", path.display()));
+        let output = capture.take();
+        let events: Vec<VMEvent> = output
+            .lines()
+            .filter(|line| line.starts_with('{'))
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events, repl.vm.events());
+        assert!(output.contains(r#"{"type":"GracefulStop","code":1,"#));
+
+        repl.run_single("!watch off\n");
+        repl.run_single("!clear_program\n");
+        repl.run_single("load $0 #100\n");
+        assert!(!capture.take().contains('{'));
+
+        // spawned processes stream too, and keep their JSON for later
+        let (mut spawner, capture) = self::repl();
+        spawner.run_single("!watch on\n");
+        spawner.run_single(&format!("!spawn {} --threads 2\n", path.display()));
+        spawner.scheduler.wait(1);
+        while spawner.scheduler.process(2).unwrap().state == ProcessState::Running {
+            std::thread::yield_now();
+        }
+        spawner.run_single("!watch off\n");
+        let output = capture.take();
+        assert_eq!(output.matches(r#""type":"Start""#).count(), 2);
+        spawner.run_single("!events 2 --json\n");
+        let output = capture.take();
+        assert!(output.starts_with(r#"{"type":"Start","at":"#));
+        assert_eq!(output.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_spawn_on_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("program.iasm");
+        fs::write(&path, ".data\n.code\nload $0 #100\nhlt\n").unwrap();
+
+        let (mut repl, capture) = repl();
+        repl.run_single(&format!("!spawn {} --node 1234\n", path.display()));
+        assert!(capture.take().starts_with("Not in a cluster"));
+
+        let config = ClusterConfig {
+            bind: "127.0.0.1:0".to_string(),
+            heartbeat_interval: std::time::Duration::from_millis(50),
+            ..ClusterConfig::default()
+        };
+        repl.join_cluster(config.clone()).unwrap();
+        let cluster = repl.cluster.as_ref().unwrap();
+        let peer = Node::start(
+            Uuid::new_v4(),
+            ClusterConfig {
+                seeds: vec![cluster.addr()],
+                ..config
+            },
+        )
+        .unwrap();
+        while cluster.peers().is_empty() {
+            std::thread::yield_now();
+        }
+
+        repl.run_single("!nodes\n");
+        let output = capture.take();
+        assert!(output.starts_with(&format!("This node: {}", repl.vm.id())));
+        assert!(output.contains(&format!("{}  {}", peer.id(), peer.addr())));
+
+        repl.run_single(&format!("!spawn {} --node nope\n", path.display()));
+        assert_eq!(capture.take(), "No node nope\n");
+        let prefix = &peer.id().to_string()[..8];
+        repl.run_single(&format!("!spawn {} --node {}\n", path.display(), prefix));
+        let mut output = String::new();
+        while !output.contains("GracefulStop") {
+            output += &capture.take();
+            std::thread::yield_now();
+        }
+        assert!(output.contains(&format!("Started job 1 on node {}", peer.id())));
+        assert!(output.contains(r#"{"type":"Start","#));
+        // it ran over there
+        assert_eq!(repl.vm.registers[0], 0);
+        peer.shutdown();
+    }
+
+    #[test]
+    fn test_block_runs_after_blank_line() {
+        let (mut repl, capture) = repl();
+        for line in [".data\n", ".code\n", "load $3 #42\n", "hlt\n"] {
+            repl.run_single(line);
+            assert_eq!(repl.prompt(), BLOCK_PROMPT);
+        }
+        assert_eq!(repl.vm.registers[3], 0);
+        repl.run_single("\n");
+        assert_eq!(repl.prompt(), PROMPT);
+        assert_eq!(repl.vm.registers[3], 42);
+        assert!(capture.take().contains("Sending assembled program to VM"));
+
+        repl.run_single(".code\n");
+        repl.cancel();
+        assert_eq!(repl.prompt(), PROMPT);
     }
 }
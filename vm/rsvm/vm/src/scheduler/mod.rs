@@ -1,22 +1,378 @@
-use std::thread;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::vm::{VMEvent, VM};
+use crate::vm::{VMError, VMEvent, VMEventType, VM};
 
-#[derive(Debug, Default, Clone)]
+/// What a VM running as a process shares with the scheduler
+#[derive(Debug, Default)]
+pub struct ProcessControl {
+    killed: AtomicBool,
+    instructions: AtomicU64,
+    events: Mutex<Vec<VMEvent>>,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl ProcessControl {
+    /// Makes the VM crash with `VMError::Killed` before its next instruction
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+        // a paused VM has to wake up to notice
+        let _paused = self.paused.lock().unwrap();
+        self.resumed.notify_all();
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_instructions(&self, instructions: u64) {
+        self.instructions.store(instructions, Ordering::Relaxed);
+    }
+
+    /// The events of this run so far
+    pub fn events(&self) -> Vec<VMEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub(crate) fn record_event(&self, event: &VMEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+
+    /// Makes the VM emit a Blocked event before its next instruction, and wait there
+    /// until resumed or killed
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    pub(crate) fn wait_while_paused(&self) {
+        let _paused = self
+            .resumed
+            .wait_while(self.paused.lock().unwrap(), |paused| {
+                *paused && !self.is_killed()
+            })
+            .unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    Running,
+    /// Waiting to be resumed, e.g. after hitting a breakpoint
+    Paused,
+    Exited {
+        code: u32,
+    },
+    Crashed {
+        code: u32,
+    },
+    Killed,
+    /// The thread died without the VM stopping
+    Panicked,
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessState::Running => f.write_str("running"),
+            ProcessState::Paused => f.write_str("paused"),
+            ProcessState::Exited { code } => write!(f, "exited({})", code),
+            ProcessState::Crashed { code } => write!(f, "crashed({})", code),
+            ProcessState::Killed => f.write_str("killed"),
+            ProcessState::Panicked => f.write_str("panicked"),
+        }
+    }
+}
+
+/// A snapshot of a process, as listed by `!ps`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub state: ProcessState,
+    pub instructions: u64,
+    /// How long it ran for, or has been running
+    pub uptime: Duration,
+}
+
+#[derive(Debug)]
+struct Process {
+    control: Arc<ProcessControl>,
+    started: Instant,
+    thread: thread::JoinHandle<()>,
+    /// Set by the thread once the VM stops, unless it panicked
+    finished: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Process {
+    fn info(&self, pid: u32) -> ProcessInfo {
+        let finished = *self.finished.lock().unwrap();
+        let state = if !self.thread.is_finished() {
+            if self.control.is_paused() {
+                ProcessState::Paused
+            } else {
+                ProcessState::Running
+            }
+        } else {
+            match self.control.events().last().map(VMEvent::event) {
+                Some(VMEventType::GracefulStop { code }) => ProcessState::Exited { code: *code },
+                Some(VMEventType::Crash {
+                    error: VMError::Killed,
+                }) => ProcessState::Killed,
+                Some(VMEventType::Crash { error }) => ProcessState::Crashed { code: error.code() },
+                _ => ProcessState::Panicked,
+            }
+        };
+        ProcessInfo {
+            pid,
+            state,
+            instructions: self.control.instructions(),
+            uptime: finished.unwrap_or_else(Instant::now) - self.started,
+        }
+    }
+}
+
+/// Runs VMs on their own threads and keeps track of them by PID
+#[derive(Debug)]
 pub struct Scheduler {
     next_pid: u32,
     max_pid: u32,
+    processes: BTreeMap<u32, Process>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scheduler {
     pub fn new() -> Scheduler {
         Scheduler {
-            next_pid: 0,
+            next_pid: 1,
             max_pid: 50000,
+            processes: BTreeMap::new(),
         }
     }
 
-    pub(crate) fn get_thread(&self, mut vm: VM) -> thread::JoinHandle<Vec<VMEvent>> {
-        thread::spawn(move || vm.run())
+    /// Starts running `vm` on a new thread. None when every PID is taken.
+    pub fn spawn(&mut self, mut vm: VM) -> Option<u32> {
+        let pid = self.allocate_pid()?;
+        let control = Arc::new(ProcessControl::default());
+        let finished = Arc::new(Mutex::new(None));
+        vm.set_process(Some(control.clone()));
+        let recorder = control.clone();
+        vm.subscribe(move |event| recorder.record_event(event));
+
+        let thread_finished = finished.clone();
+        let thread = thread::spawn(move || {
+            vm.run();
+            *thread_finished.lock().unwrap() = Some(Instant::now());
+        });
+        self.processes.insert(
+            pid,
+            Process {
+                control,
+                started: Instant::now(),
+                thread,
+                finished,
+            },
+        );
+        Some(pid)
+    }
+
+    fn allocate_pid(&mut self) -> Option<u32> {
+        for _ in 0..self.max_pid {
+            let pid = self.next_pid;
+            self.next_pid = if pid >= self.max_pid { 1 } else { pid + 1 };
+            if !self.processes.contains_key(&pid) {
+                return Some(pid);
+            }
+        }
+        None
+    }
+
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        self.processes
+            .iter()
+            .map(|(pid, process)| process.info(*pid))
+            .collect()
+    }
+
+    pub fn process(&self, pid: u32) -> Option<ProcessInfo> {
+        self.processes.get(&pid).map(|process| process.info(pid))
+    }
+
+    /// Stops the process at its next instruction. False if there is no such process.
+    pub fn kill(&self, pid: u32) -> bool {
+        match self.processes.get(&pid) {
+            Some(process) => {
+                process.control.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops the process before its next instruction until resumed.
+    /// False if there is no such process.
+    pub fn pause(&self, pid: u32) -> bool {
+        self.processes
+            .get(&pid)
+            .map(|process| process.control.pause())
+            .is_some()
+    }
+
+    pub fn resume(&self, pid: u32) -> bool {
+        self.processes
+            .get(&pid)
+            .map(|process| process.control.resume())
+            .is_some()
+    }
+
+    /// Blocks until the process stops, then forgets it and returns its events
+    pub fn wait(&mut self, pid: u32) -> Option<Vec<VMEvent>> {
+        let process = self.processes.remove(&pid)?;
+        if process.thread.join().is_err() {
+            error!("Process {} panicked", pid);
+        }
+        Some(process.control.events())
+    }
+
+    /// The events of the process so far
+    pub fn events(&self, pid: u32) -> Option<Vec<VMEvent>> {
+        self.processes
+            .get(&pid)
+            .map(|process| process.control.events())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+
+    /// Counts $0 up forever
+    fn endless_vm() -> VM {
+        let mut vm = VM::new_with_header();
+        // JMPB counts back from after itself
+        vm.add_bytes(vec![Opcode::LOAD.into(), 1, 0, 6]);
+        vm.add_bytes(vec![Opcode::INC.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::JMPB.into(), 1]);
+        vm
+    }
+
+    #[test]
+    fn test_wait_returns_events() {
+        let mut scheduler = Scheduler::new();
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::HLT.into()]);
+        let pid = scheduler.spawn(vm).unwrap();
+        assert_eq!(pid, 1);
+
+        let events = scheduler.wait(pid).unwrap();
+        assert!(matches!(events[0].event(), VMEventType::Start));
+        assert!(matches!(
+            events[1].event(),
+            VMEventType::GracefulStop { code: 1 }
+        ));
+        // waiting reaps it
+        assert!(scheduler.process(pid).is_none());
+        assert!(scheduler.wait(pid).is_none());
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler.spawn(endless_vm()).unwrap();
+        let info = scheduler.process(pid).unwrap();
+        assert_eq!(info.state, ProcessState::Running);
+
+        while scheduler.process(pid).unwrap().instructions < 1000 {
+            thread::yield_now();
+        }
+        assert!(scheduler.kill(pid));
+        assert!(!scheduler.kill(pid + 1));
+        let events = scheduler.wait(pid).unwrap();
+        assert_eq!(
+            events.last().unwrap().event(),
+            &VMEventType::Crash {
+                error: VMError::Killed
+            }
+        );
+    }
+
+    #[test]
+    fn test_breakpoint_pauses_until_resumed() {
+        let mut scheduler = Scheduler::new();
+        let mut vm = endless_vm();
+        // the INC
+        vm.set_breakpoint(VM::get_header_offset() + 4);
+        let pid = scheduler.spawn(vm).unwrap();
+        while scheduler.process(pid).unwrap().state != ProcessState::Paused {
+            thread::yield_now();
+        }
+        let events = scheduler.events(pid).unwrap();
+        assert_eq!(
+            events[1].event(),
+            &VMEventType::Breakpoint {
+                pc: VM::get_header_offset() + 4
+            }
+        );
+        // the Blocked event may not be recorded yet, but the VM can't get past it
+        let instructions = scheduler.process(pid).unwrap().instructions;
+
+        assert!(scheduler.resume(pid));
+        while scheduler.events(pid).unwrap().len() < 5 {
+            thread::yield_now();
+        }
+        let events = scheduler.events(pid).unwrap();
+        assert_eq!(events[2].event(), &VMEventType::Blocked);
+        assert!(matches!(events[3].event(), VMEventType::Breakpoint { .. }));
+        assert!(scheduler.process(pid).unwrap().instructions > instructions);
+
+        // killing wakes it up
+        assert!(scheduler.kill(pid));
+        let events = scheduler.wait(pid).unwrap();
+        assert_eq!(
+            events.last().unwrap().event(),
+            &VMEventType::Crash {
+                error: VMError::Killed
+            }
+        );
+    }
+
+    #[test]
+    fn test_pids_are_reused_after_max() {
+        let mut scheduler = Scheduler::new();
+        scheduler.max_pid = 2;
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::HLT.into()]);
+        let first = scheduler.spawn(vm.clone()).unwrap();
+        let second = scheduler.spawn(vm.clone()).unwrap();
+        assert_eq!((first, second), (1, 2));
+        assert!(scheduler.spawn(vm.clone()).is_none());
+        scheduler.wait(first);
+        assert_eq!(scheduler.spawn(vm).unwrap(), 1);
     }
 }
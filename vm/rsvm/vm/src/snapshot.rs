@@ -0,0 +1,245 @@
+use core::fmt;
+use std::{
+    error::Error,
+    io::{self, Cursor, Read},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Magic number that begins every snapshot. These spell out ISNP in ASCII.
+pub const SNAPSHOT_PREFIX: [u8; 4] = [73, 83, 78, 80];
+/// Bumped whenever the layout of a snapshot changes.
+pub const SNAPSHOT_VERSION: u16 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    BadPrefix,
+    UnsupportedVersion {
+        version: u16,
+    },
+    Truncated,
+    /// Heap blocks that overlap or run past the end of the heap
+    InvalidHeap,
+    /// An event that doesn't parse, with the reason
+    InvalidEvent(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadPrefix => f.write_str("This is not an Iridium VM snapshot"),
+            SnapshotError::UnsupportedVersion { version } => write!(
+                f,
+                "Snapshot version {} is not supported, expected {}",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Truncated => {
+                f.write_str("Snapshot ended before all of the VM state was read")
+            }
+            SnapshotError::InvalidHeap => {
+                f.write_str("Snapshot has heap blocks that overlap or run past the heap")
+            }
+            SnapshotError::InvalidEvent(reason) => {
+                write!(f, "Invalid event in snapshot: {}", reason)
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(_: io::Error) -> Self {
+        SnapshotError::Truncated
+    }
+}
+
+/// Appends VM state to a snapshot. Every length is written before its data,
+/// all numbers are little endian like the PIE header.
+pub struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        let mut buf = SNAPSHOT_PREFIX.to_vec();
+        buf.write_u16::<LittleEndian>(SNAPSHOT_VERSION).unwrap();
+        SnapshotWriter { buf }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.write_u32::<LittleEndian>(v).unwrap();
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.write_u64::<LittleEndian>(v).unwrap();
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.buf.write_i32::<LittleEndian>(v).unwrap();
+    }
+
+    pub fn i64(&mut self, v: i64) {
+        self.buf.write_i64::<LittleEndian>(v).unwrap();
+    }
+
+    pub fn f64(&mut self, v: f64) {
+        self.buf.write_f64::<LittleEndian>(v).unwrap();
+    }
+
+    pub fn optional_u64(&mut self, v: Option<u64>) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                self.u64(v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    /// Raw bytes of a known size, no length is written
+    pub fn fixed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.fixed(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for SnapshotWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads back what `SnapshotWriter` wrote, in the same order.
+pub struct SnapshotReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Checks the prefix and version before handing out a reader
+    pub fn new(snapshot: &'a [u8]) -> Result<SnapshotReader<'a>, SnapshotError> {
+        let mut reader = SnapshotReader {
+            cursor: Cursor::new(snapshot),
+        };
+        let mut prefix = [0; 4];
+        reader
+            .cursor
+            .read_exact(&mut prefix)
+            .map_err(|_| SnapshotError::BadPrefix)?;
+        if prefix != SNAPSHOT_PREFIX {
+            return Err(SnapshotError::BadPrefix);
+        }
+        let version = reader.cursor.read_u16::<LittleEndian>()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.cursor.read_u8()?)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(self.cursor.read_u32::<LittleEndian>()?)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(self.cursor.read_u64::<LittleEndian>()?)
+    }
+
+    pub fn i32(&mut self) -> Result<i32, SnapshotError> {
+        Ok(self.cursor.read_i32::<LittleEndian>()?)
+    }
+
+    pub fn i64(&mut self) -> Result<i64, SnapshotError> {
+        Ok(self.cursor.read_i64::<LittleEndian>()?)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, SnapshotError> {
+        Ok(self.cursor.read_f64::<LittleEndian>()?)
+    }
+
+    pub fn optional_u64(&mut self) -> Result<Option<u64>, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u64()?)),
+        }
+    }
+
+    pub fn fixed<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut bytes = [0; N];
+        self.cursor.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.u64()? as usize;
+        let remaining = self.cursor.get_ref().len() - self.cursor.position() as usize;
+        // don't trust the length enough to allocate it before checking
+        if len > remaining {
+            return Err(SnapshotError::Truncated);
+        }
+        let mut bytes = vec![0; len];
+        self.cursor.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_back() {
+        let mut writer = SnapshotWriter::new();
+        writer.u32(7);
+        writer.i32(-3);
+        writer.f64(1.5);
+        writer.bytes(&[1, 2, 3]);
+        let snapshot = writer.finish();
+
+        let mut reader = SnapshotReader::new(&snapshot).unwrap();
+        assert_eq!(reader.u32().unwrap(), 7);
+        assert_eq!(reader.i32().unwrap(), -3);
+        assert_eq!(reader.f64().unwrap(), 1.5);
+        assert_eq!(reader.bytes().unwrap(), vec![1, 2, 3]);
+        assert_eq!(reader.u8(), Err(SnapshotError::Truncated));
+    }
+
+    #[test]
+    fn test_reject_bad_prefix_and_version() {
+        assert!(matches!(
+            SnapshotReader::new(&[1, 2, 3, 4, 1, 0]),
+            Err(SnapshotError::BadPrefix)
+        ));
+
+        let mut snapshot = SNAPSHOT_PREFIX.to_vec();
+        snapshot.extend_from_slice(&[99, 0]);
+        assert!(matches!(
+            SnapshotReader::new(&snapshot),
+            Err(SnapshotError::UnsupportedVersion { version: 99 })
+        ));
+    }
+
+    #[test]
+    fn test_oversized_length_is_truncated() {
+        let mut writer = SnapshotWriter::new();
+        writer.u64(u64::MAX);
+        let snapshot = writer.finish();
+        let mut reader = SnapshotReader::new(&snapshot).unwrap();
+        assert_eq!(reader.bytes(), Err(SnapshotError::Truncated));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use rand_core::{OsRng, RngCore};
+use russh::keys::{
+    ssh_key::{private::Ed25519Keypair, LineEnding},
+    HashAlg, PrivateKey, PublicKey,
+};
+
+use super::SshError;
+
+/// The server's identity, in OpenSSH format and created on first start. Files from
+/// before that hold the raw 32 byte secret, which still loads.
+pub fn load_or_create_host_key(path: &Path) -> Result<PrivateKey, SshError> {
+    match fs::read(path) {
+        Ok(bytes) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(secret) => Ok(Ed25519Keypair::from_seed(&secret).into()),
+            Err(_) => PrivateKey::from_openssh(&bytes).map_err(|e| {
+                SshError::InvalidKey(format!("{} is not a host key: {}", path.display(), e))
+            }),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut secret = [0; 32];
+            OsRng.fill_bytes(&mut secret);
+            let key = PrivateKey::from(Ed25519Keypair::from_seed(&secret));
+            let contents = key
+                .to_openssh(LineEnding::LF)
+                .map_err(|e| SshError::InvalidKey(e.to_string()))?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(path)?.write_all(contents.as_bytes())?;
+            info!("Generated SSH host key {}", fingerprint(key.public_key()));
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Same format as `ssh-keygen -l`
+pub fn fingerprint(key: &PublicKey) -> String {
+    key.fingerprint(HashAlg::Sha256).to_string()
+}
+
+/// Parses one line of an OpenSSH public key or authorized keys file,
+/// `ssh-ed25519 AAAA... comment`. Key options in front are not supported.
+pub fn parse_public_key(line: &str) -> Result<PublicKey, SshError> {
+    PublicKey::from_openssh(line.trim()).map_err(|e| SshError::InvalidKey(e.to_string()))
+}
+
+/// Keys allowed to log in. A missing file means nobody is.
+pub fn load_authorized_keys(path: &Path) -> Result<Vec<PublicKey>, SshError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut keys = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_public_key(line) {
+            Ok(key) => keys.push(key),
+            Err(e) => warn!("{}:{}: {}", path.display(), number + 1, e),
+        }
+    }
+    Ok(keys)
+}
+
+/// Whether `key` is in the authorized keys file, whatever its comment there
+pub fn is_authorized(path: &Path, key: &PublicKey) -> Result<bool, SshError> {
+    Ok(load_authorized_keys(path)?
+        .iter()
+        .any(|authorized| authorized.key_data() == key.key_data()))
+}
+
+/// Appends the key in `public_key` to the authorized keys file, unless it is already there.
+/// Returns the key's fingerprint.
+pub fn add_authorized_key(path: &Path, public_key: &str) -> Result<String, SshError> {
+    let line = public_key.trim();
+    let key = parse_public_key(line)?;
+    if !is_authorized(path, &key)? {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", line)?;
+    }
+    Ok(fingerprint(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIENrSbr7BkmEqgfzYDyUg8V4fJv9x7KHzDzsK5HJWkmS me@host";
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = parse_public_key(PUBLIC_KEY).unwrap();
+        // as printed by `ssh-keygen -l`
+        assert_eq!(
+            fingerprint(&key),
+            "SHA256:pKpx8aZUl0w3JPHUmdllIWU+HyluwKMaF764oOiX5J0"
+        );
+
+        assert!(parse_public_key("ssh-rsa AAAAB3NzaC1yc2E").is_err());
+        assert!(parse_public_key("ssh-ed25519 not-base64!").is_err());
+        assert!(parse_public_key("").is_err());
+    }
+
+    #[test]
+    fn test_add_authorized_key_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        assert!(load_authorized_keys(&path).unwrap().is_empty());
+
+        add_authorized_key(&path, PUBLIC_KEY).unwrap();
+        add_authorized_key(&path, &format!("{}\n", PUBLIC_KEY)).unwrap();
+        assert_eq!(load_authorized_keys(&path).unwrap().len(), 1);
+        assert!(add_authorized_key(&path, "ssh-dss AAAA").is_err());
+
+        let renamed = PUBLIC_KEY.replace("me@host", "other");
+        assert!(is_authorized(&path, &parse_public_key(&renamed).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_host_key_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join("host_key");
+        let created = load_or_create_host_key(&path).unwrap();
+        let loaded = load_or_create_host_key(&path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+
+        // the raw secret older versions wrote
+        let path = dir.path().join("raw_host_key");
+        fs::write(&path, [7; 32]).unwrap();
+        let raw = load_or_create_host_key(&path).unwrap();
+        assert_eq!(raw, Ed25519Keypair::from_seed(&[7; 32]).into());
+    }
+}
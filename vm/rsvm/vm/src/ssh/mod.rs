@@ -0,0 +1,48 @@
+//! An SSH server for the REPL, built on russh, which does the protocol and the crypto.
+//! This module only connects sessions to REPLs and keeps the keys: an ssh-ed25519
+//! host key, and the keys users log in with in an authorized keys file.
+
+pub mod keys;
+pub mod server;
+mod session;
+
+use core::fmt;
+use std::{error::Error, io};
+
+pub use self::server::{Server, SshConfig};
+
+#[derive(Debug)]
+pub enum SshError {
+    Io(io::Error),
+    /// The connection failed, or the client broke the protocol
+    Ssh(russh::Error),
+    /// A key file could not be parsed
+    InvalidKey(String),
+    /// The client was still not logged in at the end of the login grace time
+    LoginTimedOut,
+}
+
+impl fmt::Display for SshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SshError::Io(e) => write!(f, "I/O error: {}", e),
+            SshError::Ssh(e) => write!(f, "SSH error: {}", e),
+            SshError::InvalidKey(reason) => write!(f, "Invalid key: {}", reason),
+            SshError::LoginTimedOut => f.write_str("Client did not log in in time"),
+        }
+    }
+}
+
+impl Error for SshError {}
+
+impl From<io::Error> for SshError {
+    fn from(e: io::Error) -> Self {
+        SshError::Io(e)
+    }
+}
+
+impl From<russh::Error> for SshError {
+    fn from(e: russh::Error) -> Self {
+        SshError::Ssh(e)
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    env,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use russh::{
+    server::{run_stream, Config},
+    Disconnect, MethodKind, MethodSet,
+};
+use tokio::{net::TcpStream, time::Instant};
+
+use super::{keys, session::Session, SshError};
+use crate::vm::VMLimits;
+
+/// How long a client may take from connecting to logging in
+pub const LOGIN_GRACE_TIME: Duration = Duration::from_secs(120);
+
+/// Where the SSH server keeps its files
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshConfig {
+    /// Created on first start
+    pub host_key: PathBuf,
+    /// One `ssh-ed25519 AAAA... comment` line per user, see `add-ssh-keys`
+    pub authorized_keys: PathBuf,
+}
+
+impl SshConfig {
+    pub fn in_dir<P: AsRef<Path>>(dir: P) -> SshConfig {
+        SshConfig {
+            host_key: dir.as_ref().join("ssh_host_ed25519_key"),
+            authorized_keys: dir.as_ref().join("authorized_keys"),
+        }
+    }
+}
+
+impl Default for SshConfig {
+    /// `~/.iridium`
+    fn default() -> Self {
+        let home = env::var_os("HOME").map_or_else(PathBuf::new, PathBuf::from);
+        SshConfig::in_dir(home.join(".iridium"))
+    }
+}
+
+pub struct Server {
+    bind_hostname: String,
+    bind_port: String,
+    config: SshConfig,
+    /// Applied to the VM of every session
+    pub limits: VMLimits,
+    /// The only place sessions may load programs from and `!save` and `!restore` snapshots
+    pub snapshot_dir: Option<PathBuf>,
+    /// Connections past this many are closed straight away, logged in or not
+    pub max_connections: Option<usize>,
+    /// Applies from the moment a client connects, through the key exchange and login
+    pub login_grace_time: Duration,
+    connections: Arc<AtomicUsize>,
+}
+
+/// Counts a connection for `max_connections` until it's dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    pub fn new(bind_hostname: String, bind_port: String, config: SshConfig) -> Server {
+        Server {
+            bind_hostname,
+            bind_port,
+            config,
+            limits: VMLimits::default(),
+            snapshot_dir: None,
+            max_connections: None,
+            login_grace_time: LOGIN_GRACE_TIME,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn listen(&mut self) -> Result<(), SshError> {
+        info!("Initializing SSH server...");
+        let listener = TcpListener::bind(format!("{}:{}", self.bind_hostname, self.bind_port))?;
+        self.serve(listener)
+    }
+
+    /// Runs a session for every client that connects to `listener`, on a runtime of its own
+    pub fn serve(&self, listener: TcpListener) -> Result<(), SshError> {
+        let host_key = keys::load_or_create_host_key(&self.config.host_key)?;
+        info!(
+            "SSH host key fingerprint is {}",
+            keys::fingerprint(host_key.public_key())
+        );
+        let config = Arc::new(Config {
+            keys: vec![host_key],
+            methods: MethodSet::from(&[MethodKind::PublicKey][..]),
+            // OpenSSH asks with no credentials first, to learn the methods
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            ..Config::default()
+        });
+        listener.set_nonblocking(true)?;
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.accept(listener, config))
+    }
+
+    async fn accept(&self, listener: TcpListener, config: Arc<Config>) -> Result<(), SshError> {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Unable to accept SSH connection: {}", e);
+                    continue;
+                }
+            };
+            let slot = match self.take_slot() {
+                Some(slot) => slot,
+                None => {
+                    info!("Refused SSH connection, server is full");
+                    continue;
+                }
+            };
+            let logged_in = Arc::new(AtomicBool::new(false));
+            let mut session = Session::new(
+                self.config.authorized_keys.clone(),
+                self.limits,
+                logged_in.clone(),
+            );
+            session.restrict_files(self.snapshot_dir.clone());
+            let config = config.clone();
+            let deadline = Instant::now() + self.login_grace_time;
+            tokio::spawn(async move {
+                let _slot = slot;
+                match run_session(config, stream, session, logged_in, deadline).await {
+                    Ok(()) | Err(SshError::Ssh(russh::Error::Disconnect)) => {
+                        info!("SSH session from {} ended", peer)
+                    }
+                    Err(e) => error!("SSH session from {} failed: {}", peer, e),
+                }
+            });
+        }
+    }
+
+    fn take_slot(&self) -> Option<ConnectionSlot> {
+        let taken = self.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(self.connections.clone());
+        match self.max_connections {
+            Some(max) if taken >= max => None,
+            _ => Some(slot),
+        }
+    }
+}
+
+/// Serves one client until it leaves, hanging up on it if it hasn't logged in by `deadline`
+async fn run_session(
+    config: Arc<Config>,
+    stream: TcpStream,
+    session: Session,
+    logged_in: Arc<AtomicBool>,
+    deadline: Instant,
+) -> Result<(), SshError> {
+    // a client that connects and says nothing is dropped with its stream
+    let running = tokio::time::timeout_at(deadline, run_stream(config, stream, session))
+        .await
+        .map_err(|_| SshError::LoginTimedOut)??;
+    let handle = running.handle();
+    tokio::pin!(running);
+    tokio::select! {
+        result = &mut running => return result,
+        _ = tokio::time::sleep_until(deadline) => {}
+    }
+    if !logged_in.load(Ordering::SeqCst) {
+        let _ = handle
+            .disconnect(
+                Disconnect::ByApplication,
+                "Login grace time is over".to_string(),
+                String::new(),
+            )
+            .await;
+        running.await?;
+        return Err(SshError::LoginTimedOut);
+    }
+    running.await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        process::{Command, Output, Stdio},
+        thread,
+    };
+
+    use super::*;
+
+    /// Starts a server on a free port, with a fresh user key that is authorized when `authorize` is set
+    fn start_server(dir: &Path, authorize: bool) -> u16 {
+        let key = dir.join("id_ed25519");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let config = SshConfig::in_dir(dir);
+        if authorize {
+            let public_key = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+            keys::add_authorized_key(&config.authorized_keys, &public_key).unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Server::new("127.0.0.1".into(), port.to_string(), config);
+        thread::spawn(move || server.serve(listener));
+        port
+    }
+
+    fn ssh(dir: &Path, port: u16, command: Option<&str>, input: &str) -> Output {
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-F", "/dev/null", "-T", "-p", &port.to_string(), "-i"])
+            .arg(dir.join("id_ed25519"))
+            .args([
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "IdentitiesOnly=yes",
+                "-o",
+                "StrictHostKeyChecking=no",
+                "-o",
+                "UserKnownHostsFile=/dev/null",
+                "-o",
+                "ConnectTimeout=10",
+                "-o",
+                "LogLevel=ERROR",
+                "iridium@127.0.0.1",
+            ])
+            .args(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = ssh.spawn().unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(feature = "openssh-tests"),
+        ignore = "needs the openssh-tests feature"
+    )]
+    fn test_exec_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = start_server(dir.path(), true);
+
+        let output = ssh(dir.path(), port, Some("!registers"), "");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{:?}", output);
+        assert!(stdout.contains("Listing registers and all contents:"));
+        assert!(!stdout.contains(crate::repl::PROMPT));
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(feature = "openssh-tests"),
+        ignore = "needs the openssh-tests feature"
+    )]
+    fn test_shell_session_has_its_own_repl() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = start_server(dir.path(), true);
+
+        let output = ssh(dir.path(), port, None, "load $5 #4242\n!registers\n!quit\n");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{:?}", output);
+        assert!(stdout.starts_with(crate::repl::REMOTE_BANNER));
+        assert!(stdout.contains("$5  4242\n"));
+        assert!(stdout.contains("Farewell!"));
+
+        // a second login starts from a clean VM
+        let output = ssh(dir.path(), port, Some("!registers"), "");
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("4242"));
+    }
+
+    #[test]
+    fn test_idle_and_excess_connections_are_closed() {
+        use std::{io::Read, net::TcpStream};
+
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = Server::new(
+            "127.0.0.1".into(),
+            "0".into(),
+            SshConfig::in_dir(dir.path()),
+        );
+        server.max_connections = Some(1);
+        server.login_grace_time = Duration::from_millis(300);
+        thread::spawn(move || server.serve(listener));
+
+        let connect = || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            stream
+        };
+        let mut buf = [0; 256];
+        // says nothing after the server's version, and is dropped after the grace time
+        let mut idle = connect();
+        assert!(idle.read(&mut buf).unwrap() > 0);
+        // while it's connected there is no room for another
+        assert_eq!(connect().read(&mut buf).unwrap(), 0);
+        while idle.read(&mut buf).is_ok_and(|read| read > 0) {}
+
+        thread::sleep(Duration::from_millis(100));
+        let read = connect().read(&mut buf).unwrap();
+        assert!(buf[..read].starts_with(b"SSH-2.0-"));
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(feature = "openssh-tests"),
+        ignore = "needs the openssh-tests feature"
+    )]
+    fn test_unknown_key_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = start_server(dir.path(), false);
+
+        let output = ssh(dir.path(), port, Some("!registers"), "");
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use russh::{
+    keys::PublicKey,
+    server::{Auth, ChannelOpenHandle, Handler, Msg, Session as Connection},
+    Channel, ChannelId,
+};
+
+use super::{keys, SshError};
+use crate::{
+    output::{Capture, Output},
+    repl,
+    vm::VMLimits,
+};
+
+/// One client with its own REPL, on the one session channel it may open
+pub struct Session {
+    authorized_keys: PathBuf,
+    /// Set once the client has logged in, for the server's login grace time
+    logged_in: Arc<AtomicBool>,
+    repl: repl::REPL,
+    output: Capture,
+    channel: Option<ChannelId>,
+    /// The client asked for a terminal, so it sends keystrokes and expects them echoed
+    pty: bool,
+    line: Vec<u8>,
+    closed: bool,
+}
+
+impl Session {
+    pub fn new(authorized_keys: PathBuf, limits: VMLimits, logged_in: Arc<AtomicBool>) -> Session {
+        let mut repl = repl::REPL::new();
+        repl.set_limits(limits);
+        let (sink, output) = Output::capture();
+        repl.set_output(sink);
+        Session {
+            authorized_keys,
+            logged_in,
+            repl,
+            output,
+            channel: None,
+            pty: false,
+            line: vec![],
+            closed: false,
+        }
+    }
+
+    /// See `REPL::restrict_files`
+    pub fn restrict_files(&mut self, dir: Option<PathBuf>) {
+        self.repl.restrict_files(dir);
+    }
+
+    /// Checks the key against the authorized keys file, which is read on every
+    /// attempt so newly added keys work straight away
+    fn check_key(&self, key: &PublicKey) -> Result<Auth, SshError> {
+        Ok(if keys::is_authorized(&self.authorized_keys, key)? {
+            Auth::Accept
+        } else {
+            Auth::reject()
+        })
+    }
+
+    /// Runs one line of input through the REPL. True when the user asked to quit.
+    /// The REPL runs programs to the end, so the runtime is told this blocks.
+    fn run_line(&mut self, line: &str) -> bool {
+        tokio::task::block_in_place(|| self.repl.run_single(&format!("{}\n", line)))
+    }
+
+    fn send(&self, text: &str, connection: &mut Connection) -> Result<(), SshError> {
+        let channel = match self.channel {
+            Some(channel) if !self.closed && !text.is_empty() => channel,
+            _ => return Ok(()),
+        };
+        let text = if self.pty {
+            text.replace('\n', "\r\n")
+        } else {
+            text.to_string()
+        };
+        Ok(connection.data(channel, text.into_bytes())?)
+    }
+
+    /// Sends what the REPL printed since last time
+    fn send_output(&self, connection: &mut Connection) -> Result<(), SshError> {
+        self.send(&self.output.take(), connection)
+    }
+
+    /// Sends exit status, EOF and close, after any output still to go
+    fn close(&mut self, connection: &mut Connection) -> Result<(), SshError> {
+        let channel = match self.channel {
+            Some(channel) if !self.closed => channel,
+            _ => return Ok(()),
+        };
+        self.send_output(connection)?;
+        self.closed = true;
+        connection.exit_status_request(channel, 0)?;
+        connection.eof(channel)?;
+        Ok(connection.close(channel)?)
+    }
+
+    fn receive(&mut self, data: &[u8], connection: &mut Connection) -> Result<(), SshError> {
+        for byte in data {
+            if self.closed {
+                return Ok(());
+            }
+            let mut echo = vec![];
+            match (*byte, self.pty) {
+                (b'\r', true) | (b'\n', false) => {
+                    let line = String::from_utf8_lossy(&self.line).into_owned();
+                    self.line.clear();
+                    if self.pty {
+                        self.send("\n", connection)?;
+                    }
+                    if self.run_line(line.trim_end_matches('\r')) {
+                        return self.close(connection);
+                    }
+                    self.send_output(connection)?;
+                    self.send(self.repl.prompt(), connection)?;
+                }
+                // the enter key sends a carriage return, a terminal never sends a lone newline
+                (b'\n', true) => {}
+                (0x7f, true) | (0x08, true) => {
+                    if self.line.pop().is_some() {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                }
+                (0x03, true) => {
+                    self.line.clear();
+                    self.repl.cancel();
+                    echo.extend_from_slice(b"^C\n");
+                    echo.extend_from_slice(self.repl.prompt().as_bytes());
+                }
+                (0x04, true) if self.line.is_empty() => return self.close(connection),
+                (other, _) => {
+                    self.line.push(other);
+                    echo.push(other);
+                }
+            }
+            if self.pty && !echo.is_empty() {
+                self.send(&String::from_utf8_lossy(&echo), connection)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Handler for Session {
+    type Error = SshError;
+
+    /// Spares the client signing with a key that wouldn't do
+    async fn auth_publickey_offered(
+        &mut self,
+        _user: &str,
+        key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        self.check_key(key)
+    }
+
+    async fn auth_publickey(&mut self, user: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        let auth = self.check_key(key)?;
+        if auth == Auth::Accept {
+            info!("{} logged in over SSH", user);
+        }
+        Ok(auth)
+    }
+
+    async fn auth_succeeded(&mut self, _connection: &mut Connection) -> Result<(), Self::Error> {
+        self.logged_in.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: ChannelOpenHandle,
+        _connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        // dropping the reply refuses the channel
+        if self.channel.is_none() {
+            self.channel = Some(channel.id());
+            reply.accept().await;
+        }
+        Ok(())
+    }
+
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _term: &str,
+        _col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(russh::Pty, u32)],
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        self.pty = true;
+        Ok(connection.channel_success(channel)?)
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        _variable_name: &str,
+        _variable_value: &str,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        Ok(connection.channel_success(channel)?)
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        _col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        Ok(connection.channel_success(channel)?)
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.channel_success(channel)?;
+        let banner = format!("{}\n{}", repl::REMOTE_BANNER, repl::PROMPT);
+        self.send(&banner, connection)
+    }
+
+    /// `ssh host '!registers'` runs the command and hangs up, without prompts
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        connection.channel_success(channel)?;
+        let command = String::from_utf8_lossy(data).into_owned();
+        for line in command.lines() {
+            if self.run_line(line) {
+                break;
+            }
+        }
+        self.close(connection)
+    }
+
+    async fn data(
+        &mut self,
+        _channel: ChannelId,
+        data: &[u8],
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        self.receive(data, connection)
+    }
+
+    async fn channel_eof(
+        &mut self,
+        _channel: ChannelId,
+        connection: &mut Connection,
+    ) -> Result<(), Self::Error> {
+        self.close(connection)
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    assembler::symbol::{SymbolTable, SymbolType},
+    instruction::Opcode,
+};
+
+/// One executed instruction, along with the registers it changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub opcode: Opcode,
+    /// (register, new value) pairs
+    pub writes: Vec<(usize, i32)>,
+}
+
+#[derive(Debug, Clone)]
+enum TraceSink {
+    /// Keeps only the most recent `capacity` records
+    Ring {
+        capacity: usize,
+        records: VecDeque<TraceRecord>,
+    },
+    /// Writes one line per record. Shared so a cloned VM keeps appending to the same file.
+    File(Arc<Mutex<BufWriter<File>>>),
+}
+
+/// Hook the VM calls after every instruction when tracing is turned on
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    sink: TraceSink,
+}
+
+impl Tracer {
+    pub fn ring_buffer(capacity: usize) -> Tracer {
+        Tracer {
+            sink: TraceSink::Ring {
+                capacity,
+                records: VecDeque::with_capacity(capacity),
+            },
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer {
+            sink: TraceSink::File(Arc::new(Mutex::new(BufWriter::new(file)))),
+        })
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        match &mut self.sink {
+            TraceSink::Ring { capacity, records } => {
+                if *capacity == 0 {
+                    return;
+                }
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            }
+            TraceSink::File(writer) => {
+                let mut line = format!("{:08} {:?}", record.pc, record.opcode);
+                for (register, value) in &record.writes {
+                    let _ = write!(line, " ${}={}", register, value);
+                }
+                let mut writer = writer.lock().unwrap();
+                if let Err(e) = writeln!(writer, "{}", line) {
+                    error!("Unable to write trace record: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Records still held in the ring buffer, oldest first. Always empty when tracing to a file.
+    pub fn records(&self) -> Vec<&TraceRecord> {
+        match &self.sink {
+            TraceSink::Ring { records, .. } => records.iter().collect(),
+            TraceSink::File(_) => vec![],
+        }
+    }
+
+    pub fn flush(&self) {
+        if let TraceSink::File(writer) = &self.sink {
+            let _ = writer.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Counts executed instructions per opcode and per program counter, so a report
+/// can point at the labels a program spends its time under.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    total: u64,
+    opcodes: HashMap<Opcode, u64>,
+    pcs: BTreeMap<usize, u64>,
+    /// Code labels sorted by offset
+    labels: Vec<(usize, String)>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Attribute counts to the code labels found in `symbols`
+    pub fn with_symbols(symbols: &SymbolTable) -> Profiler {
+        let mut labels: Vec<(usize, String)> = symbols
+            .symbols
+            .iter()
+            .filter(|symbol| *symbol.symbol_type() == SymbolType::Label)
+            .filter_map(|symbol| Some((symbol.offset()? as usize, symbol.name().to_string())))
+            .collect();
+        labels.sort();
+        Profiler {
+            labels,
+            ..Profiler::default()
+        }
+    }
+
+    pub fn record(&mut self, pc: usize, opcode: Opcode) {
+        self.total += 1;
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+        *self.pcs.entry(pc).or_insert(0) += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(&opcode).copied().unwrap_or(0)
+    }
+
+    /// Instruction counts per label, where an instruction belongs to the closest label before it.
+    /// Instructions before the first label are counted under `None`.
+    pub fn label_counts(&self) -> Vec<(Option<&str>, u64)> {
+        let mut counts: Vec<(Option<&str>, u64)> = vec![];
+        for (pc, count) in &self.pcs {
+            let label = self
+                .labels
+                .iter()
+                .take_while(|(offset, _)| offset <= pc)
+                .last()
+                .map(|(_, name)| name.as_str());
+            match counts.iter_mut().find(|(l, _)| *l == label) {
+                Some((_, total)) => *total += count,
+                None => counts.push((label, *count)),
+            }
+        }
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = format!("Profile: {} instructions executed\n", self.total);
+        let percent = |count: u64| count as f64 * 100.0 / self.total.max(1) as f64;
+
+        let mut opcodes: Vec<(&Opcode, &u64)> = self.opcodes.iter().collect();
+        opcodes.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        report.push_str("Hot opcodes:\n");
+        for (opcode, count) in opcodes {
+            let _ = writeln!(
+                report,
+                "  {:<12} {:>10} {:>6.2}%",
+                format!("{:?}", opcode),
+                count,
+                percent(*count)
+            );
+        }
+
+        report.push_str("Hot labels:\n");
+        for (label, count) in self.label_counts() {
+            let _ = writeln!(
+                report,
+                "  {:<12} {:>10} {:>6.2}%",
+                label.unwrap_or("(no label)"),
+                count,
+                percent(count)
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbol::Symbol;
+
+    #[test]
+    fn test_ring_buffer_keeps_latest() {
+        let mut tracer = Tracer::ring_buffer(2);
+        for pc in 0..3 {
+            tracer.record(TraceRecord {
+                pc,
+                opcode: Opcode::NOP,
+                writes: vec![],
+            });
+        }
+        let pcs: Vec<usize> = tracer.records().iter().map(|r| r.pc).collect();
+        assert_eq!(pcs, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_profiler_counts_by_label() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new_with_offset("loop".into(), SymbolType::Label, 8));
+        symbols.add_symbol(Symbol::new_with_offset(
+            "msg".into(),
+            SymbolType::IrString,
+            0,
+        ));
+        let mut profiler = Profiler::with_symbols(&symbols);
+
+        profiler.record(0, Opcode::LOAD);
+        for _ in 0..3 {
+            profiler.record(8, Opcode::INC);
+            profiler.record(12, Opcode::JMP);
+        }
+
+        assert_eq!(profiler.total(), 7);
+        assert_eq!(profiler.opcode_count(Opcode::INC), 3);
+        assert_eq!(profiler.label_counts(), vec![(Some("loop"), 6), (None, 1)]);
+        assert!(profiler.report().contains("loop"));
+    }
+}
@@ -1,30 +1,280 @@
 use chrono::prelude::*;
-use std::{io::Cursor, vec};
+use core::fmt;
+use std::{collections::BTreeSet, error::Error, io::Cursor, sync::Arc, thread, vec};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
-    instruction::Opcode,
+    assembler::{PIE_FORMAT_VERSION, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX, PIE_VERSION_OFFSET},
+    heap::{Heap, HeapError, HeapStats},
+    instruction::{Instruction, Opcode},
+    output::Output,
+    scheduler::ProcessControl,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    trace::{Profiler, TraceRecord, Tracer},
 };
 
-#[derive(Debug, Clone)]
+/// What happened to a VM. Serialized with a `type` field naming the variant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum VMEventType {
     Start,
-    GracefulStop { code: u32 },
-    Crash { code: u32 },
+    GracefulStop {
+        code: u32,
+    },
+    Crash {
+        error: VMError,
+    },
     Stop,
+    /// YIELD gave up the rest of the thread's time slice
+    Yield,
+    /// A scheduler process was paused, and waits to be resumed or killed
+    Blocked,
+    /// ALOC grew the heap to `size` bytes
+    HeapGrow {
+        size: usize,
+    },
+    /// An instruction reached outside the VM, like PRTS printing
+    Syscall {
+        name: String,
+    },
+    /// The instruction at `pc` is about to run and has a breakpoint on it
+    Breakpoint {
+        pc: usize,
+    },
 }
 
-#[derive(Debug, Clone)]
+/// Why the VM stopped a program before it finished by itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum VMError {
+    BadHeader,
+    NegativeAllocation {
+        requested: i32,
+    },
+    HeapLimitExceeded {
+        requested: usize,
+        limit: usize,
+    },
+    InstructionLimitExceeded {
+        limit: u64,
+    },
+    StackLimitExceeded {
+        limit: usize,
+    },
+    OutputLimitExceeded {
+        limit: usize,
+    },
+    InvalidFree {
+        pointer: i32,
+    },
+    /// Stopped from outside, e.g. by `!kill`
+    Killed,
+    /// POP or RET with nothing left to take
+    StackUnderflow,
+    /// DIV by a register holding 0
+    DivisionByZero,
+    /// An operand naming a register past the last one
+    InvalidRegister {
+        register: u8,
+    },
+    /// PRTS of an offset with no `\0` after it in the read-only data
+    UnterminatedString {
+        offset: usize,
+    },
+    /// JMPB or JMPF by more than there is to jump
+    InvalidJump {
+        by: i32,
+    },
+    /// The cluster node running the program went away before it finished
+    NodeLost,
+    /// The cluster node was already running as many programs for its peers as it may
+    JobLimitExceeded {
+        limit: usize,
+    },
+    /// The thread running the VM panicked
+    Panicked,
+}
+
+impl VMError {
+    /// A number for the error, e.g. for a process to exit with
+    pub fn code(&self) -> u32 {
+        match self {
+            VMError::BadHeader => 1,
+            VMError::NegativeAllocation { .. } => 2,
+            VMError::HeapLimitExceeded { .. } => 3,
+            VMError::InstructionLimitExceeded { .. } => 4,
+            VMError::StackLimitExceeded { .. } => 5,
+            VMError::OutputLimitExceeded { .. } => 6,
+            VMError::InvalidFree { .. } => 7,
+            VMError::Killed => 8,
+            VMError::StackUnderflow => 9,
+            VMError::DivisionByZero => 10,
+            VMError::InvalidRegister { .. } => 11,
+            VMError::UnterminatedString { .. } => 12,
+            VMError::InvalidJump { .. } => 13,
+            VMError::NodeLost => 14,
+            VMError::JobLimitExceeded { .. } => 15,
+            VMError::Panicked => 16,
+        }
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            VMError::BadHeader => f.write_str("Header was incorrect"),
+            VMError::NegativeAllocation { requested } => {
+                write!(
+                    f,
+                    "Tried to allocate a negative number of bytes: {}",
+                    requested
+                )
+            }
+            VMError::HeapLimitExceeded { requested, limit } => write!(
+                f,
+                "Heap would grow to {} bytes, over the limit of {}",
+                requested, limit
+            ),
+            VMError::InstructionLimitExceeded { limit } => {
+                write!(f, "Executed the maximum of {} instructions", limit)
+            }
+            VMError::StackLimitExceeded { limit } => {
+                write!(f, "Stack grew past the maximum depth of {}", limit)
+            }
+            VMError::OutputLimitExceeded { limit } => {
+                write!(
+                    f,
+                    "Program printed more than the maximum of {} bytes",
+                    limit
+                )
+            }
+            VMError::InvalidFree { pointer } => {
+                write!(
+                    f,
+                    "Tried to free {}, which is not an allocated block",
+                    pointer
+                )
+            }
+            VMError::Killed => f.write_str("Killed"),
+            VMError::StackUnderflow => f.write_str("Popped from an empty stack"),
+            VMError::DivisionByZero => f.write_str("Divided by zero"),
+            VMError::InvalidRegister { register } => {
+                write!(f, "There is no register ${}", register)
+            }
+            VMError::UnterminatedString { offset } => {
+                write!(f, "No string ends in the read-only data at {}", offset)
+            }
+            VMError::InvalidJump { by } => {
+                write!(f, "Jumped by {}, out of the program", by)
+            }
+            VMError::NodeLost => f.write_str("Lost the node running the program"),
+            VMError::JobLimitExceeded { limit } => {
+                write!(
+                    f,
+                    "The node is already running its maximum of {} jobs",
+                    limit
+                )
+            }
+            VMError::Panicked => f.write_str("The VM panicked"),
+        }
+    }
+}
+
+impl Error for VMError {}
+
+impl From<HeapError> for VMError {
+    fn from(e: HeapError) -> Self {
+        match e {
+            HeapError::LimitExceeded { requested, limit } => {
+                VMError::HeapLimitExceeded { requested, limit }
+            }
+            HeapError::InvalidFree { pointer } => VMError::InvalidFree {
+                pointer: pointer as i32,
+            },
+        }
+    }
+}
+
+/// Caps on what a single program may use, so untrusted code can be run safely.
+/// `None` leaves that resource unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VMLimits {
+    pub max_heap_bytes: Option<usize>,
+    pub max_instructions: Option<u64>,
+    /// Checked by instructions that push onto the VM stack
+    pub max_stack_depth: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+}
+
+impl VMLimits {
+    /// Tighten limits with `--max-heap <bytes>` style flag pairs, like the ones given to `!spawn`.
+    /// A flag can't lift a limit that's already set, only lower it.
+    /// Arguments that are not limit flags are left alone.
+    pub fn with_args(self, args: &[&str]) -> Result<VMLimits, String> {
+        let mut requested = VMLimits::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if !matches!(
+                *flag,
+                "--max-heap" | "--max-instructions" | "--max-stack" | "--max-output"
+            ) {
+                continue;
+            }
+            let value = args.next().ok_or(format!("Missing value for {}", flag))?;
+            let value = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid value for {}: {}", flag, value))?;
+            match *flag {
+                "--max-heap" => requested.max_heap_bytes = Some(value as usize),
+                "--max-instructions" => requested.max_instructions = Some(value),
+                "--max-stack" => requested.max_stack_depth = Some(value as usize),
+                _ => requested.max_output_bytes = Some(value as usize),
+            }
+        }
+        Ok(self.tightest(requested))
+    }
+
+    /// The lower of the two for every limit, so whoever asks for `other` can't get
+    /// more than these allow. A limit that either leaves unset is the other's.
+    pub fn tightest(self, other: VMLimits) -> VMLimits {
+        fn lower<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        VMLimits {
+            max_heap_bytes: lower(self.max_heap_bytes, other.max_heap_bytes),
+            max_instructions: lower(self.max_instructions, other.max_instructions),
+            max_stack_depth: lower(self.max_stack_depth, other.max_stack_depth),
+            max_output_bytes: lower(self.max_output_bytes, other.max_output_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VMEvent {
+    #[serde(flatten)]
     event: VMEventType,
     at: DateTime<Utc>,
     application_id: Uuid,
 }
 
+/// Called with every event a VM emits, on the thread running the VM
+#[derive(Clone)]
+struct Subscriber(Arc<dyn Fn(&VMEvent) + Send + Sync>);
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Subscriber")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VM {
     /// A unique idenfier of each VM isolate
@@ -33,13 +283,40 @@ pub struct VM {
 
     pub logical_cores: usize,
     pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
     pc: usize, // program counter
     pub program: Vec<u8>,
-    heap: Vec<u8>,
+    heap: Heap,
     ro_data: Vec<u8>,
+    /// Values saved by PUSH for POP
+    stack: Vec<i32>,
+    /// Where each RET goes back to, apart from the values so functions can't mix them up
+    call_stack: Vec<usize>,
 
     remainder: u32,   //  int left after divide
     equal_flag: bool, // the result of last comparison op
+
+    limits: VMLimits,
+    instructions_executed: u64,
+    output_bytes: usize,
+
+    /// Sees every executed instruction when set
+    tracer: Option<Tracer>,
+    /// Aggregates instruction counts, reported when the program stops
+    profiler: Option<Profiler>,
+    /// Where PRTS and the VM's own messages go
+    output: Output,
+    /// Set when the VM runs as a scheduler process, which can watch and kill it through this
+    process: Option<Arc<ProcessControl>>,
+    subscribers: Vec<Subscriber>,
+    /// Offsets of instructions that emit a Breakpoint event before they run
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
@@ -47,14 +324,26 @@ impl VM {
         VM {
             logical_cores: num_cpus::get(),
             registers: [0; 32],
+            float_registers: [0.0; 32],
             pc: 0,
             program: vec![],
-            heap: vec![],
+            heap: Heap::new(),
             remainder: 0,
             equal_flag: false,
             ro_data: vec![],
+            stack: vec![],
+            call_stack: vec![],
             id: Uuid::new_v4(),
             events: vec![],
+            limits: VMLimits::default(),
+            instructions_executed: 0,
+            output_bytes: 0,
+            tracer: None,
+            profiler: None,
+            output: Output::stdout(),
+            process: None,
+            subscribers: vec![],
+            breakpoints: BTreeSet::new(),
         }
     }
     pub fn new_with_non_zero_registers() -> VM {
@@ -72,40 +361,157 @@ impl VM {
         vm
     }
 
+    /// Run the program from its entry point, dispatching over the instructions
+    /// decoded up front rather than re-decoding the bytes on every step.
     pub fn run(&mut self) -> Vec<VMEvent> {
-        self.events
-            .push(VMEvent::new(VMEventType::Start, self.id.clone()));
+        self.run_with(VM::dispatch_decoded)
+    }
+
+    /// Same as `run`, but decodes each instruction from the raw bytes as it goes.
+    pub fn run_interpreted(&mut self) -> Vec<VMEvent> {
+        self.run_with(VM::dispatch_interpreted)
+    }
+
+    fn run_with(&mut self, dispatch: fn(&mut VM) -> Result<u32, VMError>) -> Vec<VMEvent> {
+        self.push_event(VMEventType::Start);
 
         // check header
         if !self.verify_hader() {
-            self.events.push(VMEvent::new(
-                VMEventType::Crash { code: 1 },
-                self.id.clone(),
-            ));
-            error!("Header was incorrect");
-            return self.events.clone();
+            return self.stop(Err(VMError::BadHeader));
         }
 
         self.pc = VM::get_header_offset() + self.get_starting_offset();
+        // PRTS reads the strings the assembler put between the header and the code
+        let ro_length = self.get_ro_length();
+        if ro_length > 0 {
+            let start = VM::get_header_offset();
+            self.ro_data = self.program[start..start + ro_length].to_vec();
+        }
 
         // run
-        let mut is_done = None;
-        while is_done.is_none() {
-            is_done = self.execute_instructions();
-        }
+        let result = dispatch(self);
 
         // over
-        self.events.push(VMEvent::new(
-            VMEventType::GracefulStop {
-                code: is_done.unwrap(),
-            },
-            self.id.clone(),
-        ));
+        self.stop(result)
+    }
+
+    /// Carry on from wherever the program counter is, e.g. after `restore`.
+    pub fn resume(&mut self) -> Vec<VMEvent> {
+        let result = self.dispatch_decoded();
+        self.stop(result)
+    }
+
+    fn stop(&mut self, result: Result<u32, VMError>) -> Vec<VMEvent> {
+        if let Some(tracer) = &self.tracer {
+            tracer.flush();
+        }
+        let event = match result {
+            Ok(code) => {
+                if let Some(profiler) = &self.profiler {
+                    self.output.write_str(&format!("{}\n", profiler.report()));
+                }
+                VMEventType::GracefulStop { code }
+            }
+            Err(e) => {
+                error!("{}", e);
+                VMEventType::Crash { error: e }
+            }
+        };
+        self.push_event(event);
         self.events.clone()
     }
 
+    fn push_event(&mut self, event: VMEventType) {
+        let event = VMEvent::new(event, self.id);
+        for subscriber in &self.subscribers {
+            (subscriber.0)(&event);
+        }
+        self.events.push(event);
+    }
+
+    /// Calls `subscriber` with every event from now on. Clones of the VM keep calling it.
+    pub fn subscribe<F: Fn(&VMEvent) + Send + Sync + 'static>(&mut self, subscriber: F) {
+        self.subscribers.push(Subscriber(Arc::new(subscriber)));
+    }
+
+    pub fn events(&self) -> &[VMEvent] {
+        &self.events
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// A copy to run separately, with its own id and no events yet
+    pub fn fork(&self) -> VM {
+        let mut vm = self.clone();
+        vm.id = Uuid::new_v4();
+        vm.events.clear();
+        vm
+    }
+
+    /// Emit a Breakpoint event whenever the instruction at `offset` is about to run.
+    /// A scheduler process also pauses there until resumed.
+    pub fn set_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    pub fn clear_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.remove(&offset);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn set_limits(&mut self, limits: VMLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &VMLimits {
+        &self.limits
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    pub fn set_process(&mut self, process: Option<Arc<ProcessControl>>) {
+        self.process = process;
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
     pub fn run_once(&mut self) {
-        self.execute_instructions();
+        if let Err(e) = self.execute_instructions() {
+            error!("{}", e);
+        }
     }
 
     pub fn add_byte(&mut self, b: u8) {
@@ -115,192 +521,343 @@ impl VM {
         self.program.append(&mut bytes);
     }
 
-    fn execute_instructions(&mut self) -> Option<u32> {
+    fn dispatch_interpreted(&mut self) -> Result<u32, VMError> {
+        loop {
+            if let Some(code) = self.execute_instructions()? {
+                return Ok(code);
+            }
+        }
+    }
+
+    fn dispatch_decoded(&mut self) -> Result<u32, VMError> {
+        // Jumps land on arbitrary byte offsets, so decode from every one of them
+        let decoded = self.decode_program();
+        loop {
+            let instruction = match decoded.get(self.pc) {
+                Some(Some(instruction)) => *instruction,
+                Some(None) => return Ok(self.truncated_instruction()),
+                None => return Ok(1),
+            };
+            if let Some(code) = self.step(instruction)? {
+                return Ok(code);
+            }
+        }
+    }
+
+    fn decode_program(&self) -> Vec<Option<Instruction>> {
+        (0..self.program.len())
+            .map(|offset| Instruction::decode(&self.program[offset..]))
+            .collect()
+    }
+
+    fn execute_instructions(&mut self) -> Result<Option<u32>, VMError> {
         if self.pc >= self.program.len() {
-            return Some(1);
+            return Ok(Some(1));
+        }
+
+        match Instruction::decode(&self.program[self.pc..]) {
+            Some(instruction) => self.step(instruction),
+            None => Ok(Some(self.truncated_instruction())),
+        }
+    }
+
+    fn truncated_instruction(&self) -> u32 {
+        self.output.write_str(&format!(
+            "Instruction at {} runs past the end of the program! Terminating!\n",
+            self.pc
+        ));
+        1
+    }
+
+    fn step(&mut self, instruction: Instruction) -> Result<Option<u32>, VMError> {
+        if let Some(limit) = self.limits.max_instructions {
+            if self.instructions_executed >= limit {
+                return Err(VMError::InstructionLimitExceeded { limit });
+            }
+        }
+        self.instructions_executed += 1;
+        let breakpoint = self.breakpoints.contains(&self.pc);
+        if breakpoint {
+            self.push_event(VMEventType::Breakpoint { pc: self.pc });
+        }
+        if let Some(process) = self.process.clone() {
+            if breakpoint {
+                process.pause();
+            }
+            if breakpoint || process.is_paused() {
+                self.push_event(VMEventType::Blocked);
+                process.wait_while_paused();
+            }
+            if process.is_killed() {
+                return Err(VMError::Killed);
+            }
+            process.set_instructions(self.instructions_executed);
         }
 
-        match self.decode_opcode() {
+        if self.tracer.is_none() && self.profiler.is_none() {
+            return self.execute(instruction);
+        }
+
+        let pc = self.pc;
+        let before = self.registers;
+        let result = self.execute(instruction);
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, instruction.opcode);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            let writes = (0..self.registers.len())
+                .filter(|&r| self.registers[r] != before[r])
+                .map(|r| (r, self.registers[r]))
+                .collect();
+            tracer.record(TraceRecord {
+                pc,
+                opcode: instruction.opcode,
+                writes,
+            });
+        }
+        result
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<Option<u32>, VMError> {
+        // programs can come from anywhere, so check the registers before indexing with them
+        if let Some(&register) = instruction
+            .registers()
+            .iter()
+            .find(|&&register| register as usize >= self.registers.len())
+        {
+            return Err(VMError::InvalidRegister { register });
+        }
+        let opcode_pc = self.pc;
+        self.pc += instruction.width();
+
+        match instruction.opcode {
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
+                let register = instruction.register(0);
+                let number = instruction.operand_u16(1);
                 self.registers[register] = number as i32;
             }
 
+            // arithmetic wraps around, the same in debug and release builds
             Opcode::ADD => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.registers[self.next_8_bits() as usize] = reg1 + reg2;
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.registers[instruction.register(2)] = reg1.wrapping_add(reg2);
             }
             Opcode::SUB => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.registers[self.next_8_bits() as usize] = reg1 - reg2;
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.registers[instruction.register(2)] = reg1.wrapping_sub(reg2);
             }
             Opcode::MUL => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.registers[self.next_8_bits() as usize] = reg1 * reg2;
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.registers[instruction.register(2)] = reg1.wrapping_mul(reg2);
             }
             Opcode::DIV => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.registers[self.next_8_bits() as usize] = reg1 / reg2;
-                self.remainder = (reg1 % reg2) as u32;
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                if reg2 == 0 {
+                    return Err(VMError::DivisionByZero);
+                }
+                self.registers[instruction.register(2)] = reg1.wrapping_div(reg2);
+                self.remainder = reg1.wrapping_rem(reg2) as u32;
             }
             Opcode::INC => {
-                let reg = self.next_8_bits();
-                self.registers[reg as usize] += 1;
-                self.next_16_bits(); //eat
+                let register = instruction.register(0);
+                self.registers[register] = self.registers[register].wrapping_add(1);
             }
             Opcode::DEC => {
-                let reg = self.next_8_bits();
-                self.registers[reg as usize] -= 1;
-                self.next_16_bits(); //eat
+                let register = instruction.register(0);
+                self.registers[register] = self.registers[register].wrapping_sub(1);
+            }
+            // the remainder of the last DIV
+            Opcode::REM => {
+                self.registers[instruction.register(0)] = self.remainder as i32;
             }
 
+            // the third operand is eaten, for mips or other isc write into register , we use the self.equal_flag
             Opcode::EQ => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.equal_flag = if reg1 == reg2 { true } else { false };
-                self.next_8_bits(); // eat it for mips or other isc write into register , we use the self.equal_flag
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.equal_flag = reg1 == reg2;
             }
             Opcode::NEQ => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.equal_flag = if reg1 != reg2 { true } else { false };
-                self.next_8_bits(); // eat it for mips or other isc write into register , we use the self.equal_flag
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.equal_flag = reg1 != reg2;
             }
             Opcode::GT => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.equal_flag = if reg1 > reg2 { true } else { false };
-                self.next_8_bits(); // eat it for mips or other isc write into register , we use the self.equal_flag
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.equal_flag = reg1 > reg2;
             }
             Opcode::LT => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.equal_flag = if reg1 < reg2 { true } else { false };
-                self.next_8_bits(); // eat it for mips or other isc write into register , we use the self.equal_flag
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.equal_flag = reg1 < reg2;
             }
             Opcode::GTE => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.equal_flag = if reg1 >= reg2 { true } else { false };
-                self.next_8_bits(); // eat it for mips or other isc write into register , we use the self.equal_flag
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.equal_flag = reg1 >= reg2;
             }
             Opcode::LTE => {
-                let (reg1, reg2) = self.binary_operaters_value();
-                self.equal_flag = if reg1 <= reg2 { true } else { false };
-                self.next_8_bits(); // eat it for mips or other isc write into register , we use the self.equal_flag
+                let (reg1, reg2) = self.binary_operaters_value(&instruction);
+                self.equal_flag = reg1 <= reg2;
             }
             Opcode::JEQ => {
-                let reg = self.next_8_bits() as usize;
-                let target = self.registers[reg];
+                let target = self.registers[instruction.register(0)];
                 if self.equal_flag {
                     self.pc = target as usize;
                 }
             }
             Opcode::JNEQ => {
-                let reg = self.next_8_bits() as usize;
-                let target = self.registers[reg];
+                let target = self.registers[instruction.register(0)];
                 if !self.equal_flag {
                     self.pc = target as usize;
                 }
             }
 
             Opcode::JMPB => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc -= target as usize;
+                let by = self.registers[instruction.register(0)];
+                self.pc = self
+                    .pc
+                    .checked_sub(by as usize)
+                    .ok_or(VMError::InvalidJump { by })?;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
+                let target = self.registers[instruction.register(0)];
                 self.pc = target as usize;
             }
             Opcode::JMPF => {
-                println!("jumpf");
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc += target as usize;
+                let by = self.registers[instruction.register(0)];
+                self.pc = self
+                    .pc
+                    .checked_add(by as usize)
+                    .ok_or(VMError::InvalidJump { by })?;
+            }
+
+            Opcode::PUSH => {
+                self.check_stack_depth()?;
+                self.stack.push(self.registers[instruction.register(0)]);
+            }
+            Opcode::POP => {
+                let value = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                self.registers[instruction.register(0)] = value;
+            }
+            Opcode::CALL => {
+                self.check_stack_depth()?;
+                self.call_stack.push(self.pc);
+                self.pc = self.registers[instruction.register(0)] as usize;
+            }
+            Opcode::RET => {
+                self.pc = self.call_stack.pop().ok_or(VMError::StackUnderflow)?;
             }
 
-            Opcode::NOP => {
-                self.next_16_bits();
-                self.next_8_bits();
+            Opcode::NOP => {}
+            Opcode::YIELD => {
+                thread::yield_now();
+                self.push_event(VMEventType::Yield);
             }
             Opcode::ALOC => {
-                let reg = self.next_8_bits() as usize;
-                let num_bytes = self.registers[reg];
-                let new_heap_size = self.heap.len() as i32 + num_bytes;
-                self.heap.resize(new_heap_size as usize, 0);
+                let num_bytes = self.registers[instruction.register(0)];
+                if num_bytes < 0 {
+                    return Err(VMError::NegativeAllocation {
+                        requested: num_bytes,
+                    });
+                }
+                let size = self.heap.len();
+                let pointer = self
+                    .heap
+                    .alloc(num_bytes as usize, self.limits.max_heap_bytes)
+                    .map_err(VMError::from)?;
+                self.registers[instruction.register(1)] = pointer as i32;
+                if self.heap.len() > size {
+                    self.push_event(VMEventType::HeapGrow {
+                        size: self.heap.len(),
+                    });
+                }
+            }
+            Opcode::FREE => {
+                let pointer = self.registers[instruction.register(0)];
+                if pointer < 0 {
+                    return Err(VMError::InvalidFree { pointer });
+                }
+                self.heap.free(pointer as usize).map_err(VMError::from)?;
             }
             Opcode::PRTS => {
-                let starting_offset = self.next_16_bits() as usize;
-                let mut ending_offset = starting_offset;
-                let slice = self.ro_data.as_slice();
-                // trance the string till '\0'
-                while slice[ending_offset] != 0 {
-                    ending_offset += 1;
+                self.push_event(VMEventType::Syscall {
+                    name: "prts".to_string(),
+                });
+                let starting_offset = instruction.operand_u16(0) as usize;
+                // trace the string till '\0'
+                let slice = self
+                    .ro_data
+                    .get(starting_offset..)
+                    .and_then(|rest| Some(&rest[..rest.iter().position(|b| *b == 0)?]))
+                    .ok_or(VMError::UnterminatedString {
+                        offset: starting_offset,
+                    })?;
+                let ret = std::str::from_utf8(slice);
+                self.output_bytes += slice.len();
+                if let Some(limit) = self.limits.max_output_bytes {
+                    if self.output_bytes > limit {
+                        return Err(VMError::OutputLimitExceeded { limit });
+                    }
                 }
-                let ret = std::str::from_utf8(&slice[starting_offset..ending_offset]);
                 match ret {
-                    Ok(s) => print!("{}", s),
-                    Err(e) => {
-                        println!("Error decoding string for prts instruction: {:#?}", e)
-                    }
+                    Ok(s) => self.output.write_str(s),
+                    Err(e) => self.output.write_str(&format!(
+                        "Error decoding string for prts instruction: {:#?}\n",
+                        e
+                    )),
                 }
             }
 
             Opcode::HLT => {
-                println!("HLT encountered");
-                return Some(1);
+                // the program's output is the program's, the stop shows up as an event
+                debug!("HLT encountered");
+                return Ok(Some(1));
             }
 
-            // Opcode::IGL => {
-            //     println!("Illegal instruction encounterd");
-            //     return 1;
-            // }
-            _ => {
-                println!(
-                    "Unrecognized opcode: {} found! Terminating!",
-                    self.program[self.pc]
-                );
-                return Some(1);
+            Opcode::IGL => {
+                self.output.write_str(&format!(
+                    "Unrecognized opcode: {} found! Terminating!\n",
+                    self.program[opcode_pc]
+                ));
+                return Ok(Some(1));
             }
         }
-        None
-    }
-
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+        Ok(None)
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
-    }
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    /// Both stacks count towards `max_stack_depth`
+    fn check_stack_depth(&self) -> Result<(), VMError> {
+        match self.limits.max_stack_depth {
+            Some(limit) if self.stack.len() + self.call_stack.len() >= limit => {
+                Err(VMError::StackLimitExceeded { limit })
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn binary_operaters_value(&mut self) -> (i32, i32) {
+    fn binary_operaters_value(&self, instruction: &Instruction) -> (i32, i32) {
         (
-            self.registers[self.next_8_bits() as usize],
-            self.registers[self.next_8_bits() as usize],
+            self.registers[instruction.register(0)],
+            self.registers[instruction.register(1)],
         )
     }
 
     fn verify_hader(&self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
-            return false;
-        }
-        true
+        // programs can come from other nodes, so don't trust them to be long enough
+        self.program.len() >= VM::get_header_offset()
+            && self.program.starts_with(&PIE_HEADER_PREFIX)
+            && self.get_format_version() <= PIE_FORMAT_VERSION
+            && self.program.len() >= self.code_offset()
     }
 
     // just add the header bytes before program
     fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
         let mut prepension = vec![];
         for byte in PIE_HEADER_PREFIX {
-            prepension.push(byte.clone());
+            prepension.push(byte);
         }
         while prepension.len() < PIE_HEADER_LENGTH {
             prepension.push(0);
         }
+        prepension[PIE_VERSION_OFFSET] = PIE_FORMAT_VERSION;
         prepension.append(&mut b);
         prepension
     }
@@ -321,10 +878,141 @@ impl VM {
         let mut cursor = Cursor::new(&self.program[4..8]);
         cursor.read_u32::<LittleEndian>().unwrap() as usize
     }
+
+    /// Which layout the header describes, 0 for images from before there was a version
+    fn get_format_version(&self) -> u8 {
+        self.program.get(PIE_VERSION_OFFSET).copied().unwrap_or(0)
+    }
+
+    /// How many bytes of read-only data follow the header. Images without a
+    /// version have none there, whatever is in those bytes.
+    fn get_ro_length(&self) -> usize {
+        if self.get_format_version() == 0 {
+            return 0;
+        }
+        match self.program.get(8..12) {
+            Some(mut bytes) => bytes.read_u32::<LittleEndian>().unwrap() as usize,
+            None => 0,
+        }
+    }
+
+    /// Where the code starts in a program with a header, after the read-only data
+    pub fn code_offset(&self) -> usize {
+        VM::get_header_offset().saturating_add(self.get_ro_length())
+    }
+}
+
+impl VM {
+    /// Serialize everything needed to pick the VM up again later, in this
+    /// process or another one. `logical_cores` belongs to the host and is left out.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.fixed(self.id.as_bytes());
+        w.u64(self.pc as u64);
+        w.u32(self.remainder);
+        w.u8(self.equal_flag as u8);
+        self.registers.iter().for_each(|r| w.i32(*r));
+        self.float_registers.iter().for_each(|r| w.f64(*r));
+        w.bytes(&self.program);
+        w.bytes(self.heap.memory());
+        let blocks = self.heap.blocks();
+        w.u64(blocks.len() as u64);
+        for (start, size) in blocks {
+            w.u64(start as u64);
+            w.u64(size as u64);
+        }
+        w.bytes(&self.ro_data);
+        w.u64(self.stack.len() as u64);
+        self.stack.iter().for_each(|v| w.i32(*v));
+        w.u64(self.call_stack.len() as u64);
+        self.call_stack.iter().for_each(|pc| w.u64(*pc as u64));
+
+        w.optional_u64(self.limits.max_heap_bytes.map(|v| v as u64));
+        w.optional_u64(self.limits.max_instructions);
+        w.optional_u64(self.limits.max_stack_depth.map(|v| v as u64));
+        w.optional_u64(self.limits.max_output_bytes.map(|v| v as u64));
+        w.u64(self.instructions_executed);
+        w.u64(self.output_bytes as u64);
+
+        w.u32(self.events.len() as u32);
+        for event in &self.events {
+            // events are stored as JSON so new kinds don't need a new layout
+            w.bytes(&serde_json::to_vec(event).expect("events always serialize"));
+        }
+        w.finish()
+    }
+
+    /// Rebuild a VM from the output of `snapshot`
+    pub fn restore(snapshot: &[u8]) -> Result<VM, SnapshotError> {
+        let mut r = SnapshotReader::new(snapshot)?;
+        let mut vm = VM::new();
+        vm.id = Uuid::from_bytes(r.fixed()?);
+        vm.pc = r.u64()? as usize;
+        vm.remainder = r.u32()?;
+        vm.equal_flag = r.u8()? != 0;
+        for register in vm.registers.iter_mut() {
+            *register = r.i32()?;
+        }
+        for register in vm.float_registers.iter_mut() {
+            *register = r.f64()?;
+        }
+        vm.program = r.bytes()?;
+        let memory = r.bytes()?;
+        let num_blocks = r.u64()?;
+        let mut blocks = vec![];
+        for _ in 0..num_blocks {
+            let start = r.u64()? as usize;
+            let size = r.u64()? as usize;
+            let end = start.checked_add(size).ok_or(SnapshotError::InvalidHeap)?;
+            if end > memory.len() {
+                return Err(SnapshotError::InvalidHeap);
+            }
+            blocks.push((start, size));
+        }
+        vm.heap = Heap::from_parts(memory, blocks).ok_or(SnapshotError::InvalidHeap)?;
+        vm.ro_data = r.bytes()?;
+        for _ in 0..r.u64()? {
+            vm.stack.push(r.i32()?);
+        }
+        for _ in 0..r.u64()? {
+            vm.call_stack.push(r.u64()? as usize);
+        }
+
+        vm.limits = VMLimits {
+            max_heap_bytes: r.optional_u64()?.map(|v| v as usize),
+            max_instructions: r.optional_u64()?,
+            max_stack_depth: r.optional_u64()?.map(|v| v as usize),
+            max_output_bytes: r.optional_u64()?.map(|v| v as usize),
+        };
+        vm.instructions_executed = r.u64()?;
+        vm.output_bytes = r.u64()? as usize;
+
+        let num_events = r.u32()?;
+        for _ in 0..num_events {
+            let event = serde_json::from_slice(&r.bytes()?)
+                .map_err(|e| SnapshotError::InvalidEvent(e.to_string()))?;
+            vm.events.push(event);
+        }
+        Ok(vm)
+    }
 }
 
 impl VMEvent {
-    fn new(event_type: VMEventType, application_id: Uuid) -> VMEvent {
+    pub fn event(&self) -> &VMEventType {
+        &self.event
+    }
+
+    /// When the VM emitted it
+    pub fn at(&self) -> DateTime<Utc> {
+        self.at
+    }
+
+    /// The id of the VM that emitted it
+    pub fn application_id(&self) -> Uuid {
+        self.application_id
+    }
+
+    pub(crate) fn new(event_type: VMEventType, application_id: Uuid) -> VMEvent {
         VMEvent {
             event: event_type,
             at: Utc::now(),
@@ -335,9 +1023,6 @@ impl VMEvent {
 
 #[cfg(test)]
 mod tests {
-    use nom::print;
-
-    use crate::assembler::register_parsers::register;
 
     use super::*;
     use std::vec;
@@ -394,12 +1079,12 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![Opcode::EQ.into(), 0, 1, 255];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.equal_flag);
 
         test_vm.pc = 0;
         test_vm.registers[1] = 20;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.equal_flag);
     }
 
     #[test]
@@ -434,6 +1119,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 5]);
+        vm.add_bytes(vec![Opcode::PUSH.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 6]);
+        vm.add_bytes(vec![Opcode::PUSH.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::POP.into(), 1, 0, 0]);
+        vm.add_bytes(vec![Opcode::POP.into(), 2, 0, 0]);
+        vm.add_bytes(vec![Opcode::POP.into(), 3, 0, 0]);
+        let events = vm.run();
+        assert_eq!(&vm.registers[1..3], &[6, 5]);
+        assert_eq!(
+            events.last().unwrap().event(),
+            &VMEventType::Crash {
+                error: VMError::StackUnderflow
+            }
+        );
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let header = VM::get_header_offset() as u8;
+        let mut vm = VM::new_with_header();
+        // call the INC at +8, which returns to the HLT at +6
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, header + 8]);
+        vm.add_bytes(vec![Opcode::CALL.into(), 0]);
+        vm.add_bytes(vec![Opcode::HLT.into(), 0]);
+        vm.add_bytes(vec![Opcode::INC.into(), 1, 0, 0]);
+        vm.add_bytes(vec![Opcode::RET.into()]);
+        let events = vm.run();
+        assert_eq!(vm.registers[1], 1);
+        assert_eq!(vm.pc, VM::get_header_offset() + 7);
+        assert!(vm.call_stack.is_empty());
+        assert_eq!(
+            events.last().unwrap().event(),
+            &VMEventType::GracefulStop { code: 1 }
+        );
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        let header = VM::get_header_offset() as u8;
+        let mut vm = VM::new_with_header();
+        // calls itself forever
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, header + 4]);
+        vm.add_bytes(vec![Opcode::CALL.into(), 0]);
+        vm.set_limits(VMLimits {
+            max_stack_depth: Some(10),
+            ..VMLimits::default()
+        });
+        let events = vm.run();
+        assert_eq!(vm.call_stack.len(), 10);
+        assert_eq!(
+            events.last().unwrap().event(),
+            &VMEventType::Crash {
+                error: VMError::StackLimitExceeded { limit: 10 }
+            }
+        );
+    }
+
     #[test]
     fn test_opcode_aloc() {
         let mut vm = VM::new();
@@ -443,6 +1189,39 @@ mod tests {
         assert_eq!(vm.heap.len(), 1024);
     }
 
+    #[test]
+    fn test_aloc_and_free() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 16]);
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 1, 0]);
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 2, 0]);
+        vm.add_bytes(vec![Opcode::FREE.into(), 1, 0, 0]);
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 3, 0]);
+        vm.add_bytes(vec![Opcode::HLT.into(), 0, 0, 0]);
+        vm.run();
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(vm.registers[2], 16);
+        // the freed block is handed out again instead of growing the heap
+        assert_eq!(vm.registers[3], 0);
+        assert_eq!(vm.heap.len(), 32);
+        assert_eq!(vm.heap_stats().live_blocks, 2);
+    }
+
+    #[test]
+    fn test_double_free_crashes() {
+        let mut vm = VM::new_with_header();
+        vm.registers[0] = 8;
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 1, 0]);
+        vm.add_bytes(vec![Opcode::FREE.into(), 1, 0, 0]);
+        vm.add_bytes(vec![Opcode::FREE.into(), 1, 0, 0]);
+        let events = vm.run();
+        let expected = VMError::InvalidFree { pointer: 0 };
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::Crash { error: expected }
+        );
+    }
+
     #[test]
     fn test_opcode_inc_and_dec() {
         let mut vm = VM::new();
@@ -475,11 +1254,502 @@ mod tests {
         assert_eq!(vm.registers[2], 12)
     }
 
+    #[test]
+    fn test_division_by_zero() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 5]);
+        vm.add_bytes(vec![Opcode::DIV.into(), 0, 1, 2]);
+        let events = vm.run();
+        assert_eq!(
+            events.last().unwrap().event(),
+            &VMEventType::Crash {
+                error: VMError::DivisionByZero
+            }
+        );
+        assert_eq!(VMError::DivisionByZero.code(), 10);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps() {
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::ADD.into(),
+            0,
+            1,
+            2,
+            Opcode::MUL.into(),
+            0,
+            0,
+            3,
+            Opcode::DIV.into(),
+            4,
+            5,
+            6,
+        ];
+        vm.registers[0] = i32::MAX;
+        vm.registers[1] = 1;
+        vm.registers[4] = i32::MIN;
+        vm.registers[5] = -1;
+        vm.run_once();
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.registers[2], i32::MIN);
+        assert_eq!(vm.registers[3], 1);
+        assert_eq!(vm.registers[6], i32::MIN);
+        assert_eq!(vm.remainder, 0);
+    }
+
+    #[test]
+    fn test_opcode_rem() {
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::DIV.into(), 0, 1, 2, Opcode::REM.into(), 3, 0, 0];
+        vm.registers[0] = -25;
+        vm.registers[1] = 7;
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.registers[2], -3);
+        assert_eq!(vm.registers[3], -4);
+    }
+
+    #[test]
+    fn test_decoded_dispatch_matches_interpreter() {
+        let mut vm = VM::new_with_header();
+        // count $0 up to 10, with a DIV in the loop to touch the remainder
+        vm.add_bytes(vec![Opcode::LOAD.into(), 1, 0, 10]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 2, 0, 72]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 3, 0, 3]);
+        vm.add_bytes(vec![Opcode::INC.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::DIV.into(), 0, 3, 4]);
+        vm.add_bytes(vec![Opcode::LT.into(), 0, 1, 0]);
+        vm.add_bytes(vec![Opcode::JEQ.into(), 2, Opcode::HLT.into(), 0]);
+
+        let mut interpreted = vm.clone();
+        vm.run();
+        interpreted.run_interpreted();
+
+        assert_eq!(vm.registers[0], 10);
+        assert_eq!(vm.registers, interpreted.registers);
+        assert_eq!(vm.pc, interpreted.pc);
+        assert_eq!(vm.remainder, interpreted.remainder);
+        assert_eq!(vm.equal_flag, interpreted.equal_flag);
+    }
+
+    #[test]
+    fn test_truncated_instruction_stops() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::ADD.into(), 0]);
+        let mut interpreted = vm.clone();
+        vm.run();
+        interpreted.run_interpreted();
+        assert_eq!(vm.pc, VM::get_header_offset());
+        assert_eq!(interpreted.pc, VM::get_header_offset());
+    }
+
+    #[test]
+    fn test_ro_data_from_header() {
+        let mut vm = VM::new_with_header();
+        // 3 bytes of data, and the code starts after them
+        vm.program[4] = 3;
+        vm.program[8] = 3;
+        vm.add_bytes(vec![72, 105, 0]);
+        vm.add_bytes(vec![Opcode::PRTS.into(), 0, 0]);
+        vm.add_bytes(vec![Opcode::HLT.into()]);
+        let (output, capture) = Output::capture();
+        vm.set_output(output);
+        vm.run();
+        assert_eq!(vm.ro_data, vec![72, 105, 0]);
+        assert_eq!(vm.code_offset(), VM::get_header_offset() + 3);
+        assert_eq!(capture.take(), "Hi");
+
+        // data running past the end of the program, and a version from the future
+        for (offset, value) in [(8, 100), (PIE_VERSION_OFFSET, PIE_FORMAT_VERSION + 1)] {
+            let mut vm = VM::new_with_header();
+            vm.program[offset] = value;
+            let events = vm.run();
+            assert_eq!(
+                events.last().unwrap().event(),
+                &VMEventType::Crash {
+                    error: VMError::BadHeader
+                },
+                "{}",
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_unversioned_header() {
+        // images from before the version have their code right after the header
+        let mut vm = VM::new_with_header();
+        vm.program[PIE_VERSION_OFFSET] = 0;
+        vm.program[8] = 3;
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 7]);
+        vm.add_bytes(vec![Opcode::HLT.into()]);
+        vm.run();
+        assert_eq!(vm.code_offset(), VM::get_header_offset());
+        assert!(vm.ro_data.is_empty());
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 10]);
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::HLT.into(), 0, 0, 0]);
+        vm.float_registers[3] = 2.5;
+        vm.ro_data = vec![72, 105, 0];
+        vm.run();
+        vm.stack = vec![-1, 7];
+        vm.call_stack = vec![70];
+
+        let restored = VM::restore(&vm.snapshot()).unwrap();
+        assert_eq!(restored.id, vm.id);
+        assert_eq!(restored.pc, vm.pc);
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.float_registers, vm.float_registers);
+        assert_eq!(restored.program, vm.program);
+        assert_eq!(restored.heap.len(), 10);
+        assert_eq!(restored.heap_stats().used, vm.heap_stats().used);
+        assert_eq!(restored.ro_data, vm.ro_data);
+        assert_eq!(restored.stack, vm.stack);
+        assert_eq!(restored.call_stack, vm.call_stack);
+        assert_eq!(restored.events.len(), 3);
+        assert_eq!(restored.events, vm.events);
+    }
+
+    #[test]
+    fn test_restore_and_resume() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 2]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 1, 0, 3]);
+        vm.add_bytes(vec![Opcode::MUL.into(), 0, 1, 2]);
+        vm.add_bytes(vec![Opcode::HLT.into(), 0, 0, 0]);
+        vm.pc = VM::get_header_offset();
+        vm.run_once();
+        vm.run_once();
+
+        let mut restored = VM::restore(&vm.snapshot()).unwrap();
+        restored.resume();
+        assert_eq!(restored.registers[2], 6);
+    }
+
+    #[test]
+    fn test_restore_truncated_snapshot() {
+        let vm = VM::new_with_header();
+        let snapshot = vm.snapshot();
+        let result = VM::restore(&snapshot[..snapshot.len() - 1]);
+        assert!(matches!(result, Err(SnapshotError::Truncated)));
+    }
+
+    #[test]
+    fn test_restore_invalid_heap() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 10]);
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::HLT.into(), 0, 0, 0]);
+        vm.run();
+        let mut snapshot = vm.snapshot();
+        // the one block, at 0 and 10 bytes long, made to run to the end of memory
+        let mut block = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        block.extend([0; 8]);
+        block.extend([10, 0, 0, 0, 0, 0, 0, 0]);
+        let at = snapshot
+            .windows(block.len())
+            .position(|window| window == block.as_slice())
+            .unwrap();
+        snapshot[at + 16..at + 24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            VM::restore(&snapshot),
+            Err(SnapshotError::InvalidHeap)
+        ));
+    }
+
+    #[test]
+    fn test_tracer_records_register_writes() {
+        let mut vm = VM::new_with_header();
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 2]);
+        vm.add_bytes(vec![Opcode::LOAD.into(), 1, 0, 3]);
+        vm.add_bytes(vec![Opcode::ADD.into(), 0, 1, 2]);
+        vm.add_bytes(vec![Opcode::HLT.into(), 0, 0, 0]);
+        vm.set_tracer(Some(Tracer::ring_buffer(16)));
+        vm.set_profiler(Some(Profiler::new()));
+        vm.run();
+
+        let records = vm.tracer().unwrap().records();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[2].pc, VM::get_header_offset() + 8);
+        assert_eq!(records[2].opcode, Opcode::ADD);
+        assert_eq!(records[2].writes, vec![(2, 5)]);
+        assert!(records[3].writes.is_empty());
+
+        let profiler = vm.profiler().unwrap();
+        assert_eq!(profiler.total(), 4);
+        assert_eq!(profiler.opcode_count(Opcode::LOAD), 2);
+    }
+
+    #[test]
+    fn test_events_serialize_to_json() {
+        let event = VMEvent::new(
+            VMEventType::Crash {
+                error: VMError::HeapLimitExceeded {
+                    requested: 2000,
+                    limit: 1024,
+                },
+            },
+            Uuid::new_v4(),
+        );
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.starts_with(
+            r#"{"type":"Crash","error":{"kind":"HeapLimitExceeded","requested":2000,"limit":1024},"at":"#
+        ));
+        assert_eq!(serde_json::from_str::<VMEvent>(&json).unwrap(), event);
+    }
+
+    #[test]
+    fn test_subscribers_see_every_event() {
+        let mut vm = VM::new_with_header();
+        vm.registers[0] = 16;
+        vm.ro_data = vec![72, 105, 0];
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 1, 0]);
+        vm.add_bytes(vec![Opcode::YIELD.into(), 0, 0, 0]);
+        vm.add_bytes(vec![Opcode::PRTS.into(), 0, 0]);
+        vm.add_bytes(vec![Opcode::HLT.into()]);
+        vm.set_output(Output::capture().0);
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let subscriber = seen.clone();
+        vm.subscribe(move |event| subscriber.lock().unwrap().push(event.clone()));
+
+        let events = vm.run();
+        let kinds: Vec<_> = events.iter().map(|event| event.event().clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                VMEventType::Start,
+                VMEventType::HeapGrow { size: 16 },
+                VMEventType::Yield,
+                VMEventType::Syscall {
+                    name: "prts".to_string()
+                },
+                VMEventType::GracefulStop { code: 1 },
+            ]
+        );
+        assert_eq!(*seen.lock().unwrap(), events);
+        assert!(events.iter().all(|event| event.application_id() == vm.id()));
+
+        // a fork keeps the subscribers but starts over as a new VM
+        let mut fork = vm.fork();
+        assert!(fork.events().is_empty());
+        assert_ne!(fork.id(), vm.id());
+        fork.set_breakpoint(VM::get_header_offset() + 4);
+        fork.run();
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 11);
+        assert_eq!(
+            seen[7].event(),
+            &VMEventType::Breakpoint {
+                pc: VM::get_header_offset() + 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_negative_aloc_crashes() {
+        let mut vm = VM::new_with_header();
+        vm.registers[0] = -16;
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 0, 0]);
+        let events = vm.run();
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::Crash {
+                error: VMError::NegativeAllocation { requested: -16 }
+            }
+        );
+        assert!(vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut vm = VM::new_with_header();
+        vm.set_limits(VMLimits {
+            max_heap_bytes: Some(1024),
+            ..VMLimits::default()
+        });
+        vm.registers[0] = 1000;
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 1, 0]);
+        vm.add_bytes(vec![Opcode::ALOC.into(), 0, 2, 0]);
+        let events = vm.run();
+        let expected = VMError::HeapLimitExceeded {
+            requested: 2000,
+            limit: 1024,
+        };
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::Crash { error: expected }
+        );
+        assert_eq!(vm.heap.len(), 1000);
+    }
+
+    #[test]
+    fn test_instruction_limit_stops_infinite_loop() {
+        let mut vm = VM::new_with_header();
+        vm.set_limits(VMLimits {
+            max_instructions: Some(100),
+            ..VMLimits::default()
+        });
+        // jump back onto the load forever
+        vm.add_bytes(vec![Opcode::LOAD.into(), 0, 0, 64]);
+        vm.add_bytes(vec![Opcode::JMP.into(), 0, 0, 0]);
+        let events = vm.run();
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::Crash {
+                error: VMError::InstructionLimitExceeded { limit: 100 }
+            }
+        );
+        assert_eq!(vm.instructions_executed(), 100);
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let mut vm = VM::new_with_header();
+        vm.set_limits(VMLimits {
+            max_output_bytes: Some(8),
+            ..VMLimits::default()
+        });
+        vm.ro_data = vec![72, 101, 108, 108, 111, 0];
+        // PRTS only takes 3 bytes
+        vm.add_bytes(vec![Opcode::PRTS.into(), 0, 0]);
+        vm.add_bytes(vec![Opcode::PRTS.into(), 0, 0]);
+        let (output, capture) = Output::capture();
+        vm.set_output(output);
+        let events = vm.run();
+        assert_eq!(
+            events.last().unwrap().event,
+            VMEventType::Crash {
+                error: VMError::OutputLimitExceeded { limit: 8 }
+            }
+        );
+        // the first string made it out before the limit was hit
+        assert_eq!(capture.take(), "Hello");
+    }
+
+    #[test]
+    fn test_untrusted_bytecode() {
+        let crash = |code: Vec<u8>, ro_data: Vec<u8>| {
+            let mut results = vec![];
+            for interpreted in [false, true] {
+                let mut vm = VM::new_with_header();
+                vm.add_bytes(code.clone());
+                vm.ro_data = ro_data.clone();
+                let events = if interpreted {
+                    vm.run_interpreted()
+                } else {
+                    vm.run()
+                };
+                results.push(events.last().unwrap().event().clone());
+            }
+            assert_eq!(results[0], results[1]);
+            results.remove(0)
+        };
+        let error = |error| VMEventType::Crash { error };
+        assert_eq!(
+            crash(vec![Opcode::LOAD.into(), 200, 0, 1], vec![]),
+            error(VMError::InvalidRegister { register: 200 })
+        );
+        assert_eq!(
+            crash(vec![Opcode::ADD.into(), 40, 0, 1], vec![]),
+            error(VMError::InvalidRegister { register: 40 })
+        );
+        assert_eq!(
+            crash(vec![Opcode::PRTS.into(), 0, 5], vec![]),
+            error(VMError::UnterminatedString { offset: 5 })
+        );
+        assert_eq!(
+            crash(vec![Opcode::PRTS.into(), 0, 0], vec![72, 105]),
+            error(VMError::UnterminatedString { offset: 0 })
+        );
+        let jump_back = vec![Opcode::LOAD.into(), 0, 0, 200, Opcode::JMPB.into(), 0];
+        assert_eq!(
+            crash(jump_back, vec![]),
+            error(VMError::InvalidJump { by: 200 })
+        );
+        // JMPF of a negative number is a jump by nearly usize::MAX
+        let mut jump_forward = vec![Opcode::LOAD.into(), 0, 0, 1];
+        jump_forward.extend([Opcode::LOAD.into(), 1, 0, 2, Opcode::SUB.into(), 0, 1, 0]);
+        jump_forward.extend([Opcode::JMPF.into(), 0]);
+        assert_eq!(
+            crash(jump_forward, vec![]),
+            error(VMError::InvalidJump { by: -1 })
+        );
+
+        let mut vm = VM::new_with_header();
+        vm.registers[0] = i32::MAX;
+        vm.registers[1] = i32::MIN;
+        vm.add_bytes(vec![
+            Opcode::INC.into(),
+            0,
+            0,
+            0,
+            Opcode::DEC.into(),
+            1,
+            0,
+            0,
+        ]);
+        vm.add_bytes(vec![Opcode::HLT.into()]);
+        vm.run();
+        assert_eq!(vm.registers[0], i32::MIN);
+        assert_eq!(vm.registers[1], i32::MAX);
+    }
+
+    #[test]
+    fn test_limits_from_args() {
+        let limits = VMLimits::default()
+            .with_args(&[
+                "path.iasm",
+                "--max-heap",
+                "4096",
+                "--max-instructions",
+                "10",
+            ])
+            .unwrap();
+        assert_eq!(limits.max_heap_bytes, Some(4096));
+        assert_eq!(limits.max_instructions, Some(10));
+        assert_eq!(limits.max_output_bytes, None);
+
+        // flags only ever lower limits that are set
+        let configured = VMLimits {
+            max_heap_bytes: Some(100),
+            max_stack_depth: Some(8),
+            ..VMLimits::default()
+        };
+        let limits = configured
+            .with_args(&[
+                "--max-heap",
+                "4096",
+                "--max-stack",
+                "4",
+                "--max-output",
+                "9",
+            ])
+            .unwrap();
+        assert_eq!(limits.max_heap_bytes, Some(100));
+        assert_eq!(limits.max_stack_depth, Some(4));
+        assert_eq!(limits.max_output_bytes, Some(9));
+        assert_eq!(limits.max_instructions, None);
+        assert_eq!(configured.with_args(&[]).unwrap(), configured);
+
+        assert!(VMLimits::default().with_args(&["--max-heap"]).is_err());
+        assert!(VMLimits::default()
+            .with_args(&["--max-output", "lots"])
+            .is_err());
+    }
+
     #[test]
     fn test_opcode_prts() {
         let mut vm = VM::new();
         vm.ro_data
-            .append(&mut vec![72, 101, 108, 108, 101, '\n' as u8, 0]);
+            .append(&mut vec![72, 101, 108, 108, 101, b'\n', 0]);
         vm.program = vec![Opcode::PRTS.into(), 0, 0, 0];
         vm.run_once();
     }